@@ -40,7 +40,7 @@ pub struct SubtractionCompiler;
 
 impl Compiler for SubtractionCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let (lhs, rhs) = (node(), node());
         let mul = binary::<Mul>(rhs.clone(), super::constant(-1.));
         let add = binary::<Add>(lhs.clone(), mul.clone());
@@ -82,6 +82,7 @@ impl Compiler for SubtractionCompiler {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -89,6 +90,58 @@ impl Compiler for SubtractionCompiler {
     }
 }
 
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Neg;
+
+impl Operator for Neg {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp_data = get_vec(&tensors[0].0);
+        let (ind, val) = (
+            tensors[0].1.index_expression(),
+            tensors[0].1.valid_expression(),
+        );
+        let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
+        for (i, out) in data.iter_mut().enumerate() {
+            if val.exec_single_var(i) != 0 {
+                *out = -inp_data[ind.exec_single_var(i)];
+            }
+        }
+        vec![Tensor::new(data)]
+    }
+}
+
+/// Replace multiplications by -1 with a negation
+#[derive(Debug, Default)]
+pub struct NegationCompiler;
+
+impl Compiler for NegationCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let inp = node();
+        let mul = binary::<Mul>(inp.clone(), super::constant(-1.));
+        let mut s = mul.clone().search(graph);
+
+        while s.next_match() {
+            if s.check_no_delete(&[mul.id]) {
+                continue;
+            }
+            let mul = s.get(&mul);
+            let (a, a_edge) = graph
+                .graph
+                .edges_connecting(s.get(&inp), mul)
+                .next()
+                .map(|e| (e.source(), e.weight().as_data().unwrap()))
+                .unwrap();
+            let neg = graph.add_op(Neg).input(a, a_edge.1, a_edge.2).finish();
+            move_outgoing_edge(mul, neg, &mut graph.graph);
+            remap(mul, neg, &mut ids, graph);
+
+            graph.graph.remove_node(mul);
+            s.try_delete();
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Equal;
 
@@ -113,7 +166,7 @@ impl Operator for Equal {
             } else {
                 0.0
             };
-            data[i] = if a < b { 1. } else { 0. };
+            data[i] = if a == b { 1. } else { 0. };
         }
         vec![Tensor::new(data)]
     }
@@ -124,7 +177,7 @@ pub struct EqualCompiler;
 
 impl Compiler for EqualCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let one = super::constant(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<LessThan>(lhs.clone(), rhs.clone());
@@ -160,6 +213,7 @@ impl Compiler for EqualCompiler {
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(eq, equals, &mut graph.graph);
+            remap(eq, equals, &mut ids, graph);
 
             graph.graph.remove_node(eq);
             s.try_delete();
@@ -222,18 +276,12 @@ impl Compiler for GatherCompiler {
                 .as_data()
                 .unwrap()
                 .2;
-            let embed_dim = graph
-                .graph
-                .edges_connecting(s.get(&embedding), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2
-                .shape()[2]
-                .to_usize()
-                .unwrap();
+            // Only (batch, vocab, embed) lookups are gathers, other one-hot selections are left alone
+            let emb_dims = emb_shape.shape();
+            if emb_dims.len() != 3 {
+                continue;
+            }
+            let embed_dim = emb_dims[2].to_usize().unwrap();
 
             let gather = graph
                 .add_op(Gather { embed_dim })
@@ -247,6 +295,6 @@ impl Compiler for GatherCompiler {
     }
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
+pub(crate) fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
     tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
 }
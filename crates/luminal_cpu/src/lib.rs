@@ -14,14 +14,93 @@ use luminal::{
 
 // Ops and compilers specific to CPU execution
 
-pub type CPUCompiler = (
-    matmul::MatMulCompiler,
-    binary::SubtractionCompiler,
-    binary::EqualCompiler,
-    other::ARangeCompiler,
-    binary::GatherCompiler,
-    UnaryFusionCompiler,
-);
+/// Compile graphs to run on the CPU. Each fusion pass can be turned off, so when a fused op gives wrong results
+/// the passes can be bisected: `CPUCompiler { matmul: false, ..Default::default() }` leaves matmuls as a
+/// multiply and sum reduce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CPUCompiler {
+    pub matmul: bool,
+    pub subtraction: bool,
+    pub equal: bool,
+    pub arange: bool,
+    pub gather: bool,
+    pub negation: bool,
+    pub softmax: bool,
+    pub log_softmax: bool,
+    pub unary: bool,
+    pub contiguous: bool,
+}
+
+impl Default for CPUCompiler {
+    fn default() -> Self {
+        Self {
+            matmul: true,
+            subtraction: true,
+            equal: true,
+            arange: true,
+            gather: true,
+            negation: true,
+            softmax: true,
+            log_softmax: true,
+            unary: true,
+            contiguous: true,
+        }
+    }
+}
+
+impl CPUCompiler {
+    /// Run no fusions, leaving the primitive ops as they are
+    pub fn unfused() -> Self {
+        Self {
+            matmul: false,
+            subtraction: false,
+            equal: false,
+            arange: false,
+            gather: false,
+            negation: false,
+            softmax: false,
+            log_softmax: false,
+            unary: false,
+            contiguous: false,
+        }
+    }
+}
+
+impl Compiler for CPUCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        if self.matmul {
+            matmul::MatMulCompiler::default().compile(graph, &mut ids);
+        }
+        if self.subtraction {
+            binary::SubtractionCompiler.compile(graph, &mut ids);
+        }
+        if self.equal {
+            binary::EqualCompiler.compile(graph, &mut ids);
+        }
+        if self.arange {
+            other::ARangeCompiler.compile(graph, &mut ids);
+        }
+        if self.gather {
+            binary::GatherCompiler.compile(graph, &mut ids);
+        }
+        if self.negation {
+            binary::NegationCompiler.compile(graph, &mut ids);
+        }
+        if self.softmax {
+            other::SoftmaxCompiler.compile(graph, &mut ids);
+        }
+        if self.log_softmax {
+            other::LogSoftmaxCompiler.compile(graph, &mut ids);
+        }
+        if self.unary {
+            UnaryFusionCompiler.compile(graph, &mut ids);
+        }
+        if self.contiguous {
+            other::ContiguousCompiler.compile(graph, &mut ids);
+        }
+    }
+}
 
 pub(crate) fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
@@ -190,4 +269,85 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_equal() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let b = cx.tensor::<R1<4>>().set(vec![1., 3., 2., 4.]);
+        let mut c = a.equals(b).retrieve();
+
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute();
+        assert_exact(&c.data(), &[1., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn test_neg() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1.5, -2., 0.]);
+        let mut b = (-a).retrieve();
+        let mut c = (0. - a).retrieve();
+
+        cx.compile(CPUCompiler::default(), (&mut b, &mut c));
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::binary::Neg>()));
+        cx.execute();
+        assert_exact(&b.data(), &[-1.5, 2., 0.]);
+        assert_exact(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_deterministic_sum_reduce() {
+        let data = random_vec(4 * 10_000);
+        let mut cx = Graph::new();
+        cx.deterministic = true;
+        let a = cx.tensor::<R2<4, 10_000>>().set(data.clone());
+        let mut b = a.sum_reduce::<_, Axis<1>>().retrieve();
+
+        cx.compile(CPUCompiler::default(), &mut b);
+        cx.execute();
+        let first = b.data();
+        b.drop();
+        cx.execute();
+        assert_eq!(
+            b.data().iter().map(|f| f.to_bits()).collect::<Vec<_>>(),
+            first.iter().map(|f| f.to_bits()).collect::<Vec<_>>()
+        );
+        // Reductions on the CPU already add each row up in order
+        let sequential = data
+            .chunks(10_000)
+            .map(|row| row.iter().fold(0., |acc, x| acc + x))
+            .collect::<Vec<f32>>();
+        assert_exact(&first, &sequential);
+    }
+
+    #[test]
+    fn test_disable_matmul_fusion() {
+        let (a_data, b_data) = (random_vec(6), random_vec(12));
+        let build = |compiler: CPUCompiler| {
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+            let b = cx.tensor::<R2<3, 4>>().set(b_data.clone());
+            let mut c = a.matmul(b).retrieve();
+            cx.compile(compiler, &mut c);
+            cx.execute();
+            (cx.op_histogram(), c.data())
+        };
+
+        let (fused_ops, fused) = build(CPUCompiler::default());
+        assert_eq!(fused_ops.get("MatMul2D"), Some(&1));
+        assert_eq!(fused_ops.get("Mul"), None);
+
+        let (ops, unfused) = build(CPUCompiler {
+            matmul: false,
+            ..Default::default()
+        });
+        assert_eq!(ops.get("MatMul2D"), None);
+        assert_eq!(ops.get("Mul"), Some(&1));
+        assert_eq!(ops.get("SumReduce"), Some(&1));
+        assert_close(&fused, &unfused);
+    }
 }
@@ -4,7 +4,7 @@ use luminal::{
 };
 use rustc_hash::FxHashMap;
 
-use super::binary::Sub;
+use super::binary::{get_vec, Sub};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ARange {
@@ -71,3 +71,340 @@ impl Compiler for ARangeCompiler {
         }
     }
 }
+
+/// Densely repack a permuted tensor with a strided copy instead of evaluating index expressions per element
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CPUContiguous;
+
+impl Operator for CPUContiguous {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1;
+        // Sliced, padded or expanded inputs need the full index expressions
+        if shape.is_sliced() || shape.is_padded() || shape.fake.iter().any(|f| *f) {
+            return Contiguous.process(inp);
+        }
+        let inp_data = get_vec(&inp[0].0);
+        let dims = shape.shape_usize();
+        let strides = shape
+            .strides()
+            .into_iter()
+            .map(|s| s.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let n_elements = dims.iter().product::<usize>();
+        let mut out_data = Vec::with_capacity(n_elements);
+        let mut index = vec![0; dims.len()];
+        for _ in 0..n_elements {
+            out_data.push(
+                inp_data[index
+                    .iter()
+                    .zip(&strides)
+                    .map(|(i, s)| i * s)
+                    .sum::<usize>()],
+            );
+            // Step to the next logical index, carrying into outer dimensions
+            for (ind, dim) in index.iter_mut().zip(&dims).rev() {
+                *ind += 1;
+                if *ind < *dim {
+                    break;
+                }
+                *ind = 0;
+            }
+        }
+        vec![Tensor::new(out_data)]
+    }
+}
+
+/// Replace generic contiguous ops with the strided CPU version
+#[derive(Debug, Default)]
+pub struct ContiguousCompiler;
+
+impl Compiler for ContiguousCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for id in graph.graph.node_indices().collect::<Vec<_>>() {
+            let op = graph.graph.node_weight_mut(id).unwrap();
+            if op.as_any().is::<Contiguous>() {
+                *op = Box::new(CPUContiguous);
+            }
+        }
+    }
+}
+
+/// Softmax along an axis, computed in one pass over each row
+#[derive(Debug, Clone, PartialEq)]
+pub struct CPUSoftmax(pub usize);
+
+impl Operator for CPUSoftmax {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>();
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>();
+        let dim_size = sh[self.0];
+        let inp_data = get_vec(&inp[0].0);
+        let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut out_data = vec![0.; front_size * dim_size * back_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let row = |k: usize| i * dim_size * back_size + k * back_size + j;
+                let mut max = f32::NEG_INFINITY;
+                for k in 0..dim_size {
+                    let idx = row(k);
+                    out_data[idx] = if val.exec_single_var(idx) != 0 {
+                        inp_data[ind.exec_single_var(idx)]
+                    } else {
+                        0.0
+                    };
+                    max = max.max(out_data[idx]);
+                }
+                let mut sum = 0.;
+                for k in 0..dim_size {
+                    out_data[row(k)] = (out_data[row(k)] - max).exp();
+                    sum += out_data[row(k)];
+                }
+                for k in 0..dim_size {
+                    out_data[row(k)] /= sum;
+                }
+            }
+        }
+        vec![Tensor::new(out_data)]
+    }
+}
+
+/// Replace the softmax pattern with a fused op
+#[derive(Debug, Default)]
+pub struct SoftmaxCompiler;
+
+impl Compiler for SoftmaxCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        // mul(exp2(sub(x, max_reduce(x)) * 1/ln(2)), recip(sum_reduce(exp2(...))))
+        let max_reduce = op::<MaxReduce>();
+        let sub = unary::<Sub>(max_reduce.clone());
+        let exp2 = unary::<Exp2>(binary::<Mul>(
+            sub.clone(),
+            super::constant(1.0 / f32::ln(2.)),
+        ));
+        let sum_reduce = unary::<SumReduce>(exp2.clone());
+        let mul = unary::<Mul>(unary::<Recip>(sum_reduce.clone()));
+        let mut s = mul.clone().search(graph);
+
+        while s.next_match() {
+            if s.check_no_delete(&[mul.id]) {
+                continue;
+            }
+            let (max_reduce, mul) = (s.get(&max_reduce), s.get(&mul));
+            let src = graph.get_sources(max_reduce)[0];
+            let sub_srcs = graph.get_sources(s.get(&sub));
+            let axis = graph.get_op::<MaxReduce>(max_reduce).0;
+            // Make sure the same tensor is being normalized along the same axis it was reduced on
+            if sub_srcs[0].0 != src.0
+                || sub_srcs[0].2 != src.2
+                || sub_srcs[1].0 != max_reduce
+                || graph.get_op::<SumReduce>(s.get(&sum_reduce)).0 != axis
+                || !graph
+                    .get_sources(mul)
+                    .iter()
+                    .any(|(n, _, _)| *n == s.get(&exp2))
+            {
+                continue;
+            }
+            let softmax = graph
+                .add_op(CPUSoftmax(axis))
+                .input(src.0, src.1, src.2)
+                .finish();
+            move_outgoing_edge(mul, softmax, graph);
+            remap(mul, softmax, &mut ids, graph);
+
+            graph.remove_node(mul);
+            s.try_delete();
+        }
+    }
+}
+
+/// Log softmax along an axis, computed in one pass over each row as `x - max - ln(sum(exp(x - max)))`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CPULogSoftmax(pub usize);
+
+impl Operator for CPULogSoftmax {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>();
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>();
+        let dim_size = sh[self.0];
+        let inp_data = get_vec(&inp[0].0);
+        let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut out_data = vec![0.; front_size * dim_size * back_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let row = |k: usize| i * dim_size * back_size + k * back_size + j;
+                let mut max = f32::NEG_INFINITY;
+                for k in 0..dim_size {
+                    let idx = row(k);
+                    out_data[idx] = if val.exec_single_var(idx) != 0 {
+                        inp_data[ind.exec_single_var(idx)]
+                    } else {
+                        0.0
+                    };
+                    max = max.max(out_data[idx]);
+                }
+                let mut sum = 0.;
+                for k in 0..dim_size {
+                    out_data[row(k)] -= max;
+                    sum += out_data[row(k)].exp();
+                }
+                let log_sum = sum.ln();
+                for k in 0..dim_size {
+                    out_data[row(k)] -= log_sum;
+                }
+            }
+        }
+        vec![Tensor::new(out_data)]
+    }
+}
+
+/// Replace the log softmax pattern with a fused op
+#[derive(Debug, Default)]
+pub struct LogSoftmaxCompiler;
+
+impl Compiler for LogSoftmaxCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        // sub(sub(x, max_reduce(x)), log2(sum_reduce(exp2(sub(...) * 1/ln(2)))) * ln(2))
+        let max_reduce = op::<MaxReduce>();
+        let sub = unary::<Sub>(max_reduce.clone());
+        let exp2 = unary::<Exp2>(binary::<Mul>(
+            sub.clone(),
+            super::constant(1.0 / f32::ln(2.)),
+        ));
+        let sum_reduce = unary::<SumReduce>(exp2.clone());
+        let ln = binary::<Mul>(
+            unary::<Log2>(sum_reduce.clone()),
+            super::constant(f32::ln(2.)),
+        );
+        let out = unary::<Sub>(ln.clone());
+        let mut s = out.clone().search(graph);
+
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                continue;
+            }
+            let (max_reduce, sub, out) = (s.get(&max_reduce), s.get(&sub), s.get(&out));
+            let src = graph.get_sources(max_reduce)[0];
+            let sub_srcs = graph.get_sources(sub);
+            let out_srcs = graph.get_sources(out);
+            let axis = graph.get_op::<MaxReduce>(max_reduce).0;
+            // Make sure the same tensor is being normalized along the same axis it was reduced on
+            if sub_srcs[0].0 != src.0
+                || sub_srcs[0].2 != src.2
+                || sub_srcs[1].0 != max_reduce
+                || graph.get_op::<SumReduce>(s.get(&sum_reduce)).0 != axis
+                || out_srcs[0].0 != sub
+                || out_srcs[1].0 != s.get(&ln)
+            {
+                continue;
+            }
+            let log_softmax = graph
+                .add_op(CPULogSoftmax(axis))
+                .input(src.0, src.1, src.2)
+                .finish();
+            move_outgoing_edge(out, log_softmax, graph);
+            remap(out, log_softmax, &mut ids, graph);
+
+            graph.remove_node(out);
+            s.try_delete();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use super::{CPUContiguous, CPULogSoftmax, CPUSoftmax};
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_contiguous_permute() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<2, 3, 4>>()
+            .set((0..24).map(|i| i as f32).collect::<Vec<_>>());
+        let mut b = a
+            .permute::<R3<4, 2, 3>, LAxes3<2, 0, 1>>()
+            .contiguous()
+            .retrieve();
+        cx.execute();
+        let unoptimized_b = b.data();
+        b.drop();
+
+        cx.compile(CPUCompiler::default(), &mut b);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<CPUContiguous>()));
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(
+            (0..24).map(|i| i as f32).collect::<Vec<_>>(),
+            (DConst::<2>, DConst::<3>, DConst::<4>),
+        );
+        let d_b = d_a.permute::<Rank3<4, 2, 3>, DAxes3<2, 0, 1>>();
+        assert_exact(&b.data(), &d_b.as_vec());
+        assert_exact(&b.data(), &unoptimized_b);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let mut cx = Graph::new();
+        let data = random_vec(24);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let mut b = a.softmax::<LAxis<2>>().retrieve();
+        let mut c = a.softmax::<LAxis<1>>().retrieve();
+
+        cx.compile(CPUCompiler::default(), (&mut b, &mut c));
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<CPUSoftmax>())
+                .count(),
+            2
+        );
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(&b.data(), &d_a.clone().softmax::<DAxis<2>>().as_vec());
+        assert_close(&c.data(), &d_a.softmax::<DAxis<1>>().as_vec());
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let mut cx = Graph::new();
+        let mut data = random_vec(32);
+        // Large magnitudes overflow exp unless the max is taken out first
+        for (i, d) in data[8..16].iter_mut().enumerate() {
+            *d = 1000. * (i as f32 - 4.);
+        }
+        let a = cx.tensor::<R2<4, 8>>().set(data.clone());
+        let mut b = a.log_softmax::<LAxis<1>>().retrieve();
+        cx.execute();
+        let unfused = b.data();
+        b.drop();
+
+        cx.compile(CPUCompiler::default(), &mut b);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<CPULogSoftmax>()));
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(data, (DConst::<4>, DConst::<8>));
+        let d_b = d_a.log_softmax::<DAxis<1>>().as_vec();
+        assert!(b.data().iter().all(|i| i.is_finite()));
+        assert_close(&b.data(), &d_b);
+        assert_close(&unfused, &d_b);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{any::Any, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchConfig};
 
 use luminal::{
     op::*,
@@ -9,16 +9,18 @@ use luminal::{
 use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
+    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, idx_guard,
+    input_dyn_dims,
     other::CudaARange,
-    prim::{CudaAdd, CudaCopyToDevice, CudaLessThan, CudaMul, CudaSumReduce},
-    render_dyn_dim_inputs, CudaData, CudaFloat,
+    prim::{CudaAdd, CudaCopyToDevice, CudaLessThan, CudaMul},
+    reduce, render_dyn_dim_inputs, CudaFloat, LaunchOnStream, OpStream,
 };
 
 #[derive(Clone)]
 pub struct CudaSub<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
@@ -36,12 +38,13 @@ impl<T: CudaFloat> CudaSub<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!(
             "
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         out[idx] =
             (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}])
             - (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
@@ -50,6 +53,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -63,7 +67,7 @@ impl<T: CudaFloat> Operator for CudaSub<T> {
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -74,14 +78,21 @@ impl<T: CudaFloat> Operator for CudaSub<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("input0 - input1".to_string()));
         }
@@ -94,7 +105,7 @@ pub struct SubtractionCompiler<T: CudaFloat>(PhantomData<T>);
 
 impl<T: CudaFloat> Compiler for SubtractionCompiler<T> {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let dev = CudaDevice::new(0).unwrap();
         let (lhs, rhs) = (node(), node());
         let mul = binary::<CudaMul<T>>(rhs.clone(), constant::<T>(-1.));
@@ -140,6 +151,7 @@ impl<T: CudaFloat> Compiler for SubtractionCompiler<T> {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -147,10 +159,251 @@ impl<T: CudaFloat> Compiler for SubtractionCompiler<T> {
     }
 }
 
+/// Fused `a * b + c`, computed with a single rounding using fmaf
+#[derive(Clone)]
+pub struct CudaMulAdd<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaMulAdd);
+
+impl<T: CudaFloat> CudaMulAdd<T> {
+    pub fn new(
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        c_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (c_idx, c_valid) = get_idx_valid_exps(c_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape, c_shape]);
+        let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, const {type_name} *inp_c, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    {guard}
+        float a_val = ({a_valid}) == 0 ? 0.0 : (float)inp_a[{a_idx}];
+        float b_val = ({b_valid}) == 0 ? 0.0 : (float)inp_b[{b_idx}];
+        float c_val = ({c_valid}) == 0 ? 0.0 : (float)inp_c[{c_idx}];
+        out[idx] = ({type_name})fmaf(a_val, b_val, c_val);
+    }}
+}}");
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMulAdd<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let c = get_buffer_from_tensor::<T>(&tensors[2].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            a.as_kernel_param(),
+            b.as_kernel_param(),
+            c.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("fmaf(input0, input1, input2)".to_string()));
+        }
+        None
+    }
+}
+
+/// Fuse a multiply feeding into an add into a single fma kernel
+#[derive(Debug, Default)]
+pub struct MulAddCompiler<T: CudaFloat>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for MulAddCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        let (mul, addend) = (op::<CudaMul<T>>(), node());
+        let add = binary::<CudaAdd<T>>(mul.clone(), addend.clone());
+        let mut s = add.clone().search(graph);
+        while s.next_match() {
+            let (mul, addend, add) = (s.get(&mul), s.get(&addend), s.get(&add));
+            if mul == addend || graph.no_delete.contains(&mul) || graph.get_dests(mul).len() != 1 {
+                continue;
+            }
+            // The product must be read as-is, so each output element lines up with the product it's made from
+            let mul_shape = graph
+                .edges_connecting(mul, add)
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            if mul_shape.is_reshaped() {
+                continue;
+            }
+            let (_, c_out, c_shape) = graph
+                .edges_connecting(addend, add)
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            let srcs = graph.get_sources(mul);
+            let (a, a_out, a_shape) = srcs[0];
+            let (b, b_out, b_shape) = srcs[1];
+            let fma = graph
+                .add_op(CudaMulAdd::<T>::new(
+                    a_shape,
+                    b_shape,
+                    c_shape,
+                    dev.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(a, a_out, a_shape)
+                .input(b, b_out, b_shape)
+                .input(addend, c_out, c_shape)
+                .finish();
+            move_outgoing_edge(add, fma, &mut graph.graph);
+            remap(add, fma, &mut ids, graph);
+            graph.graph.remove_node(add);
+            s.try_delete();
+        }
+    }
+}
+
+/// Picks `b` where `cond` is nonzero and `c` elsewhere
+#[derive(Clone)]
+pub struct CudaSelect<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaSelect);
+
+impl<T: CudaFloat> CudaSelect<T> {
+    pub fn new(
+        cond_shape: ShapeTracker,
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (cond_idx, cond_valid) = get_idx_valid_exps(cond_shape);
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[cond_shape, a_shape, b_shape]);
+        let type_name = T::type_name();
+        let guard = idx_guard(cond_shape.n_elements(), "numel");
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_cond, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    {guard}
+        float cond = ({cond_valid}) == 0 ? 0.0 : (float)inp_cond[{cond_idx}];
+        if (cond != 0.0) {{
+            out[idx] = ({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}];
+        }} else {{
+            out[idx] = ({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}];
+        }}
+    }}
+}}");
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSelect<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let cond = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let a = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[2].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            cond.as_kernel_param(),
+            a.as_kernel_param(),
+            b.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("(input0 != 0.0 ? input1 : input2)".to_string()));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct CudaEqual<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
@@ -168,12 +421,13 @@ impl<T: CudaFloat> CudaEqual<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!(
             "
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         {type_name} a_val = ({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}];
         {type_name} b_val = ({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}];
         out[idx] = ({type_name})(a_val == b_val);
@@ -182,6 +436,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -195,7 +450,7 @@ impl<T: CudaFloat> Operator for CudaEqual<T> {
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -206,14 +461,21 @@ impl<T: CudaFloat> Operator for CudaEqual<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("(float)(input0 == input1)".to_string()));
         }
@@ -282,13 +544,15 @@ impl<T: CudaFloat> Compiler for EqualCompiler<T> {
 pub struct CudaGather<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     pub embed_dim: usize,
+    pub vocab_size: usize,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaGather);
 
 impl<T: CudaFloat> CudaGather<T> {
-    pub fn new(device: Arc<CudaDevice>, embed_dim: usize) -> Self {
+    pub fn new(device: Arc<CudaDevice>, embed_dim: usize, vocab_size: usize) -> Self {
         let type_name = T::type_name();
         let code = format!("
 #include \"cuda_fp16.h\"
@@ -302,7 +566,9 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *weights
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             embed_dim,
+            vocab_size,
             _phantom: Default::default(),
         }
     }
@@ -313,19 +579,29 @@ impl<T: CudaFloat> Operator for CudaGather<T> {
         // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
         let indexes = inputs[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
         let weights = get_buffer_from_tensor::<T>(&inputs[1].0);
+        // The kernel reads rows directly, so bad indexes would read past the weights
+        for &index in indexes {
+            assert!(
+                index >= 0.0 && (index as usize) < self.vocab_size,
+                "Gather index {index} is out of range for {} embeddings",
+                self.vocab_size
+            );
+        }
 
-        let mut indexes_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
-        self.device
-            .htod_copy_into(indexes.clone(), &mut indexes_buffer)
-            .unwrap();
+        let mut indexes_buffer = unsafe {
+            self.stream
+                .alloc_scratch::<f32>(&self.device, indexes.len())
+        };
+        self.stream
+            .htod_copy_into(&self.device, indexes.clone(), &mut indexes_buffer);
         let mut out = self
-            .device
-            .alloc_zeros::<T>(indexes.len() * self.embed_dim)
-            .unwrap();
+            .stream
+            .alloc_zeros::<T>(&self.device, indexes.len() * self.embed_dim);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig {
                         grid_dim: (
                             indexes.len().div_ceil(16) as u32,
@@ -346,7 +622,14 @@ impl<T: CudaFloat> Operator for CudaGather<T> {
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -355,14 +638,14 @@ pub struct GatherCompiler<T: CudaFloat>(PhantomData<T>);
 
 impl<T: CudaFloat> Compiler for GatherCompiler<T> {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let dev = CudaDevice::new(0).unwrap();
         let indexes = node();
         let ind_copy = unary::<CudaCopyToDevice<T>>(indexes.clone());
         let equal = binary::<CudaEqual<T>>(op::<CudaARange<T>>(), ind_copy.clone());
         let embeddings = node();
         let mul = binary::<CudaMul<T>>(embeddings.clone(), equal.clone());
-        let sum_reduce = unary::<CudaSumReduce<T>>(mul.clone());
+        let sum_reduce = mul.clone().connect(reduce::<T>(ReduceOp::Sum));
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
             if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
@@ -376,7 +659,16 @@ impl<T: CudaFloat> Compiler for GatherCompiler<T> {
                 .as_data()
                 .unwrap()
                 .2;
-            let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
+            // Only (batch, vocab, embed) lookups are gathers, other one-hot selections are left alone
+            let emb_dims = emb_shape.shape();
+            if emb_dims.len() != 3 {
+                continue;
+            }
+            let (Some(vocab_size), Some(embed_dim)) =
+                (emb_dims[1].to_usize(), emb_dims[2].to_usize())
+            else {
+                continue;
+            };
             let index_shape = graph
                 .edges_connecting(s.get(&indexes), s.get(&ind_copy))
                 .next()
@@ -386,11 +678,12 @@ impl<T: CudaFloat> Compiler for GatherCompiler<T> {
                 .unwrap()
                 .2;
             let gather = graph
-                .add_op(CudaGather::<T>::new(dev.clone(), embed_dim))
+                .add_op(CudaGather::<T>::new(dev.clone(), embed_dim, vocab_size))
                 .input(s.get(&indexes), 0, index_shape)
                 .input(s.get(&embeddings), 0, emb_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
         }
@@ -1,4 +1,4 @@
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchConfig};
 use regex::Regex;
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::{any::Any, fmt::Debug, iter::once, marker::PhantomData, mem::size_of, sync::Arc};
@@ -10,8 +10,8 @@ use luminal::prelude::{
 };
 
 use crate::{
-    compile_and_load_kernel, expr_to_cuda_string, get_buffer_from_tensor, prim::CudaConstant,
-    CudaData, CudaFloat,
+    compile_and_load_kernel, expr_to_cuda_string, get_buffer_from_tensor, idx_guard,
+    prim::CudaConstant, CudaFloat, LaunchOnStream, OpStream,
 };
 
 use super::{input_dyn_dims, render_dyn_dim_inputs};
@@ -233,6 +233,7 @@ impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
                         dyn_chars: vec![],
                         subexpressions: subexpressions_b.clone(),
                         device: device.clone(),
+                        stream: Default::default(),
                         output_buffer_sizes,
                         _phantom: Default::default(),
                     })
@@ -413,12 +414,16 @@ impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
             }
 
             let (dyn_chars, rendered) = render_dyn_dim_inputs(&shapes_used);
+            let guard = idx_guard(
+                op.output_buffer_sizes[0].clone() / std::mem::size_of::<T>(),
+                "n_elements",
+            );
             let kernel = format!(
                 "
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({} {type_name}* out, const int n_elements{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < n_elements) {{
+    {guard}
         {}
         out[idx] = ({type_name})({});
     }}
@@ -448,6 +453,7 @@ pub struct FusedElementwiseOp<T> {
     dyn_chars: Vec<char>,
     subexpressions: Vec<(String, ShapeTracker)>,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     output_buffer_sizes: Vec<BigExpression>,
     _phantom: PhantomData<T>,
 }
@@ -463,7 +469,7 @@ impl<T: CudaFloat> Operator for FusedElementwiseOp<T> {
         let out_size =
             self.output_buffer_sizes[0].exec(dyn_map).unwrap() / std::mem::size_of::<T>();
         let out_size_int = out_size as i32;
-        let out = self.device.alloc_zeros::<T>(out_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, out_size);
 
         let mut params = vec![];
         for (buf, _) in &tensors {
@@ -478,14 +484,21 @@ impl<T: CudaFloat> Operator for FusedElementwiseOp<T> {
             self.kernel
                 .clone()
                 .unwrap()
-                .launch(LaunchConfig::for_num_elems(out_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(out_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::<String>::default());
         }
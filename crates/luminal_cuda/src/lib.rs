@@ -5,6 +5,10 @@ mod other;
 mod prim;
 mod quantized;
 mod unary;
+pub use other::{
+    BufferReuseCompiler, PinnedCopyCompiler, ReusedOutputs, SinkCopiesCompiler, StreamCompiler,
+};
+pub use prim::CudaCopyFromDevice;
 pub use quantized::*;
 
 #[cfg(test)]
@@ -13,13 +17,26 @@ mod tests;
 
 use itertools::Itertools;
 use luminal_cudarc::{
-    driver::{CudaDevice, CudaFunction, CudaSlice, DeviceRepr},
+    driver::{
+        result, sys, CudaDevice, CudaFunction, CudaSlice, CudaStream, DevicePtr, DeviceRepr,
+        DeviceSlice, DriverError, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+    },
     nvrtc::{compile_ptx_with_opts, CompileOptions},
 };
 use prim::CudaConstant;
 use rustc_hash::FxHashMap;
 
-use std::{collections::hash_map::DefaultHasher, ffi::c_void, fmt::Write, hash::Hasher, sync::Arc};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::hash_map::DefaultHasher,
+    ffi::c_void,
+    fmt::Write,
+    hash::Hasher,
+    mem::ManuallyDrop,
+    rc::{Rc, Weak},
+    sync::Arc,
+};
 
 use luminal::{op::InputTensor, prelude::*};
 
@@ -29,20 +46,41 @@ pub type CudaCompiler<T> = (
     SpecialOpsCompiler<T>,
     other::CopyCompiler<T>,
     elementwise_fusion::ElementwiseFusionCompiler<T>,
+    other::SinkCopiesCompiler<T>,
 );
 
+/// Generic simplifications followed by [CudaCompiler], in the order they need to run. See [default_cuda_pipeline]
+pub type CudaPipeline<T> = (GenericCompiler, CudaCompiler<T>);
+
+/// Every pass needed to run a graph on CUDA, in the right order: generic simplifications first so the CUDA patterns
+/// see a cleaned up graph, then lowering to CUDA ops, then the CUDA specific fusions
+pub fn default_cuda_pipeline<T: CudaFloat>() -> CudaPipeline<T> {
+    Default::default()
+}
+
 /// Compiler to replace cuda primops with specialized variants
 pub type SpecialOpsCompiler<T> = (
-    binary::SubtractionCompiler<T>,
+    (
+        unary::ClampCompiler<T>,
+        unary::CudaSigmoidCompiler<T>,
+        unary::CudaTanhCompiler<T>,
+        binary::SubtractionCompiler<T>,
+    ),
     binary::EqualCompiler<T>,
     other::ARangeCompiler<T>,
+    other::ConcatCompiler<T>,
     binary::GatherCompiler<T>,
     unary::CudaExpCompiler<T>,
-    unary::CudaCosCompiler<T>,
+    (unary::CudaCosCompiler<T>, unary::CudaSinCosCompiler<T>),
     unary::MeanReduceCompiler<T>,
     unary::StdNormCompiler<T>,
     unary::SoftmaxCompiler<T>,
-    matmul::MatMulCompiler<T>,
+    (matmul::MatMulCompiler<T>, matmul::MatMulBiasCompiler<T>),
+    (
+        unary::CudaNegCompiler<T>,
+        binary::MulAddCompiler<T>,
+        unary::ScalarCompiler<T>,
+    ),
 );
 
 pub trait CudaFloat:
@@ -74,15 +112,39 @@ impl CudaFloat for f32 {
         "float"
     }
 }
+
+/// A buffer on the device. Buffers an op allocated while reusing its outputs go back to that op once dropped,
+/// see [BufferReuseCompiler]
 #[derive(Debug)]
-pub struct CudaData<T>(pub CudaSlice<T>);
+pub struct CudaData<T>(pub ManuallyDrop<CudaSlice<T>>, Weak<OutputCache>);
+
+impl<T> CudaData<T> {
+    /// Wrap a buffer that's freed once dropped
+    pub fn new(buffer: CudaSlice<T>) -> Self {
+        Self(ManuallyDrop::new(buffer), Weak::new())
+    }
+}
 
 impl<T: DeviceRepr> Clone for CudaData<T> {
     fn clone(&self) -> Self {
-        Self(self.0.try_clone().unwrap())
+        Self::new(self.0.try_clone().unwrap())
     }
 }
 
+impl<T> Drop for CudaData<T> {
+    fn drop(&mut self) {
+        let buffer = unsafe { ManuallyDrop::take(&mut self.0) };
+        if let (Some(cache), false) = (self.1.upgrade(), buffer.is_empty()) {
+            let (device, bytes) = (buffer.device(), buffer.num_bytes());
+            let buffer = unsafe { device.upgrade_device_ptr(buffer.leak(), bytes) };
+            cache.borrow_mut().push(buffer);
+        }
+    }
+}
+
+/// Buffers an op's outputs were in, handed back once dropped for the op's next execution to write into
+type OutputCache = RefCell<Vec<CudaSlice<u8>>>;
+
 impl<T: CudaFloat> Data for CudaData<T> {
     fn as_any(&self) -> &dyn std::any::Any {
         self
@@ -91,6 +153,11 @@ impl<T: CudaFloat> Data for CudaData<T> {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn to_host_vec(&self) -> Option<Vec<f32>> {
+        let buf = self.0.device().dtoh_sync_copy(&*self.0).ok()?;
+        Some(buf.into_iter().map(T::to_f32).collect())
+    }
 }
 
 impl CudaFloat for f16 {
@@ -123,9 +190,23 @@ impl CudaFloat for u8 {
     }
 }
 
+/// Render a float as a CUDA expression. Non-finite values don't have a literal form, so they're built from their bits
+fn float_literal(f: f32) -> String {
+    if f.is_nan() {
+        "__int_as_float(0x7fffffff)".to_string()
+    } else if f.is_infinite() {
+        format!(
+            "{}__int_as_float(0x7f800000)",
+            if f < 0. { "-" } else { "" }
+        )
+    } else {
+        format!("{f:?}")
+    }
+}
+
 fn expr_to_cuda_string(expr: &BigExpression) -> String {
     let mut symbols = vec![];
-    for term in expr.terms.clone() {
+    for term in expr.clone().lower_pow2_div_mod().terms {
         let new_symbol = match term {
             Term::Num(n) => n.to_string(),
             Term::Var(c) => {
@@ -145,6 +226,16 @@ fn expr_to_cuda_string(expr: &BigExpression) -> String {
                 symbols.pop().unwrap(),
                 symbols.pop().unwrap()
             ),
+            Term::FloorMod => {
+                let (a, b) = (symbols.pop().unwrap(), symbols.pop().unwrap());
+                format!("((({a}) % ({b}) + ({b})) % ({b}))")
+            }
+            Term::Select => format!(
+                "(({}) != 0 ? ({}) : ({}))",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
             _ => format!(
                 "({}{term:?}{})",
                 symbols.pop().unwrap(),
@@ -163,6 +254,32 @@ fn get_idx_valid_exps(shape: ShapeTracker) -> (String, String) {
     )
 }
 
+/// Like [get_idx_valid_exps], but with the dynamic dimensions currently bound in `dyn_map` folded in as constants.
+/// Also returns the values folded in, since the kernel needs to be regenerated if they change
+fn get_bound_idx_valid_exps(
+    shape: ShapeTracker,
+    dyn_map: &FxHashMap<char, usize>,
+) -> (String, String, Vec<(char, usize)>) {
+    let bound = render_dyn_dim_inputs(&[shape])
+        .0
+        .into_iter()
+        .filter_map(|c| dyn_map.get(&c).map(|v| (c, *v)))
+        .collect();
+    (
+        expr_to_cuda_string(
+            &shape
+                .index_expression()
+                .replace_constant_folds_in_kernel(dyn_map),
+        ),
+        expr_to_cuda_string(
+            &shape
+                .valid_expression()
+                .replace_constant_folds_in_kernel(dyn_map),
+        ),
+        bound,
+    )
+}
+
 fn render_dyn_dim_inputs(shapes: &[ShapeTracker]) -> (Vec<char>, String) {
     let symbols: Vec<char> = shapes
         .iter()
@@ -207,6 +324,30 @@ where
     n
 }
 
+/// Select a [CudaReduce](prim::CudaReduce) of the given [ReduceOp](luminal::op::ReduceOp)
+pub fn reduce<T: CudaFloat>(reduce_op: luminal::op::ReduceOp) -> SelectGraph {
+    let mut n = op::<prim::CudaReduce<T>>();
+    n.check(move |o, _| {
+        o.as_any()
+            .downcast_ref::<prim::CudaReduce<T>>()
+            .map(|r| r.op == reduce_op)
+            .unwrap_or_default()
+    });
+    n
+}
+
+/// Threads per block launched by [LaunchConfig::for_num_elems](luminal_cudarc::driver::LaunchConfig::for_num_elems)
+const BLOCK_SIZE: usize = 1024;
+
+/// Open the bounds check of a kernel running a thread per element. When `numel` is known to fill every block
+/// launched no thread can be out of bounds, so only a bare block is opened
+fn idx_guard(numel: BigExpression, numel_var: &str) -> String {
+    match numel.to_usize() {
+        Some(n) if n % BLOCK_SIZE == 0 => "{".to_string(),
+        _ => format!("if (idx < {numel_var}) {{"),
+    }
+}
+
 fn hash<T: std::hash::Hash>(obj: T) -> u64 {
     let mut hasher = DefaultHasher::new();
     obj.hash(&mut hasher);
@@ -228,10 +369,245 @@ fn input_dyn_dims(
     }
 }
 
+/// Where an op runs its work and allocates its outputs. The stream is assigned by [StreamCompiler] through the
+/// op's `"stream"` custom key, and unassigned ops run on the device's default stream. [BufferReuseCompiler] turns
+/// on output reuse through the `"buffers"` key
+#[derive(Clone, Default)]
+struct OpStream {
+    assigned: Option<AssignedStream>,
+    outputs: Option<Rc<OutputCache>>,
+}
+
+#[derive(Clone)]
+struct AssignedStream {
+    stream: Rc<CudaStream>,
+    /// Streams of the other branches this op's inputs come from, each with the event the op waits on them through
+    waits: Vec<(Rc<CudaStream>, Rc<CudaEvent>)>,
+    /// Whether an input comes from an op queued on the default stream
+    wait_default: bool,
+    /// The event the op waits on the default stream through
+    default_event: Rc<CudaEvent>,
+    device: Arc<CudaDevice>,
+    /// Whether the device allocates in stream order
+    pools: bool,
+}
+
+impl AssignedStream {
+    /// Hold back later work on this stream until the work queued on `on` so far is done
+    fn wait_on(&self, on: sys::CUstream, event: &CudaEvent) {
+        unsafe {
+            result::event::record(event.event, on).unwrap();
+            result::stream::wait_event(
+                self.stream.stream,
+                event.event,
+                sys::CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT,
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// An event an op records on another stream to wait on it. Each op creates its own once, rather than one per launch
+struct CudaEvent {
+    event: sys::CUevent,
+    device: Arc<CudaDevice>,
+}
+
+impl CudaEvent {
+    fn new(device: &Arc<CudaDevice>) -> Self {
+        device.bind_to_thread().unwrap();
+        Self {
+            event: result::event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING).unwrap(),
+            device: device.clone(),
+        }
+    }
+}
+
+impl Drop for CudaEvent {
+    fn drop(&mut self) {
+        self.device.bind_to_thread().unwrap();
+        unsafe { result::event::destroy(self.event) }.unwrap();
+    }
+}
+
+impl OpStream {
+    /// Take what's sent through the `"stream"` or `"buffers"` custom key, acknowledging that the op supports it.
+    /// Turning on output reuse returns the op's new output cache
+    fn assign(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "buffers" {
+            // Buffers from an earlier compile are freed along with their old cache
+            self.outputs = (*input.downcast::<bool>().unwrap()).then(Default::default);
+            return Some(Box::new(self.outputs.as_ref().map(Rc::downgrade)));
+        }
+        self.assigned = *input.downcast::<Option<AssignedStream>>().unwrap();
+        Some(Box::new(()))
+    }
+
+    /// Wrap a buffer allocated for one of this op's outputs, so it comes back to the op once dropped if the op
+    /// reuses its outputs
+    fn output<T>(&self, buffer: CudaSlice<T>) -> CudaData<T> {
+        let mut data = CudaData::new(buffer);
+        if let Some(outputs) = &self.outputs {
+            data.1 = Rc::downgrade(outputs);
+        }
+        data
+    }
+
+    /// Take back a buffer of `len` elements from the last execution's outputs. If none fit, the shapes changed, so
+    /// the rest are freed rather than kept around
+    fn reuse_output<T>(&self, device: &Arc<CudaDevice>, len: usize) -> Option<CudaSlice<T>> {
+        let mut outputs = self.outputs.as_ref()?.borrow_mut();
+        let bytes = len * std::mem::size_of::<T>();
+        let Some(i) = outputs.iter().position(|b| b.len() == bytes) else {
+            outputs.clear();
+            return None;
+        };
+        let buffer = outputs.swap_remove(i);
+        // Consumers from the last execution might still be reading it on another stream, and the default stream
+        // waited on them all
+        if let Some(assigned) = &self.assigned {
+            assigned.wait_on(*device.cu_stream(), &assigned.default_event);
+        }
+        Some(unsafe { device.upgrade_device_ptr(buffer.leak(), len) })
+    }
+
+    /// Allocate a zeroed buffer on this stream
+    fn alloc_zeros<T: DeviceRepr + ValidAsZeroBits>(
+        &self,
+        device: &Arc<CudaDevice>,
+        len: usize,
+    ) -> CudaSlice<T> {
+        let Some(assigned) = &self.assigned else {
+            let Some(mut buffer) = self.reuse_output(device, len) else {
+                return device.alloc_zeros(len).unwrap();
+            };
+            device.memset_zeros(&mut buffer).unwrap();
+            return buffer;
+        };
+        unsafe {
+            let buffer = self.alloc::<T>(device, len);
+            result::memset_d8_async(
+                *buffer.device_ptr(),
+                0,
+                buffer.num_bytes(),
+                assigned.stream.stream,
+            )
+            .unwrap();
+            buffer
+        }
+    }
+
+    /// Allocate a buffer on this stream, leaving its contents unset. Outputs are allocated in the stream's own
+    /// order rather than on the default stream, so the branch never waits on the default stream for them. If the
+    /// op reuses its outputs, one of the same size from the last execution is taken instead.
+    ///
+    /// # Safety
+    /// The buffer must be fully written before it's read
+    unsafe fn alloc<T: DeviceRepr>(&self, device: &Arc<CudaDevice>, len: usize) -> CudaSlice<T> {
+        if let Some(buffer) = self.reuse_output(device, len) {
+            return buffer;
+        }
+        self.alloc_scratch(device, len)
+    }
+
+    /// Allocate a buffer on this stream for use only within the op, leaving its contents unset
+    ///
+    /// # Safety
+    /// The buffer must be fully written before it's read
+    unsafe fn alloc_scratch<T: DeviceRepr>(
+        &self,
+        device: &Arc<CudaDevice>,
+        len: usize,
+    ) -> CudaSlice<T> {
+        let Some(assigned) = &self.assigned else {
+            return device.alloc(len).unwrap();
+        };
+        let bytes = len * std::mem::size_of::<T>();
+        let ptr = if assigned.pools {
+            result::malloc_async(assigned.stream.stream, bytes)
+        } else {
+            result::malloc_sync(bytes)
+        };
+        device.upgrade_device_ptr(ptr.unwrap(), len)
+    }
+
+    /// Copy host data into a buffer on this stream
+    fn htod_copy_into<T: DeviceRepr + Unpin>(
+        &self,
+        device: &Arc<CudaDevice>,
+        src: Vec<T>,
+        dst: &mut CudaSlice<T>,
+    ) {
+        let Some(assigned) = &self.assigned else {
+            device.htod_copy_into(src, dst).unwrap();
+            return;
+        };
+        // Copies from pageable memory are staged before this returns, so `src` can be dropped right after
+        unsafe { result::memcpy_htod_async(*dst.device_ptr(), &src, assigned.stream.stream) }
+            .unwrap();
+    }
+
+    /// Queue work on this stream. It first waits on the streams this op's inputs come from, and the default
+    /// stream then waits on it, since that's where buffers are freed once the op's inputs are dropped
+    fn run<R>(&self, f: impl FnOnce(Option<&CudaStream>) -> R) -> R {
+        let Some(assigned) = &self.assigned else {
+            return f(None);
+        };
+        assigned.device.bind_to_thread().unwrap();
+        for (stream, event) in &assigned.waits {
+            assigned.wait_on(stream.stream, event);
+        }
+        if assigned.wait_default {
+            assigned.wait_on(*assigned.device.cu_stream(), &assigned.default_event);
+        }
+        let out = f(Some(&assigned.stream));
+        assigned.device.wait_for(&assigned.stream).unwrap();
+        out
+    }
+}
+
+/// Launch kernels on the stream [StreamCompiler] assigned to the op
+trait LaunchOnStream<Params> {
+    unsafe fn launch_on(
+        self,
+        stream: &OpStream,
+        cfg: LaunchConfig,
+        params: Params,
+    ) -> Result<(), DriverError>;
+}
+
+impl<Params> LaunchOnStream<Params> for CudaFunction
+where
+    CudaFunction: LaunchAsync<Params>,
+{
+    unsafe fn launch_on(
+        self,
+        stream: &OpStream,
+        cfg: LaunchConfig,
+        params: Params,
+    ) -> Result<(), DriverError> {
+        stream.run(|s| match s {
+            Some(s) => self.launch_on_stream(s, cfg, params),
+            None => self.launch(cfg, params),
+        })
+    }
+}
+
+thread_local! {
+    /// Counted per thread, so graphs compiled and run on other threads don't show up
+    static KERNEL_COMPILATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The number of kernels this thread has compiled with NVRTC so far. Kernels already loaded on a device are reused rather than recompiled
+pub fn kernel_compilations() -> usize {
+    KERNEL_COMPILATIONS.get()
+}
+
 fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFunction {
     let name = format!("kernel_{}", hash(&code));
     code = code.replace("kernel", &name);
     if !device.has_func(&name, &name) {
+        KERNEL_COMPILATIONS.set(KERNEL_COMPILATIONS.get() + 1);
         device
             .load_ptx(
                 compile_ptx_with_opts(
@@ -1,14 +1,15 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
 use luminal_cudarc::{
     cublas::{sys::cublasOperation_t::*, CudaBlas},
-    driver::{CudaDevice, DevicePtr, DevicePtrMut},
+    driver::{CudaDevice, CudaFunction, DevicePtr, DevicePtrMut, DeviceRepr, LaunchConfig},
 };
+use rustc_hash::FxHashMap;
 
 use crate::{
-    get_buffer_from_tensor,
-    prim::{CudaMul, CudaSumReduce},
-    CudaData, CudaFloat,
+    compile_and_load_kernel, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
+    prim::{CudaAdd, CudaMul, CudaReduce},
+    render_dyn_dim_inputs, CudaData, CudaFloat, LaunchOnStream, OpStream,
 };
 use luminal::{
     op::{InputTensor, Operator},
@@ -16,8 +17,58 @@ use luminal::{
 };
 
 #[derive(Clone)]
-pub struct Matmul<T>(Arc<CudaBlas>, Arc<CudaDevice>, PhantomData<T>);
-crate::debug_type!(Matmul);
+pub struct Matmul<T>(
+    Arc<CudaBlas>,
+    Arc<CudaDevice>,
+    Option<BiasInit>,
+    PhantomData<T>,
+    OpStream,
+);
+
+impl<T> std::fmt::Debug for Matmul<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.2.is_some() {
+            write!(f, "MatmulBias")
+        } else {
+            write!(f, "Matmul")
+        }
+    }
+}
+
+/// Fills the matmul output with a (usually broadcasted) bias before the gemm accumulates into it
+#[derive(Clone)]
+pub struct BiasInit {
+    function: CudaFunction,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl BiasInit {
+    fn new<T: CudaFloat>(
+        bias_shape: ShapeTracker,
+        device: &Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx, valid) = get_idx_valid_exps(bias_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[bias_shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *bias, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = ({valid}) == 0 ? ({type_name})0.0 : bias[{idx}];
+    }}
+}}"
+        );
+        Self {
+            function: compile_and_load_kernel(code, device),
+            dyn_symbols,
+            dyn_map,
+        }
+    }
+}
 
 impl<T: CudaFloat> Operator for Matmul<T> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
@@ -35,9 +86,31 @@ impl<T: CudaFloat> Operator for Matmul<T> {
         let a = get_buffer_from_tensor::<T>(&inp[0].0);
         let b = get_buffer_from_tensor::<T>(&inp[1].0);
         let mut out = self
-            .1
-            .alloc_zeros::<T>((m * n * batch_size) as usize)
-            .unwrap();
+            .4
+            .alloc_zeros::<T>(&self.1, (m * n * batch_size) as usize);
+        // Write bias into the output and have the gemm accumulate on top of it
+        let beta = if let Some(bias) = &self.2 {
+            let numel = (m * n * batch_size) as usize;
+            let mut params = vec![
+                (&out).as_kernel_param(),
+                get_buffer_from_tensor::<T>(&inp[2].0).as_kernel_param(),
+                numel.as_kernel_param(),
+            ];
+            input_dyn_dims(&mut params, &bias.dyn_symbols, bias.dyn_map);
+            unsafe {
+                bias.function
+                    .clone()
+                    .launch_on(
+                        &self.4,
+                        LaunchConfig::for_num_elems(numel as u32),
+                        &mut params,
+                    )
+                    .unwrap();
+            }
+            1.0
+        } else {
+            0.0
+        };
         let (a_row_major, b_row_major) = (
             inp[0].1.indexes[inp[0].1.len() - 1] > inp[0].1.indexes[inp[0].1.len() - 2],
             inp[1].1.indexes[inp[1].1.len() - 1] > inp[1].1.indexes[inp[1].1.len() - 2],
@@ -49,59 +122,70 @@ impl<T: CudaFloat> Operator for Matmul<T> {
             (true, false) => (CUBLAS_OP_T, CUBLAS_OP_N),
         };
 
+        // Run the gemm on the same stream as the rest of this node's kernels
         let a_dims = inp[0].1.fake.iter().filter(|f| !**f).count();
         let b_dims = inp[1].1.fake.iter().filter(|f| !**f).count();
-        if T::is_f32() {
-            unsafe {
-                luminal_cudarc::cublas::result::sgemm_strided_batched(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &1.0_f32 as *const f32,
-                    *b.device_ptr() as *const f32,
-                    if b_row_major { n } else { k },
-                    if b_dims == 2 { 0 } else { (n * k) as i64 },
-                    *a.device_ptr() as *const f32,
-                    if a_row_major { k } else { m },
-                    if a_dims == 2 { 0 } else { (m * k) as i64 },
-                    &0.0_f32 as *const f32,
-                    *out.device_ptr_mut() as *mut f32,
-                    n,
-                    (m * n) as i64,
-                    batch_size,
-                )
-                .unwrap();
-            }
-        } else {
-            unsafe {
-                luminal_cudarc::cublas::result::hgemm_strided_batched(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &f16::from_f32(1.0) as *const f16,
-                    *b.device_ptr() as *const f16,
-                    if b_row_major { n } else { k },
-                    if b_dims == 2 { 0 } else { (n * k) as i64 },
-                    *a.device_ptr() as *const f16,
-                    if a_row_major { k } else { m },
-                    if a_dims == 2 { 0 } else { (m * k) as i64 },
-                    &f16::from_f32(0.0) as *const f16,
-                    *out.device_ptr_mut() as *mut f16,
-                    n,
-                    (m * n) as i64,
-                    batch_size,
-                )
-                .unwrap();
+        self.4.run(|stream| {
+            unsafe { self.0.set_stream(stream) }.unwrap();
+            if T::is_f32() {
+                unsafe {
+                    luminal_cudarc::cublas::result::sgemm_strided_batched(
+                        *self.0.handle(),
+                        transa,
+                        transb,
+                        n,
+                        m,
+                        k,
+                        &1.0_f32 as *const f32,
+                        *b.device_ptr() as *const f32,
+                        if b_row_major { n } else { k },
+                        if b_dims == 2 { 0 } else { (n * k) as i64 },
+                        *a.device_ptr() as *const f32,
+                        if a_row_major { k } else { m },
+                        if a_dims == 2 { 0 } else { (m * k) as i64 },
+                        &beta as *const f32,
+                        *out.device_ptr_mut() as *mut f32,
+                        n,
+                        (m * n) as i64,
+                        batch_size,
+                    )
+                    .unwrap();
+                }
+            } else {
+                unsafe {
+                    luminal_cudarc::cublas::result::hgemm_strided_batched(
+                        *self.0.handle(),
+                        transa,
+                        transb,
+                        n,
+                        m,
+                        k,
+                        &f16::from_f32(1.0) as *const f16,
+                        *b.device_ptr() as *const f16,
+                        if b_row_major { n } else { k },
+                        if b_dims == 2 { 0 } else { (n * k) as i64 },
+                        *a.device_ptr() as *const f16,
+                        if a_row_major { k } else { m },
+                        if a_dims == 2 { 0 } else { (m * k) as i64 },
+                        &f16::from_f32(beta) as *const f16,
+                        *out.device_ptr_mut() as *mut f16,
+                        n,
+                        (m * n) as i64,
+                        batch_size,
+                    )
+                    .unwrap();
+                }
             }
-        }
+        });
+
+        vec![Tensor::new(self.4.output(out))]
+    }
 
-        vec![Tensor::new(CudaData(out))]
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.4.assign(key, input);
+        }
+        None
     }
 }
 
@@ -124,10 +208,10 @@ where
             [None, Some(true), Some(false)],
             [Some(true), Some(false), Some(false)],
         ]);
-        let mut sr2d = op::<CudaSumReduce<T>>();
+        let mut sr2d = op::<CudaReduce<T>>();
         sr2d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 2
+            if let Some(o) = o.as_any().downcast_ref::<CudaReduce<T>>() {
+                o.op == ReduceOp::Sum && o.dim == 2
             } else {
                 false
             }
@@ -139,10 +223,10 @@ where
             [Some(false), Some(false), Some(true), Some(false)],
             [None, Some(true), Some(false), Some(false)],
         ]);
-        let mut sr3d = op::<CudaSumReduce<T>>();
+        let mut sr3d = op::<CudaReduce<T>>();
         sr3d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 3
+            if let Some(o) = o.as_any().downcast_ref::<CudaReduce<T>>() {
+                o.op == ReduceOp::Sum && o.dim == 3
             } else {
                 false
             }
@@ -160,10 +244,10 @@ where
             ],
             [None, None, Some(true), Some(false), Some(false)],
         ]);
-        let mut sr4d = op::<CudaSumReduce<T>>();
+        let mut sr4d = op::<CudaReduce<T>>();
         sr4d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 4
+            if let Some(o) = o.as_any().downcast_ref::<CudaReduce<T>>() {
+                o.op == ReduceOp::Sum && o.dim == 4
             } else {
                 false
             }
@@ -185,10 +269,10 @@ where
             ],
             [None, None, None, Some(true), Some(false), Some(false)],
         ]);
-        let mut sr5d = op::<CudaSumReduce<T>>();
+        let mut sr5d = op::<CudaReduce<T>>();
         sr5d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 5
+            if let Some(o) = o.as_any().downcast_ref::<CudaReduce<T>>() {
+                o.op == ReduceOp::Sum && o.dim == 5
             } else {
                 false
             }
@@ -222,6 +306,8 @@ where
                 .add_op(Matmul::<T>(
                     Arc::new(CudaBlas::new(dev.clone()).unwrap()),
                     dev.clone(),
+                    None,
+                    Default::default(),
                     Default::default(),
                 ))
                 .input(src1, 0, src1_shape)
@@ -239,3 +325,64 @@ where
         }
     }
 }
+
+/// Fuse an add following a matmul (such as a linear layer's bias) into the matmul by accumulating onto it
+#[derive(Default)]
+pub struct MatMulBiasCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat + 'static> Compiler for MatMulBiasCompiler<T>
+where
+    CudaData<T>: Data,
+{
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let (matmul, bias) = (op::<Matmul<T>>(), node());
+        let add = binary::<CudaAdd<T>>(matmul.clone(), bias.clone());
+        let mut s = add.clone().search(graph);
+        while s.next_match() {
+            let (matmul, bias, add) = (s.get(&matmul), s.get(&bias), s.get(&add));
+            if matmul == bias
+                || graph.no_delete.contains(&matmul)
+                || graph.get_dests(matmul).len() != 1
+                || graph.get_op::<Matmul<T>>(matmul).2.is_some()
+            {
+                continue;
+            }
+            // The matmul output must be read as-is
+            let matmul_shape = graph
+                .edges_connecting(matmul, add)
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            if matmul_shape.is_reshaped() {
+                continue;
+            }
+            let (_, bias_out, bias_shape) = graph
+                .edges_connecting(bias, add)
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            let dyn_map: *const FxHashMap<char, usize> = &graph.dyn_map;
+            let op = graph.get_op_mut::<Matmul<T>>(matmul);
+            op.2 = Some(BiasInit::new::<T>(bias_shape, &op.1, dyn_map));
+            graph.add_edge(
+                bias,
+                matmul,
+                Dependency::Data {
+                    input_order: 2,
+                    output_order: bias_out,
+                    shape: bias_shape,
+                },
+            );
+
+            move_outgoing_edge(add, matmul, &mut graph.graph);
+            remap(add, matmul, &mut ids, graph);
+            graph.graph.remove_node(add);
+        }
+    }
+}
@@ -1,21 +1,32 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{
+    any::Any,
+    fmt::Debug,
+    marker::PhantomData,
+    rc::{Rc, Weak},
+    sync::Arc,
+};
 
 use itertools::Itertools;
+use luminal::op::{Function, InputTensor, Operator};
 use luminal::prelude::{petgraph::visit::EdgeRef, *};
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, LaunchAsync, LaunchConfig};
-use rustc_hash::FxHashMap;
+use luminal_cudarc::driver::{
+    sys, CudaDevice, CudaFunction, CudaStream, DevicePtr, DeviceRepr, LaunchConfig,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
     binary::CudaSub,
-    compile_and_load_kernel, constant,
-    prim::{CudaAdd, CudaContiguous, CudaCopyFromDevice, CudaCopyToDevice, CudaSumReduce},
-    CudaData, CudaFloat,
+    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
+    prim::{CudaAdd, CudaContiguous, CudaCopyFromDevice, CudaCopyToDevice},
+    reduce, render_dyn_dim_inputs, AssignedStream, CudaEvent, CudaFloat, LaunchOnStream, OpStream,
+    OutputCache,
 };
 
 #[derive(Clone)]
 pub struct CudaARange<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     pub size: BigExpression,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
@@ -42,6 +53,7 @@ extern \"C\" __global__ void kernel({type_name} *out, int n_elements) {{
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             size,
             _phantom: Default::default(),
             dyn_map,
@@ -55,18 +67,26 @@ impl<T: CudaFloat> Operator for CudaARange<T> {
             .size
             .exec(unsafe { self.dyn_map.as_ref().unwrap() })
             .unwrap();
-        let mut out = self.device.alloc_zeros::<T>(n_elements).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, n_elements);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(n_elements as u32),
                     (&mut out, n_elements as i32),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -81,9 +101,10 @@ impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
         let contig_one = constant::<T>(1.);
         let contig1 = unary::<CudaContiguous<T>>(contig_one.clone());
         let sum_reduce =
-            unary::<CudaSumReduce<T>>(unary::<CudaContiguous<T>>(unary::<CudaContiguous<T>>(
-                unary::<CudaContiguous<T>>(contig1.clone()),
-            )));
+            unary::<CudaContiguous<T>>(unary::<CudaContiguous<T>>(unary::<CudaContiguous<T>>(
+                contig1.clone(),
+            )))
+            .connect(reduce::<T>(ReduceOp::Sum));
         let sub = binary::<CudaSub<T>>(sum_reduce.clone(), constant::<T>(1.));
         let mut s1 = sub.clone().search(graph);
         let neg_one = constant::<T>(-1.);
@@ -122,6 +143,390 @@ impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
     }
 }
 
+/// Concatenate two tensors along an axis. Each input comes in padded out to the full output shape,
+/// so each output element is read from whichever input is valid at that index.
+#[derive(Clone)]
+pub struct CudaConcat<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    pub axis: usize,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> core::fmt::Debug for CudaConcat<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaConcat({})", self.axis)
+    }
+}
+
+impl<T: CudaFloat> CudaConcat<T> {
+    pub fn new(
+        axis: usize,
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        if (({a_valid}) != 0) {{
+            out[idx] = inp_a[{a_idx}];
+        }} else if (({b_valid}) != 0) {{
+            out[idx] = inp_b[{b_idx}];
+        }} else {{
+            out[idx] = ({type_name})0.0;
+        }}
+    }}
+}}");
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            stream: Default::default(),
+            axis,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaConcat<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let out_size = tensors[0].1.n_elements().to_usize().unwrap();
+
+        let out = self.stream.alloc_zeros::<T>(&self.device, out_size);
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            a.as_kernel_param(),
+            b.as_kernel_param(),
+            out_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(out_size as u32),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
+    }
+}
+
+/// Find the axis two padded inputs of an add are concatenated along, if they are
+fn concat_axis(a: ShapeTracker, b: ShapeTracker) -> Option<usize> {
+    let zero = InternedExpression::from(0);
+    if a.len() != b.len() || a.is_sliced() || b.is_sliced() {
+        return None;
+    }
+    let mut axis = None;
+    for i in 0..a.len() {
+        let (a_pad, b_pad) = (a.padding[a.indexes[i]], b.padding[b.indexes[i]]);
+        if a_pad == (zero, zero) && b_pad == (zero, zero) {
+            continue;
+        }
+        // a must be padded at the end by exactly the length of b, and b at the start by the length of a
+        if axis.is_some()
+            || a_pad.0 != zero
+            || b_pad.1 != zero
+            || a.dims[a.indexes[i]] != b_pad.0
+            || b.dims[b.indexes[i]] != a_pad.1
+        {
+            return None;
+        }
+        axis = Some(i);
+    }
+    axis
+}
+
+#[derive(Debug, Default)]
+pub struct ConcatCompiler<T: CudaFloat>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for ConcatCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        let add = op::<CudaAdd<T>>();
+        let mut s = add.clone().search(graph);
+        while s.next_match() {
+            let add = s.get(&add);
+            let srcs = graph.get_sources(add);
+            if srcs.len() != 2 {
+                continue;
+            }
+            let Some(axis) = concat_axis(srcs[0].2, srcs[1].2) else {
+                continue;
+            };
+            let concat = graph
+                .add_op(CudaConcat::<T>::new(
+                    axis,
+                    srcs[0].2,
+                    srcs[1].2,
+                    dev.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(srcs[0].0, srcs[0].1, srcs[0].2)
+                .input(srcs[1].0, srcs[1].1, srcs[1].2)
+                .finish();
+            move_outgoing_edge(add, concat, graph);
+            remap(add, concat, &mut ids, graph);
+            graph.remove_node(add);
+        }
+    }
+}
+
+/// Make copies back to the host go into pinned host buffers, reused across executions.
+/// Only copies that are just read back, with no ops consuming them, are changed. Run this after the rest of the cuda compilers
+#[derive(Debug, Default)]
+pub struct PinnedCopyCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for PinnedCopyCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            if graph
+                .edges_directed(node, petgraph::Direction::Outgoing)
+                .any(|e| !e.weight().is_schedule())
+            {
+                continue;
+            }
+            if let Some(copy) = graph.try_get_op_mut::<CudaCopyFromDevice<T>>(node) {
+                copy.use_pinned_buffer();
+            }
+        }
+    }
+}
+
+/// Reuse device buffers across executions. Once an op's output is dropped, after its last consumer ran, the buffer
+/// goes back to that op, and the op's next execution writes into it rather than allocating. Buffers are matched
+/// by size, so when shapes change the op allocates fresh ones and frees the rest. They're freed along with the op
+/// when it leaves the graph, and when this compiler runs again.
+/// Run this after the rest of the cuda compilers, since ops added later allocate as before
+#[derive(Debug, Default)]
+pub struct BufferReuseCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for BufferReuseCompiler<T> {
+    type Output = ReusedOutputs;
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) -> ReusedOutputs {
+        let mut outputs = FxHashMap::default();
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            // Ops that don't allocate on the device, like copies back to the host, don't take it
+            if let Some(cache) = graph
+                .graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("buffers", Box::new(true))
+            {
+                if let Some(cache) = *cache.downcast::<Option<Weak<OutputCache>>>().unwrap() {
+                    outputs.insert(node, cache);
+                }
+            }
+        }
+        ReusedOutputs(outputs)
+    }
+}
+
+/// The buffers each op reusing its outputs is holding for its next execution. The ops own them, so dropping this
+/// changes nothing
+#[derive(Debug, Default)]
+pub struct ReusedOutputs(FxHashMap<NodeIndex, Weak<OutputCache>>);
+
+impl ReusedOutputs {
+    /// Device pointers of every buffer waiting to be written into again, sorted
+    pub fn device_ptrs(&self) -> Vec<u64> {
+        self.0
+            .values()
+            .filter_map(Weak::upgrade)
+            .flat_map(|cache| {
+                cache
+                    .borrow()
+                    .iter()
+                    .map(|b| *b.device_ptr())
+                    .collect::<Vec<_>>()
+            })
+            .sorted()
+            .collect()
+    }
+}
+
+/// Run independent branches of the graph on separate streams so their kernels can overlap. Each op continues
+/// the stream of its first input not already continued by another op, and otherwise starts a new branch on the
+/// next stream (cycling through `streams` of them). Ops are handed their stream through the `"stream"` custom
+/// key, and wait on the streams their inputs came from. The default stream, where copies back to the host run
+/// and buffers are freed, waits on each op in turn.
+/// Run this after the rest of the cuda compilers, since ops added later run on the default stream. The output
+/// maps each op ran off the default stream to the index of its stream
+#[derive(Debug)]
+pub struct StreamCompiler<T> {
+    streams: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> StreamCompiler<T> {
+    /// Spread branches over at most `streams` streams
+    pub fn new(streams: usize) -> Self {
+        assert!(streams > 0, "Need at least one stream");
+        Self {
+            streams,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for StreamCompiler<T> {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl<T: CudaFloat> Compiler for StreamCompiler<T> {
+    type Output = FxHashMap<NodeIndex, usize>;
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) -> FxHashMap<NodeIndex, usize> {
+        // Streams are forked on the device the graph's inputs are copied to. Graphs spread over several devices,
+        // or without any copies, stay on the default streams
+        let Ok(device) = graph
+            .node_indices()
+            .filter_map(|n| graph.try_get_op::<CudaCopyToDevice<T>>(n))
+            .map(|copy| copy.device().clone())
+            .unique_by(|device| device.ordinal())
+            .exactly_one()
+        else {
+            return FxHashMap::default();
+        };
+        let pools = device
+            .attribute(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_MEMORY_POOLS_SUPPORTED)
+            .unwrap()
+            > 0;
+        let mut streams: Vec<Rc<CudaStream>> = vec![];
+        let mut assigned = FxHashMap::default();
+        let mut continued = FxHashSet::default();
+        let mut branches = 0;
+        for node in petgraph::algo::toposort(&graph.graph, None).unwrap() {
+            // Also clears the stream from an earlier compile. Ops that don't take one, like copies to and from
+            // the host, stay on the default stream
+            let supported = graph
+                .graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("stream", Box::new(None::<AssignedStream>))
+                .is_some();
+            if !supported {
+                continue;
+            }
+            let sources = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(n, _, _)| n)
+                .collect::<Vec<_>>();
+            let source = sources
+                .iter()
+                .copied()
+                .find(|n| assigned.contains_key(n) && !continued.contains(n));
+            let stream = if let Some(source) = source {
+                continued.insert(source);
+                assigned[&source]
+            } else {
+                branches += 1;
+                (branches - 1) % self.streams
+            };
+            if stream == streams.len() {
+                streams.push(Rc::new(device.fork_default_stream().unwrap()));
+            }
+            let waits = sources
+                .iter()
+                .filter_map(|n| assigned.get(n).copied())
+                .filter(|s| *s != stream)
+                .unique()
+                .map(|s| (streams[s].clone(), Rc::new(CudaEvent::new(&device))))
+                .collect();
+            // Copies to the device finish before they return, so only other ops on the default stream need waiting on
+            let wait_default = sources.iter().any(|n| {
+                !assigned.contains_key(n)
+                    && !graph.check_node_type::<CudaCopyToDevice<T>>(*n)
+                    && !graph.check_node_type::<Function>(*n)
+            });
+            graph.graph.node_weight_mut(node).unwrap().custom(
+                "stream",
+                Box::new(Some(AssignedStream {
+                    stream: streams[stream].clone(),
+                    waits,
+                    wait_default,
+                    default_event: Rc::new(CudaEvent::new(&device)),
+                    device: device.clone(),
+                    pools,
+                })),
+            );
+            assigned.insert(node, stream);
+        }
+        assigned
+    }
+}
+
+/// Schedule copies back to the host after all device compute, so no transfer forces a sync mid-graph
+/// and the final transfers run back to back. Run this after the rest of the cuda compilers
+#[derive(Debug, Default)]
+pub struct SinkCopiesCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for SinkCopiesCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        let copies = graph
+            .node_indices()
+            .filter(|n| graph.check_node_type::<CudaCopyFromDevice<T>>(*n))
+            .collect::<Vec<_>>();
+        if copies.is_empty() {
+            return;
+        }
+        // Anything downstream of a copy runs on the host after it, so it can't be scheduled before the copies
+        let mut downstream = FxHashSet::default();
+        let mut dfs = petgraph::visit::Dfs::empty(&graph.graph);
+        dfs.stack.extend(copies.iter().copied());
+        while let Some(node) = dfs.next(&graph.graph) {
+            downstream.insert(node);
+        }
+        let sinks = graph
+            .node_indices()
+            .filter(|n| !downstream.contains(n))
+            .filter(|n| {
+                graph
+                    .edges_directed(*n, petgraph::Direction::Outgoing)
+                    .all(|e| downstream.contains(&e.target()))
+            })
+            .collect::<Vec<_>>();
+        for copy in copies {
+            for sink in &sinks {
+                if graph.graph.find_edge(*sink, copy).is_none() {
+                    graph.add_schedule_dependency(*sink, copy);
+                }
+            }
+        }
+    }
+}
+
 // Sometimes CopyTo -> CopyFrom and CopyFrom -> CopyTo patterns remain, so let's clean them up
 #[derive(Debug, Default)]
 pub struct CopyCompiler<T>(PhantomData<T>);
@@ -197,3 +602,27 @@ impl<T: CudaFloat> Compiler for CopyCompiler<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_close};
+
+    use crate::{tests::assert_op_in_graph, CudaCompiler};
+
+    use super::CudaConcat;
+    #[test]
+    fn test_concat() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(luminal::tests::random_vec(12));
+        let b = cx.tensor::<R2<3, 2>>().set(luminal::tests::random_vec(6));
+        let mut c = a.concat_along::<R2<3, 6>, Axis<1>, _>(b).retrieve();
+        cx.execute();
+        let unopt = c.data();
+
+        cx.compile(CudaCompiler::<f32>::default(), &mut c);
+        assert_op_in_graph::<CudaConcat<f32>>(&cx);
+        cx.execute();
+
+        assert_close(&unopt, &c.data());
+    }
+}
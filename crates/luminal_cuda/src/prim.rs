@@ -1,16 +1,27 @@
-use crate::{compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims, CudaData, CudaFloat};
+use crate::{
+    compile_and_load_kernel, get_buffer_from_tensor, idx_guard, input_dyn_dims, CudaData,
+    CudaFloat, LaunchOnStream, OpStream,
+};
 
-use super::{get_idx_valid_exps, render_dyn_dim_inputs};
+use super::{get_bound_idx_valid_exps, get_idx_valid_exps, render_dyn_dim_inputs};
 use itertools::Itertools;
 use rustc_hash::FxHashMap;
 
 use std::{
     any::{Any, TypeId},
+    ffi::c_void,
     marker::PhantomData,
-    sync::Arc,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
 };
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{
+    result, sys, CudaDevice, CudaFunction, DevicePtr, DeviceRepr, DeviceSlice, LaunchConfig,
+};
 
 use luminal::{
     op::{Function as LFunction, *},
@@ -26,6 +37,11 @@ impl<T> CudaCopyToDevice<T> {
     pub fn new(dev: Arc<CudaDevice>) -> Self {
         CudaCopyToDevice(dev, Default::default())
     }
+
+    /// The device tensors are copied to
+    pub fn device(&self) -> &Arc<CudaDevice> {
+        &self.0
+    }
 }
 
 impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
@@ -40,18 +56,195 @@ impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
             .copied()
             .map(T::from_f32)
             .collect::<Vec<_>>();
-        vec![Tensor::new(CudaData(self.0.htod_sync_copy(&vec).unwrap()))]
+        vec![Tensor::new(CudaData::new(
+            self.0.htod_sync_copy(&vec).unwrap(),
+        ))]
+    }
+}
+
+/// Page-locked host memory. The device can copy straight into it without staging through a pageable buffer
+struct PinnedBuffer<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T> PinnedBuffer<T> {
+    fn new(device: &CudaDevice, len: usize) -> Self {
+        device.bind_to_thread().unwrap();
+        let mut ptr = std::ptr::null_mut();
+        unsafe {
+            sys::cuMemAllocHost_v2(&mut ptr, len.max(1) * std::mem::size_of::<T>())
+                .result()
+                .unwrap();
+        }
+        Self {
+            ptr: ptr as *mut T,
+            len,
+        }
+    }
+
+    fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T> Drop for PinnedBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            sys::cuMemFreeHost(self.ptr as *mut c_void);
+        }
+    }
+}
+
+/// A copy from the device into pinned host memory that's still in flight
+struct PendingCopy<T> {
+    buffer: PinnedBuffer<T>,
+    state: Arc<CopyState>,
+    device: Arc<CudaDevice>,
+}
+
+impl<T> Drop for PendingCopy<T> {
+    fn drop(&mut self) {
+        // Don't free the buffer out from under a copy that's still writing to it
+        if !self.state.done.load(Ordering::Acquire) {
+            self.device.synchronize().unwrap();
+        }
+    }
+}
+
+/// Completion flag for an in-flight copy, along with the waker of whoever's waiting on it
+#[derive(Default)]
+struct CopyState {
+    done: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Host callback enqueued on the stream behind the copy. Receives a leaked `Arc<CopyState>`
+unsafe extern "C" fn copy_finished(state: *mut c_void) {
+    let state = Arc::from_raw(state as *const CopyState);
+    state.done.store(true, Ordering::Release);
+    let waker = state.waker.lock().unwrap().take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Host data still being copied back from the device. Resolves to a `Vec<f32>` once the copy finishes
+pub struct PendingHostData<T>(Arc<PendingCopy<T>>);
+
+impl<T> Clone for PendingHostData<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> std::fmt::Debug for PendingHostData<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PendingHostData({} elements)", self.0.buffer.len)
+    }
+}
+
+impl<T: CudaFloat> Data for PendingHostData<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Option<Box<dyn Data>>> {
+        let state = &self.0.state;
+        if !state.done.load(Ordering::Acquire) {
+            *state.waker.lock().unwrap() = Some(cx.waker().clone());
+            // The copy may have finished before the waker was stored
+            if !state.done.load(Ordering::Acquire) {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(Some(Box::new(
+            self.0
+                .buffer
+                .as_slice()
+                .iter()
+                .copied()
+                .map(T::to_f32)
+                .collect::<Vec<_>>(),
+        )))
+    }
+}
+
+/// Host data copied back from the device into a pinned buffer. The copy reuses the buffer on the next execution
+/// once this is dropped
+pub struct PinnedHostData<T>(Rc<PinnedBuffer<T>>);
+
+impl<T> Clone for PinnedHostData<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> std::fmt::Debug for PinnedHostData<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PinnedHostData({} elements)", self.0.len)
+    }
+}
+
+impl<T: CudaFloat> Data for PinnedHostData<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn to_host_vec(&self) -> Option<Vec<f32>> {
+        Some(self.0.as_slice().iter().copied().map(T::to_f32).collect())
     }
 }
 
 /// Copy a tensor from the GPU
-#[derive(Clone)]
-pub struct CudaCopyFromDevice<T>(Arc<CudaDevice>, PhantomData<T>);
+pub struct CudaCopyFromDevice<T> {
+    device: Arc<CudaDevice>,
+    /// Copy into a pinned host buffer, reused across executions, instead of allocating a new one for each copy
+    use_pinned: bool,
+    pinned: Option<Rc<PinnedBuffer<T>>>,
+    /// Return without waiting for the copy to finish. Set through the "async" custom key by `Graph::execute_async`
+    run_async: bool,
+}
 crate::debug_type!(CudaCopyFromDevice);
 
+impl<T> Clone for CudaCopyFromDevice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            device: self.device.clone(),
+            use_pinned: self.use_pinned,
+            pinned: None,
+            run_async: self.run_async,
+        }
+    }
+}
+
 impl<T> CudaCopyFromDevice<T> {
     pub fn new(dev: Arc<CudaDevice>) -> Self {
-        CudaCopyFromDevice(dev, Default::default())
+        Self {
+            device: dev,
+            use_pinned: false,
+            pinned: None,
+            run_async: false,
+        }
+    }
+
+    /// Copy into a pinned host buffer, which is kept around and reused by later executions.
+    /// The output is [PinnedHostData] rather than a `Vec<f32>`, so only use this for copies read back with `data()`
+    pub fn use_pinned_buffer(&mut self) {
+        self.use_pinned = true;
+    }
+
+    /// The address and length of the pinned host buffer, if one has been allocated
+    pub fn pinned_buffer(&self) -> Option<(*const T, usize)> {
+        self.pinned.as_ref().map(|p| (p.ptr as *const T, p.len))
     }
 }
 
@@ -61,14 +254,61 @@ impl<T: CudaFloat> Operator for CudaCopyFromDevice<T> {
             // Already off device
             return vec![inp.pop().unwrap().0.cloned()];
         }
-        let buf = self
-            .0
-            .dtoh_sync_copy(get_buffer_from_tensor::<T>(&inp[0].0))
-            .unwrap();
+        let src = get_buffer_from_tensor::<T>(&inp[0].0);
+        if self.run_async {
+            // Each pending copy owns its buffer, since it's read after this op returns
+            let mut buffer = PinnedBuffer::<T>::new(&self.device, src.len());
+            let state = Arc::new(CopyState::default());
+            unsafe {
+                result::memcpy_dtoh_async(
+                    buffer.as_mut_slice(),
+                    *src.device_ptr(),
+                    *self.device.cu_stream(),
+                )
+                .unwrap();
+                // Wake the waiting future from the stream once the copy lands, instead of having it poll
+                sys::cuLaunchHostFunc(
+                    *self.device.cu_stream(),
+                    Some(copy_finished),
+                    Arc::into_raw(state.clone()) as *mut c_void,
+                )
+                .result()
+                .unwrap();
+            }
+            return vec![Tensor::new(PendingHostData(Arc::new(PendingCopy {
+                buffer,
+                state,
+                device: self.device.clone(),
+            })))];
+        }
+        if self.use_pinned {
+            // The last output may still be holding the buffer
+            let reusable = self
+                .pinned
+                .as_mut()
+                .and_then(Rc::get_mut)
+                .is_some_and(|p| p.len == src.len());
+            if !reusable {
+                self.pinned = Some(Rc::new(PinnedBuffer::new(&self.device, src.len())));
+            }
+            let pinned = self.pinned.as_mut().unwrap();
+            self.device
+                .dtoh_sync_copy_into(src, Rc::get_mut(pinned).unwrap().as_mut_slice())
+                .unwrap();
+            return vec![Tensor::new(PinnedHostData(pinned.clone()))];
+        }
+        let buf = self.device.dtoh_sync_copy(src).unwrap();
         vec![Tensor::new(
             buf.into_iter().map(T::to_f32).collect::<Vec<_>>(),
         )]
     }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "async" {
+            self.run_async = *input.downcast_ref::<bool>().unwrap();
+        }
+        None
+    }
 }
 
 /// Constant value on device
@@ -76,6 +316,7 @@ impl<T: CudaFloat> Operator for CudaCopyFromDevice<T> {
 pub struct CudaConstant<T> {
     pub value: ConstantValue,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
 }
@@ -94,6 +335,7 @@ impl<T> CudaConstant<T> {
         Self {
             value,
             device,
+            stream: Default::default(),
             dyn_map,
             _phantom: Default::default(),
         }
@@ -102,21 +344,25 @@ impl<T> CudaConstant<T> {
 
 impl<T: CudaFloat> Operator for CudaConstant<T> {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut a = unsafe { self.device.alloc::<T>(1).unwrap() };
+        let mut a = unsafe { self.stream.alloc::<T>(&self.device, 1) };
         let value = match &self.value {
             ConstantValue::Expression(e) => {
                 T::from_f32(e.exec(unsafe { self.dyn_map.as_ref().unwrap() }).unwrap() as f32)
             }
             ConstantValue::Float(f) => T::from_f32(*f),
         };
-        self.device.htod_copy_into(vec![value], &mut a).unwrap();
-        vec![Tensor::new(CudaData(a))]
+        self.stream
+            .htod_copy_into(&self.device, vec![value], &mut a);
+        vec![Tensor::new(self.stream.output(a))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             if let ConstantValue::Float(f) = self.value {
-                return Some(Box::new(format!("{f:?}")));
+                return Some(Box::new(crate::float_literal(f)));
             }
         }
         None
@@ -127,7 +373,14 @@ impl<T: CudaFloat> Operator for CudaConstant<T> {
 pub struct CudaContiguous<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
+    shape: ShapeTracker,
+    /// Dynamic dimensions folded into `function` as constants, along with their values
+    bound_dims: Vec<(char, usize)>,
+    /// Kernel taking every dynamic dimension as a parameter, used once the folded dims no longer match.
+    /// Compiled on warmup or the first mismatch and then reused, so changing dims never recompile
+    generic: Option<CudaFunction>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
 }
@@ -139,8 +392,29 @@ impl<T: CudaFloat> CudaContiguous<T> {
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
     ) -> Self {
-        let (idx, valid) = get_idx_valid_exps(shape);
-        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let (function, bound_dims) =
+            Self::compile(shape, &device, unsafe { dyn_map.as_ref().unwrap() });
+        Self {
+            generic: bound_dims.is_empty().then(|| function.clone()),
+            function,
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+            shape,
+            bound_dims,
+            dyn_symbols: render_dyn_dim_inputs(&[shape]).0,
+            dyn_map,
+        }
+    }
+
+    /// Compile the kernel with the currently bound dynamic dimensions folded into the index expressions
+    fn compile(
+        shape: ShapeTracker,
+        device: &Arc<CudaDevice>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> (CudaFunction, Vec<(char, usize)>) {
+        let (idx, valid, bound_dims) = get_bound_idx_valid_exps(shape, dyn_map);
+        let (_, rendered) = render_dyn_dim_inputs(&[shape]);
         let type_name = T::type_name();
         let code = format!(
             "
@@ -151,21 +425,33 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         out[idx] = inp_a[{idx}];
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
+        (compile_and_load_kernel(code, device), bound_dims)
+    }
+
+    fn generic_kernel(&mut self) -> &CudaFunction {
+        if self.generic.is_none() {
+            self.generic = Some(Self::compile(self.shape, &self.device, &FxHashMap::default()).0);
         }
+        self.generic.as_ref().unwrap()
     }
 }
 impl<T: CudaFloat> Operator for CudaContiguous<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // The folded kernel is only valid while the dims folded into it keep their values
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        let function = if self
+            .bound_dims
+            .iter()
+            .all(|(c, v)| dyn_map.get(c) == Some(v))
+        {
+            self.function.clone()
+        } else {
+            self.generic_kernel().clone()
+        };
         let res_shape = tensors[0].1.contiguous();
         let inp_size = res_shape.n_elements().to_usize().unwrap();
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -173,19 +459,29 @@ impl<T: CudaFloat> Operator for CudaContiguous<T> {
         ];
         input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
         unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+            function
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("input0".to_string()));
         }
+        if key == "warmup" {
+            // Have the fallback kernel ready in case the folded dims change
+            self.generic_kernel();
+        }
         None
     }
 }
@@ -194,6 +490,7 @@ impl<T: CudaFloat> Operator for CudaContiguous<T> {
 pub struct CudaLog2<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaLog2);
@@ -214,6 +511,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -223,21 +521,25 @@ impl<T: CudaFloat> Operator for CudaLog2<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("log2(input0)".to_string()));
         }
@@ -250,6 +552,7 @@ impl<T: CudaFloat> Operator for CudaLog2<T> {
 pub struct CudaExp2<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaExp2);
@@ -270,6 +573,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -278,21 +582,25 @@ impl<T: CudaFloat> Operator for CudaExp2<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("exp2(input0)".to_string()));
         }
@@ -305,6 +613,7 @@ impl<T: CudaFloat> Operator for CudaExp2<T> {
 pub struct CudaSqrt<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaSqrt);
@@ -326,6 +635,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -334,21 +644,25 @@ impl<T: CudaFloat> Operator for CudaSqrt<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new(format!(
                 "{}(input0)",
@@ -364,6 +678,7 @@ impl<T: CudaFloat> Operator for CudaSqrt<T> {
 pub struct CudaSin<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaSin);
@@ -386,6 +701,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
                 &device,
             ),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -395,21 +711,25 @@ impl<T: CudaFloat> Operator for CudaSin<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("sin(input0)".to_string()));
         }
@@ -422,6 +742,7 @@ impl<T: CudaFloat> Operator for CudaSin<T> {
 pub struct CudaRecip<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaRecip);
@@ -443,6 +764,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -452,21 +774,25 @@ impl<T: CudaFloat> Operator for CudaRecip<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new(format!(
                 "{}(input0)",
@@ -482,6 +808,7 @@ impl<T: CudaFloat> Operator for CudaRecip<T> {
 pub struct CudaAdd<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -499,12 +826,13 @@ impl<T: CudaFloat> CudaAdd<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!(
             "
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         out[idx] =
             (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}])
             + (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
@@ -513,6 +841,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -525,7 +854,7 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { self.stream.alloc::<T>(&self.device, inp_size) };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -537,14 +866,21 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("input0 + input1".to_string()));
         }
@@ -556,6 +892,7 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
 pub struct CudaMul<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -573,17 +910,19 @@ impl<T: CudaFloat> CudaMul<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!("
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         out[idx] = (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]) * (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -596,7 +935,7 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { self.stream.alloc::<T>(&self.device, inp_size) };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -608,14 +947,21 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("input0 * input1".to_string()));
         }
@@ -627,6 +973,7 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
 pub struct CudaMod<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -644,17 +991,19 @@ impl<T: CudaFloat> CudaMod<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!("
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         out[idx] = fmod((({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]), (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]));
     }}
 }}");
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -667,7 +1016,7 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { self.stream.alloc::<T>(&self.device, inp_size) };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -679,14 +1028,21 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("fmod(input0, input1)".to_string()));
         }
@@ -698,6 +1054,7 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
 pub struct CudaLessThan<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -715,11 +1072,12 @@ impl<T: CudaFloat> CudaLessThan<T> {
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
         let type_name = T::type_name();
+        let guard = idx_guard(a_shape.n_elements(), "numel");
         let code = format!("
 #include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
     int idx = blockIdx.x * blockDim.x + threadIdx.x;
-    if (idx < numel) {{
+    {guard}
         {type_name} a_t = (({a_valid}) != 0) ? inp_a[{a_idx}] : ({type_name})0.0;
         {type_name} b_t = (({b_valid}) != 0) ? inp_b[{b_idx}] : ({type_name})0.0;
         if (a_t < b_t) {{
@@ -732,6 +1090,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
@@ -744,7 +1103,7 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { self.stream.alloc::<T>(&self.device, inp_size) };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -756,14 +1115,21 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("(float)(input0 < input1 ? 1.0 : 0.0)".to_string()));
         }
@@ -771,187 +1137,219 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct CudaSumReduce<T> {
-    function: CudaFunction,
-    pub device: Arc<CudaDevice>,
-    pub dim: usize,
-    _phantom: PhantomData<T>,
-    dyn_symbols: Vec<char>,
-    dyn_map: *const FxHashMap<char, usize>,
-}
-crate::debug_type!(CudaSumReduce);
-
-impl<T: CudaFloat> CudaSumReduce<T> {
-    pub fn new(
-        dim: usize,
-        shape: ShapeTracker,
-        device: Arc<CudaDevice>,
-        dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
-        let (idx, valid) = get_idx_valid_exps(shape);
-        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
-        let type_name = T::type_name();
-        let code = format!("#include \"cuda_fp16.h\"
+/// Threads cooperating on a single reduction in the sum / max reduce kernels
+const REDUCE_THREADS: usize = 256;
+
+/// Render a reduction kernel. Each output element is reduced by a row of `blockDim.x` threads, which stride
+/// over the reduced dimension and then combine their partial results in shared memory.
+fn render_reduce_kernel(
+    type_name: &str,
+    idx: &str,
+    valid: &str,
+    rendered: &str,
+    identity: &str,
+    combine: impl Fn(&str, &str) -> String,
+) -> String {
+    let accumulate = combine("reduce_value", &format!("(float)inp[{idx}]"));
+    let merge = combine("partials[tid]", "partials[tid + s]");
+    format!("#include \"cuda_fp16.h\"
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, int numel{rendered}) {{
-    int i_ = blockIdx.x * blockDim.x + threadIdx.x;
+    __shared__ float partials[{REDUCE_THREADS}];
+    int i_ = blockIdx.x * blockDim.y + threadIdx.y;
+    int tid = threadIdx.y * blockDim.x + threadIdx.x;
 
+    float reduce_value = {identity};
     if (i_ < numel) {{
         int a_ = i_ / back_size;
         int b_ = i_ % back_size;
-        float reduce_value = 0.0;
-        for (int c_ = 0; c_ < dim_size; c_++) {{
+        for (int c_ = threadIdx.x; c_ < dim_size; c_ += blockDim.x) {{
             int idx = a_ * dim_size * back_size + c_ * back_size + b_;
             if (({valid}) != 0) {{
-                reduce_value = reduce_value + (float)inp[{idx}];
+                reduce_value = {accumulate};
             }}
         }}
-        out[i_] = ({type_name})reduce_value;
     }}
-}}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            dim,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
+    partials[tid] = reduce_value;
+    __syncthreads();
+    for (int s = blockDim.x / 2; s > 0; s >>= 1) {{
+        if (threadIdx.x < s) {{
+            partials[tid] = {merge};
+        }}
+        __syncthreads();
+    }}
+    if (threadIdx.x == 0 && i_ < numel) {{
+        out[i_] = ({type_name})partials[tid];
+    }}
+}}")
+}
+
+/// Render the reduction kernel for a [ReduceOp], substituting its identity and combiner into the shared template
+fn render_reduce_op_kernel(
+    type_name: &str,
+    idx: &str,
+    valid: &str,
+    rendered: &str,
+    op: ReduceOp,
+) -> String {
+    match op {
+        ReduceOp::Sum => render_reduce_kernel(type_name, idx, valid, rendered, "0.0", |a, b| {
+            format!("{a} + {b}")
+        }),
+        ReduceOp::Max => render_reduce_kernel(
+            type_name,
+            idx,
+            valid,
+            rendered,
+            "-__int_as_float(0x7f800000)",
+            |a, b| format!("max({a}, {b})"),
+        ),
+        ReduceOp::Min => render_reduce_kernel(
+            type_name,
+            idx,
+            valid,
+            rendered,
+            "__int_as_float(0x7f800000)",
+            |a, b| format!("min({a}, {b})"),
+        ),
+        ReduceOp::Product => {
+            render_reduce_kernel(type_name, idx, valid, rendered, "1.0", |a, b| {
+                format!("{a} * {b}")
+            })
         }
     }
 }
-impl<T> Operator for CudaSumReduce<T>
-where
-    T: CudaFloat,
-    CudaData<T>: Data,
-{
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut shape = tensors[0].1;
-        shape.remove_dim(self.dim);
-        let inp_size = shape.n_elements().to_usize().unwrap();
-        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let front_size: usize = tensors[0]
-            .1
-            .shape()
-            .iter()
-            .take(self.dim)
-            .map(|i| i.to_usize().unwrap())
-            .product();
-        let back_size: usize = tensors[0]
-            .1
-            .shape()
-            .iter()
-            .skip(self.dim + 1)
-            .map(|i| i.to_usize().unwrap())
-            .product();
-        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            inp.as_kernel_param(),
-            front_size.as_kernel_param(),
-            back_size.as_kernel_param(),
-            dim_size.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
-        }
-        vec![Tensor::new(CudaData(out))]
+/// Run a kernel rendered by [render_reduce_kernel] over the input, reducing `dim`
+#[allow(clippy::too_many_arguments)]
+fn launch_reduce<T: CudaFloat>(
+    function: &CudaFunction,
+    device: &Arc<CudaDevice>,
+    stream: &OpStream,
+    dim: usize,
+    sequential: bool,
+    dyn_symbols: &[char],
+    dyn_map: *const FxHashMap<char, usize>,
+    tensors: Vec<(InputTensor, ShapeTracker)>,
+) -> Vec<Tensor> {
+    let mut shape = tensors[0].1;
+    shape.remove_dim(dim);
+    let inp_size = shape.n_elements().to_usize().unwrap();
+    let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+    let front_size: usize = tensors[0]
+        .1
+        .shape()
+        .iter()
+        .take(dim)
+        .map(|i| i.to_usize().unwrap())
+        .product();
+    let back_size: usize = tensors[0]
+        .1
+        .shape()
+        .iter()
+        .skip(dim + 1)
+        .map(|i| i.to_usize().unwrap())
+        .product();
+    let dim_size = tensors[0].1.shape()[dim].to_usize().unwrap();
+
+    // Every output is written once from its accumulator, so there's nothing to zero
+    let out = unsafe { stream.alloc::<T>(device, inp_size) };
+    let mut params = vec![
+        (&out).as_kernel_param(),
+        inp.as_kernel_param(),
+        front_size.as_kernel_param(),
+        back_size.as_kernel_param(),
+        dim_size.as_kernel_param(),
+        inp_size.as_kernel_param(),
+    ];
+    input_dyn_dims(&mut params, dyn_symbols, dyn_map);
+    unsafe {
+        function
+            .clone()
+            .launch_on(
+                stream,
+                reduce_launch_config(inp_size, dim_size, sequential),
+                &mut params,
+            )
+            .unwrap();
+    }
+    vec![Tensor::new(stream.output(out))]
+}
+
+/// Launch config for the reduction kernels. Small reduced dimensions (or sequential reductions) get a thread per
+/// output element, larger ones spread each output element across up to `REDUCE_THREADS` threads.
+fn reduce_launch_config(n_outputs: usize, dim_size: usize, sequential: bool) -> LaunchConfig {
+    let threads_per_output = if sequential {
+        1
+    } else {
+        dim_size.next_power_of_two().min(REDUCE_THREADS)
+    };
+    let outputs_per_block = REDUCE_THREADS / threads_per_output;
+    LaunchConfig {
+        grid_dim: (n_outputs.div_ceil(outputs_per_block).max(1) as u32, 1, 1),
+        block_dim: (threads_per_output as u32, outputs_per_block as u32, 1),
+        shared_mem_bytes: 0,
     }
 }
 
+/// Reduce a dimension with any [ReduceOp]
 #[derive(Clone)]
-pub struct CudaMaxReduce<T> {
+pub struct CudaReduce<T> {
     function: CudaFunction,
     pub device: Arc<CudaDevice>,
+    stream: OpStream,
     pub dim: usize,
+    pub op: ReduceOp,
+    /// Reduce each output with a single thread, in the same order as the CPU. See [Graph::deterministic]
+    pub sequential: bool,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
 }
-crate::debug_type!(CudaMaxReduce);
+crate::debug_type!(CudaReduce);
 
-impl<T: CudaFloat> CudaMaxReduce<T> {
+impl<T: CudaFloat> CudaReduce<T> {
     pub fn new(
         dim: usize,
+        op: ReduceOp,
         shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
     ) -> Self {
         let (idx, valid) = get_idx_valid_exps(shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
-        let type_name = T::type_name();
-        let code = format!("#include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, int numel{rendered}) {{
-    int i_ = blockIdx.x * blockDim.x + threadIdx.x;
-
-    if (i_ < numel) {{
-        int a_ = i_ / back_size;
-        int b_ = i_ % back_size;
-        float reduce_value = -__int_as_float(0x7f800000);
-        for (int c_ = 0; c_ < dim_size; c_++) {{
-            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
-            if (({valid}) != 0) {{
-                reduce_value = max(reduce_value, (float)inp[{idx}]);
-            }}
-        }}
-        out[i_] = ({type_name})reduce_value;
-    }}
-}}");
+        let code = render_reduce_op_kernel(T::type_name(), &idx, &valid, &rendered, op);
         Self {
             function: compile_and_load_kernel(code, &device),
             device,
+            stream: Default::default(),
             dim,
+            op,
+            sequential: false,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
         }
     }
 }
-impl<T: CudaFloat> Operator for CudaMaxReduce<T> {
+impl<T: CudaFloat> Operator for CudaReduce<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut shape = tensors[0].1;
-        shape.remove_dim(self.dim);
-        let inp_size = shape.n_elements().to_usize().unwrap();
-        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let front_size: usize = tensors[0]
-            .1
-            .shape()
-            .iter()
-            .take(self.dim)
-            .map(|i| i.to_usize().unwrap())
-            .product();
-        let back_size: usize = tensors[0]
-            .1
-            .shape()
-            .iter()
-            .skip(self.dim + 1)
-            .map(|i| i.to_usize().unwrap())
-            .product();
-        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
+        launch_reduce::<T>(
+            &self.function,
+            &self.device,
+            &self.stream,
+            self.dim,
+            self.sequential,
+            &self.dyn_symbols,
+            self.dyn_map,
+            tensors,
+        )
+    }
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            inp.as_kernel_param(),
-            front_size.as_kernel_param(),
-            back_size.as_kernel_param(),
-            dim_size.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
         }
-        vec![Tensor::new(CudaData(out))]
+        None
     }
 }
 
@@ -970,6 +1368,10 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
             .filter(|n| {
                 graph.node_weight(*n).unwrap().as_any().is::<Function>()
                     && graph.edges(*n).count() != 0
+                    // Functions already feeding copies were handled by an earlier run
+                    && !graph
+                        .edges(*n)
+                        .all(|e| graph.check_node_type::<CudaCopyToDevice<T>>(e.target()))
             })
             .collect::<Vec<_>>()
         {
@@ -1017,8 +1419,11 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
             .to_retrieve
             .iter()
             .map(|(a, b)| (*a, *b))
-            // Filter to non-functions
-            .filter(|(n, _)| !graph.node_weight(*n).unwrap().as_any().is::<LFunction>())
+            // Filter to non-functions, and outputs that aren't already copied back
+            .filter(|(n, _)| {
+                !graph.node_weight(*n).unwrap().as_any().is::<LFunction>()
+                    && !graph.check_node_type::<CudaCopyFromDevice<T>>(*n)
+            })
             .collect::<Vec<_>>()
         {
             if graph
@@ -1052,6 +1457,7 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
         }
 
         // Swap primitive ops
+        let sequential = graph.deterministic;
         for id in graph.node_indices().collect::<Vec<_>>() {
             let shapes = graph
                 .edges_directed(id, petgraph::Direction::Incoming)
@@ -1075,6 +1481,22 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                 ));
             } else if is::<Recip>(op) {
                 *op_ref = Box::new(CudaRecip::<T>::new(dev.clone()));
+            } else if is::<IsNan>(op) {
+                *op_ref = Box::new(crate::unary::CudaIsNan::<T>::new(dev.clone()));
+            } else if is::<IsInf>(op) {
+                *op_ref = Box::new(crate::unary::CudaIsInf::<T>::new(dev.clone()));
+            } else if let Some(NanToNum {
+                nan,
+                posinf,
+                neginf,
+            }) = op_ref.as_any().downcast_ref()
+            {
+                *op_ref = Box::new(crate::unary::CudaNanToNum::<T>::new(
+                    dev.clone(),
+                    *nan,
+                    *posinf,
+                    *neginf,
+                ));
             } else if is::<Sqrt>(op) {
                 *op_ref = Box::new(CudaSqrt::<T>::new(dev.clone()));
             } else if is::<Add>(op) {
@@ -1105,26 +1527,65 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                     dev.clone(),
                     &graph.dyn_map,
                 ));
-            } else if is::<Contiguous>(op) {
-                *op_ref = Box::new(CudaContiguous::<T>::new(
+            } else if is::<Select>(op) {
+                *op_ref = Box::new(crate::binary::CudaSelect::<T>::new(
                     shapes[0],
+                    shapes[1],
+                    shapes[2],
                     dev.clone(),
                     &graph.dyn_map,
                 ));
-            } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
-                *op_ref = Box::new(CudaSumReduce::<T>::new(
-                    *dim,
+            } else if is::<Contiguous>(op) {
+                *op_ref = Box::new(CudaContiguous::<T>::new(
                     shapes[0],
                     dev.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(CudaReduce {
+                    sequential,
+                    ..CudaReduce::<T>::new(
+                        *dim,
+                        ReduceOp::Sum,
+                        shapes[0],
+                        dev.clone(),
+                        &graph.dyn_map,
+                    )
+                });
             } else if let Some(MaxReduce(dim)) = op_ref.as_any().downcast_ref() {
-                *op_ref = Box::new(CudaMaxReduce::<T>::new(
-                    *dim,
-                    shapes[0],
+                *op_ref = Box::new(CudaReduce {
+                    sequential,
+                    ..CudaReduce::<T>::new(
+                        *dim,
+                        ReduceOp::Max,
+                        shapes[0],
+                        dev.clone(),
+                        &graph.dyn_map,
+                    )
+                });
+            } else if let Some(ProductReduce(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(CudaReduce {
+                    sequential,
+                    ..CudaReduce::<T>::new(
+                        *dim,
+                        ReduceOp::Product,
+                        shapes[0],
+                        dev.clone(),
+                        &graph.dyn_map,
+                    )
+                });
+            } else if let Some(norm) = op_ref.as_any().downcast_ref::<FusedLayerNorm>() {
+                *op_ref = Box::new(crate::unary::CudaLayerNorm::<T>::new(
                     dev.clone(),
+                    norm,
+                    &shapes,
                     &graph.dyn_map,
                 ));
+            } else if let Some(Reduce { axis, op }) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(CudaReduce {
+                    sequential,
+                    ..CudaReduce::<T>::new(*axis, *op, shapes[0], dev.clone(), &graph.dyn_map)
+                });
             }
         }
     }
@@ -1,6 +1,6 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchConfig};
 use petgraph::visit::EdgeRef;
 
 use luminal::{
@@ -9,7 +9,8 @@ use luminal::{
 };
 
 use crate::{
-    binary::CudaGather, compile_and_load_kernel, get_buffer_from_tensor, CudaData, CudaFloat,
+    binary::CudaGather, compile_and_load_kernel, get_buffer_from_tensor, CudaFloat, LaunchOnStream,
+    OpStream,
 };
 
 /// Multiplies a BxMxK matrix with a KxN matrix, resulting in a BxMxN matrix. This expects the first input to be a quantized 2D matrix
@@ -17,6 +18,7 @@ use crate::{
 pub struct QuantizedMatmul<T> {
     matvec_function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(QuantizedMatmul);
@@ -108,6 +110,7 @@ extern \"C\" __global__ void kernel(
     }}
 }}"), &device),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -140,7 +143,7 @@ impl<T: 'static + CudaFloat> Operator for QuantizedMatmul<T> {
         let k = b_shape[b_dims - 2];
         let n = b_shape[b_dims - 1];
 
-        let out = unsafe { self.device.alloc::<T>(batch_size * m * n).unwrap() };
+        let out = unsafe { self.stream.alloc::<T>(&self.device, batch_size * m * n) };
 
         // Matvec
         let mut params = vec![
@@ -155,7 +158,8 @@ impl<T: 'static + CudaFloat> Operator for QuantizedMatmul<T> {
         unsafe {
             self.matvec_function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig {
                         grid_dim: (n.div_ceil(8) as u32, 1, (m * batch_size) as u32),
                         block_dim: (8, 8, 1),
@@ -166,7 +170,14 @@ impl<T: 'static + CudaFloat> Operator for QuantizedMatmul<T> {
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -174,6 +185,7 @@ impl<T: 'static + CudaFloat> Operator for QuantizedMatmul<T> {
 pub struct QuantizedGather<T> {
     pipeline: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     embed_dim: usize,
     _phantom: PhantomData<T>,
 }
@@ -198,7 +210,7 @@ extern \"C\" __global__ void kernel(const float* inp, const block_q8_0* weights,
         int block_idx = ((int)inp[pos_x] * embedding_dim + pos_y) / QK8_0;
         out[pos_x * embedding_dim + pos_y] = ({type_name})weights[block_idx].qs[pos_y % QK8_0] * ({type_name})weights[block_idx].d;
     }}
-}}"), &device), device, embed_dim, _phantom: Default::default()}
+}}"), &device), device, stream: Default::default(), embed_dim, _phantom: Default::default()}
     }
 }
 
@@ -206,15 +218,16 @@ impl<T: CudaFloat> Operator for QuantizedGather<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Setup buffers
         let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let mut index_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
-        self.device
-            .htod_copy_into(indexes.clone(), &mut index_buffer)
-            .unwrap();
+        let mut index_buffer = unsafe {
+            self.stream
+                .alloc_scratch::<f32>(&self.device, indexes.len())
+        };
+        self.stream
+            .htod_copy_into(&self.device, indexes.clone(), &mut index_buffer);
 
         let out = unsafe {
-            self.device
-                .alloc::<T>(indexes.len() * self.embed_dim)
-                .unwrap()
+            self.stream
+                .alloc::<T>(&self.device, indexes.len() * self.embed_dim)
         };
 
         // Set inputs
@@ -230,7 +243,8 @@ impl<T: CudaFloat> Operator for QuantizedGather<T> {
         unsafe {
             self.pipeline
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig {
                         grid_dim: (indexes.len() as u32, self.embed_dim as u32, 1),
                         block_dim: (16, 16, 1),
@@ -241,7 +255,14 @@ impl<T: CudaFloat> Operator for QuantizedGather<T> {
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -320,7 +341,7 @@ mod tests {
                 Vec::<u8>::from_raw_parts(weights.as_ptr() as *mut u8, n_bytes, n_bytes)
             })
             .unwrap();
-        Tensor::new(CudaData(buffer))
+        Tensor::new(CudaData::new(buffer))
     }
 
     #[test]
@@ -162,6 +162,49 @@ fn test_sum_reduce() {
     assert_close(&d.data(), &d_d.as_vec());
 }
 
+#[test]
+fn test_prod_reduce() {
+    let data = random_vec(6);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(data.clone());
+    let mut b = a.prod_reduce::<_, LAxis<1>>().retrieve();
+    let c = cx.tensor::<R2<2, 0>>().set(vec![]);
+    let mut d = c.prod_reduce::<R1<2>, LAxis<1>>().retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut d));
+    cx.execute();
+
+    crate::tests::assert_op_in_graph::<crate::prim::CudaReduce<f32>>(&cx);
+    let expected = data
+        .chunks(3)
+        .map(|r| r.iter().product::<f32>())
+        .collect::<Vec<_>>();
+    assert_close(&b.data(), &expected);
+    assert_exact(&d.data(), &[1., 1.]);
+}
+
+#[test]
+fn test_reduce() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set([[1., -2., 3.], [4., 0.5, -6.]]);
+    let mut sum = a.reduce::<R1<2>, LAxis<1>>(ReduceOp::Sum).retrieve();
+    let mut max = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Max).retrieve();
+    let mut min = a.min_reduce::<R1<2>, LAxis<1>>().retrieve();
+    let mut prod = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Product).retrieve();
+
+    cx.compile(
+        CudaCompiler::<f32>::default(),
+        (&mut sum, &mut max, &mut min, &mut prod),
+    );
+    cx.execute();
+
+    crate::tests::assert_op_in_graph::<crate::prim::CudaReduce<f32>>(&cx);
+    assert_exact(&sum.data(), &[2., -1.5]);
+    assert_exact(&max.data(), &[4., 0.5, 3.]);
+    assert_exact(&min.data(), &[-2., -6.]);
+    assert_exact(&prod.data(), &[4., -1., -18.]);
+}
+
 #[test]
 fn test_sum_reduce2() {
     let mut cx = Graph::new();
@@ -210,6 +253,25 @@ fn test_max_reduce() {
     assert_close(&d.data(), &d_d.as_vec());
 }
 
+#[test]
+fn test_large_reduce() {
+    let data = random_vec(200_000);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 100_000>>().set(data.clone());
+    let mut b = a.sum_reduce::<_, LAxis<1>>().retrieve();
+    let mut c = a.max_reduce::<_, LAxis<1>>().retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<2>, DConst::<100_000>));
+    let d_b = d_a.clone().sum::<_, DAxis<1>>();
+    let d_c = d_a.max::<_, DAxis<1>>();
+    assert_close_precision(&b.data(), &d_b.as_vec(), 1e-2);
+    assert_exact(&c.data(), &d_c.as_vec());
+}
+
 #[test]
 fn test_mean_reduce() {
     let data = random_vec(40960);
@@ -734,3 +796,870 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_unresolved_dyn_dim() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>,)>().set_dyn(random_vec(4), &[4]);
+    let mut b = a.exp2().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.dyn_map.clear();
+    let err = cx.try_execute().unwrap_err();
+    assert!(matches!(err, GraphError::UnresolvedDim { dim: 'a', .. }));
+    assert!(err.to_string().contains("unresolved dynamic dimension 'a'"));
+}
+
+#[test]
+fn test_matmul_bias() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 3>>().set(random_vec(12));
+    let w = cx.tensor::<R2<3, 5>>().set(random_vec(15));
+    let bias = cx.tensor::<R1<5>>().set(random_vec(5));
+    let mut out = (a.matmul(w) + bias.expand()).retrieve();
+    cx.execute();
+    let unfused = out.data();
+    out.drop();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    let matmuls = cx
+        .node_indices()
+        .filter(|n| cx.check_node_type::<crate::matmul::Matmul<f32>>(*n))
+        .collect::<Vec<_>>();
+    assert_eq!(matmuls.len(), 1);
+    assert_eq!(
+        format!("{:?}", cx.node_weight(matmuls[0]).unwrap()),
+        "MatmulBias"
+    );
+    assert!(!cx
+        .node_indices()
+        .any(|n| cx.check_node_type::<crate::prim::CudaAdd<f32>>(n)));
+    cx.execute();
+
+    assert_close(&out.data(), &unfused);
+}
+
+#[test]
+fn test_neg() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.5, -2., 0.]);
+    let mut b = (-a).retrieve();
+    let mut c = (0. - a).retrieve();
+    cx.execute();
+    assert_exact(&b.data(), &c.data());
+    b.drop();
+    c.drop();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    crate::tests::assert_op_in_graph::<crate::unary::CudaNeg<f32>>(&cx);
+    cx.execute();
+    assert_exact(&b.data(), &[-1.5, 2., 0.]);
+    assert_exact(&b.data(), &c.data());
+}
+
+#[test]
+fn test_softmax_cpu_parity() {
+    let data = random_vec(3 * 64);
+    let build = |cx: &mut Graph| {
+        cx.tensor::<R2<3, 64>>()
+            .set(data.clone())
+            .softmax::<LAxis<1>>()
+            .retrieve()
+    };
+    let mut cpu_cx = Graph::new();
+    let mut cpu_out = build(&mut cpu_cx);
+    cpu_cx.compile(luminal_cpu::CPUCompiler::default(), &mut cpu_out);
+    let mut cuda_cx = Graph::new();
+    let mut cuda_out = build(&mut cuda_cx);
+    cuda_cx.compile(CudaCompiler::<f32>::default(), &mut cuda_out);
+    crate::tests::assert_op_in_graph::<crate::unary::CudaSoftmax<f32>>(&cuda_cx);
+    cpu_cx.execute();
+    cuda_cx.execute();
+
+    assert_close(&cpu_out.data(), &cuda_out.data());
+}
+
+#[test]
+fn test_compile_report() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let mut b = a.exp2().retrieve();
+    let ((), report) = cx.compile(
+        Reported(crate::prim::PrimitiveCompiler::<f32>::default()),
+        &mut b,
+    );
+    // Copies to and from the device get inserted around the exp2
+    assert_eq!(report.nodes_before, 2);
+    assert_eq!(report.nodes_after, 4);
+    assert!(report.name.starts_with("PrimitiveCompiler"));
+    cx.execute();
+    assert_close(&b.data(), &[2., 4., 8.]);
+}
+
+#[test]
+fn test_pinned_copy_from_device() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let mut b = (a * 2.).retrieve();
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::PinnedCopyCompiler::<f32>::default(),
+        ),
+        &mut b,
+    );
+    let copy = cx
+        .node_indices()
+        .find(|n| cx.check_node_type::<crate::CudaCopyFromDevice<f32>>(*n))
+        .unwrap();
+
+    cx.execute();
+    assert_exact(&b.data(), &[2., 4., 6., 8.]);
+    // The output is read straight out of the pinned buffer rather than copied into a new Vec
+    assert!(cx
+        .get_tensor_ref(b.id, 0)
+        .unwrap()
+        .is::<crate::prim::PinnedHostData<f32>>());
+    let (ptr, len) = cx
+        .get_op::<crate::CudaCopyFromDevice<f32>>(copy)
+        .pinned_buffer()
+        .unwrap();
+    assert_eq!(len, 4);
+
+    a.set(vec![5., 6., 7., 8.]);
+    b.drop();
+    cx.execute();
+    assert_exact(&b.data(), &[10., 12., 14., 16.]);
+    // The same host buffer was used for the second copy
+    assert_eq!(
+        cx.get_op::<crate::CudaCopyFromDevice<f32>>(copy)
+            .pinned_buffer(),
+        Some((ptr, len))
+    );
+}
+
+#[test]
+fn test_copies_from_device_run_last() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<256>>().set(random_vec(256));
+    let b = cx.tensor::<R1<256>>().set(random_vec(256));
+    let mut c = (a.exp() * 2.).retrieve();
+    let mut d = (b.sin() + b).sqrt().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut c, &mut d));
+
+    let order = cx
+        .visualize_execution_schedule()
+        .steps
+        .into_iter()
+        .map(|s| s.node)
+        .collect::<Vec<_>>();
+    let copies = order
+        .iter()
+        .positions(|n| cx.check_node_type::<crate::CudaCopyFromDevice<f32>>(*n))
+        .collect::<Vec<_>>();
+    assert_eq!(copies.len(), 2);
+    // Both branches finish computing before either result is copied back
+    let last_compute = copies
+        .iter()
+        .flat_map(|i| cx.get_sources(order[*i]))
+        .map(|(src, _, _)| order.iter().position(|n| *n == src).unwrap())
+        .max()
+        .unwrap();
+    assert!(last_compute < *copies.iter().min().unwrap());
+    assert_eq!(
+        copies.iter().max().unwrap() - copies.iter().min().unwrap(),
+        1
+    );
+
+    cx.execute();
+    assert_eq!(c.data().len(), 256);
+    assert_eq!(d.data().len(), 256);
+}
+
+#[test]
+fn test_mul_add_fusion() {
+    let (a_data, b_data, c_data) = (random_vec(32 * 64), random_vec(32 * 64), random_vec(64));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<32, 64>>().set(a_data.clone());
+    let b = cx.tensor::<R2<32, 64>>().set(b_data.clone());
+    let c = cx.tensor::<R1<64>>().set(c_data.clone());
+    let mut d = (a * b + c.expand::<_, LAxis<0>>()).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut d);
+    cx.execute();
+
+    crate::tests::assert_op_in_graph::<crate::binary::CudaMulAdd<f32>>(&cx);
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(a_data, (DConst::<32>, DConst::<64>));
+    let d_b = d_dev.tensor_from_vec(b_data, (DConst::<32>, DConst::<64>));
+    let d_c = d_dev.tensor_from_vec(c_data, (DConst::<64>,));
+    let d_d = d_a * d_b + d_c.broadcast::<(DConst<32>, DConst<64>), _>();
+    assert_close(&d.data(), &d_d.as_vec());
+}
+
+#[test]
+fn test_execute_async() {
+    let data = random_vec(4096);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4096>>().set(data);
+    let mut b = (a.exp() * a.sin()).retrieve();
+    let mut c = a.sum_reduce::<_, LAxis<0>>().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+
+    cx.execute();
+    let (sync_b, sync_c) = (b.data(), c.data());
+    b.drop();
+    c.drop();
+
+    /// Wakes the test thread when the copies land
+    struct Unpark(std::thread::Thread);
+    impl std::task::Wake for Unpark {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = std::task::Waker::from(std::sync::Arc::new(Unpark(std::thread::current())));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut pending = std::pin::pin!(cx.execute_async());
+    while std::future::Future::poll(pending.as_mut(), &mut context).is_pending() {
+        std::thread::park();
+    }
+    assert_exact(&b.data(), &sync_b);
+    assert_exact(&c.data(), &sync_c);
+}
+
+#[test]
+fn test_sin_cos_fusion() {
+    let data = random_vec(1024);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<1024>>().set(data.clone());
+    let mut sin = a.sin().retrieve();
+    let mut cos = a.cos().retrieve();
+    // Consumers that aren't retrieved, so the sin and cos can be fused
+    let mut sum = (a.sin() * 2. + a.cos()).retrieve();
+
+    cx.compile(
+        CudaCompiler::<f32>::default(),
+        (&mut sin, &mut cos, &mut sum),
+    );
+    cx.execute();
+
+    crate::tests::assert_op_in_graph::<crate::unary::CudaSinCos<f32>>(&cx);
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<1024>,));
+    let d_sin = d_a.clone().sin();
+    let d_cos = d_a.cos();
+    assert_close(&sin.data(), &d_sin.as_vec());
+    assert_close(&cos.data(), &d_cos.as_vec());
+    assert_close(&sum.data(), &(d_sin * 2. + d_cos).as_vec());
+}
+
+#[test]
+fn test_check_finite() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1., 0., 2., 4.]);
+    let mut b = a.recip().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+
+    // Found on the device, before the result gets copied back
+    let err = cx.execute_check_finite().unwrap_err();
+    assert!(!err.op.starts_with("CudaCopy"));
+    assert_eq!((err.index, err.value), (1, f32::INFINITY));
+}
+
+#[test]
+fn test_expand_dim() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R2<4, 3>>().set(vec![1.; 12]);
+    let mut c = (a.expand_dim::<R2<4, 3>>(0, 4) * b).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut c);
+    cx.execute();
+
+    assert_exact(&c.data(), &[1., 2., 3., 1., 2., 3., 1., 2., 3., 1., 2., 3.]);
+}
+
+#[test]
+fn test_is_nan_is_inf() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R1<5>>()
+        .set([1., f32::NAN, f32::INFINITY, -f32::INFINITY, -2.]);
+    let mut nan = a.is_nan().retrieve();
+    let mut inf = a.is_inf().retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut nan, &mut inf));
+    cx.execute();
+
+    assert_exact(&nan.data(), &[0., 1., 0., 0., 0.]);
+    assert_exact(&inf.data(), &[0., 0., 1., 1., 0.]);
+}
+
+#[test]
+fn test_nan_to_num() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R1<5>>()
+        .set([1., f32::NAN, f32::INFINITY, -f32::INFINITY, -2.]);
+    let mut b = a.nan_to_num(0., 100., -100.).retrieve();
+    // Non-finite replacements need their own literals in the kernel
+    let mut c = a
+        .nan_to_num(f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY)
+        .retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    assert_exact(&b.data(), &[1., 0., 100., -100., -2.]);
+    assert_exact(
+        &c.data(),
+        &[1., f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY, -2.],
+    );
+}
+
+#[test]
+fn test_causal_mask_select() {
+    let data = random_vec(16);
+    let mut cx = Graph::new();
+    let scores = cx.tensor::<R2<4, 4>>().set(data);
+    let mut masked = cx
+        .causal_mask::<LConst<4>>()
+        .select(scores, cx.constant(f32::NEG_INFINITY).expand())
+        .retrieve();
+    cx.execute();
+    let unoptimized = masked.data();
+    masked.drop();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut masked);
+    cx.execute();
+
+    assert_exact(&masked.data(), &unoptimized);
+}
+
+#[test]
+fn test_primitive_compiler_idempotent() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([4., 5., 6.]);
+    let mut c = (a * b).exp2().retrieve();
+
+    cx.compile(crate::prim::PrimitiveCompiler::<f32>::default(), &mut c);
+    let structure = |cx: &Graph| {
+        (
+            cx.node_indices()
+                .map(|n| format!("{:?}", cx.node_weight(n).unwrap()))
+                .sorted()
+                .collect::<Vec<_>>(),
+            cx.edge_count(),
+        )
+    };
+    let (first, first_output) = (structure(&cx), c.id);
+    // A second run finds everything already on device and leaves the graph alone
+    cx.compile(crate::prim::PrimitiveCompiler::<f32>::default(), &mut c);
+    assert_eq!(structure(&cx), first);
+    assert_eq!(c.id, first_output);
+
+    cx.execute();
+    assert_close(&c.data(), &[4f32.exp2(), 10f32.exp2(), 18f32.exp2()]);
+}
+
+#[test]
+fn test_clip() {
+    let data = random_vec(512)
+        .into_iter()
+        .map(|i| i * 4. - 2.)
+        .collect_vec();
+    let expected = data.iter().map(|i| i.clamp(0., 1.)).collect::<Vec<_>>();
+    // The min / max composition is matched whether or not its shared nodes were merged first
+    for generic in [false, true] {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<512>>().set(data.clone());
+        let mut b = a.clip(0., 1.).retrieve();
+        cx.execute();
+        assert_exact(&b.data(), &expected);
+        b.drop();
+
+        if generic {
+            cx.compile(GenericCompiler::default(), &mut b);
+        }
+        cx.compile(CudaCompiler::<f32>::default(), &mut b);
+        crate::tests::assert_op_in_graph::<crate::unary::CudaScalar<f32>>(&cx);
+        assert!(!cx
+            .node_indices()
+            .any(|n| cx.check_node_type::<crate::prim::CudaLessThan<f32>>(n)));
+        cx.execute();
+
+        assert_exact(&b.data(), &expected);
+    }
+}
+
+#[test]
+fn test_scalar_ops() {
+    let data = random_vec(512);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<512>>().set(data.clone());
+    let mut b = (a * 3.).retrieve();
+    let mut c = (a + 0.5).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    crate::tests::assert_op_in_graph::<crate::unary::CudaScalar<f32>>(&cx);
+    cx.execute();
+
+    assert_close(&b.data(), &data.iter().map(|i| i * 3.).collect::<Vec<_>>());
+    assert_close(&c.data(), &data.iter().map(|i| i + 0.5).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_structural_diff_copies() {
+    let build = |cx: &mut Graph| {
+        let a = cx.named_tensor::<R1<3>>("a").set([1., 2., 3.]);
+        a.exp2().retrieve()
+    };
+    let mut before = Graph::new();
+    build(&mut before);
+    let mut after = Graph::new();
+    let mut b = build(&mut after);
+    after.compile(crate::prim::PrimitiveCompiler::<f32>::default(), &mut b);
+
+    let diff = before.structural_diff(&after);
+    assert!(diff
+        .added_nodes
+        .iter()
+        .any(|n| n.starts_with("CudaCopyToDevice")));
+    assert!(diff
+        .added_nodes
+        .iter()
+        .any(|n| n.starts_with("CudaCopyFromDevice")));
+    assert!(diff.removed_nodes.contains(&"Exp2 [3]".to_string()));
+    assert!(diff.added_nodes.contains(&"CudaExp2 [3]".to_string()));
+}
+
+#[test]
+fn test_gather_rows() {
+    let weights = random_vec(5 * 3);
+    let build = |cx: &mut Graph| {
+        let emb = cx.tensor::<R2<5, 3>>().set(weights.clone());
+        let indexes = cx.tensor::<R1<4>>().set(vec![4., 0., 2., 4.]);
+        emb.gather(indexes).retrieve()
+    };
+    let mut cx = Graph::new();
+    let mut out = build(&mut cx);
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    crate::tests::assert_op_in_graph::<crate::binary::CudaGather<f32>>(&cx);
+    cx.execute();
+
+    let mut cpu_cx = Graph::new();
+    let mut cpu_out = build(&mut cpu_cx);
+    cpu_cx.compile(luminal_cpu::CPUCompiler::default(), &mut cpu_out);
+    cpu_cx.execute();
+
+    assert_exact(&out.data(), &cpu_out.data());
+    assert_exact(&out.data()[..3], &weights[12..]);
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn test_gather_out_of_range() {
+    let mut cx = Graph::new();
+    let emb = cx.tensor::<R2<5, 3>>().set(random_vec(5 * 3));
+    let indexes = cx.tensor::<R1<2>>().set(vec![1., 5.]);
+    let mut out = emb.gather(indexes).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    cx.execute();
+}
+
+#[test]
+fn test_fused_layer_norm() {
+    let (data, weight_data, bias_data) = (random_vec(4 * 64), random_vec(64), random_vec(64));
+    let build = |cx: &mut Graph| {
+        let a = cx.tensor::<R2<4, 64>>().set(data.clone());
+        let weight = cx.tensor::<R1<64>>().set(weight_data.clone());
+        let bias = cx.tensor::<R1<64>>().set(bias_data.clone());
+        let b = (a.layer_norm::<LAxis<1>, _>(1e-5) * weight.expand() + bias.expand()).retrieve();
+        let c = a.layer_norm::<LAxis<0>, _>(1e-5).retrieve();
+        (b, c)
+    };
+    let mut cx = Graph::new();
+    let (mut b, mut c) = build(&mut cx);
+    cx.fuse_layernorm((&mut b, &mut c));
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    crate::tests::assert_op_in_graph::<crate::unary::CudaLayerNorm<f32>>(&cx);
+    cx.execute();
+
+    let mut cpu_cx = Graph::new();
+    let (mut cpu_b, mut cpu_c) = build(&mut cpu_cx);
+    cpu_cx.compile(
+        luminal_cpu::CPUCompiler::default(),
+        (&mut cpu_b, &mut cpu_c),
+    );
+    cpu_cx.execute();
+
+    assert_close(&b.data(), &cpu_b.data());
+    assert_close(&c.data(), &cpu_c.data());
+}
+
+#[test]
+fn test_op_histogram() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([4., 5., 6.]);
+    let mut c = ((a + b) * a + b).retrieve();
+
+    cx.compile(crate::prim::PrimitiveCompiler::<f32>::default(), &mut c);
+    let hist = cx.op_histogram();
+    assert!(!hist.contains_key("Add"));
+    assert!(!hist.contains_key("Mul"));
+    assert_eq!(hist["CudaAdd"], 2);
+    assert_eq!(hist["CudaMul"], 1);
+}
+
+#[test]
+fn test_dyn_add() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'N'>,)>();
+    let b = cx.tensor::<(Dyn<'N'>,)>();
+    let mut c = (a + b).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut c);
+    // Dyn dims are resolved on each op's input shapes before it runs, so launches follow N
+    for n in [3, 700] {
+        let (a_data, b_data) = (random_vec(n), random_vec(n));
+        a.set_dyn(a_data.clone(), &[n]);
+        b.set_dyn(b_data.clone(), &[n]);
+        cx.execute();
+
+        let expected = a_data.iter().zip(&b_data).map(|(a, b)| a + b).collect_vec();
+        assert_eq!(c.data().len(), n);
+        assert_close(&c.data(), &expected);
+        c.drop();
+    }
+}
+
+#[test]
+fn test_warmup_no_compilation() {
+    // Contiguous ops fold the dims bound at compile time into their kernel, and need another kernel once they change
+    let mut cx = Graph::new();
+    cx.set_dyn_dim('N', 3);
+    let a = cx.tensor::<(Dyn<'N'>, LConst<4>)>();
+    let mut b = a
+        .permute::<(LConst<4>, Dyn<'N'>), _>()
+        .contiguous()
+        .retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+
+    cx.warmup();
+    let compiled = crate::kernel_compilations();
+    for n in [3, 5, 8] {
+        a.set_dyn(random_vec(n * 4), &[n, 4]);
+        cx.execute();
+        b.drop();
+    }
+    assert_eq!(crate::kernel_compilations(), compiled);
+}
+
+#[test]
+fn test_sigmoid_tanh_saturation() {
+    let data = vec![-50., -20., -1., 0., 1., 20., 50.];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<7>>().set(data.clone());
+    let mut sigmoid = a.sigmoid().retrieve();
+    let mut tanh = a.tanh().retrieve();
+    cx.compile(
+        <(GenericCompiler, CudaCompiler<f32>)>::default(),
+        (&mut sigmoid, &mut tanh),
+    );
+    crate::tests::assert_op_in_graph::<crate::unary::CudaSigmoid<f32>>(&cx);
+    crate::tests::assert_op_in_graph::<crate::unary::CudaTanh<f32>>(&cx);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<7>,));
+    assert_close(&sigmoid.data(), &d_a.clone().sigmoid().as_vec());
+    assert_close(&tanh.data(), &d_a.tanh().as_vec());
+    let (sigmoid, tanh) = (sigmoid.data(), tanh.data());
+    assert!(sigmoid.iter().chain(&tanh).all(|v| v.is_finite()));
+    assert!((0.0..1e-20).contains(&sigmoid[0]));
+    assert_eq!(sigmoid[6], 1.0);
+    assert_eq!((tanh[0], tanh[6]), (-1.0, 1.0));
+}
+
+#[test]
+fn test_bound_dims_folded_into_kernel() {
+    let mut shape = ShapeTracker::new(&['N'.into(), 4.into()]);
+    shape.permute(&[1, 0]);
+    let (idx, valid, bound) =
+        crate::get_bound_idx_valid_exps(shape, &[('N', 512)].into_iter().collect());
+    assert!(!idx.contains('N') && !valid.contains('N'), "{idx} {valid}");
+    assert_eq!(bound, vec![('N', 512)]);
+    // Unbound dims are left for the kernel to take as inputs
+    let (idx, _, bound) = crate::get_bound_idx_valid_exps(shape, &Default::default());
+    assert!(idx.contains('N') && bound.is_empty());
+
+    // Once a folded dim changes, the op falls back to a kernel taking it as a parameter, compiled only once
+    let mut cx = Graph::new();
+    cx.set_dyn_dim('N', 3);
+    let a = cx.tensor::<(Dyn<'N'>, LConst<4>)>();
+    let mut b = a
+        .permute::<(LConst<4>, Dyn<'N'>), _>()
+        .contiguous()
+        .retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    let compiled = crate::kernel_compilations();
+    for (n, new_compilations) in [(3, 0), (5, 1), (7, 1), (3, 1)] {
+        let data = random_vec(n * 4);
+        a.set_dyn(data.clone(), &[n, 4]);
+        cx.execute();
+        assert_eq!(crate::kernel_compilations() - compiled, new_compilations);
+
+        let expected = (0..4)
+            .flat_map(|j| (0..n).map(move |i| (i, j)))
+            .map(|(i, j)| data[i * 4 + j])
+            .collect_vec();
+        assert_exact(&b.data(), &expected);
+        b.drop();
+    }
+}
+
+#[test]
+fn test_buffer_reuse() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 8>>().set(random_vec(4 * 8));
+    let w = cx.tensor::<R2<8, 8>>().set(random_vec(8 * 8));
+    let mut b = a.matmul(w).max_reduce::<_, LAxis<1>>().retrieve();
+    let (_, reused) = cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::BufferReuseCompiler::<f32>::default(),
+        ),
+        &mut b,
+    );
+
+    cx.execute();
+    let first = b.data();
+    // Every device output went back to its op once consumed
+    let ptrs = reused.device_ptrs();
+    assert!(!ptrs.is_empty());
+
+    for _ in 0..3 {
+        b.drop();
+        cx.execute();
+        assert_exact(&b.data(), &first);
+        // Later runs wrote into the same buffers rather than allocating new ones
+        assert_eq!(reused.device_ptrs(), ptrs);
+    }
+
+    // Recompiling hands the ops fresh caches, freeing the old buffers
+    let recompiled = cx.compile(crate::BufferReuseCompiler::<f32>::default(), &mut b);
+    assert!(reused.device_ptrs().is_empty());
+    assert!(recompiled.device_ptrs().is_empty());
+    b.drop();
+    cx.execute();
+    assert_exact(&b.data(), &first);
+    assert_eq!(recompiled.device_ptrs().len(), ptrs.len());
+}
+
+#[test]
+fn test_buffer_reuse_shape_change() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'N'>, LConst<8>)>();
+    let mut b = (a.exp() * 2.).sum_reduce::<_, LAxis<1>>().retrieve();
+    let (_, reused) = cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::BufferReuseCompiler::<f32>::default(),
+        ),
+        &mut b,
+    );
+    let mut last: Option<(usize, Vec<u64>)> = None;
+    for n in [2, 2, 5, 5, 3] {
+        let data = random_vec(n * 8);
+        a.set_dyn(data.clone(), &[n, 8]);
+        cx.execute();
+        let expected = data
+            .chunks(8)
+            .map(|r| r.iter().map(|i| i.exp() * 2.).sum::<f32>())
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+        b.drop();
+
+        let ptrs = reused.device_ptrs();
+        if let Some((last_n, last_ptrs)) = &last {
+            // Buffers sized for the old shapes were freed, so the cache doesn't grow
+            assert_eq!(ptrs.len(), last_ptrs.len());
+            if *last_n == n {
+                assert_eq!(&ptrs, last_ptrs);
+            }
+        }
+        last = Some((n, ptrs));
+    }
+}
+
+#[test]
+fn test_full_tile_idx_guard_elided() {
+    assert_eq!(crate::idx_guard(2048.into(), "numel"), "{");
+    assert_eq!(crate::idx_guard(2000.into(), "numel"), "if (idx < numel) {");
+    assert_eq!(crate::idx_guard('n'.into(), "numel"), "if (idx < numel) {");
+
+    // Kernels without the guard still cover every element
+    let mut cx = Graph::new();
+    let a_data = random_vec(2048);
+    let b_data = random_vec(2048);
+    let a = cx.tensor::<R1<2048>>().set(a_data.clone());
+    let b = cx.tensor::<R1<2048>>().set(b_data.clone());
+    let mut c = (a + b).retrieve();
+    let mut d = (a * b).exp().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut c, &mut d));
+    cx.execute();
+
+    let (c_ref, d_ref): (Vec<f32>, Vec<f32>) = a_data
+        .iter()
+        .zip(&b_data)
+        .map(|(a, b)| (a + b, (a * b).exp()))
+        .unzip();
+    assert_close(&c.data(), &c_ref);
+    assert_close(&d.data(), &d_ref);
+}
+
+#[test]
+fn test_default_cuda_pipeline() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 8>>().set(random_vec(2 * 8));
+    let model: (
+        luminal_nn::Linear<8, 16>,
+        luminal_nn::ReLU,
+        luminal_nn::Linear<16, 4>,
+    ) = InitModule::initialize(&mut cx);
+    let mut b = model.forward(a).retrieve();
+    cx.execute();
+    let unoptimized = b.data();
+    b.drop();
+
+    cx.compile(crate::default_cuda_pipeline::<f32>(), &mut b);
+    // Every primitive op got lowered, and the matmuls got fused out of their multiplies and reductions
+    let histogram = cx.op_histogram();
+    for primitive in [
+        "Add",
+        "Mul",
+        "Recip",
+        "SumReduce",
+        "MaxReduce",
+        "LessThan",
+        "Contiguous",
+    ] {
+        assert!(!histogram.contains_key(primitive), "{histogram:?}");
+    }
+    assert_eq!(histogram.get("Matmul"), Some(&2), "{histogram:?}");
+
+    cx.execute();
+    assert_close(&b.data(), &unoptimized);
+}
+
+#[test]
+fn test_sum_reduce_overwrites_output() {
+    for deterministic in [false, true] {
+        let mut cx = Graph::new();
+        cx.deterministic = deterministic;
+        let a = cx.tensor::<R2<4, 16>>();
+        let mut b = (a.sum_reduce::<_, LAxis<1>>() * 1.5).retrieve();
+        // 1000 outputs don't fill the last block, with either 16 or 256 outputs per block
+        let c = cx.tensor::<R2<1000, 16>>();
+        let mut d = (c.sum_reduce::<_, LAxis<1>>() * 1.5).retrieve();
+        cx.compile(
+            (
+                CudaCompiler::<f32>::default(),
+                crate::BufferReuseCompiler::<f32>::default(),
+            ),
+            (&mut b, &mut d),
+        );
+        // The output buffer isn't zeroed, so a reused one must still come out exactly right
+        for _ in 0..2 {
+            let (a_data, c_data) = (random_vec(4 * 16), random_vec(1000 * 16));
+            a.set(a_data.clone());
+            c.set(c_data.clone());
+            b.drop();
+            d.drop();
+            cx.execute();
+            let sums = |data: &[f32]| {
+                data.chunks(16)
+                    .map(|r| r.iter().sum::<f32>() * 1.5)
+                    .collect::<Vec<_>>()
+            };
+            assert_close(&b.data(), &sums(&a_data));
+            assert_close(&d.data(), &sums(&c_data));
+        }
+    }
+}
+
+#[test]
+fn test_streams_overlap_branches() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<256, 256>>().set(random_vec(256 * 256));
+    let b = cx.tensor::<R2<256, 256>>().set(random_vec(256 * 256));
+    let w = cx.tensor::<R2<256, 256>>().set(
+        random_vec(256 * 256)
+            .into_iter()
+            .map(|i| i * 0.1)
+            .collect::<Vec<_>>(),
+    );
+    // Two branches sharing nothing but the weight
+    let (mut x, mut y) = (a, b);
+    for _ in 0..16 {
+        x = x.matmul(w).sin();
+        y = y.matmul(w).cos();
+    }
+    let (mut x, mut y) = (x.retrieve(), y.retrieve());
+    cx.compile(CudaCompiler::<f32>::default(), (&mut x, &mut y));
+
+    fn fastest_run(cx: &mut Graph, outputs: &[NodeIndex]) -> std::time::Duration {
+        (0..6)
+            .map(|_| {
+                cx.drop_tensors(outputs.to_vec());
+                let start = std::time::Instant::now();
+                cx.execute();
+                start.elapsed()
+            })
+            .skip(1)
+            .min()
+            .unwrap()
+    }
+    let serial = fastest_run(&mut cx, &[x.id, y.id]);
+    let (serial_x, serial_y) = (x.data(), y.data());
+
+    let streams = cx.compile(crate::StreamCompiler::<f32>::new(2), ());
+    assert_eq!(streams.values().unique().count(), 2);
+    let streamed = fastest_run(&mut cx, &[x.id, y.id]);
+    assert_close(&x.data(), &serial_x);
+    assert_close(&y.data(), &serial_y);
+    // Each branch's kernels are too small to fill the device, so running them side by side should be faster. Allow
+    // some timing noise, but not streams costing more than they save
+    assert!(
+        streamed < serial.mul_f64(1.1),
+        "Streamed {streamed:?} vs serial {serial:?}"
+    );
+}
+
+#[test]
+fn test_deterministic_sum_reduce() {
+    let mut cx = Graph::new();
+    cx.deterministic = true;
+    let data = random_vec(8 * 65536);
+    let a = cx.tensor::<R2<8, 65536>>().set(data.clone());
+    let mut b = a.sum_reduce::<_, LAxis<1>>().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+
+    cx.execute();
+    let first = b.data();
+    b.drop();
+    cx.execute();
+    assert_eq!(
+        b.data().iter().map(|f| f.to_bits()).collect::<Vec<_>>(),
+        first.iter().map(|f| f.to_bits()).collect::<Vec<_>>()
+    );
+    // Summed in order, the same as on the CPU
+    let sequential = data
+        .chunks(65536)
+        .map(|row| row.iter().fold(0., |acc, x| acc + x))
+        .collect::<Vec<f32>>();
+    assert_exact(&first, &sequential);
+}
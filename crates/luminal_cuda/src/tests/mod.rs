@@ -122,7 +122,10 @@ unary_test!(|a| a.recip(), |a| a.recip(), test_recip);
 unary_test!(|a| a * a, |a| a.clone() * a, test_square);
 unary_test!(|a| a.exp(), |a| a.exp(), test_exp);
 unary_test!(|a| a.cos(), |a| a.cos(), test_cos);
+unary_test!(|a| -a, |a| -a, test_neg);
 unary_test!(|a| a.softmax(), |a| a.softmax(), test_softmax);
+unary_test!(|a| a.sigmoid(), |a| a.sigmoid(), test_sigmoid);
+unary_test!(|a| a.tanh(), |a| a.tanh(), test_tanh);
 unary_test!(
     |a| a.mean_norm::<luminal::shape::Axis<0>>(),
     |a| a.clone() - a.mean::<_, dfdx::prelude::Axis<0>>().broadcast(),
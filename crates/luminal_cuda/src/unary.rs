@@ -1,4 +1,4 @@
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchConfig};
 use num_traits::float::FloatConst;
 use rustc_hash::FxHashMap;
 use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
@@ -6,18 +6,19 @@ use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
 use petgraph::visit::EdgeRef;
 
 use luminal::{
-    op::{ConstantValue, InputTensor, Operator},
+    op::{ConstantValue, FusedLayerNorm, InputTensor, Operator},
     prelude::*,
 };
 
 use crate::{
     binary::CudaSub,
-    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
+    compile_and_load_kernel, constant, float_literal, get_buffer_from_tensor, get_idx_valid_exps,
+    input_dyn_dims,
     prim::{
-        CudaAdd, CudaConstant, CudaContiguous, CudaExp2, CudaMaxReduce, CudaMul, CudaRecip,
-        CudaSin, CudaSqrt, CudaSumReduce,
+        CudaAdd, CudaConstant, CudaContiguous, CudaExp2, CudaLessThan, CudaMul, CudaRecip,
+        CudaReduce, CudaSin, CudaSqrt,
     },
-    render_dyn_dim_inputs, CudaData, CudaFloat,
+    reduce, render_dyn_dim_inputs, CudaFloat, LaunchOnStream, OpStream,
 };
 
 /// Special kernel for efficient mean reduction
@@ -25,6 +26,7 @@ use crate::{
 pub struct CudaMeanReduce<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     pub dim: usize,
     pub dyn_symbols: Vec<char>,
     pub dyn_map: *const FxHashMap<char, usize>,
@@ -70,6 +72,7 @@ extern \"C\" __global__ void kernel(const {type_name} *inp, {type_name} *out, in
         Self {
             function: compile_and_load_kernel(code, &dev),
             device: dev,
+            stream: Default::default(),
             dim,
             dyn_symbols,
             dyn_map,
@@ -85,7 +88,7 @@ impl<T: CudaFloat> Operator for CudaMeanReduce<T> {
         sh.remove_dim(self.dim);
         let inp_size = sh.n_elements().to_usize().unwrap();
         let inp_size_int = inp_size as i32;
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         let front_size = tensors[0]
             .1
             .shape()
@@ -113,10 +116,21 @@ impl<T: CudaFloat> Operator for CudaMeanReduce<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    &mut params,
+                )
                 .unwrap();
         }
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -131,7 +145,7 @@ impl<T: CudaFloat> Compiler for MeanReduceCompiler<T> {
         // Look for the mean-reduce pattern
         // mul(recip(fake_sum_reduce(const_ones)), sum_reduce(x))
         let fake_sum_reduce = op::<CudaConstant<T>>();
-        let sum_reduce = op::<CudaSumReduce<T>>();
+        let sum_reduce = reduce::<T>(ReduceOp::Sum);
         let mul = binary::<CudaMul<T>>(
             sum_reduce.clone(),
             unary::<CudaRecip<T>>(fake_sum_reduce.clone()),
@@ -143,7 +157,7 @@ impl<T: CudaFloat> Compiler for MeanReduceCompiler<T> {
                 continue;
             }
             let (sum_reduce, mul) = (s.get(&sum_reduce), s.get(&mul));
-            let dim = graph.get_op::<CudaSumReduce<T>>(sum_reduce).dim;
+            let dim = graph.get_op::<CudaReduce<T>>(sum_reduce).dim;
             // Insert MeanReduce op
             let src = graph.get_sources(sum_reduce)[0];
             let mean_reduce = graph
@@ -172,6 +186,7 @@ impl<T: CudaFloat> Compiler for MeanReduceCompiler<T> {
 pub struct CudaStdNorm<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     epsilon: f32, // Epsilon
     _phantom: PhantomData<T>,
 }
@@ -257,6 +272,7 @@ extern \"C\" __global__ void kernel(const  {type_name} * src0, {type_name} * dst
         Self {
             function: compile_and_load_kernel(kernel_code, &device),
             device,
+            stream: Default::default(),
             epsilon,
             _phantom: Default::default(),
         }
@@ -268,9 +284,8 @@ impl<T: CudaFloat> Operator for CudaStdNorm<T> {
         let row_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
         let row_size_int = row_size as i32;
         let out = self
-            .device
-            .alloc_zeros::<T>(tensors[0].1.n_elements().to_usize().unwrap())
-            .unwrap();
+            .stream
+            .alloc_zeros::<T>(&self.device, tensors[0].1.n_elements().to_usize().unwrap());
         let mut params = vec![
             get_buffer_from_tensor::<T>(&tensors[0].0).as_kernel_param(),
             (&out).as_kernel_param(),
@@ -291,7 +306,8 @@ impl<T: CudaFloat> Operator for CudaStdNorm<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig {
                         grid_dim: (batch_size as u32, 1, 1),
                         block_dim: (nth as u32, 1, 1),
@@ -302,7 +318,14 @@ impl<T: CudaFloat> Operator for CudaStdNorm<T> {
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -397,10 +420,125 @@ impl<T: CudaFloat> Compiler for StdNormCompiler<T> {
     }
 }
 
+/// Layer norm along an axis with an optional scale and shift, one thread per row
+#[derive(Clone)]
+pub struct CudaLayerNorm<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    pub axis: usize,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaLayerNorm);
+
+impl<T: CudaFloat> CudaLayerNorm<T> {
+    /// `shapes` holds the input shape followed by the scale and shift shapes, if present
+    pub fn new(
+        dev: Arc<CudaDevice>,
+        norm: &FusedLayerNorm,
+        shapes: &[ShapeTracker],
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let type_name = T::type_name();
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(shapes);
+        let read = |name: &str, shape: ShapeTracker| {
+            let (idx, valid) = get_idx_valid_exps(shape);
+            format!("(({valid}) == 0 ? 0.0f : (float){name}[{idx}])")
+        };
+        let x = read("inp", shapes[0]);
+        let (mut affine_inputs, mut affine) = (String::new(), String::new());
+        if norm.scale {
+            affine_inputs.push_str(&format!(", const {type_name} *scale"));
+            affine.push_str(&format!(" * {}", read("scale", shapes[1])));
+        }
+        if norm.shift {
+            affine_inputs.push_str(&format!(", const {type_name} *shift"));
+            affine.push_str(&format!(" + {}", read("shift", shapes[shapes.len() - 1])));
+        }
+        let eps = float_literal(norm.epsilon);
+        let code = format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp{affine_inputs}, int n_rows, int back_size, int dim_size{rendered}) {{
+    int i_ = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i_ < n_rows) {{
+        int a_ = i_ / back_size;
+        int b_ = i_ % back_size;
+        float mean = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            mean += {x};
+        }}
+        mean /= (float)dim_size;
+        float var = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float d = {x} - mean;
+            var += d * d;
+        }}
+        float inv_std = rsqrtf(var / (float)dim_size + {eps});
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            out[idx] = ({type_name})(({x} - mean) * inv_std{affine});
+        }}
+    }}
+}}");
+        Self {
+            function: compile_and_load_kernel(code, &dev),
+            device: dev,
+            stream: Default::default(),
+            axis: norm.axis,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaLayerNorm<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = tensors[0].1.shape_usize();
+        let out = self
+            .stream
+            .alloc_zeros::<T>(&self.device, sh.iter().product::<usize>());
+        let n_rows = (sh.iter().product::<usize>() / sh[self.axis]) as i32;
+        let back_size = sh.iter().skip(self.axis + 1).product::<usize>() as i32;
+        let dim_size = sh[self.axis] as i32;
+        let mut params = vec![(&out).as_kernel_param()];
+        for (t, _) in &tensors {
+            params.push(get_buffer_from_tensor::<T>(t).as_kernel_param());
+        }
+        params.push(n_rows.as_kernel_param());
+        params.push(back_size.as_kernel_param());
+        params.push(dim_size.as_kernel_param());
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(n_rows as u32),
+                    &mut params,
+                )
+                .unwrap();
+        }
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct CudaExp<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(CudaExp);
@@ -422,6 +560,7 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, in
                 &device,
             ),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -431,21 +570,25 @@ impl<T: CudaFloat> Operator for CudaExp<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
             return Some(Box::new("exp(input0)".to_string()));
         }
@@ -499,16 +642,17 @@ impl<T: CudaFloat> Compiler for CudaExpCompiler<T> {
     }
 }
 
-/// Special kernel for cos
+/// Special kernel for the sigmoid, computed in a single pass without overflowing for large inputs
 #[derive(Clone)]
-pub struct CudaCos<T> {
+pub struct CudaSigmoid<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
-crate::debug_type!(CudaCos);
+crate::debug_type!(CudaSigmoid);
 
-impl<T: CudaFloat> CudaCos<T> {
+impl<T: CudaFloat> CudaSigmoid<T> {
     fn new(device: Arc<CudaDevice>) -> Self {
         let type_name = T::type_name();
         Self {
@@ -518,38 +662,49 @@ impl<T: CudaFloat> CudaCos<T> {
 extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
     int i = blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
-        out[i] = cos(inp[i]);
+        float x = (float)inp[i];
+        float e = __expf(-fabsf(x));
+        out[i] = x >= 0.0f ? 1.0f / (1.0f + e) : e / (1.0f + e);
     }}
 }}"
                 ),
                 &device,
             ),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
 }
-impl<T: CudaFloat> Operator for CudaCos<T> {
+
+impl<T: CudaFloat> Operator for CudaSigmoid<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig::for_num_elems(inp_size as u32),
                     (&mut out, inp, inp_size),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
         if key == "elementwise" {
-            return Some(Box::new("cos(input0)".to_string()));
+            return Some(Box::new(
+                "((float)input0 >= 0.0f ? 1.0f / (1.0f + __expf(-(float)input0)) : __expf((float)input0) / (1.0f + __expf((float)input0)))"
+                    .to_string(),
+            ));
         }
 
         None
@@ -557,135 +712,1241 @@ impl<T: CudaFloat> Operator for CudaCos<T> {
 }
 
 #[derive(Default, Debug)]
-pub struct CudaCosCompiler<T>(PhantomData<T>);
+pub struct CudaSigmoidCompiler<T: CudaFloat>(PhantomData<T>);
 
-impl<T: CudaFloat> Compiler for CudaCosCompiler<T> {
+impl<T: CudaFloat> Compiler for CudaSigmoidCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let dev = CudaDevice::new(0).unwrap();
-        // Look for the cos pattern
-        // sin(add(mul(const_neg_one, x), const_pi_over_2))
-
-        let const_pi = constant::<T>(f32::PI() / 2.);
-        let inp = node();
-        let sub = binary::<CudaSub<T>>(inp.clone(), const_pi.clone());
-        let sin = unary::<CudaSin<T>>(sub.clone());
-        let mut s = sin.clone().search(graph);
-        while s.next_match() {
-            if s.check_no_delete(&[sin.id]) {
-                // An intermediate node can't be deleted
-                continue;
+        // Look for the sigmoid pattern
+        // mul(recip(add(e, 1)), add(mul(neg, sub(e, 1)), 1)) where e = exp(mul(x, sub(mul(neg, 2), 1))) and neg = less_than(x, 0)
+        // Without arithmetic elimination the recip is still multiplied by one
+        for times_one in [false, true] {
+            let scaled = op::<CudaMul<T>>();
+            let exp2 = unary::<CudaExp2<T>>(binary::<CudaMul<T>>(
+                scaled.clone(),
+                constant::<T>(1.0 / f32::ln(2.)),
+            ));
+            let mut recip =
+                unary::<CudaRecip<T>>(binary::<CudaAdd<T>>(exp2.clone(), constant::<T>(1.)));
+            if times_one {
+                recip = binary::<CudaMul<T>>(recip, constant::<T>(1.));
             }
+            let gate = op::<CudaAdd<T>>();
+            let out = binary::<CudaMul<T>>(recip, gate.clone());
+            let mut s = out.clone().search(graph);
+            while s.next_match() {
+                if s.check_no_delete(&[out.id]) {
+                    // An intermediate node can't be deleted
+                    continue;
+                }
+                let (exp2, gate) = (s.get(&exp2), s.get(&gate));
+                let Some(x) = find_sign_input::<T>(graph, s.get(&scaled), |graph, sign, neg| {
+                    // sub(mul(neg, 2), 1)
+                    graph.check_node_type::<CudaAdd<T>>(sign)
+                        && sources_match(
+                            graph,
+                            sign,
+                            |n| is_mul_of::<T>(graph, n, neg, |n| is_constant::<T>(graph, n, 2.)),
+                            |n| is_minus_one::<T>(graph, n),
+                        )
+                }) else {
+                    continue;
+                };
+                // add(mul(neg, sub(e, 1)), 1)
+                if !sources_match(
+                    graph,
+                    gate,
+                    |n| {
+                        is_mul_of::<T>(
+                            graph,
+                            n,
+                            |n| is_negative::<T>(graph, n, x.0),
+                            |n| {
+                                graph.check_node_type::<CudaAdd<T>>(n)
+                                    && sources_match(
+                                        graph,
+                                        n,
+                                        |n| n == exp2,
+                                        |n| is_minus_one::<T>(graph, n),
+                                    )
+                            },
+                        )
+                    },
+                    |n| is_constant::<T>(graph, n, 1.),
+                ) {
+                    continue;
+                }
 
-            // Insert cos op
-            let shape = graph
-                .edges_directed(s.get(&sub), petgraph::Direction::Incoming)
-                .filter(|e| !e.weight().is_schedule())
-                .find(|e| e.source() != s.get(&const_pi))
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let cos = graph
-                .add_op(CudaCos::<T>::new(dev.clone()))
-                .input(s.get(&inp), 0, shape)
-                .finish();
+                // Insert sigmoid op
+                let sigmoid = graph
+                    .add_op(CudaSigmoid::<T>::new(dev.clone()))
+                    .input(x.0, x.1, x.2)
+                    .finish();
 
-            // Create edges to dests
-            let sin = s.get(&sin);
-            move_outgoing_edge(sin, cos, graph);
-            remap(sin, cos, &mut ids, graph);
+                // Create edges to dests
+                let out = s.get(&out);
+                move_outgoing_edge(out, sigmoid, graph);
+                remap(out, sigmoid, &mut ids, graph);
 
-            // Remove the old ops
-            graph.remove_node(sin);
-            s.try_delete();
+                // Remove the old ops
+                remove_dead_nodes(graph, out);
+            }
         }
     }
 }
 
-/// Special kernel for efficient softmax. Currently only works on the last dim
+/// Special kernel for tanh, using the tanhf intrinsic
 #[derive(Clone)]
-pub struct CudaSoftmax<T> {
+pub struct CudaTanh<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    stream: OpStream,
     _phantom: PhantomData<T>,
 }
-crate::debug_type!(CudaSoftmax);
+crate::debug_type!(CudaTanh);
 
-impl<T: CudaFloat> CudaSoftmax<T> {
+impl<T: CudaFloat> CudaTanh<T> {
     fn new(device: Arc<CudaDevice>) -> Self {
         let type_name = T::type_name();
         Self {
             function: compile_and_load_kernel(
                 format!(
-                    "
-#include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel(const {type_name} * x, {type_name} * dst, const int ncols) {{
-    const int row = blockDim.x*blockIdx.x + threadIdx.x;
-    const int block_size = blockDim.y;
-    const int tid = threadIdx.y;
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = tanhf((float)inp[i]);
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
 
-    {type_name} max_val = -__int_as_float(0x7f800000);
+impl<T: CudaFloat> Operator for CudaTanh<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
 
-    for (int col = tid; col < ncols; col += block_size) {{
-        const int i = row*ncols + col;
-        max_val = fmaxf(max_val, x[i]);
-    }}
+        vec![Tensor::new(self.stream.output(out))]
+    }
 
-    // find the max value in the block
-    #pragma unroll
-    for (int mask = 16; mask > 0; mask >>= 1) {{
-        max_val = fmaxf(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
-    }}
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("tanhf((float)input0)".to_string()));
+        }
 
-    {type_name} tmp = 0.;
+        None
+    }
+}
 
-    for (int col = tid; col < ncols; col += block_size) {{
-        const int i = row*ncols + col;
-        const {type_name} val = exp(x[i] - max_val);
-        tmp += static_cast<{type_name}>(val);
-        dst[i] = val;
-    }}
+#[derive(Default, Debug)]
+pub struct CudaTanhCompiler<T: CudaFloat>(PhantomData<T>);
 
-    // sum up partial sums
-    #pragma unroll
-    for (int mask = 16; mask > 0; mask >>= 1) {{
-        tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
-    }}
+impl<T: CudaFloat> Compiler for CudaTanhCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        // Look for the tanh pattern
+        // mul(recip(add(e, 1)), mul(sub(1, e), sign)) where e = exp(mul(mul(x, sign), -2)) and sign = sub(1, mul(neg, 2))
+
+        let scaled = op::<CudaMul<T>>();
+        let exp2 = unary::<CudaExp2<T>>(binary::<CudaMul<T>>(
+            binary::<CudaMul<T>>(scaled.clone(), constant::<T>(-2.)),
+            constant::<T>(1.0 / f32::ln(2.)),
+        ));
+        let recip = unary::<CudaRecip<T>>(binary::<CudaAdd<T>>(exp2.clone(), constant::<T>(1.)));
+        let numerator = op::<CudaMul<T>>();
+        let out = binary::<CudaMul<T>>(recip, numerator.clone());
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            let (exp2, numerator) = (s.get(&exp2), s.get(&numerator));
+            let is_sign = |graph: &Graph, sign, neg: &dyn Fn(NodeIndex) -> bool| {
+                // sub(1, mul(neg, 2))
+                graph.check_node_type::<CudaAdd<T>>(sign)
+                    && sources_match(
+                        graph,
+                        sign,
+                        |n| is_constant::<T>(graph, n, 1.),
+                        |n| {
+                            is_mul_of::<T>(
+                                graph,
+                                n,
+                                |n| is_constant::<T>(graph, n, -1.),
+                                |n| {
+                                    is_mul_of::<T>(graph, n, neg, |n| {
+                                        is_constant::<T>(graph, n, 2.)
+                                    })
+                                },
+                            )
+                        },
+                    )
+            };
+            let Some(x) = find_sign_input::<T>(graph, s.get(&scaled), |graph, sign, neg| {
+                is_sign(graph, sign, &neg)
+            }) else {
+                continue;
+            };
+            // mul(sub(1, e), sign)
+            if !sources_match(
+                graph,
+                numerator,
+                |n| {
+                    graph.check_node_type::<CudaAdd<T>>(n)
+                        && sources_match(
+                            graph,
+                            n,
+                            |n| is_constant::<T>(graph, n, 1.),
+                            |n| {
+                                is_mul_of::<T>(
+                                    graph,
+                                    n,
+                                    |n| n == exp2,
+                                    |n| is_constant::<T>(graph, n, -1.),
+                                )
+                            },
+                        )
+                },
+                |n| is_sign(graph, n, &|n| is_negative::<T>(graph, n, x.0)),
+            ) {
+                continue;
+            }
 
-    const {type_name} inv_tmp = ({type_name})1. / tmp;
+            // Insert tanh op
+            let tanh = graph
+                .add_op(CudaTanh::<T>::new(dev.clone()))
+                .input(x.0, x.1, x.2)
+                .finish();
 
-    for (int col = tid; col < ncols; col += block_size) {{
-        const int i = row*ncols + col;
-        dst[i] *= inv_tmp;
+            // Create edges to dests
+            let out = s.get(&out);
+            move_outgoing_edge(out, tanh, graph);
+            remap(out, tanh, &mut ids, graph);
+
+            // Remove the old ops
+            remove_dead_nodes(graph, out);
+        }
+    }
+}
+
+/// Find the `x` in `mul(x, sign)`, where `is_sign` checks the sign term given a check for `less_than(x, 0)`
+fn find_sign_input<T: CudaFloat>(
+    graph: &Graph,
+    scaled: NodeIndex,
+    is_sign: impl Fn(&Graph, NodeIndex, &dyn Fn(NodeIndex) -> bool) -> bool,
+) -> Option<(NodeIndex, u8, ShapeTracker)> {
+    let srcs = graph.get_sources(scaled);
+    if srcs.len() != 2 {
+        return None;
+    }
+    [(0, 1), (1, 0)].into_iter().find_map(|(x, sign)| {
+        let x = srcs[x];
+        // A reshaped input can't be processed elementwise
+        (!x.2.is_reshaped() && is_sign(graph, srcs[sign].0, &|n| is_negative::<T>(graph, n, x.0)))
+            .then_some(x)
+    })
+}
+
+/// Is this node `less_than(x, 0)`
+fn is_negative<T: CudaFloat>(graph: &Graph, node: NodeIndex, x: NodeIndex) -> bool {
+    graph.check_node_type::<CudaLessThan<T>>(node)
+        && matches!(
+            graph.get_sources(node).as_slice(),
+            [(a, _, _), (b, _, _)] if *a == x && is_constant::<T>(graph, *b, 0.)
+        )
+}
+
+/// Is this node a constant -1, or the unfolded `mul(1, -1)`
+fn is_minus_one<T: CudaFloat>(graph: &Graph, node: NodeIndex) -> bool {
+    is_constant::<T>(graph, node, -1.)
+        || is_mul_of::<T>(
+            graph,
+            node,
+            |n| is_constant::<T>(graph, n, 1.),
+            |n| is_constant::<T>(graph, n, -1.),
+        )
+}
+
+fn is_mul_of<T: CudaFloat>(
+    graph: &Graph,
+    node: NodeIndex,
+    a: impl Fn(NodeIndex) -> bool,
+    b: impl Fn(NodeIndex) -> bool,
+) -> bool {
+    graph.check_node_type::<CudaMul<T>>(node) && sources_match(graph, node, a, b)
+}
+
+fn is_constant<T: CudaFloat>(graph: &Graph, node: NodeIndex, value: f32) -> bool {
+    matches!(graph.try_get_op::<CudaConstant<T>>(node), Some(c) if c.value == ConstantValue::Float(value))
+}
+
+/// Do the two sources of this node satisfy `a` and `b`, in either order
+fn sources_match(
+    graph: &Graph,
+    node: NodeIndex,
+    a: impl Fn(NodeIndex) -> bool,
+    b: impl Fn(NodeIndex) -> bool,
+) -> bool {
+    match graph.get_sources(node).as_slice() {
+        [(x, _, _), (y, _, _)] => (a(*x) && b(*y)) || (a(*y) && b(*x)),
+        _ => false,
+    }
+}
+
+/// Remove a node along with all of its sources that no longer have any consumers
+fn remove_dead_nodes(graph: &mut Graph, node: NodeIndex) {
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        if !graph.contains_node(node)
+            || graph.no_delete.contains(&node)
+            || graph
+                .edges_directed(node, petgraph::Direction::Outgoing)
+                .any(|e| !e.weight().is_schedule())
+        {
+            continue;
+        }
+        stack.extend(graph.get_sources(node).into_iter().map(|(n, _, _)| n));
+        graph.remove_node(node);
+    }
+}
+
+/// Special kernel for negation
+#[derive(Clone)]
+pub struct CudaNeg<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaNeg);
+
+impl<T: CudaFloat> CudaNeg<T> {
+    fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = -inp[i];
     }}
-}}
-",
+}}"
                 ),
                 &device,
             ),
             device,
+            stream: Default::default(),
             _phantom: Default::default(),
         }
     }
 }
 
-impl<T: CudaFloat> Operator for CudaSoftmax<T> {
+impl<T: CudaFloat> Operator for CudaNeg<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Setup buffers
-        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let batch_size = tensors[0]
-            .1
-            .shape()
-            .iter()
-            .take(tensors[0].1.len() - 1)
-            .map(|i| i.to_usize().unwrap())
-            .product::<usize>()
-            .max(1);
-        let axis_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("-input0".to_string()));
+        }
+
+        None
+    }
+}
+
+/// 1.0 where the input is NaN, 0.0 otherwise
+#[derive(Clone)]
+pub struct CudaIsNan<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaIsNan);
+
+impl<T: CudaFloat> CudaIsNan<T> {
+    pub(crate) fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = isnan((float)inp[i]) ? ({type_name})1.0 : ({type_name})0.0;
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaIsNan<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("(isnan((float)input0) ? 1.0 : 0.0)".to_string()));
+        }
+
+        None
+    }
+}
+
+/// 1.0 where the input is infinite, 0.0 otherwise
+#[derive(Clone)]
+pub struct CudaIsInf<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaIsInf);
+
+impl<T: CudaFloat> CudaIsInf<T> {
+    pub(crate) fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = isinf((float)inp[i]) ? ({type_name})1.0 : ({type_name})0.0;
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaIsInf<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("(isinf((float)input0) ? 1.0 : 0.0)".to_string()));
+        }
+
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct CudaNanToNum<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    nan: f32,
+    posinf: f32,
+    neginf: f32,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaNanToNum);
+
+impl<T: CudaFloat> CudaNanToNum<T> {
+    pub(crate) fn new(device: Arc<CudaDevice>, nan: f32, posinf: f32, neginf: f32) -> Self {
+        let type_name = T::type_name();
+        let (nan_lit, posinf_lit, neginf_lit) = (
+            float_literal(nan),
+            float_literal(posinf),
+            float_literal(neginf),
+        );
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        float x = (float)inp[i];
+        out[i] = ({type_name})(isnan(x) ? {nan_lit} : isinf(x) ? (x > 0 ? {posinf_lit} : {neginf_lit}) : x);
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            nan,
+            posinf,
+            neginf,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaNanToNum<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new(format!(
+                "(isnan((float)input0) ? {} : isinf((float)input0) ? ((float)input0 > 0 ? {} : {}) : (float)input0)",
+                float_literal(self.nan),
+                float_literal(self.posinf),
+                float_literal(self.neginf)
+            )));
+        }
+
+        None
+    }
+}
+
+/// An elementwise op with a scalar baked into the kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarOp {
+    Add(f32),
+    Mul(f32),
+    /// Clamp into `[min, max]`
+    Clamp(f32, f32),
+}
+
+impl ScalarOp {
+    /// Render the op applied to `x` as a CUDA float expression
+    fn render(&self, x: &str) -> String {
+        match self {
+            ScalarOp::Add(c) => format!("({x} + {})", float_literal(*c)),
+            ScalarOp::Mul(c) => format!("({x} * {})", float_literal(*c)),
+            ScalarOp::Clamp(min, max) => {
+                format!(
+                    "fminf(fmaxf({x}, {}), {})",
+                    float_literal(*min),
+                    float_literal(*max)
+                )
+            }
+        }
+    }
+}
+
+/// Apply a [ScalarOp] without a second buffer holding the scalar
+#[derive(Clone)]
+pub struct CudaScalar<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    op: ScalarOp,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaScalar);
+
+impl<T: CudaFloat> CudaScalar<T> {
+    pub(crate) fn new(device: Arc<CudaDevice>, op: ScalarOp) -> Self {
+        let type_name = T::type_name();
+        let expr = op.render("(float)inp[i]");
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = ({type_name})({expr});
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            op,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaScalar<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new(self.op.render("(float)input0")));
+        }
+
+        None
+    }
+}
+
+/// Replace adds and muls with a float constant by scalar kernels, so the constant doesn't need its own buffer
+#[derive(Default, Debug)]
+pub struct ScalarCompiler<T: CudaFloat>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for ScalarCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        for is_add in [true, false] {
+            // add(x, c) / mul(x, c)
+            let inp = node();
+            let cons = op::<CudaConstant<T>>();
+            let bin = if is_add {
+                binary::<CudaAdd<T>>(inp.clone(), cons.clone())
+            } else {
+                binary::<CudaMul<T>>(inp.clone(), cons.clone())
+            };
+            let mut s = bin.clone().search(graph);
+            while s.next_match() {
+                if s.check_no_delete(&[bin.id]) {
+                    continue;
+                }
+                let ConstantValue::Float(c) = graph.get_op::<CudaConstant<T>>(s.get(&cons)).value
+                else {
+                    continue;
+                };
+                let (_, output_order, src_shape) = graph
+                    .edges_connecting(s.get(&inp), s.get(&bin))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap();
+                if src_shape.is_reshaped() {
+                    continue;
+                }
+                let scalar = graph
+                    .add_op(CudaScalar::<T>::new(
+                        dev.clone(),
+                        if is_add {
+                            ScalarOp::Add(c)
+                        } else {
+                            ScalarOp::Mul(c)
+                        },
+                    ))
+                    .input(s.get(&inp), output_order, src_shape)
+                    .finish();
+
+                let bin = s.get(&bin);
+                move_outgoing_edge(bin, scalar, graph);
+                remap(bin, scalar, &mut ids, graph);
+                graph.remove_node(bin);
+                s.try_delete();
+            }
+        }
+    }
+}
+
+/// Replace `clip(min, max)`, built from primitives as `min(max(x, min), max)`, with a single clamp kernel
+#[derive(Default, Debug)]
+pub struct ClampCompiler<T: CudaFloat>(PhantomData<T>);
+
+impl<T: CudaFloat> ClampCompiler<T> {
+    fn float_constant(graph: &Graph, node: NodeIndex) -> Option<f32> {
+        match graph.try_get_op::<CudaConstant<T>>(node)?.value {
+            ConstantValue::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Split the sources of a binary op of type `O` into the one passing `check` and the other
+    fn split<O: Operator + 'static, R>(
+        graph: &Graph,
+        node: NodeIndex,
+        check: impl Fn((NodeIndex, u8, ShapeTracker)) -> Option<R>,
+    ) -> Option<(R, (NodeIndex, u8, ShapeTracker))> {
+        if !graph.check_node_type::<O>(node) {
+            return None;
+        }
+        let [a, b] = graph.get_sources(node)[..] else {
+            return None;
+        };
+        check(a)
+            .map(|r| (r, b))
+            .or_else(|| check(b).map(|r| (r, a)))
+    }
+
+    /// Match `mul(x, -1)`, returning x
+    fn neg(graph: &Graph, node: NodeIndex) -> Option<(NodeIndex, u8, ShapeTracker)> {
+        let (_, x) = Self::split::<CudaMul<T>, _>(graph, node, |(n, _, _)| {
+            (Self::float_constant(graph, n)? == -1.).then_some(())
+        })?;
+        Some(x)
+    }
+
+    /// Match `max(x, c)` for a float constant c, as built by `max_f32`:
+    /// `add(mul(lt(x, c), c), mul(add(-lt(x, c), 1), x))`, returning x and c
+    #[allow(clippy::type_complexity)]
+    fn max_constant(
+        graph: &Graph,
+        node: NodeIndex,
+    ) -> Option<((NodeIndex, u8, ShapeTracker), f32)> {
+        let less_than = |n: NodeIndex| {
+            if !graph.check_node_type::<CudaLessThan<T>>(n) {
+                return None;
+            }
+            let [x, (c, _, _)] = graph.get_sources(n)[..] else {
+                return None;
+            };
+            Some((x, Self::float_constant(graph, c)?))
+        };
+        // mul(lt(x, c), c)
+        let ((x, c), (rest, _, _)) = Self::split::<CudaAdd<T>, _>(graph, node, |(n, _, _)| {
+            let ((x, c), (c_node, _, _)) =
+                Self::split::<CudaMul<T>, _>(graph, n, |(lt, _, _)| less_than(lt))?;
+            (Self::float_constant(graph, c_node)? == c).then_some((x, c))
+        })?;
+        // mul(add(-lt(x, c), 1), x)
+        let (_, x_again) = Self::split::<CudaMul<T>, _>(graph, rest, |(n, _, _)| {
+            let (_, (one, _, _)) = Self::split::<CudaAdd<T>, _>(graph, n, |(m, _, _)| {
+                (less_than(Self::neg(graph, m)?.0)? == (x, c)).then_some(())
+            })?;
+            (Self::float_constant(graph, one)? == 1.).then_some(())
+        })?;
+        (x_again == x).then_some((x, c))
+    }
+
+    /// Remove `node` and everything upstream of it that nothing else uses, stopping at `keep`
+    fn remove_unused(graph: &mut Graph, node: NodeIndex, keep: NodeIndex) {
+        if node == keep
+            || graph.no_delete.contains(&node)
+            || graph
+                .edges_directed(node, petgraph::Direction::Outgoing)
+                .next()
+                .is_some()
+        {
+            return;
+        }
+        let srcs = graph.get_sources(node);
+        graph.remove_node(node);
+        for (src, _, _) in srcs {
+            if graph.contains_node(src) {
+                Self::remove_unused(graph, src, keep);
+            }
+        }
+    }
+}
+
+impl<T: CudaFloat> Compiler for ClampCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            if !graph.contains_node(node) || graph.no_delete.contains(&node) {
+                continue;
+            }
+            // min(y, max) is built as -max(-y, -max)
+            let Some(((neg_y, _, _), neg_max)) =
+                Self::neg(graph, node).and_then(|(inner, _, _)| Self::max_constant(graph, inner))
+            else {
+                continue;
+            };
+            let Some(((x, x_out, x_shape), min)) =
+                Self::neg(graph, neg_y).and_then(|(y, _, _)| Self::max_constant(graph, y))
+            else {
+                continue;
+            };
+            // The kernel reads its input as-is
+            if x_shape.is_reshaped() {
+                continue;
+            }
+            let clamp = graph
+                .add_op(CudaScalar::<T>::new(
+                    dev.clone(),
+                    ScalarOp::Clamp(min, -neg_max),
+                ))
+                .input(x, x_out, x_shape)
+                .finish();
+            move_outgoing_edge(node, clamp, graph);
+            remap(node, clamp, &mut ids, graph);
+            Self::remove_unused(graph, node, x);
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct CudaNegCompiler<T: CudaFloat>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for CudaNegCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        // Look for the neg pattern
+        // mul(x, -1)
+        let inp = node();
+        let mul = binary::<CudaMul<T>>(inp.clone(), constant::<T>(-1.));
+        let mut s = mul.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[mul.id]) {
+                continue;
+            }
+            let (_, output_order, src_shape) = graph
+                .edges_connecting(s.get(&inp), s.get(&mul))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            if src_shape.is_reshaped() {
+                continue;
+            }
+            let neg = graph
+                .add_op(CudaNeg::<T>::new(dev.clone()))
+                .input(s.get(&inp), output_order, src_shape)
+                .finish();
+
+            let mul = s.get(&mul);
+            move_outgoing_edge(mul, neg, graph);
+            remap(mul, neg, &mut ids, graph);
+            graph.remove_node(mul);
+            s.try_delete();
+        }
+    }
+}
+
+/// Special kernel for cos
+#[derive(Clone)]
+pub struct CudaCos<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaCos);
+
+impl<T: CudaFloat> CudaCos<T> {
+    fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        out[i] = cos(inp[i]);
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
+impl<T: CudaFloat> Operator for CudaCos<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        if key == "elementwise" {
+            return Some(Box::new("cos(input0)".to_string()));
+        }
+
+        None
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct CudaCosCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for CudaCosCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        // Look for the cos pattern
+        // sin(add(mul(const_neg_one, x), const_pi_over_2))
+
+        let const_pi = constant::<T>(f32::PI() / 2.);
+        let inp = node();
+        let sub = binary::<CudaSub<T>>(inp.clone(), const_pi.clone());
+        let sin = unary::<CudaSin<T>>(sub.clone());
+        let mut s = sin.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[sin.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+
+            // Insert cos op
+            let shape = graph
+                .edges_directed(s.get(&sub), petgraph::Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .find(|e| e.source() != s.get(&const_pi))
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            let cos = graph
+                .add_op(CudaCos::<T>::new(dev.clone()))
+                .input(s.get(&inp), 0, shape)
+                .finish();
+
+            // Create edges to dests
+            let sin = s.get(&sin);
+            move_outgoing_edge(sin, cos, graph);
+            remap(sin, cos, &mut ids, graph);
+
+            // Remove the old ops
+            graph.remove_node(sin);
+            s.try_delete();
+        }
+    }
+}
+
+/// Computes both sin (output 0) and cos (output 1) of the same input in one kernel
+#[derive(Clone)]
+pub struct CudaSinCos<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaSinCos);
+
+impl<T: CudaFloat> CudaSinCos<T> {
+    fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *sin_out, {type_name} *cos_out, const {type_name} *inp, int numel) {{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        float s, c;
+        sincosf((float)inp[i], &s, &c);
+        sin_out[i] = ({type_name})s;
+        cos_out[i] = ({type_name})c;
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
+impl<T: CudaFloat> Operator for CudaSinCos<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
+        let mut sin_out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        let mut cos_out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on(
+                    &self.stream,
+                    LaunchConfig::for_num_elems(inp_size as u32),
+                    (&mut sin_out, &mut cos_out, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![
+            Tensor::new(self.stream.output(sin_out)),
+            Tensor::new(self.stream.output(cos_out)),
+        ]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
+    }
+}
+
+/// Replace a sin and a cos of the same input with a single CudaSinCos. Should run after CudaCosCompiler
+#[derive(Default, Debug)]
+pub struct CudaSinCosCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for CudaSinCosCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        for cos in graph.node_indices().collect::<Vec<_>>() {
+            // Ids can only be remapped to the fused op's first output, so a cos someone holds on to is left alone
+            if !graph.check_node_type::<CudaCos<T>>(cos)
+                || graph.no_delete.contains(&cos)
+                || ids.to_ids_mut().into_iter().any(|id| *id == cos)
+            {
+                continue;
+            }
+            let (src, output_order, shape) = graph.get_sources(cos)[0];
+            // Find a sin reading the same input the same way
+            let Some(sin) = graph
+                .edges_directed(src, petgraph::Direction::Outgoing)
+                .filter(|e| graph.check_node_type::<CudaSin<T>>(e.target()))
+                .filter(|e| !graph.no_delete.contains(&e.target()))
+                .find(|e| {
+                    e.weight()
+                        .as_data()
+                        .map(|(_, o, sh)| o == output_order && sh == shape)
+                        .unwrap_or_default()
+                })
+                .map(|e| e.target())
+            else {
+                continue;
+            };
+
+            let sincos = graph
+                .add_op(CudaSinCos::<T>::new(dev.clone()))
+                .input(src, output_order, shape)
+                .finish();
+            for (node, out) in [(sin, 0), (cos, 1)] {
+                for (weight, target) in graph
+                    .edges_directed(node, petgraph::Direction::Outgoing)
+                    .map(|e| (*e.weight(), e.target()))
+                    .collect::<Vec<_>>()
+                {
+                    let weight = match weight {
+                        Dependency::Data {
+                            input_order, shape, ..
+                        } => Dependency::Data {
+                            input_order,
+                            output_order: out,
+                            shape,
+                        },
+                        schedule => schedule,
+                    };
+                    graph.add_edge(sincos, target, weight);
+                }
+                remap(node, sincos, &mut ids, graph);
+                graph.remove_node(node);
+            }
+        }
+    }
+}
+
+/// Special kernel for efficient softmax. Currently only works on the last dim
+#[derive(Clone)]
+pub struct CudaSoftmax<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    stream: OpStream,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaSoftmax);
+
+impl<T: CudaFloat> CudaSoftmax<T> {
+    fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            function: compile_and_load_kernel(
+                format!(
+                    "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel(const {type_name} * x, {type_name} * dst, const int ncols) {{
+    const int row = blockDim.x*blockIdx.x + threadIdx.x;
+    const int block_size = blockDim.y;
+    const int tid = threadIdx.y;
+
+    {type_name} max_val = -__int_as_float(0x7f800000);
+
+    for (int col = tid; col < ncols; col += block_size) {{
+        const int i = row*ncols + col;
+        max_val = fmaxf(max_val, x[i]);
+    }}
+
+    // find the max value in the block
+    #pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {{
+        max_val = fmaxf(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
+    }}
+
+    {type_name} tmp = 0.;
+
+    for (int col = tid; col < ncols; col += block_size) {{
+        const int i = row*ncols + col;
+        const {type_name} val = exp(x[i] - max_val);
+        tmp += static_cast<{type_name}>(val);
+        dst[i] = val;
+    }}
+
+    // sum up partial sums
+    #pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {{
+        tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
+    }}
+
+    const {type_name} inv_tmp = ({type_name})1. / tmp;
+
+    for (int col = tid; col < ncols; col += block_size) {{
+        const int i = row*ncols + col;
+        dst[i] *= inv_tmp;
+    }}
+}}
+",
+                ),
+                &device,
+            ),
+            device,
+            stream: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSoftmax<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Setup buffers
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let batch_size = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .take(tensors[0].1.len() - 1)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>()
+            .max(1);
+        let axis_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
         let axis_size_int = axis_size as i32;
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = self.stream.alloc_zeros::<T>(&self.device, inp_size);
 
         let mut params = vec![
             get_buffer_from_tensor::<T>(&tensors[0].0).as_kernel_param(),
@@ -695,7 +1956,8 @@ impl<T: CudaFloat> Operator for CudaSoftmax<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on(
+                    &self.stream,
                     LaunchConfig {
                         grid_dim: (batch_size as u32, 1, 1),
                         block_dim: (1, 32, 1),
@@ -706,7 +1968,14 @@ impl<T: CudaFloat> Operator for CudaSoftmax<T> {
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(self.stream.output(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if matches!(key, "stream" | "buffers") {
+            return self.stream.assign(key, input);
+        }
+        None
     }
 }
 
@@ -721,12 +1990,11 @@ impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
         // Look for the mean-reduce pattern
         // mul(recip(fake_sum_reduce(const_ones)), sum_reduce(x))
 
-        let max_reduce = op::<CudaMaxReduce<T>>();
-        let mul = unary::<CudaMul<T>>(unary::<CudaRecip<T>>(unary::<CudaSumReduce<T>>(unary::<
-            CudaExp<T>,
-        >(
-            unary::<CudaSub<T>>(max_reduce.clone()),
-        ))));
+        let max_reduce = reduce::<T>(ReduceOp::Max);
+        let mul = unary::<CudaMul<T>>(unary::<CudaRecip<T>>(
+            unary::<CudaExp<T>>(unary::<CudaSub<T>>(max_reduce.clone()))
+                .connect(reduce::<T>(ReduceOp::Sum)),
+        ));
 
         let mut s = mul.clone().search(graph);
         while s.next_match() {
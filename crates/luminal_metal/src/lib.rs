@@ -397,7 +397,7 @@ fn render_dyn_dim_inputs(shapes: &[ShapeTracker], offset: usize) -> (Vec<char>,
 
 fn expr_to_metal_string(expr: &BigExpression) -> String {
     let mut symbols = vec![];
-    for term in expr.terms.clone() {
+    for term in expr.clone().lower_pow2_div_mod().terms {
         let new_symbol = match term {
             Term::Num(n) => n.to_string(),
             Term::Var(c) => {
@@ -417,6 +417,16 @@ fn expr_to_metal_string(expr: &BigExpression) -> String {
                 symbols.pop().unwrap(),
                 symbols.pop().unwrap()
             ),
+            Term::FloorMod => {
+                let (a, b) = (symbols.pop().unwrap(), symbols.pop().unwrap());
+                format!("((({a}) % ({b}) + ({b})) % ({b}))")
+            }
+            Term::Select => format!(
+                "(({}) != 0 ? ({}) : ({}))",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
             Term::Lt => format!(
                 "(int)({} < {})",
                 symbols.pop().unwrap(),
@@ -1563,6 +1563,168 @@ impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
     }
 }
 
+/// Reduce a dimension with any [ReduceOp]
+#[derive(Clone)]
+pub struct MetalReduce<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    pub op: ReduceOp,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+crate::debug_type!(MetalReduce<T>);
+
+impl<T> PartialEq for MetalReduce<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && self.op == other.op
+    }
+}
+
+impl<T: MetalFloat> MetalReduce<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        dim: usize,
+        op: ReduceOp,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let type_name = T::type_name();
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 6);
+        let (identity, combine) = match op {
+            ReduceOp::Sum => ("0.0", "reduce_value + val"),
+            ReduceOp::Max => ("-INFINITY", "max(reduce_value, val)"),
+            ReduceOp::Min => ("INFINITY", "min(reduce_value, val)"),
+            ReduceOp::Product => ("1.0", "reduce_value * val"),
+        };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device int& n_elements [[buffer(2)]], device int& front_size [[buffer(3)]], device int& back_size [[buffer(4)]], device int& dim_size [[buffer(5)]], uint i_ [[thread_position_in_grid]]{rendered}) {{
+    if (i_ < n_elements) {{
+        int a_ = i_ / back_size;
+        int b_ = i_ % back_size;
+        float reduce_value = {identity};
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            if (({valid_exp}) != 0) {{
+                float val = (float)inp[{idx_exp}];
+                reduce_value = {combine};
+            }}
+        }}
+        out[i_] = ({type_name})reduce_value;
+    }}
+}}");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dim,
+            op,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalReduce<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        let mut sh = input_shapes[0];
+        sh.remove_dim(self.dim);
+        vec![sh.n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let mut sh = inputs[0].1;
+        sh.remove_dim(self.dim);
+        let inp_size = sh.n_elements().to_usize().unwrap();
+        let front_size: usize = inputs[0]
+            .1
+            .shape()
+            .iter()
+            .take(self.dim)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let back_size: usize = inputs[0]
+            .1
+            .shape()
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let dim_size = inputs[0].1.shape()[self.dim].to_usize().unwrap();
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, inp_size as u32);
+        encoder.set_u32(3, front_size as u32);
+        encoder.set_u32(4, back_size as u32);
+        encoder.set_u32(5, dim_size as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            6,
+        );
+
+        // Execute
+        encoder.dispatch_1d(inp_size);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalReduce<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            // Setup command queue / command buffer / encoder
+            let command_buffer = self.queue.new_command_buffer();
+            let mut sh = tensors[0].1;
+            sh.remove_dim(self.dim);
+            let inp_size = sh.n_elements().to_usize().unwrap();
+            let out = self.device.new_buffer(
+                (inp_size * std::mem::size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct PrimitiveCompiler<T>(PhantomData<T>);
 
@@ -1768,6 +1930,24 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(ProductReduce(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(MetalReduce::<T>::new(
+                    src_shapes[0],
+                    *dim,
+                    ReduceOp::Product,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if let Some(Reduce { axis, op }) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(MetalReduce::<T>::new(
+                    src_shapes[0],
+                    *axis,
+                    *op,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Contiguous>(op) {
                 *op_ref = Box::new(MetalContiguous::<T>::new(
                     src_shapes[0],
@@ -106,6 +106,44 @@ fn test_max_reduce() {
     assert_close(&d.data(), &d_d.as_vec());
 }
 
+#[test]
+fn test_prod_reduce() {
+    let data = random_vec(6);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(data.clone());
+    let mut b = a.prod_reduce::<_, LAxis<1>>().retrieve();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    let expected = data
+        .chunks(3)
+        .map(|r| r.iter().product::<f32>())
+        .collect::<Vec<_>>();
+    assert_close(&b.data(), &expected);
+}
+
+#[test]
+fn test_reduce() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set([[1., -2., 3.], [4., 0.5, -6.]]);
+    let mut sum = a.reduce::<R1<2>, LAxis<1>>(ReduceOp::Sum).retrieve();
+    let mut max = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Max).retrieve();
+    let mut min = a.min_reduce::<R1<2>, LAxis<1>>().retrieve();
+    let mut prod = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Product).retrieve();
+
+    cx.compile(
+        MetalCompiler::<f32>::default(),
+        (&mut sum, &mut max, &mut min, &mut prod),
+    );
+    cx.execute();
+
+    assert_exact(&sum.data(), &[2., -1.5]);
+    assert_exact(&max.data(), &[4., 0.5, 3.]);
+    assert_exact(&min.data(), &[-2., -6.]);
+    assert_exact(&prod.data(), &[4., -1., -18.]);
+}
+
 #[test]
 fn test_mean_reduce() {
     let data = random_vec(40960);
@@ -1,12 +1,15 @@
 use luminal::prelude::*;
 use rand::{thread_rng, Rng};
 
+/// 1D convolution over the last dimension. `DILATION` is the number of elements skipped between kernel elements,
+/// and `PADDING` zeros are added to both sides of the input
 pub struct Conv1D<
     const CH_IN: usize,
     const CH_OUT: usize,
     const KERNEL: usize,
     const STRIDE: usize = KERNEL,
     const DILATION: usize = 0,
+    const PADDING: usize = 0,
 > {
     pub weight: GraphTensor<R3<CH_OUT, CH_IN, KERNEL>>,
 }
@@ -17,13 +20,14 @@ impl<
         const KERNEL: usize,
         const STRIDE: usize,
         const DILATION: usize,
-    > InitModule for Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION>
+        const PADDING: usize,
+    > InitModule for Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION, PADDING>
 {
     fn initialize(cx: &mut Graph) -> Self {
         // Init weight as uniform(-1, 1)
         let mut rng = thread_rng();
         Self {
-            weight: cx.named_tensor("Weight").set(
+            weight: cx.named_parameter("Weight").set(
                 (0..(CH_IN * CH_OUT * KERNEL))
                     .map(|_| rng.gen_range(-1_f32..1_f32))
                     .collect::<Vec<_>>(),
@@ -38,7 +42,8 @@ impl<
         const KERNEL: usize,
         const STRIDE: usize,
         const DILATION: usize,
-    > SerializeModule for Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION>
+        const PADDING: usize,
+    > SerializeModule for Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION, PADDING>
 {
     fn serialize(&self, s: &mut luminal::module::Serializer) {
         s.tensor("weight", self.weight);
@@ -52,12 +57,20 @@ impl<
         const KERNEL: usize,
         const STRIDE: usize,
         const DILATION: usize,
-    > Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION>
+        const PADDING: usize,
+    > Conv1D<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION, PADDING>
 {
     pub fn forward<const DIM_IN: usize, const DIM_OUT: usize>(
         &self,
         input: GraphTensor<R2<CH_IN, DIM_IN>>,
     ) -> GraphTensor<R2<CH_OUT, DIM_OUT>> {
+        let input = if PADDING > 0 {
+            input
+                .pad::<(Const<CH_IN>, Dyn<'-'>), _, _>(&[(0, 0), (PADDING, PADDING)])
+                .contiguous()
+        } else {
+            input.realize()
+        };
         self.weight
             .dyn_reshape::<(Const<CH_OUT>, Dyn<'-'>)>(vec![CH_OUT.into(), (CH_IN * KERNEL).into()])
             .matmul(
@@ -102,7 +115,7 @@ impl<
         // Init weight as uniform(-1, 1)
         let mut rng = thread_rng();
         Self {
-            weight: cx.named_tensor("Weight").set(
+            weight: cx.named_parameter("Weight").set(
                 (0..(CH_IN * CH_OUT * KERNELX * KERNELY))
                     .map(|_| rng.gen_range(-1_f32..1_f32))
                     .collect::<Vec<_>>(),
@@ -179,8 +192,17 @@ impl<
 
 #[cfg(test)]
 mod tests {
+    use dfdx::{
+        shapes::{Const as DConst, Rank2, Rank3, Rank4},
+        tensor::{Cpu, TensorFromVec},
+        tensor_ops::{BroadcastTo, GatherTo, ReshapeTo, SumTo},
+    };
+
     use super::{Conv1D, Conv2D};
-    use luminal::{prelude::*, tests::assert_close};
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
 
     #[test]
     fn test_conv1d_simple() {
@@ -251,6 +273,95 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_conv1d_dilated_padded() {
+        let mut cx = Graph::new();
+
+        const KERNEL: usize = 3;
+        const STRIDE: usize = 2;
+        const DILATION: usize = 1;
+        const PADDING: usize = 2;
+        const DIM_IN: usize = 11;
+        const DIM_OUT: usize =
+            ((DIM_IN + 2 * PADDING - (DILATION + 1) * (KERNEL - 1) - 1) / STRIDE) + 1;
+
+        let model = Conv1D::<1, 1, KERNEL, STRIDE, DILATION, PADDING>::initialize(&mut cx);
+        let weight = [0.5, -1.25, 2.];
+        model.weight.set(weight.to_vec());
+        let input = (0..DIM_IN)
+            .map(|i| (i * i % 7) as f32 - 3.)
+            .collect::<Vec<_>>();
+        let inp1 = cx.tensor::<R2<1, DIM_IN>>().set(input.clone());
+
+        let out1 = model.forward::<DIM_IN, DIM_OUT>(inp1).retrieve();
+        cx.execute();
+
+        // Direct convolution over the zero padded input
+        let padded = [vec![0.; PADDING], input, vec![0.; PADDING]].concat();
+        let expected = (0..DIM_OUT)
+            .map(|o| {
+                weight
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| w * padded[o * STRIDE + k * (DILATION + 1)])
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&out1.data(), &expected);
+    }
+
+    #[test]
+    fn test_conv1d_dilated_strided_dfdx() {
+        let mut cx = Graph::new();
+
+        const CH_IN: usize = 2;
+        const CH_OUT: usize = 3;
+        const KERNEL: usize = 3;
+        const STRIDE: usize = 2;
+        const DILATION: usize = 2;
+        const PADDING: usize = 2;
+        const DIM_IN: usize = 13;
+        const DIM_OUT: usize =
+            ((DIM_IN + 2 * PADDING - (DILATION + 1) * (KERNEL - 1) - 1) / STRIDE) + 1;
+
+        let model = Conv1D::<CH_IN, CH_OUT, KERNEL, STRIDE, DILATION, PADDING>::initialize(&mut cx);
+        let weight = random_vec(CH_OUT * CH_IN * KERNEL);
+        model.weight.set(weight.clone());
+        let input = random_vec(CH_IN * DIM_IN);
+        let inp1 = cx.tensor::<R2<CH_IN, DIM_IN>>().set(input.clone());
+
+        let out1 = model.forward::<DIM_IN, DIM_OUT>(inp1).retrieve();
+        cx.execute();
+
+        // dfdx has no stable conv1d, so gather each output's dilated window out of the padded input
+        // and contract it with the kernel
+        const DIM_PADDED: usize = DIM_IN + 2 * PADDING;
+        let d_dev = Cpu::default();
+        let padded = input
+            .chunks(DIM_IN)
+            .flat_map(|row| [vec![0.; PADDING], row.to_vec(), vec![0.; PADDING]].concat())
+            .collect::<Vec<_>>();
+        let d_inp = d_dev.tensor_from_vec(padded, (DConst::<CH_IN>, DConst::<DIM_PADDED>));
+        let windows = (0..CH_IN)
+            .flat_map(|_| {
+                (0..DIM_OUT).flat_map(|o| (0..KERNEL).map(move |k| o * STRIDE + k * (DILATION + 1)))
+            })
+            .collect::<Vec<_>>();
+        let windows =
+            d_dev.tensor_from_vec(windows, (DConst::<CH_IN>, DConst::<{ DIM_OUT * KERNEL }>));
+        let d_weight = d_dev.tensor_from_vec(
+            weight,
+            (DConst::<CH_OUT>, DConst::<CH_IN>, DConst::<KERNEL>),
+        );
+        let d_windows = d_inp
+            .gather::<Rank2<CH_IN, { DIM_OUT * KERNEL }>, _>(windows)
+            .reshape::<Rank3<CH_IN, DIM_OUT, KERNEL>>()
+            .broadcast::<Rank4<CH_OUT, CH_IN, DIM_OUT, KERNEL>, _>();
+        let d_weight = d_weight.broadcast::<Rank4<CH_OUT, CH_IN, DIM_OUT, KERNEL>, _>();
+        let d_out = (d_windows * d_weight).sum::<Rank2<CH_OUT, DIM_OUT>, _>();
+        assert_close(&out1.data(), &d_out.as_vec());
+    }
+
     #[test]
     fn test_conv2d() {
         let mut cx = Graph::new();
@@ -7,7 +7,9 @@ pub struct Embedding<const N: usize, const DIM: usize> {
 impl<const A: usize, const B: usize> InitModule for Embedding<A, B> {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            weight: cx.named_tensor("Embedding Weight").set(random_vec(A * B)),
+            weight: cx
+                .named_parameter("Embedding Weight")
+                .set(random_vec(A * B)),
         }
     }
 }
@@ -42,6 +44,66 @@ impl<B: Dimension, S: Dimension, const N: usize, const DIM: usize> Module<GraphT
     }
 }
 
+/// Fixed sinusoidal position encodings for up to `MAX_LEN` positions, added to the input embeddings.
+/// Even dimensions `2i` hold `sin(pos / 10000^(2i / DIM))` and the odd dimension after each holds the matching cos
+pub struct SinusoidalPositionalEncoding<const MAX_LEN: usize, const DIM: usize> {
+    pub table: GraphTensor<R2<MAX_LEN, DIM>>,
+}
+
+impl<const MAX_LEN: usize, const DIM: usize> InitModule
+    for SinusoidalPositionalEncoding<MAX_LEN, DIM>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        let pos = cx
+            .arange::<Const<MAX_LEN>>()
+            .expand::<R2<MAX_LEN, DIM>, Axis<1>>();
+        let dim = cx
+            .arange::<Const<DIM>>()
+            .expand::<R2<MAX_LEN, DIM>, Axis<0>>();
+        let odd = dim % 2.;
+        // Each cos shares the frequency of the sin before it
+        let inv_freq = ((dim - odd) * (-(10000_f32.ln()) / DIM as f32)).exp();
+        let angle = pos * inv_freq;
+        Self {
+            table: angle.sin() * (1. - odd) + angle.cos() * odd,
+        }
+    }
+}
+
+impl<const MAX_LEN: usize, const DIM: usize> SerializeModule
+    for SinusoidalPositionalEncoding<MAX_LEN, DIM>
+{
+    // Nothing learned
+    fn serialize(&self, _: &mut luminal::module::Serializer) {}
+}
+
+// Single
+impl<S: Dimension, const MAX_LEN: usize, const DIM: usize> Module<GraphTensor<(S, Const<DIM>)>>
+    for SinusoidalPositionalEncoding<MAX_LEN, DIM>
+{
+    type Output = GraphTensor<(S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(S, Const<DIM>)>) -> Self::Output {
+        input + self.table.slice((..S::const_size(), ..)).realize()
+    }
+}
+
+// Batch
+impl<B: Dimension, S: Dimension, const MAX_LEN: usize, const DIM: usize>
+    Module<GraphTensor<(B, S, Const<DIM>)>> for SinusoidalPositionalEncoding<MAX_LEN, DIM>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        input
+            + self
+                .table
+                .slice((..S::const_size(), ..))
+                .realize::<(S, Const<DIM>)>()
+                .expand()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dfdx::{
@@ -51,7 +113,7 @@ mod tests {
 
     use luminal::prelude::Module;
 
-    use super::Embedding;
+    use super::{Embedding, SinusoidalPositionalEncoding};
     use dfdx::nn::BuildOnDevice;
     luminal::test_imports!();
 
@@ -89,4 +151,38 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
         assert_close(&batch_out.data(), &d_batch_out.as_vec());
     }
+
+    #[test]
+    fn test_sinusoidal_positional_encoding() {
+        let mut cx = Graph::new();
+        let model: SinusoidalPositionalEncoding<16, 6> = InitModule::initialize(&mut cx);
+        let embeddings = random_vec(2 * 4 * 6);
+        let a = cx.tensor::<R2<4, 6>>().set(vec![0.; 24]);
+        let batch = cx.tensor::<R3<2, 4, 6>>().set(embeddings.clone());
+        let mut b = model.forward(a).retrieve();
+        let mut batch_out = model.forward(batch).retrieve();
+
+        cx.compile(GenericCompiler::default(), (&mut b, &mut batch_out));
+        cx.execute();
+
+        let reference = (0..4)
+            .flat_map(|pos| {
+                (0..6).map(move |d| {
+                    let angle = pos as f32 / 10000_f32.powf((d - d % 2) as f32 / 6.);
+                    if d % 2 == 0 {
+                        angle.sin()
+                    } else {
+                        angle.cos()
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &reference);
+        let batch_reference = embeddings
+            .iter()
+            .zip(reference.iter().cycle())
+            .map(|(e, r)| e + r)
+            .collect::<Vec<_>>();
+        assert_close(&batch_out.data(), &batch_reference);
+    }
 }
@@ -1,3 +1,5 @@
+use std::{any::Any, marker::PhantomData};
+
 use luminal::prelude::*;
 
 mod activation;
@@ -43,3 +45,187 @@ impl<I, T: Module<I, Output = I>, const N: usize> Module<I> for Repeated<T, N> {
         input
     }
 }
+
+/// A layer held in a [Sequential]. Its input and output types are erased so layers of different shapes can be stored together,
+/// [Sequential::push] checks they line up.
+pub trait SequentialLayer: SerializeModule {
+    fn forward_any(&self, input: Box<dyn Any>) -> Box<dyn Any>;
+}
+
+struct Erased<L, I>(L, PhantomData<fn(I)>);
+
+impl<L: SerializeModule, I> SerializeModule for Erased<L, I> {
+    fn serialize(&self, s: &mut Serializer) {
+        self.0.serialize(s)
+    }
+}
+
+impl<I: 'static, L: Module<I> + SerializeModule> SequentialLayer for Erased<L, I>
+where
+    L::Output: 'static,
+{
+    fn forward_any(&self, input: Box<dyn Any>) -> Box<dyn Any> {
+        Box::new(self.0.forward(*input.downcast::<I>().unwrap()))
+    }
+}
+
+impl SerializeModule for Box<dyn SequentialLayer> {
+    fn serialize(&self, s: &mut Serializer) {
+        (**self).serialize(s)
+    }
+}
+
+/// Layers ran one after another, chosen at runtime rather than fixed in a tuple type.
+/// `O` is the output of the last layer, which is the input of the next one pushed.
+pub struct Sequential<I, O = I> {
+    pub layers: Vec<Box<dyn SequentialLayer>>,
+    _phantom: PhantomData<fn(I) -> O>,
+}
+
+impl<I> Default for Sequential<I> {
+    fn default() -> Self {
+        Self {
+            layers: vec![],
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<I> Sequential<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<I, O: 'static> Sequential<I, O> {
+    /// Add a layer to the end
+    pub fn push<L: Module<O> + SerializeModule + 'static>(
+        mut self,
+        layer: L,
+    ) -> Sequential<I, L::Output>
+    where
+        L::Output: 'static,
+    {
+        self.layers
+            .push(Box::new(Erased(layer, PhantomData::<fn(O)>)));
+        Sequential {
+            layers: self.layers,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<I, O> SerializeModule for Sequential<I, O> {
+    fn serialize(&self, s: &mut Serializer) {
+        for (i, l) in self.layers.iter().enumerate() {
+            s.module(&format!("layer{i}"), l);
+        }
+    }
+}
+
+impl<I: 'static, O: 'static> Module<I> for Sequential<I, O> {
+    type Output = O;
+
+    fn forward(&self, input: I) -> Self::Output {
+        let mut x: Box<dyn Any> = Box::new(input);
+        for l in &self.layers {
+            x = l.forward_any(x);
+        }
+        *x.downcast::<O>().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Linear, ReLU, Sequential};
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    #[test]
+    fn test_sequential() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(random_vec(4));
+        let model: (Linear<4, 4>, ReLU, Linear<4, 4>, ReLU, Linear<4, 4>) =
+            InitModule::initialize(&mut cx);
+        let weights = [model.0.weight, model.2.weight, model.4.weight];
+
+        // Same layers, with the depth picked at runtime
+        let depth = weights.len();
+        let mut seq = Sequential::new();
+        for (i, weight) in weights.into_iter().enumerate() {
+            seq = seq.push(Linear { weight });
+            if i < depth - 1 {
+                seq = seq.push(ReLU);
+            }
+        }
+        assert_eq!(seq.layers.len(), 5);
+        assert_eq!(params(&seq).len(), 3);
+        assert!(param_dict(&seq).contains_key("layer4/weight"));
+
+        let b = model.forward(a).retrieve();
+        let c = seq.forward(a).retrieve();
+        cx.execute();
+        assert_close(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_sequential_widths() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(random_vec(3));
+        let model: (Linear<3, 4>, ReLU, Linear<4, 2>) = InitModule::initialize(&mut cx);
+
+        let seq = Sequential::new()
+            .push(Linear {
+                weight: model.0.weight,
+            })
+            .push(ReLU)
+            .push(Linear {
+                weight: model.2.weight,
+            });
+
+        let b = model.forward(a).retrieve();
+        let c: GraphTensor<R1<2>> = seq.forward(a).retrieve();
+        cx.execute();
+        assert_close(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_num_params() {
+        let mut cx = Graph::new();
+        let model: (Linear<3, 4>, ReLU, Linear<4, 2>) = InitModule::initialize(&mut cx);
+        assert_eq!(model.num_params(), 3 * 4 + 4 * 2);
+        assert_eq!(model.param_bytes::<f32>(), 80);
+        assert_eq!(model.param_bytes::<f16>(), 40);
+
+        // Tied weights only count once
+        let tied = (
+            Linear {
+                weight: model.0.weight,
+            },
+            model.0,
+        );
+        assert_eq!(tied.num_params(), 12);
+    }
+
+    #[test]
+    fn test_from_modules() {
+        let mut cx = Graph::new();
+        let model: (Linear<4, 8>, ReLU, Linear<8, 2>) = InitModule::initialize(&mut cx);
+        let mlp = Graph::from_modules(&model, cx.tensor::<R1<4>>());
+
+        // Reference built by hand, marking the output ourselves
+        let x = cx.tensor::<R1<4>>();
+        let expected = model.forward(x).retrieve();
+        let mut outputs = vec![];
+        for input in [random_vec(4), random_vec(4)] {
+            x.set(input.clone());
+            expected.drop();
+            let out = mlp.run(input);
+            assert_close(&out, &expected.data());
+            outputs.push(out);
+        }
+        assert_ne!(outputs[0], outputs[1]);
+    }
+}
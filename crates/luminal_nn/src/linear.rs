@@ -12,7 +12,7 @@ impl<const A: usize, const B: usize> InitModule for Linear<A, B> {
         // Init weight as uniform(-1, 1)
         let mut rng = thread_rng();
         Self {
-            weight: cx.named_tensor("Weight").set(
+            weight: cx.named_parameter("Weight").set(
                 (0..(A * B))
                     .map(|_| rng.gen_range(-1_f32..1_f32))
                     .collect::<Vec<_>>(),
@@ -48,7 +48,7 @@ impl<const A: usize, const B: usize> InitModule for PermutedLinear<A, B> {
         // Init weight as uniform(-1, 1)
         let mut rng = thread_rng();
         Self {
-            weight: cx.named_tensor("Weight").set(
+            weight: cx.named_parameter("Weight").set(
                 (0..(A * B))
                     .map(|_| rng.gen_range(-1_f32..1_f32))
                     .collect::<Vec<_>>(),
@@ -77,6 +77,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::Linear;
+    use crate::ReLU;
     use luminal::{prelude::*, tests::assert_close};
     #[test]
     fn test_linear() {
@@ -101,4 +102,14 @@ mod tests {
         assert_close(&unoptimized_b, &b.data());
         assert_close(&unoptimized_batch_out, &batch_out.data());
     }
+
+    #[test]
+    fn test_parameters() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R1<3>>();
+        let model = <(Linear<3, 4>, ReLU, Linear<4, 2>)>::initialize(&mut cx);
+        let _ = model.forward(input).retrieve();
+
+        assert_eq!(cx.parameters(), vec![model.0.weight.id, model.2.weight.id]);
+    }
 }
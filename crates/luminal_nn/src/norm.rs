@@ -36,7 +36,7 @@ pub struct RMSNorm<const DIM: usize> {
 impl<const DIM: usize> InitModule for RMSNorm<DIM> {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            weight: cx.named_tensor("RMSNorm Weight").set(vec![1.0; DIM]),
+            weight: cx.named_parameter("RMSNorm Weight").set(vec![1.0; DIM]),
             epsilon: 1e-6,
         }
     }
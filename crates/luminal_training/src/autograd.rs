@@ -6,8 +6,8 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
     op::{
-        Add, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, Recip, Sin, Sqrt,
-        SumReduce,
+        Add, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, ProductReduce, Recip,
+        Reduce, ReduceOp, Sin, Sqrt, StopGradient, SumReduce,
     },
     prelude::{tinyvec::ArrayVec, *},
 };
@@ -31,6 +31,12 @@ fn build_dfs_set(
     while let Some(n) = stack.pop() {
         if !set.contains(&n) {
             set.insert(n);
+            // Gradients don't flow back past detached tensors
+            if direction == Direction::Incoming
+                && graph.node_weight(n).unwrap().as_any().is::<StopGradient>()
+            {
+                continue;
+            }
             stack.extend(
                 graph
                     .edges_directed(n, direction)
@@ -72,7 +78,7 @@ impl Compiler for Autograd {
             // Check if the node is undifferentiable
             let graph_ref: *mut Graph = graph;
             let op = graph.node_weight(fwd_node).unwrap().as_any().type_id();
-            if op == TypeId::of::<Function>() {
+            if op == TypeId::of::<Function>() || op == TypeId::of::<StopGradient>() {
                 continue;
             }
             if op == TypeId::of::<Mod>() || op == TypeId::of::<LessThan>() {
@@ -116,31 +122,26 @@ impl Compiler for Autograd {
                 if valid_set.contains(&inps[1].id) {
                     add_grad(inps[0] * prev_grad, inps[1], graph, &mut grads);
                 }
-            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
-                .try_get_op::<SumReduce>(fwd_node)
-                .cloned()
-            {
-                // f(x) = sum_reduce(x)
-                // f'(x) = 1
-                if valid_set.contains(&inps[0].id) {
-                    prev_grad
-                        .shape
-                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
-                    add_grad(prev_grad, inps[0], graph, &mut grads);
-                }
-            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
-                .try_get_op::<MaxReduce>(fwd_node)
-                .cloned()
-            {
-                // f(x) = max_reduce(x)
-                // f'(x) = x == max_reduce(x)
+            } else if let Some((dim, reduce_op)) = as_reduce(graph, fwd_node) {
                 if valid_set.contains(&inps[0].id) {
-                    // fwd_nod is already max_reduce(x)
-                    prev_grad
-                        .shape
-                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
-                    let reduced = GraphTensor::<()>::from_id(fwd_node, prev_grad.shape, graph_ref);
-                    let grad = inps[0].equals(reduced) * prev_grad;
+                    let dim_size = inps[0].shape.dims[inps[0].shape.indexes[dim]];
+                    prev_grad.shape.expand(dim, dim_size);
+                    // fwd_node is already reduce(x), broadcast back over the reduced dim
+                    let mut reduced_shape = inps[0].shape.contiguous();
+                    reduced_shape.remove_dim(dim);
+                    reduced_shape.expand(dim, dim_size);
+                    let reduced = GraphTensor::<()>::from_id(fwd_node, reduced_shape, graph_ref);
+                    let grad = match reduce_op {
+                        // f(x) = sum_reduce(x)
+                        // f'(x) = 1
+                        ReduceOp::Sum => prev_grad,
+                        // f(x) = max_reduce(x)
+                        // f'(x) = x == max_reduce(x)
+                        ReduceOp::Max | ReduceOp::Min => inps[0].equals(reduced) * prev_grad,
+                        // f(x) = prod_reduce(x)
+                        // f'(x) = prod_reduce(x) / x (undefined where x is 0)
+                        ReduceOp::Product => reduced / inps[0] * prev_grad,
+                    };
                     add_grad(grad, inps[0], graph, &mut grads);
                 }
             } else if op == TypeId::of::<Contiguous>() {
@@ -179,7 +180,42 @@ impl Compiler for Autograd {
         }
 
         // Create a gradient array to match 1-1 with the weight array passed in
-        self.0.iter().map(|weight| grads[weight]).collect()
+        self.0
+            .iter()
+            .map(|weight| {
+                grads.get(weight).copied().unwrap_or_else(|| {
+                    // Weight doesn't affect the loss (it's detached), so it gets a zero gradient
+                    let shape = graph
+                        .edges_directed(*weight, Direction::Outgoing)
+                        .find_map(|e| e.weight().as_data())
+                        .map(|(_, _, sh)| {
+                            sh.dims
+                                .into_iter()
+                                .zip(sh.fake)
+                                .filter(|(_, fake)| !fake)
+                                .map(|(d, _)| d.expr())
+                                .collect::<Vec<_>>()
+                        })
+                        .unwrap_or_default();
+                    (graph.constant(0.0).id, ShapeTracker::fake(&shape))
+                })
+            })
+            .collect()
+    }
+}
+
+/// The reduced dimension and combiner of a reduction node
+fn as_reduce(graph: &Graph, node: NodeIndex) -> Option<(usize, ReduceOp)> {
+    if let Some(SumReduce(dim)) = graph.try_get_op(node) {
+        Some((*dim, ReduceOp::Sum))
+    } else if let Some(MaxReduce(dim)) = graph.try_get_op(node) {
+        Some((*dim, ReduceOp::Max))
+    } else if let Some(ProductReduce(dim)) = graph.try_get_op(node) {
+        Some((*dim, ReduceOp::Product))
+    } else if let Some(Reduce { axis, op }) = graph.try_get_op(node) {
+        Some((*axis, *op))
+    } else {
+        None
     }
 }
 
@@ -212,10 +248,8 @@ fn add_grad(
 
     // Check to see if a reshape was done here. If so, we may need to assert grad shape is contiguous or insert a contiguous call
     if let Some((_, _, mut pre_fwd_shape)) = graph.get_sources(fwd.id).first() {
-        if let Some(SumReduce(dim)) = graph.try_get_op(fwd.id) {
-            pre_fwd_shape.remove_dim(*dim);
-        } else if let Some(MaxReduce(dim)) = graph.try_get_op(fwd.id) {
-            pre_fwd_shape.remove_dim(*dim);
+        if let Some((dim, _)) = as_reduce(graph, fwd.id) {
+            pre_fwd_shape.remove_dim(dim);
         }
         if grad.shape.shape() != pre_fwd_shape.shape() {
             if !grad.shape.is_contiguous() {
@@ -265,6 +299,50 @@ mod tests {
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<3>>("Input").set([2., -3., 4.]);
+        let b = a.min_reduce::<R0, _>() + a.reduce::<R0, _>(ReduceOp::Product);
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // d(min)/da = [0, 1, 0], d(prod)/da = [-12, 8, -6]
+        assert_close(&get_vec(grads[0], &mut cx), &[-12., 9., -6.]);
+    }
+
+    #[test]
+    fn test_autograd_prod_reduce() {
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor::<R2<2, 2>>("Input")
+            .set([[2., 3.], [-1., 5.]]);
+        let b = a.prod_reduce::<R1<2>, LAxis<1>>().sum_reduce();
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        assert_close(&get_vec(grads[0], &mut cx), &[3., 2., 5., -1.]);
+    }
+
+    #[test]
+    fn test_autograd_detach() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<2>>("A").set([10., 5.]);
+        let b = cx.named_tensor::<R1<2>>("B").set([2., 3.]);
+        let c = (a.exp2().detach() * b).sum_reduce();
+
+        let grads = cx.compile(Autograd::new((a, b), c), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        assert_exact(&get_vec(grads[0], &mut cx), &[0., 0.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[1024., 32.]);
+    }
+
     #[test]
     fn test_autograd_matmul() {
         let mut cx = Graph::new();
@@ -21,6 +21,13 @@ pub fn mae_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) ->
     (prediction - target).abs().mean_reduce()
 }
 
+/// [L1 loss](https://en.wikipedia.org/wiki/Least_absolute_deviations), the same as [mae_loss].
+///
+/// This computes `(prediction - target).abs().mean()`
+pub fn l1_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) -> GraphTensor<()> {
+    mae_loss(prediction, target)
+}
+
 /// [Huber Loss](https://en.wikipedia.org/wiki/Huber_loss)
 /// uses absolute error when the error is higher than `beta`, and squared error when the
 /// error is lower than `beta`.
@@ -79,6 +86,27 @@ pub fn cross_entropy_with_logits_loss<S: Shape>(
     (-(probs * target_probabilities).mean_reduce()) / inv_last_axis_numel
 }
 
+/// [Cross entropy loss](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression)
+/// against class indices. This computes `-logits.log_softmax()[target].mean()`
+///
+/// ### Inputs
+///
+/// - `logits`: The un-normalized output from a model, one row per example. [log_softmax()] is called **in** this function
+/// - `targets`: The index of the correct class for each example, stored as floats
+pub fn cross_entropy_loss<B: Dimension, C: Dimension>(
+    logits: GraphTensor<(B, C)>,
+    targets: GraphTensor<(B,)>,
+) -> GraphTensor<()> {
+    let log_probs = logits.log_softmax::<Axis<1>>();
+    // Pick out each row's target class with a one-hot mask
+    let one_hot = targets
+        .expand_to::<(B, C)>(log_probs.shape)
+        .equals(logits.graph().arange::<C>().expand_to(log_probs.shape));
+    -(log_probs * one_hot)
+        .sum_reduce::<_, Axis<1>>()
+        .mean_reduce()
+}
+
 /// [KL Divergence loss](https://en.wikipedia.org/wiki/Kullback%E2%80%93Leibler_divergence).
 /// This computes `(target_probs * (target_probs.log() - logits.log_softmax())).sum(-1).mean()`
 ///
@@ -118,3 +146,44 @@ pub fn binary_cross_entropy_with_logits_loss<S: Shape>(
     let bce = (1.0 - target_probabilities) * logits + (1.0 + (-logits).exp()).ln();
     bce.mean_reduce()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_mse_l1_loss() {
+        let mut cx = Graph::new();
+        let pred = cx.tensor::<R2<2, 2>>().set([[1., 2.], [3., 4.]]);
+        let same = cx.tensor::<R2<2, 2>>().set([[1., 2.], [3., 4.]]);
+        let target = cx.tensor::<R2<2, 2>>().set([[2., 0.], [3., 8.]]);
+        let mse_same = super::mse_loss(pred, same).retrieve();
+        let l1_same = l1_loss(pred, same).retrieve();
+        let mse = super::mse_loss(pred, target).retrieve();
+        let l1 = l1_loss(pred, target).retrieve();
+        cx.execute();
+
+        assert_exact(&mse_same.data(), &[0.]);
+        assert_exact(&l1_same.data(), &[0.]);
+        // Differences are 1, 2, 0, 4
+        assert_close(&mse.data(), &[21. / 4.]);
+        assert_close(&l1.data(), &[7. / 4.]);
+    }
+
+    #[test]
+    fn test_cross_entropy_loss() {
+        let mut cx = Graph::new();
+        let logits = cx
+            .tensor::<R2<2, 3>>()
+            .set([[20., -20., -20.], [-20., -20., 20.]]);
+        let correct = cx.tensor::<R1<2>>().set([0., 2.]);
+        let wrong = cx.tensor::<R1<2>>().set([1., 0.]);
+        let correct_loss = cross_entropy_loss(logits, correct).retrieve();
+        let wrong_loss = cross_entropy_loss(logits, wrong).retrieve();
+        cx.execute();
+
+        assert!(correct_loss.data()[0].abs() < 1e-4);
+        assert_close(&wrong_loss.data(), &[40.]);
+    }
+}
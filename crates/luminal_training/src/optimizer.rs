@@ -58,5 +58,73 @@ pub fn sgd_on_graph(
     (new_weights, lr)
 }
 
+/// Scale gradients down so their global L2 norm is at most `max_norm`. Gradients with a smaller norm are left unchanged.
+///
+/// `clipped = gradient * max_norm / max(norm, max_norm)`
+///
+/// Output: (Clipped gradients, Global norm tensor)
+pub fn clip_grad_norm(
+    graph: &mut Graph,
+    grads: &[(NodeIndex, ShapeTracker)],
+    max_norm: f32,
+) -> (Vec<(NodeIndex, ShapeTracker)>, GraphTensor<()>) {
+    let mut squared_sum = graph.constant(0.);
+    for (grad_id, grad_shape) in grads.iter().copied() {
+        let gradient = GraphTensor::<()>::from_id(grad_id, grad_shape, graph);
+        let mut sum = gradient * gradient;
+        for _ in 0..grad_shape.len() {
+            sum = sum.sum_reduce_axis(0);
+        }
+        squared_sum += sum;
+    }
+    let norm = squared_sum.sqrt();
+    let scale = max_norm / norm.max_f32(max_norm);
+
+    let clipped = grads
+        .iter()
+        .copied()
+        .map(|(grad_id, grad_shape)| {
+            let gradient = GraphTensor::<()>::from_id(grad_id, grad_shape, graph);
+            let clipped = gradient * scale.expand_to(grad_shape);
+            (clipped.id, clipped.shape)
+        })
+        .collect();
+    (clipped, norm)
+}
+
 // /// Implements the [Adam](https://arxiv.org/abs/1412.6980) algorithm.
 // pub fn adam(grads: &[(NodeIndex, ShapeTracker)]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set([3., 0.]);
+        let b = cx.tensor::<R2<1, 1>>().set([[4.]]);
+        let grads = [(a.id, a.shape), (b.id, b.shape)];
+        let (clipped, norm) = clip_grad_norm(&mut cx, &grads, 1.);
+        let (unclipped, _) = clip_grad_norm(&mut cx, &grads, 10.);
+        let norm = norm.retrieve();
+        let clipped = clipped
+            .into_iter()
+            .map(|(id, sh)| GraphTensor::<()>::from_id(id, sh, &mut cx).retrieve())
+            .collect::<Vec<_>>();
+        let unclipped = unclipped
+            .into_iter()
+            .map(|(id, sh)| GraphTensor::<()>::from_id(id, sh, &mut cx).retrieve())
+            .collect::<Vec<_>>();
+        cx.execute();
+
+        assert_close(&norm.data(), &[5.]);
+        // Scaled down to a norm of exactly the threshold
+        assert_close(&clipped[0].data(), &[0.6, 0.]);
+        assert_close(&clipped[1].data(), &[0.8]);
+        // Under the threshold, so untouched
+        assert_exact(&unclipped[0].data(), &[3., 0.]);
+        assert_exact(&unclipped[1].data(), &[4.]);
+    }
+}
@@ -131,7 +131,7 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
                             })
                             .collect::<Vec<_>>(),
                     )],
-                    GgmlDType::Q8_0 => vec![Tensor::new(CudaData(
+                    GgmlDType::Q8_0 => vec![Tensor::new(CudaData::new(
                         device.htod_sync_copy::<u8>(&bytes).unwrap(),
                     ))],
                     _ => unimplemented!(),
@@ -43,13 +43,13 @@ impl<const I: usize, const H: usize> InitModule for Mlp<I, H> {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
             gate_proj: PermutedLinear {
-                weight: cx.named_tensor("Gate"),
+                weight: cx.named_parameter("Gate"),
             },
             up_proj: PermutedLinear {
-                weight: cx.named_tensor("Up"),
+                weight: cx.named_parameter("Up"),
             },
             down_proj: PermutedLinear {
-                weight: cx.named_tensor("Down"),
+                weight: cx.named_parameter("Down"),
             },
         }
     }
@@ -171,10 +171,10 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
 impl InitModule for SelfAttention {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            q_proj: cx.named_tensor("Q Proj"),
-            k_proj: cx.named_tensor("K Proj"),
-            v_proj: cx.named_tensor("V Proj"),
-            o_proj: cx.named_tensor("O Proj"),
+            q_proj: cx.named_parameter("Q Proj"),
+            k_proj: cx.named_parameter("K Proj"),
+            v_proj: cx.named_parameter("V Proj"),
+            o_proj: cx.named_parameter("O Proj"),
         }
     }
 }
@@ -235,12 +235,12 @@ impl InitModule for TransformerBlock {
         Self {
             attention: InitModule::initialize(cx),
             attention_norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
+                weight: cx.named_parameter("RMS Norm Weight"),
                 epsilon: 1e-5,
             },
             feed_forward: InitModule::initialize(cx),
             feed_forward_norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
+                weight: cx.named_parameter("RMS Norm Weight"),
                 epsilon: 1e-5,
             },
         }
@@ -307,13 +307,13 @@ impl InitModule for MistralLM {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
             embedding: Embedding {
-                weight: cx.named_tensor("Embedding Weight"),
+                weight: cx.named_parameter("Embedding Weight"),
             },
             norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
+                weight: cx.named_parameter("RMS Norm Weight"),
                 epsilon: 1e-5,
             },
-            lm_head: cx.named_tensor("LM Head"),
+            lm_head: cx.named_parameter("LM Head"),
             layers: (0..NUM_LAYERS)
                 .map(|_| InitModule::initialize(cx))
                 .collect(),
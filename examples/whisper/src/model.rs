@@ -44,9 +44,9 @@ where
 impl<const I: usize, const H: usize> InitModule for Mlp<I, H> {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            gate_proj: cx.named_tensor("Gate Weight"),
-            up_proj: cx.named_tensor("Up Weight"),
-            down_proj: cx.named_tensor("Down Weight"),
+            gate_proj: cx.named_parameter("Gate Weight"),
+            up_proj: cx.named_parameter("Up Weight"),
+            down_proj: cx.named_parameter("Down Weight"),
         }
     }
 }
@@ -182,10 +182,10 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
 impl InitModule for SelfAttention {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            q_proj: cx.named_tensor("Q Proj"),
-            k_proj: cx.named_tensor("K Proj"),
-            v_proj: cx.named_tensor("V Proj"),
-            o_proj: cx.named_tensor("O Proj"),
+            q_proj: cx.named_parameter("Q Proj"),
+            k_proj: cx.named_parameter("K Proj"),
+            v_proj: cx.named_parameter("V Proj"),
+            o_proj: cx.named_parameter("O Proj"),
         }
     }
 }
@@ -327,7 +327,7 @@ impl InitModule for MistralLM {
                 norm.epsilon = 1e-5;
                 norm
             },
-            lm_head: cx.named_tensor("LM Head"),
+            lm_head: cx.named_parameter("LM Head"),
             layers: (0..NUM_LAYERS)
                 .map(|_| InitModule::initialize(cx))
                 .collect(),
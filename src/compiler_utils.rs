@@ -244,6 +244,55 @@ impl<C: Default + Compiler + Debug> Default for Timed<C> {
     }
 }
 
+/// How long a compiler pass took and how it changed the size of the graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassReport {
+    pub name: String,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    pub elapsed: std::time::Duration,
+}
+
+impl std::fmt::Display for PassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} nodes in {}ms",
+            self.name,
+            self.nodes_before,
+            self.nodes_after,
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+/// Wrap this around a compiler to get a [PassReport] back along with the compiler's output.
+/// Wrap each pass in a tuple to see which passes changed the graph
+#[derive(Debug)]
+pub struct Reported<C: Compiler + Debug>(pub C);
+
+impl<C: Compiler + Debug> Compiler for Reported<C> {
+    type Output = (C::Output, PassReport);
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, remap: T) -> Self::Output {
+        let nodes_before = graph.node_count();
+        let start = std::time::Instant::now();
+        let output = self.0.compile(graph, remap);
+        let report = PassReport {
+            name: format!("{:?}", self.0),
+            nodes_before,
+            nodes_after: graph.node_count(),
+            elapsed: start.elapsed(),
+        };
+        (output, report)
+    }
+}
+
+impl<C: Default + Compiler + Debug> Default for Reported<C> {
+    fn default() -> Self {
+        Self(C::default())
+    }
+}
+
 macro_rules! tuple_impls {
     ([$($name:ident),+] , [$($idx:tt),+]) => {
         impl<
@@ -456,7 +505,7 @@ impl Graph {
             .count()
             <= dests
         {
-            self.graph.remove_node(node);
+            self.remove_node(node);
         }
     }
 
@@ -573,6 +622,17 @@ pub fn remap<T: ToIdsMut>(from: NodeIndex, to: NodeIndex, mut ids: T, graph: &mu
     if let Some(w) = graph.to_retrieve.remove(&from) {
         graph.to_retrieve.insert(to, w);
     }
+    // Transfer input and parameter registrations, unless the target has its own
+    if let Some(input) = graph.inputs.remove(&from) {
+        graph.inputs.entry(to).or_insert(input);
+    }
+    if let Some(i) = graph.params.iter().position(|p| *p == from) {
+        if graph.params.contains(&to) {
+            graph.params.remove(i);
+        } else {
+            graph.params[i] = to;
+        }
+    }
 }
 
 pub fn move_outgoing_edge<N, E: Clone>(
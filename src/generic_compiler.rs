@@ -1,4 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
 
 use itertools::Itertools;
 use petgraph::{
@@ -9,7 +12,10 @@ use petgraph::{
 };
 
 use crate::{
-    op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
+    op::{
+        Add, CastToBf16, CastToF32, Constant, ConstantValue, Function, FusedLayerNorm, MaxReduce,
+        Mul, Operator, ProductReduce, Recip, Reduce, Sqrt, SumReduce,
+    },
     prelude::*,
 };
 
@@ -28,12 +34,12 @@ pub struct CSE;
 impl Compiler for CSE {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        // Look for nodes that have the exact same srcs
+        // Look for nodes that have the exact same srcs, reading the same outputs of them
         // Loop cause I'm lazy
         let mut eliminated = true;
         while eliminated {
             eliminated = false;
-            let mut srcs_set: HashMap<Vec<NodeIndex>, Vec<NodeIndex>> = HashMap::new();
+            let mut srcs_set: HashMap<Vec<(NodeIndex, u8)>, Vec<NodeIndex>> = HashMap::new();
             for node in graph.graph.node_indices().collect_vec() {
                 if graph
                     .graph
@@ -49,7 +55,7 @@ impl Compiler for CSE {
                     .edges_directed(node, petgraph::Direction::Incoming)
                     .filter(|e| !e.weight().is_schedule())
                     .sorted_by_key(|e| e.weight().as_data().unwrap().0)
-                    .map(|e| e.source())
+                    .map(|e| (e.source(), e.weight().as_data().unwrap().1))
                     .collect_vec();
 
                 if let Some(other_nodes) = srcs_set.get(&srcs) {
@@ -81,7 +87,7 @@ impl Compiler for CSE {
                         // Transfer all references to node over to other node
                         remap(node, *other_node, &mut ids, graph);
                         // Remove node
-                        graph.graph.remove_node(node);
+                        graph.remove_node(node);
                         eliminated = true;
                         break;
                     }
@@ -100,7 +106,7 @@ impl Compiler for CSE {
     }
 }
 
-/// Remove maxreduces and sumreduces that don't do anything
+/// Remove maxreduces, sumreduces, productreduces and generic reduces that don't do anything
 #[derive(Default)]
 pub struct RemoveSingleReductions;
 
@@ -108,22 +114,15 @@ impl Compiler for RemoveSingleReductions {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
         for node in graph.graph.node_indices().collect::<Vec<_>>() {
-            let dim = if let Some(red) = graph
-                .graph
-                .node_weight(node)
-                .unwrap()
-                .as_any()
-                .downcast_ref::<SumReduce>()
-            {
+            let op = graph.graph.node_weight(node).unwrap().as_any();
+            let dim = if let Some(red) = op.downcast_ref::<SumReduce>() {
+                Some(red.0)
+            } else if let Some(red) = op.downcast_ref::<MaxReduce>() {
                 Some(red.0)
+            } else if let Some(red) = op.downcast_ref::<Reduce>() {
+                Some(red.axis)
             } else {
-                graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .downcast_ref::<MaxReduce>()
-                    .map(|red| red.0)
+                op.downcast_ref::<ProductReduce>().map(|red| red.0)
             };
             if let Some(dim) = dim {
                 if graph
@@ -150,13 +149,247 @@ impl Compiler for RemoveSingleReductions {
                         .unwrap();
                     remap(node, upstream, &mut ids, graph);
                     move_outgoing_edge(node, upstream, &mut graph.graph);
-                    graph.graph.remove_node(node);
+                    graph.remove_node(node);
                 }
             }
         }
     }
 }
 
+/// Fuse the primitive layer norm subgraph into a single [FusedLayerNorm] op, recovering epsilon from its constant.
+/// An elementwise multiply and add directly after the norm are folded in as the scale and shift.
+#[derive(Default, Debug)]
+pub struct FuseLayerNorm;
+
+impl Compiler for FuseLayerNorm {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        // mul(recip(sqrt(add(mean(mul(c, c)), eps))), c) where c = add(x, mul(mean(x), -1))
+        let (mean_const, var_const) = (op::<Constant>(), op::<Constant>());
+        let mean_sum = op::<SumReduce>();
+        let mean = binary::<Mul>(mean_sum.clone(), unary::<Recip>(mean_const.clone()));
+        let centered = unary::<Add>(binary::<Mul>(mean.clone(), constant(-1.)));
+        let square = unary::<Mul>(centered.clone());
+        let var_sum = unary::<SumReduce>(square.clone());
+        let var = binary::<Mul>(var_sum.clone(), unary::<Recip>(var_const.clone()));
+        let mut eps = op::<Constant>();
+        eps.check(|o, _| {
+            matches!(
+                o.as_any().downcast_ref::<Constant>(),
+                Some(Constant(ConstantValue::Float(_), _))
+            )
+        });
+        let norm = unary::<Mul>(unary::<Recip>(unary::<Sqrt>(binary::<Add>(
+            var.clone(),
+            eps.clone(),
+        ))));
+        let mut s = norm.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[norm.id]) {
+                continue;
+            }
+            let (centered, norm) = (s.get(&centered), s.get(&norm));
+            let x = graph.get_sources(s.get(&mean_sum))[0];
+            let axis = graph.get_op::<SumReduce>(s.get(&mean_sum)).0;
+            let dim_size = BigExpression::from(x.2.shape()[axis].clone());
+            let is_dim_size = |n| matches!(graph.get_op::<Constant>(n), Constant(ConstantValue::Expression(e), _) if *e == dim_size);
+            // Both means divide by the row length, and the same input is centered, squared and normalized, each
+            // reading the centered values as is
+            if graph.get_op::<SumReduce>(s.get(&var_sum)).0 != axis
+                || !is_dim_size(s.get(&mean_const))
+                || !is_dim_size(s.get(&var_const))
+                || !graph.get_sources(centered).contains(&x)
+                || graph
+                    .get_sources(s.get(&square))
+                    .iter()
+                    .any(|(n, _, _)| *n != centered)
+                || !graph
+                    .get_sources(norm)
+                    .iter()
+                    .any(|(n, _, _)| *n == centered)
+                || !unmodified_edges(graph, centered, s.get(&square))
+                || !unmodified_edges(graph, centered, norm)
+            {
+                continue;
+            }
+            let Constant(ConstantValue::Float(epsilon), _) = *graph.get_op::<Constant>(s.get(&eps))
+            else {
+                continue;
+            };
+
+            // Fold in a directly following scale and shift
+            let mut folded = vec![norm];
+            let scale = fold_elementwise::<Mul>(graph, &mut folded);
+            let shift = fold_elementwise::<Add>(graph, &mut folded);
+            let out = *folded.last().unwrap();
+
+            let mut fused = graph
+                .add_op(FusedLayerNorm {
+                    axis,
+                    epsilon,
+                    scale: scale.is_some(),
+                    shift: shift.is_some(),
+                })
+                .input(x.0, x.1, x.2);
+            for (n, o, sh) in scale.into_iter().chain(shift) {
+                fused = fused.input(n, o, sh);
+            }
+            let fused = fused.finish();
+            move_outgoing_edge(out, fused, graph);
+            remap(out, fused, &mut ids, graph);
+
+            for node in folded.into_iter().skip(1).rev() {
+                graph.remove_node(node);
+            }
+            graph.remove_node(norm);
+            s.try_delete();
+        }
+    }
+}
+
+/// If the last folded node feeds only an op of type `O`, fold that op in too and return its other input
+fn fold_elementwise<O: Operator + 'static>(
+    graph: &Graph,
+    folded: &mut Vec<NodeIndex>,
+) -> Option<(NodeIndex, u8, ShapeTracker)> {
+    let last = *folded.last().unwrap();
+    if graph.no_delete.contains(&last) {
+        return None;
+    }
+    let (dest,) = graph
+        .edges_directed(last, Direction::Outgoing)
+        .filter(|e| !e.weight().is_schedule())
+        .map(|e| e.target())
+        .collect_tuple()?;
+    if !graph.check_node_type::<O>(dest) || !unmodified_edges(graph, last, dest) {
+        return None;
+    }
+    let other = graph
+        .get_sources(dest)
+        .into_iter()
+        .filter(|(n, _, _)| *n != last)
+        .exactly_one()
+        .ok()?;
+    folded.push(dest);
+    Some(other)
+}
+
+/// Whether every input `to` takes from `from` reads it as is, rather than permuted, sliced or padded
+fn unmodified_edges(graph: &Graph, from: NodeIndex, to: NodeIndex) -> bool {
+    graph
+        .get_sources(to)
+        .iter()
+        .filter(|(n, _, _)| *n == from)
+        .all(|(_, _, sh)| !sh.is_reshaped())
+}
+
+/// Merge input tensors holding identical data into one node, so the data is only stored (and copied to devices) once.
+/// Only inputs explicitly marked with `mark_constant` are merged, and never parameters or named inputs, since those are
+/// set again later. Inputs are compared by the data they're currently set to, so only run this once they're set.
+#[derive(Default, Debug)]
+pub struct DedupeConstantInputs;
+
+impl Compiler for DedupeConstantInputs {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        /// Run `f` on the data an input is currently set to, if it's loaded as f32s on the host
+        fn with_data<R>(graph: &Graph, node: NodeIndex, f: impl FnOnce(&[f32]) -> R) -> Option<R> {
+            let Function(_, load) = graph.try_get_op::<Function>(node)?;
+            let tensor = load(vec![]).pop()?;
+            tensor.downcast_ref::<Vec<f32>>().map(|data| f(data))
+        }
+
+        // Only the length and hash of each input are stored, candidates with the same ones are loaded again to compare
+        let mut kept: HashMap<(usize, u64), Vec<NodeIndex>> = HashMap::new();
+        for node in graph.graph.node_indices().collect_vec() {
+            if !graph.constant_inputs.contains(&node)
+                || graph.params.contains(&node)
+                || graph.is_named_input(node)
+                || graph.edges_directed(node, Direction::Incoming).count() != 0
+            {
+                continue;
+            }
+            let Some(key) = with_data(graph, node, |data| {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                data.iter().for_each(|f| f.to_bits().hash(&mut hasher));
+                (data.len(), hasher.finish())
+            }) else {
+                continue;
+            };
+            let candidates = kept.entry(key).or_default();
+            let same = |other: NodeIndex| {
+                with_data(graph, other, |a| {
+                    with_data(graph, node, |b| {
+                        a.iter().zip(b).all(|(a, b)| a.to_bits() == b.to_bits())
+                    })
+                })
+                .flatten()
+                .unwrap_or_default()
+            };
+            if let Some(keep) = candidates.iter().copied().find(|other| same(*other)) {
+                move_outgoing_edge(node, keep, &mut graph.graph);
+                remap(node, keep, &mut ids, graph);
+                graph.remove_node(node);
+            } else {
+                candidates.push(node);
+            }
+        }
+    }
+}
+
+/// Promote the inputs of ops mixing dtypes to a common dtype, NumPy-style, by inserting cast ops.
+/// A bf16 input combined with an f32 one is cast up to f32, so the op computes and outputs f32.
+#[derive(Default, Debug)]
+pub struct InsertCastOps;
+
+impl Compiler for InsertCastOps {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        // Share one cast between all consumers of a bf16 output
+        let mut casts = HashMap::new();
+        for node in graph.graph.node_indices().collect_vec() {
+            let srcs = graph.get_sources(node);
+            let (bf16, f32): (Vec<_>, Vec<_>) = srcs
+                .iter()
+                .partition(|(n, _, _)| graph.check_node_type::<CastToBf16>(*n));
+            if bf16.is_empty() || f32.is_empty() {
+                continue;
+            }
+            for &(src, output_order, shape) in bf16 {
+                let cast = *casts.entry((src, output_order)).or_insert_with(|| {
+                    graph
+                        .add_op(CastToF32)
+                        .input(src, output_order, shape)
+                        .finish()
+                });
+                let edge = graph
+                    .edges_connecting(src, node)
+                    .find(|e| {
+                        e.weight()
+                            .as_data()
+                            .map(|(_, o, s)| o == output_order && s == shape)
+                            .unwrap_or_default()
+                    })
+                    .unwrap()
+                    .id();
+                let weight = graph.remove_edge(edge).unwrap();
+                let Dependency::Data { input_order, .. } = weight else {
+                    unreachable!()
+                };
+                graph.add_edge(
+                    cast,
+                    node,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+        }
+    }
+}
+
 /// Remove unused nodes
 #[derive(Default, Debug)]
 pub struct RemoveUnusedNodes;
@@ -305,9 +538,9 @@ impl Compiler for ArithmeticElimination {
                 .count()
                 == 1
             {
-                graph.graph.remove_node(zero);
+                graph.remove_node(zero);
             }
-            graph.graph.remove_node(add);
+            graph.remove_node(add);
         }
         // x * 1, 1 * x
         let one = constant(1.);
@@ -365,7 +598,7 @@ impl Compiler for ArithmeticElimination {
             }
             remap(mul, inp, &mut ids, graph);
             graph.safe_remove_node(one, 1);
-            graph.graph.remove_node(mul);
+            graph.remove_node(mul);
         }
         // recip(recip(x))
         let inp = node();
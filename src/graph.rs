@@ -2,18 +2,30 @@
 
 use crate::prelude::*;
 use std::{
+    future::Future,
     io::Write,
     ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
     time::Duration,
 };
 
 use super::compiler_utils::{ToIds, ToIdsMut};
 use colored::Colorize;
 use itertools::Itertools;
-use petgraph::{stable_graph::StableGraph, visit::EdgeRef, Direction};
+use petgraph::{
+    stable_graph::StableGraph,
+    visit::{EdgeRef, IntoEdgeReferences},
+    Direction,
+};
 use rustc_hash::{FxHashMap, FxHashSet};
 
 pub type MainGraph = StableGraph<Box<dyn Operator>, Dependency>;
+/// A mapping from original nodes to their copies
+pub type NodeMap = FxHashMap<NodeIndex, NodeIndex>;
+
+/// Name of input tensors created without one, such as through [`Graph::tensor`]
+pub const UNNAMED_INPUT: &str = "Tensor";
 
 /// A Luminal compute graph.
 ///
@@ -31,6 +43,18 @@ pub struct Graph {
     pub no_delete: FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: FxHashMap<NodeIndex, (u8, ShapeTracker)>,
+    /// Names and shapes of the input tensors created with `tensor` / `named_tensor`
+    pub inputs: FxHashMap<NodeIndex, (String, ShapeTracker)>,
+    /// Input tensors explicitly marked with `mark_constant` as holding fixed data (cleared again by `set_deferred`)
+    pub constant_inputs: FxHashSet<NodeIndex>,
+    /// Seed all random ops derive their own seeds from
+    pub seed: u64,
+    /// Have GPU backends reduce sequentially, in the CPU's summation order, so sums come out bit-identical between
+    /// runs and match the CPU. CPU reductions are always sequential, so only the CUDA compilers read it. Set it
+    /// before compiling. Slower on GPUs
+    pub deterministic: bool,
+    /// Learnable tensors registered by modules, in the order they were created
+    pub params: Vec<NodeIndex>,
     /// A list of current node to run, source nodes, and view nodes to delete after execution.
     #[allow(clippy::type_complexity)]
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
@@ -38,6 +62,53 @@ pub struct Graph {
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
 }
 
+/// A graph execution that's still finishing, such as device to host copies still in flight. Resolves once all tensors are ready
+pub struct PendingExecution<'a> {
+    graph: &'a mut Graph,
+}
+
+impl Future for PendingExecution<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut ready = true;
+        for tensor in self.graph.tensors.values_mut() {
+            // Pending data holds on to the waker and wakes it once it's ready
+            ready &= tensor.poll_ready(cx).is_ready();
+        }
+        if ready {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A module ran on an input, with the output marked for retrieval. See [Graph::from_modules]
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleGraph<I: Shape, O: Shape> {
+    pub input: GraphTensor<I>,
+    pub output: GraphTensor<O>,
+}
+
+impl<I: Shape, O: Shape> ModuleGraph<I, O> {
+    /// Compile the graph, keeping the input and output pointing at the right nodes
+    pub fn compile<C: Compiler>(&mut self, compiler: C) -> C::Output {
+        let graph = unsafe { self.output.graph_ref.as_mut().unwrap() };
+        graph.compile(compiler, (&mut self.input, &mut self.output))
+    }
+}
+
+impl<I: ConstShape, O: Shape> ModuleGraph<I, O> {
+    /// Run the graph on new input data, returning the output
+    pub fn run<T: Data + Clone, D: ToData<I, T>>(&self, input: D) -> Vec<f32> {
+        self.input.set(input);
+        self.output.drop();
+        self.output.graph().execute();
+        self.output.data()
+    }
+}
+
 /// A dependency between two nodes
 #[derive(Debug, Clone, Copy)]
 #[allow(clippy::large_enum_variant)]
@@ -52,6 +123,269 @@ pub enum Dependency {
     Schedule,
 }
 
+/// The name and shape of a tensor going into or out of a graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorSignature {
+    pub node: NodeIndex,
+    pub name: String,
+    /// Logical shape. Dynamic dimensions are left as their symbols
+    pub shape: Vec<BigExpression>,
+}
+
+/// The interface of a graph: the tensors it expects to be set and the tensors it produces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphSignature {
+    pub inputs: Vec<TensorSignature>,
+    pub outputs: Vec<TensorSignature>,
+}
+
+impl std::fmt::Display for GraphSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (kind, tensors) in [("Input", &self.inputs), ("Output", &self.outputs)] {
+            for t in tensors {
+                writeln!(
+                    f,
+                    "{kind} {} ({}): ({})",
+                    t.name,
+                    t.node.index(),
+                    t.shape.iter().map(|d| d.to_string()).join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An op output containing a NaN or infinite value
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonFiniteOutput {
+    pub node: NodeIndex,
+    /// Debug name of the op producing the value
+    pub op: String,
+    /// The shapes of the op's inputs
+    pub input_shapes: Vec<Vec<usize>>,
+    pub output: u8,
+    /// Index of the first non-finite element in the output
+    pub index: usize,
+    pub value: f32,
+}
+
+impl std::fmt::Display for NonFiniteOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Node {} ({}) produced {} at index {} of output {} (inputs: {:?})",
+            self.node.index(),
+            self.op,
+            self.value,
+            self.index,
+            self.output,
+            self.input_shapes
+        )
+    }
+}
+
+/// Structural differences between two graphs, found by [`Graph::structural_diff`]. Nodes are labelled by op and output
+/// shape, and edges by the labels of the nodes they connect along with the output and input they go between
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<String>,
+    pub removed_nodes: Vec<String>,
+    pub added_edges: Vec<String>,
+    pub removed_edges: Vec<String>,
+}
+
+impl GraphDiff {
+    /// Check if the graphs have the same structure
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+}
+
+impl std::fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (sign, kind, labels) in [
+            ("+", "node", &self.added_nodes),
+            ("-", "node", &self.removed_nodes),
+            ("+", "edge", &self.added_edges),
+            ("-", "edge", &self.removed_edges),
+        ] {
+            for label in labels {
+                writeln!(f, "{sign} {kind} {label}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Named inputs passed to [`Graph::set_all_inputs`] that don't line up with the graph's inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputMismatch {
+    /// Inputs of the graph that weren't given a value
+    pub missing: Vec<String>,
+    /// Given names that aren't inputs of the graph
+    pub unexpected: Vec<String>,
+    /// Inputs given the wrong number of elements, as (name, expected, given)
+    pub wrong_length: Vec<(String, usize, usize)>,
+}
+
+impl std::fmt::Display for InputMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Missing inputs: [{}], unexpected inputs: [{}]",
+            self.missing.join(", "),
+            self.unexpected.join(", ")
+        )?;
+        if !self.wrong_length.is_empty() {
+            write!(
+                f,
+                ", wrong lengths: [{}]",
+                self.wrong_length
+                    .iter()
+                    .map(|(name, expected, given)| format!(
+                        "{name} (expected {expected}, got {given})"
+                    ))
+                    .join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InputMismatch {}
+
+/// An op that panicked while running, found by [`Graph::execute_catch_panics`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpPanic {
+    pub node: NodeIndex,
+    /// Debug name of the op that panicked
+    pub op: String,
+    /// The panic message, if it was a string
+    pub message: String,
+}
+
+impl std::fmt::Display for OpPanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Node {} ({}) panicked: {}",
+            self.node.index(),
+            self.op,
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for OpPanic {}
+
+/// A structural problem with a graph, found by [`Graph::validate`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    /// The graph has a cycle running through this node
+    Cycle(NodeIndex),
+    /// An op has a different number of inputs than it expects
+    WrongArity {
+        node: NodeIndex,
+        op: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An input shape of a node that will run uses a dynamic dimension with no value set
+    UnresolvedDim {
+        node: NodeIndex,
+        op: String,
+        input: u8,
+        dim: char,
+    },
+    /// A tensor marked for retrieval no longer exists in the graph
+    DanglingRetrieve(NodeIndex),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Cycle(node) => write!(f, "Cycle through node {}", node.index()),
+            GraphError::WrongArity {
+                node,
+                op,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Node {} ({op}) expects {expected} input{} but has {found}",
+                node.index(),
+                if *expected == 1 { "" } else { "s" },
+            ),
+            GraphError::UnresolvedDim {
+                node,
+                op,
+                input,
+                dim,
+            } => write!(
+                f,
+                "Node {} ({op}) input {input} has unresolved dynamic dimension '{dim}'. Set it with Graph::set_dyn_dim before executing",
+                node.index(),
+            ),
+            GraphError::DanglingRetrieve(node) => write!(
+                f,
+                "Node {} is marked for retrieval but isn't in the graph",
+                node.index()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// A single step of an execution schedule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleStep {
+    /// The node ran at this step
+    pub node: NodeIndex,
+    /// The tensors alive while this node runs, along with their size in elements
+    pub live: Vec<((NodeIndex, u8), usize)>,
+    /// The tensors freed once this node finishes
+    pub freed: Vec<(NodeIndex, u8)>,
+}
+
+impl ScheduleStep {
+    /// Total number of elements alive at this step
+    pub fn live_elements(&self) -> usize {
+        self.live.iter().map(|(_, s)| *s).sum()
+    }
+}
+
+/// The buffer lifetimes of a graph over its execution order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionSchedule {
+    pub steps: Vec<ScheduleStep>,
+    /// The highest number of elements alive at any one step
+    pub peak: usize,
+}
+
+impl std::fmt::Display for ExecutionSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(
+                f,
+                "{i:>4} | {:>4} | live: {} ({} elements) | freed: {:?}",
+                step.node.index(),
+                step.live.len(),
+                step.live_elements(),
+                step.freed
+                    .iter()
+                    .map(|(n, i)| (n.index(), *i))
+                    .collect_vec()
+            )?;
+        }
+        write!(f, "Peak: {} elements", self.peak)
+    }
+}
+
 impl Dependency {
     /// Try to extract dependency data
     pub fn as_data(self) -> Option<(u8, u8, ShapeTracker)> {
@@ -79,6 +413,41 @@ impl Graph {
         Graph::default()
     }
 
+    /// Create a new graph with space preallocated for a number of nodes and edges
+    pub fn with_capacity(nodes: usize, edges: usize) -> Graph {
+        Graph {
+            graph: StableGraph::with_capacity(nodes, edges),
+            ..Default::default()
+        }
+    }
+
+    /// Add a chain of shape-preserving ops (such as unary ops) in one go, each consuming the previous one's output.
+    /// The input is read with the given shape, and all later links are contiguous. Returns the last node in the chain.
+    pub fn add_op_chain(
+        &mut self,
+        input: NodeIndex,
+        shape: ShapeTracker,
+        ops: impl IntoIterator<Item = Box<dyn Operator>>,
+    ) -> NodeIndex {
+        self.linearized_graph = None;
+        let (mut prev, mut shape, contiguous) = (input, shape, shape.contiguous());
+        for op in ops {
+            let node = self.graph.add_node(op);
+            self.graph.add_edge(
+                prev,
+                node,
+                Dependency::Data {
+                    input_order: 0,
+                    output_order: 0,
+                    shape,
+                },
+            );
+            prev = node;
+            shape = contiguous;
+        }
+        prev
+    }
+
     /// Try to remove the tensor data from the graph
     pub fn get_tensor(&mut self, id: NodeIndex, ind: u8) -> Option<Tensor> {
         self.tensors.remove(&(id, ind))
@@ -103,6 +472,21 @@ impl Graph {
         }
     }
 
+    /// Keep an intermediate node's output around after execution, such as for inspecting activations while debugging.
+    /// Marked nodes aren't fused away or freed, so mark them before compiling and pass them in the compile remap.
+    pub fn mark_debug(&mut self, node: NodeIndex) {
+        let shape = self
+            .output_shape(node)
+            .expect("Node has no output to capture");
+        self.keep_tensors(node);
+        self.to_retrieve.insert(node, (0, shape));
+    }
+
+    /// Get the output of a node marked with [mark_debug](Self::mark_debug) from the last execution
+    pub fn get_debug(&self, node: NodeIndex) -> Option<Vec<f32>> {
+        self.get_tensor_ref(node, 0)?.to_host_vec()
+    }
+
     /// Set a tensor's data
     pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
         self.tensors.insert((id, ind), tensor);
@@ -113,24 +497,550 @@ impl Graph {
         self.dyn_map.insert(dimension, val);
     }
 
+    /// Set the seed for all random ops. Each op combines it with its own node index, so adding other nodes doesn't change its values
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
     /// Create a new tensor with shape S
     pub fn tensor<S: Shape>(&mut self) -> GraphTensor<S> {
-        self.named_tensor("Tensor")
+        self.named_tensor(UNNAMED_INPUT)
+    }
+
+    /// Create a new tensor with shape S and set its data in one go
+    pub fn tensor_with<S: ConstShape>(&mut self, data: impl ToData<S, Vec<f32>>) -> GraphTensor<S> {
+        self.tensor().set(data)
+    }
+
+    /// Create a new dynamically shaped tensor and set its data, along with the concrete dimensions of the data
+    pub fn tensor_with_dyn<S: Shape>(
+        &mut self,
+        data: impl Data + Clone,
+        shape: &[usize],
+    ) -> GraphTensor<S> {
+        self.tensor().set_dyn(data, shape)
     }
 
     /// Create a new tensor with shape S and a name. This name will show up on the graph when displayed
     pub fn named_tensor<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
+        self.input_tensor(name, S::to_tracker())
+    }
+
+    /// Create a new tensor with a shape only known at runtime, such as when importing a model. Dimensions can be static
+    /// or symbolic. The tensor is shapeless at the type level, so it's used with the ops taking axes at runtime
+    pub fn tensor_dyn(&mut self, shape: &[Expression]) -> GraphTensor<()> {
+        self.input_tensor(UNNAMED_INPUT, ShapeTracker::new(shape))
+    }
+
+    /// Run a module's forward pass on an input tensor, marking the output for retrieval. The returned handle tracks
+    /// both ends, so the graph can be compiled and ran without keeping hold of node ids.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// # struct Double;
+    /// # impl<S: Shape> Module<GraphTensor<S>> for Double {
+    /// #     type Output = GraphTensor<S>;
+    /// #     fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+    /// #         input * 2.
+    /// #     }
+    /// # }
+    /// let mut cx = Graph::new();
+    /// let model = Graph::from_modules(&Double, cx.tensor::<R1<2>>());
+    /// assert_eq!(model.run(vec![1., 2.]), vec![2., 4.]);
+    /// ```
+    pub fn from_modules<M: Module<GraphTensor<I>, Output = GraphTensor<O>>, I: Shape, O: Shape>(
+        module: &M,
+        input: GraphTensor<I>,
+    ) -> ModuleGraph<I, O> {
+        ModuleGraph {
+            input,
+            output: module.forward(input).retrieve(),
+        }
+    }
+
+    fn input_tensor<S: Shape>(&mut self, name: &str, shape: ShapeTracker) -> GraphTensor<S> {
+        let id = self.graph.add_node(Box::new(Function(
+            format!("{name} Load"),
+            Box::new(|_| panic!("You must set a value for this tensor!")),
+        )));
+        self.inputs.insert(id, (name.to_string(), shape));
         GraphTensor {
-            id: self.graph.add_node(Box::new(Function(
-                format!("{name} Load"),
-                Box::new(|_| panic!("You must set a value for this tensor!")),
-            ))),
+            id,
             graph_ref: self,
-            shape: S::to_tracker(),
+            shape,
             _phantom: Default::default(),
         }
     }
 
+    /// Check if a node is an input created with a name (through `named_tensor` or `named_parameter`)
+    pub fn is_named_input(&self, node: NodeIndex) -> bool {
+        self.inputs
+            .get(&node)
+            .is_some_and(|(name, _)| name != UNNAMED_INPUT)
+    }
+
+    /// Create a new named tensor and register it as a learnable parameter of the model
+    pub fn named_parameter<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
+        let t = self.named_tensor(name);
+        self.params.push(t.id);
+        t
+    }
+
+    /// All learnable parameters registered by modules, in a stable (creation) order
+    pub fn parameters(&self) -> Vec<NodeIndex> {
+        self.params
+            .iter()
+            .copied()
+            .filter(|n| self.graph.contains_node(*n))
+            .collect()
+    }
+
+    /// Deep-copy `root` and everything it depends on into this graph, for instance to repeat a block.
+    /// Nodes in `shared` (such as weights or the block input) are not copied, the copies depend on the originals instead.
+    /// Only primitive ops can be copied, so this should be done before compiling.
+    pub fn clone_subgraph(&mut self, root: NodeIndex, shared: &[NodeIndex]) -> NodeMap {
+        let mut to_copy = vec![];
+        let mut stack = vec![root];
+        let mut seen = FxHashSet::default();
+        while let Some(node) = stack.pop() {
+            if shared.contains(&node) || !seen.insert(node) {
+                continue;
+            }
+            to_copy.push(node);
+            stack.extend(self.graph.neighbors_directed(node, Direction::Incoming));
+        }
+
+        let mut map = NodeMap::default();
+        for &node in &to_copy {
+            let op = self.graph.node_weight(node).unwrap();
+            let new_op = crate::op::clone_primitive(op.as_ref()).unwrap_or_else(|| {
+                panic!(
+                    "Can't clone {op:?} (node {}). Mark it as shared instead",
+                    node.index()
+                )
+            });
+            map.insert(node, self.graph.add_node(new_op));
+        }
+        for &node in &to_copy {
+            for (src, weight) in self
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .map(|e| (e.source(), *e.weight()))
+                .collect_vec()
+            {
+                self.graph
+                    .add_edge(*map.get(&src).unwrap_or(&src), map[&node], weight);
+            }
+        }
+        self.linearized_graph = None;
+        map
+    }
+
+    /// Collapse a region of the graph into a single op, for instance in a custom fusion pass.
+    /// `nodes` are the region's internal nodes, and `inputs` are the external nodes feeding it, in the order the new
+    /// op receives them. Consumers of the region's output are rewired to the new op, whose id is returned.
+    pub fn replace_subgraph(
+        &mut self,
+        nodes: &[NodeIndex],
+        inputs: &[NodeIndex],
+        new_op: Box<dyn Operator>,
+    ) -> NodeIndex {
+        // The output is the single region node consumed outside the region (or kept around)
+        let outputs = nodes
+            .iter()
+            .copied()
+            .filter(|n| {
+                self.no_delete.contains(n)
+                    || self
+                        .graph
+                        .neighbors_directed(*n, Direction::Outgoing)
+                        .any(|d| !nodes.contains(&d))
+            })
+            .collect_vec();
+        assert_eq!(
+            outputs.len(),
+            1,
+            "Region to replace must have exactly one output, found {outputs:?}"
+        );
+        let output = outputs[0];
+
+        let new_node = self.graph.add_node(new_op);
+        for (i, &input) in inputs.iter().enumerate() {
+            let (output_order, shape) = self
+                .graph
+                .edges_directed(input, Direction::Outgoing)
+                .filter(|e| nodes.contains(&e.target()))
+                .find_map(|e| e.weight().as_data().map(|(_, o, s)| (o, s)))
+                .unwrap_or_else(|| {
+                    panic!("Node {} doesn't feed the region to replace", input.index())
+                });
+            self.graph.add_edge(
+                input,
+                new_node,
+                Dependency::Data {
+                    input_order: i as u8,
+                    output_order,
+                    shape,
+                },
+            );
+        }
+        for (weight, target) in self
+            .graph
+            .edges_directed(output, Direction::Outgoing)
+            .filter(|e| !nodes.contains(&e.target()))
+            .map(|e| (*e.weight(), e.target()))
+            .collect_vec()
+        {
+            self.graph.add_edge(new_node, target, weight);
+        }
+        remap(output, new_node, (), self);
+        for node in nodes {
+            self.remove_node(*node);
+        }
+        self.linearized_graph = None;
+        new_node
+    }
+
+    /// Remove a node along with everything the graph tracks about it outside of the node graph: its input and
+    /// parameter registrations, kept and retrieved markers and stored tensors. Removed indices get reused by new nodes,
+    /// so leaving these behind would attach them to an unrelated node.
+    pub fn remove_node(&mut self, node: NodeIndex) -> Option<Box<dyn Operator>> {
+        self.inputs.remove(&node);
+        self.params.retain(|p| *p != node);
+        self.constant_inputs.remove(&node);
+        self.no_delete.remove(&node);
+        self.to_retrieve.remove(&node);
+        self.tensors.retain(|(n, _), _| *n != node);
+        self.graph.remove_node(node)
+    }
+
+    /// Remove a node that passes its single input through unchanged, such as a contiguous op on already contiguous
+    /// data, connecting its input straight to its consumers. Returns the input node.
+    pub fn bypass_node(&mut self, node: NodeIndex) -> NodeIndex {
+        let sources = self.get_sources(node);
+        assert_eq!(
+            sources.len(),
+            1,
+            "Only nodes with a single input can be bypassed, node {} has {}",
+            node.index(),
+            sources.len()
+        );
+        let (input, output_order, _) = sources[0];
+        for (weight, target) in self
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .map(|e| (*e.weight(), e.target()))
+            .collect_vec()
+        {
+            let weight = match weight {
+                Dependency::Data {
+                    input_order, shape, ..
+                } => Dependency::Data {
+                    input_order,
+                    output_order,
+                    shape,
+                },
+                schedule => schedule,
+            };
+            self.graph.add_edge(input, target, weight);
+        }
+        // The input's own retrieval takes precedence, otherwise retrieve the output the bypassed node read
+        let retrieve = self.to_retrieve.remove(&node);
+        remap(node, input, (), self);
+        if let Some((_, shape)) = retrieve {
+            self.to_retrieve
+                .entry(input)
+                .or_insert((output_order, shape));
+        }
+        self.remove_node(node);
+        self.linearized_graph = None;
+        input
+    }
+
+    /// Describe the inputs and retrieved outputs of this graph
+    pub fn signature(&self) -> GraphSignature {
+        let inputs = self
+            .inputs
+            .iter()
+            .filter(|(n, _)| self.graph.contains_node(**n))
+            .map(|(n, (name, st))| TensorSignature {
+                node: *n,
+                name: name.clone(),
+                shape: st.shape(),
+            })
+            .sorted_by_key(|t| t.node)
+            .collect();
+        let outputs = self
+            .to_retrieve
+            .iter()
+            .map(|(n, (_, st))| TensorSignature {
+                node: *n,
+                name: self
+                    .inputs
+                    .get(n)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_else(|| format!("{:?}", self.graph.node_weight(*n).unwrap())),
+                shape: st.shape(),
+            })
+            .sorted_by_key(|t| t.node)
+            .collect();
+        GraphSignature { inputs, outputs }
+    }
+
+    /// Set every named input of the graph from a map of names to data. Unnamed tensors and parameters are skipped.
+    /// Nothing is set unless every input is given a value, every name matches an input, and every value has as many
+    /// elements as its input. Inputs with unknown dynamic dimensions aren't length checked.
+    pub fn set_all_inputs(
+        &mut self,
+        inputs: std::collections::HashMap<String, Vec<f32>>,
+    ) -> Result<(), InputMismatch> {
+        let nodes = self
+            .inputs
+            .iter()
+            .filter(|(n, _)| {
+                self.is_named_input(**n)
+                    && self.graph.contains_node(**n)
+                    && !self.params.contains(n)
+            })
+            .map(|(n, (name, shape))| (*n, name.clone(), *shape))
+            .collect::<Vec<_>>();
+        let missing = nodes
+            .iter()
+            .map(|(_, name, _)| name)
+            .filter(|name| !inputs.contains_key(*name))
+            .unique()
+            .sorted()
+            .cloned()
+            .collect::<Vec<_>>();
+        let unexpected = inputs
+            .keys()
+            .filter(|k| !nodes.iter().any(|(_, name, _)| name == *k))
+            .sorted()
+            .cloned()
+            .collect::<Vec<_>>();
+        let wrong_length = nodes
+            .iter()
+            .filter_map(|(_, name, shape)| {
+                let given = inputs.get(name)?.len();
+                let expected = shape.n_elements().exec(&self.dyn_map)?;
+                (given != expected).then(|| (name.clone(), expected, given))
+            })
+            .unique()
+            .sorted()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() || !unexpected.is_empty() || !wrong_length.is_empty() {
+            return Err(InputMismatch {
+                missing,
+                unexpected,
+                wrong_length,
+            });
+        }
+        for (node, name, _) in nodes {
+            let data = inputs[&name].clone();
+            self.get_op_mut::<Function>(node).1 =
+                Box::new(move |_| vec![Tensor::new(data.to_owned())]);
+        }
+        Ok(())
+    }
+
+    /// Number of input tensors in this graph
+    pub fn input_count(&self) -> usize {
+        self.inputs
+            .keys()
+            .filter(|n| self.graph.contains_node(**n))
+            .count()
+    }
+
+    /// Number of tensors marked for retrieval from this graph
+    pub fn output_count(&self) -> usize {
+        self.to_retrieve.len()
+    }
+
+    /// Every tensor marked for retrieval along with its shape, sorted by node. Dynamic dimensions are resolved
+    /// when all of the shape's dimensions are set, and left symbolic otherwise
+    pub fn outputs(&self) -> Vec<(NodeIndex, ShapeTracker)> {
+        self.to_retrieve
+            .iter()
+            .map(|(node, (_, st))| {
+                let mut st = *st;
+                let bound = st
+                    .dims
+                    .iter()
+                    .chain(
+                        st.padding
+                            .iter()
+                            .chain(st.mask.iter())
+                            .flat_map(|(a, b)| [a, b]),
+                    )
+                    .flat_map(|e| e.to_symbols())
+                    .all(|c| self.dyn_map.contains_key(&c));
+                if bound {
+                    st.resolve_global_dyn_dims(&self.dyn_map);
+                }
+                (*node, st)
+            })
+            .sorted_by_key(|(node, _)| *node)
+            .collect()
+    }
+
+    /// Count the nodes in the graph by their op's [name](Operator::name)
+    pub fn op_histogram(&self) -> std::collections::HashMap<&'static str, usize> {
+        self.graph.node_weights().map(|op| op.name()).counts()
+    }
+
+    /// The shape of a node's output, as seen by the first consumer (or as retrieved for outputs)
+    fn output_shape(&self, node: NodeIndex) -> Option<ShapeTracker> {
+        self.to_retrieve.get(&node).map(|(_, sh)| *sh).or_else(|| {
+            self.graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data())
+                .min_by_key(|(_, o, _)| *o)
+                .map(|(_, _, sh)| sh)
+        })
+    }
+
+    /// Compare the structure of two graphs, such as before and after a compiler pass. Nodes are identified by their
+    /// op and output shape, and edges by the nodes they connect, so node indexes don't need to line up.
+    /// Changes are relative to `self`: nodes only in `other` are added, nodes only in `self` are removed.
+    pub fn structural_diff(&self, other: &Graph) -> GraphDiff {
+        fn node_label(graph: &Graph, node: NodeIndex) -> String {
+            let op = format!("{:?}", graph.graph.node_weight(node).unwrap());
+            match graph.output_shape(node) {
+                Some(sh) => format!(
+                    "{op} [{}]",
+                    sh.shape().iter().map(|d| d.to_string()).join(", ")
+                ),
+                None => op,
+            }
+        }
+        fn labels(graph: &Graph) -> (Vec<String>, Vec<String>) {
+            let nodes = graph
+                .graph
+                .node_indices()
+                .map(|n| node_label(graph, n))
+                .collect();
+            let edges = graph
+                .graph
+                .edge_references()
+                .map(|e| {
+                    let (src, dst) = (node_label(graph, e.source()), node_label(graph, e.target()));
+                    match e.weight() {
+                        Dependency::Data {
+                            input_order,
+                            output_order,
+                            ..
+                        } => format!("{src} ({output_order}) -> {dst} ({input_order})"),
+                        Dependency::Schedule => format!("{src} -> {dst} (schedule)"),
+                    }
+                })
+                .collect();
+            (nodes, edges)
+        }
+        // Multiset differences, so duplicated ops are counted
+        fn difference(a: &[String], b: &[String]) -> Vec<String> {
+            let mut counts = FxHashMap::<&String, usize>::default();
+            for l in b {
+                *counts.entry(l).or_default() += 1;
+            }
+            a.iter()
+                .filter(|l| match counts.get_mut(l) {
+                    Some(c) if *c > 0 => {
+                        *c -= 1;
+                        false
+                    }
+                    _ => true,
+                })
+                .cloned()
+                .sorted()
+                .collect()
+        }
+        let (self_nodes, self_edges) = labels(self);
+        let (other_nodes, other_edges) = labels(other);
+        GraphDiff {
+            added_nodes: difference(&other_nodes, &self_nodes),
+            removed_nodes: difference(&self_nodes, &other_nodes),
+            added_edges: difference(&other_edges, &self_edges),
+            removed_edges: difference(&self_edges, &other_edges),
+        }
+    }
+
+    /// Export the graph topology as JSON for external tooling. The schema is
+    /// `{"nodes": [{"id", "op", "shape"}], "edges": [{"src", "dst", "input", "output", "shape"} | {"src", "dst", "schedule": true}]}`,
+    /// with nodes sorted by id and edges sorted by destination and input. Shapes are lists of dimension expressions
+    /// as strings, and a node's shape is its output as seen by the first consumer (or `null` if nothing consumes it).
+    pub fn to_json(&self) -> String {
+        fn json_str(s: &str) -> String {
+            let mut out = String::with_capacity(s.len() + 2);
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+            out
+        }
+        fn json_shape(shape: &ShapeTracker) -> String {
+            format!(
+                "[{}]",
+                shape
+                    .shape()
+                    .iter()
+                    .map(|d| json_str(&d.to_string()))
+                    .join(",")
+            )
+        }
+
+        let nodes = self
+            .graph
+            .node_indices()
+            .sorted()
+            .map(|n| {
+                let shape = self
+                    .output_shape(n)
+                    .map(|sh| json_shape(&sh))
+                    .unwrap_or_else(|| "null".to_string());
+                format!(
+                    "{{\"id\":{},\"op\":{},\"shape\":{shape}}}",
+                    n.index(),
+                    json_str(&format!("{:?}", self.graph.node_weight(n).unwrap()))
+                )
+            })
+            .join(",");
+        let edges = self
+            .graph
+            .edge_references()
+            .sorted_by_key(|e| {
+                (
+                    e.target(),
+                    e.weight().as_data().map(|(i, _, _)| i),
+                    e.source(),
+                )
+            })
+            .map(|e| match e.weight() {
+                Dependency::Data {
+                    input_order,
+                    output_order,
+                    shape,
+                } => format!(
+                    "{{\"src\":{},\"dst\":{},\"input\":{input_order},\"output\":{output_order},\"shape\":{}}}",
+                    e.source().index(),
+                    e.target().index(),
+                    json_shape(shape)
+                ),
+                Dependency::Schedule => format!(
+                    "{{\"src\":{},\"dst\":{},\"schedule\":true}}",
+                    e.source().index(),
+                    e.target().index()
+                ),
+            })
+            .join(",");
+        format!("{{\"nodes\":[{nodes}],\"edges\":[{edges}]}}")
+    }
+
     /// Compile the graph using the given compiler
     pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) -> C::Output {
         let output = compiler.compile(self, remap);
@@ -139,6 +1049,16 @@ impl Graph {
         output
     }
 
+    /// Replace layer norms built from primitive ops with single [FusedLayerNorm](op::FusedLayerNorm) ops. See [FuseLayerNorm]
+    pub fn fuse_layernorm<T: ToIdsMut>(&mut self, remap: T) {
+        self.compile(FuseLayerNorm, remap)
+    }
+
+    /// Insert casts so ops mixing dtypes (such as bf16 and f32) see a common dtype. See [InsertCastOps]
+    pub fn insert_cast_ops(&mut self) {
+        self.compile(InsertCastOps, ())
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
         self.linearized_graph = Some(
@@ -189,14 +1109,164 @@ impl Graph {
         self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
     }
 
+    /// Check that every dynamic dimension used by the input shapes of nodes that will run is set in the dyn map,
+    /// returning the first offending node otherwise. Ran before every execution.
+    pub fn check_unresolved_dims(&self) -> Result<(), GraphError> {
+        match self.unresolved_dims().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`Graph::check_unresolved_dims`], but panics with the error
+    pub fn assert_no_unresolved_dims(&self) {
+        if let Err(err) = self.check_unresolved_dims() {
+            panic!("{err}");
+        }
+    }
+
+    fn unresolved_dims(&self) -> Vec<GraphError> {
+        let mut errors = vec![];
+        for edge in (&self.graph).edge_references() {
+            let Some((input, _, st)) = edge.weight().as_data() else {
+                continue;
+            };
+            if self.tensors.contains_key(&(edge.target(), 0)) {
+                // Won't be ran
+                continue;
+            }
+            let symbols = st
+                .dims
+                .into_iter()
+                .chain(st.padding.into_iter().flat_map(|(a, b)| [a, b]))
+                .chain(st.mask.into_iter().flat_map(|(a, b)| [a, b]))
+                .flat_map(|e| e.to_symbols());
+            for c in symbols {
+                if !self.dyn_map.contains_key(&c) {
+                    errors.push(GraphError::UnresolvedDim {
+                        node: edge.target(),
+                        op: format!("{:?}", self.graph.node_weight(edge.target()).unwrap()),
+                        input,
+                        dim: c,
+                    });
+                }
+            }
+        }
+        errors
+    }
+
+    /// Check that every op with a fixed input count has exactly that many incoming data edges
+    pub fn check_input_arity(&self) -> Result<(), String> {
+        match self.arity_errors().into_iter().next() {
+            Some(err) => Err(err.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn arity_errors(&self) -> Vec<GraphError> {
+        let mut errors = vec![];
+        for node in self.graph.node_indices() {
+            let op = self.graph.node_weight(node).unwrap();
+            let Some(expected) = op.expected_inputs() else {
+                continue;
+            };
+            let found = self
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .count();
+            if found != expected {
+                errors.push(GraphError::WrongArity {
+                    node,
+                    op: format!("{op:?}"),
+                    expected,
+                    found,
+                });
+            }
+        }
+        errors
+    }
+
+    /// Check the graph for every structural problem at once: cycles, ops with the wrong number of inputs,
+    /// unset dynamic dimensions on nodes that will run, and retrieved tensors missing from the graph
+    pub fn validate(&self) -> Vec<GraphError> {
+        let mut errors = vec![];
+        if let Err(cycle) = petgraph::algo::toposort(&self.graph, None) {
+            errors.push(GraphError::Cycle(cycle.node_id()));
+        }
+        errors.extend(self.arity_errors());
+        errors.extend(self.unresolved_dims());
+        errors.extend(
+            self.to_retrieve
+                .keys()
+                .filter(|n| !self.graph.contains_node(**n))
+                .sorted()
+                .map(|n| GraphError::DanglingRetrieve(*n)),
+        );
+        errors
+    }
+
+    /// Check the graph has no cycles. If it does, the nodes of one cycle are returned in edge order,
+    /// starting from the node the cycle was detected at (the last node has an edge back to the first).
+    pub fn assert_acyclic(&self) -> Result<(), Vec<NodeIndex>> {
+        let Err(cycle) = petgraph::algo::toposort(&self.graph, None) else {
+            return Ok(());
+        };
+        // Breadth first search from the detected node back to itself gives the shortest cycle through it
+        let start = cycle.node_id();
+        let mut parents = FxHashMap::default();
+        let mut queue = std::collections::VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            for next in self.graph.neighbors_directed(node, Direction::Outgoing) {
+                if next == start {
+                    let mut path = vec![node];
+                    while let Some(&parent) = parents.get(path.last().unwrap()) {
+                        path.push(parent);
+                    }
+                    path.reverse();
+                    return Err(path);
+                }
+                if let std::collections::hash_map::Entry::Vacant(e) = parents.entry(next) {
+                    e.insert(node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        unreachable!("Toposort reported a cycle through a node that can't reach itself")
+    }
+
     /// Execute the graph.
     pub fn execute(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear
+        if let Err(err) = self.try_execute() {
+            panic!("{err}");
+        }
+    }
+
+    /// Execute the graph, returning an error instead of running anything if a dynamic dimension it needs isn't set
+    pub fn try_execute(&mut self) -> Result<(), GraphError> {
+        self.check_unresolved_dims()?;
         if self.linearized_graph.is_none() {
             self.toposort();
         }
+        let Ok(()) =
+            self.run_ops(|_, op, srcs| Ok::<_, std::convert::Infallible>(op.process(srcs)));
+        Ok(())
+    }
+
+    /// Run every op in order, handing its inputs to `run` and storing the outputs it returns.
+    /// Stops at the first error, leaving the outputs of later ops unset.
+    fn run_ops<E>(
+        &mut self,
+        mut run: impl FnMut(
+            NodeIndex,
+            &mut Box<dyn Operator>,
+            Vec<(InputTensor, ShapeTracker)>,
+        ) -> Result<Vec<Tensor>, E>,
+    ) -> Result<(), E> {
+        // Track the number of views pointing to each tensor so we know when to clear
         let mut consumers = self.consumers_map.as_ref().unwrap().clone();
         let mut dim_stack = Vec::new();
+        let mut result = Ok(());
 
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
             if self.tensors.contains_key(&(*node, 0)) {
@@ -212,7 +1282,13 @@ impl Graph {
             }
 
             // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            let tensors = match run(*node, self.graph.node_weight_mut(*node).unwrap(), srcs) {
+                Ok(tensors) => tensors,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
@@ -223,10 +1299,54 @@ impl Graph {
             }
         }
         self.reset();
+        result
+    }
+
+    /// Execute the graph without waiting for results to be copied back to the host.
+    /// Ops at the end of the graph that support it (such as copies from a device) are told to run asynchronously
+    /// through the "async" custom key. Await the returned handle before reading the results.
+    pub fn execute_async(&mut self) -> PendingExecution<'_> {
+        self.set_async(true);
+        self.execute();
+        self.set_async(false);
+        PendingExecution { graph: self }
+    }
+
+    fn set_async(&mut self, enabled: bool) {
+        // Only outputs can be left unfinished, anything consumed by another op has to be ready when it runs
+        for node in self
+            .graph
+            .node_indices()
+            .filter(|n| {
+                self.graph
+                    .edges_directed(*n, Direction::Outgoing)
+                    .next()
+                    .is_none()
+            })
+            .collect::<Vec<_>>()
+        {
+            self.graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("async", Box::new(enabled));
+        }
+    }
+
+    /// Prepare every op to run without executing anything, so the first real execution doesn't pay one-time setup costs.
+    /// Ops that would otherwise build state lazily (such as CUDA kernels specialized to the current dyn dims, which
+    /// need a fallback once those change) do it when sent the "warmup" custom key.
+    pub fn warmup(&mut self) {
+        for node in self.graph.node_indices().collect::<Vec<_>>() {
+            self.graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("warmup", Box::new(()));
+        }
     }
 
     /// Execute the graph without deleting intermediate tensors
     pub fn execute_no_delete(&mut self) {
+        self.assert_no_unresolved_dims();
         // Track the number of views pointing to each tensor so we know when to clear;
         if self.linearized_graph.is_none() {
             self.toposort();
@@ -259,6 +1379,91 @@ impl Graph {
         }
     }
 
+    /// Execute the graph, checking each op's outputs for NaN or infinite values and stopping at the first op producing
+    /// one. Outputs living on a device are copied back to check, so this is slow.
+    pub fn execute_check_finite(&mut self) -> Result<(), NonFiniteOutput> {
+        self.assert_no_unresolved_dims();
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
+        let mut dim_stack = Vec::new();
+        let mut result = Ok(());
+
+        'nodes: for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+            if self.tensors.contains_key(&(*node, 0)) {
+                continue;
+            }
+
+            let mut srcs =
+                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+
+            // Substitute in the dyn dims
+            for (_, st) in srcs.iter_mut() {
+                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+            }
+            let input_shapes = srcs
+                .iter()
+                .map(|(_, st)| st.shape_usize())
+                .collect::<Vec<_>>();
+
+            // Execute
+            let op = self.graph.node_weight_mut(*node).unwrap();
+            let tensors = op.process(srcs);
+            for (i, tensor) in tensors.iter().enumerate() {
+                let Some(data) = tensor.to_host_vec() else {
+                    continue;
+                };
+                if let Some((index, value)) =
+                    data.into_iter().enumerate().find(|(_, v)| !v.is_finite())
+                {
+                    result = Err(NonFiniteOutput {
+                        node: *node,
+                        op: format!("{op:?}"),
+                        input_shapes,
+                        output: i as u8,
+                        index,
+                        value,
+                    });
+                    break 'nodes;
+                }
+            }
+            for (i, tensor) in tensors.into_iter().enumerate() {
+                self.tensors.insert((*node, i as u8), tensor);
+            }
+
+            // Bookkeep remaining consumers
+            for (id, ind, _) in src_ids {
+                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
+            }
+        }
+        self.reset();
+        result
+    }
+
+    /// Execute the graph, catching a panic in any op and returning it as an error naming the node instead of unwinding
+    /// through the caller.
+    /// Outputs of ops after the failing one are left unset, and the failing op may be left in a bad state.
+    pub fn execute_catch_panics(&mut self) -> Result<(), OpPanic> {
+        self.assert_no_unresolved_dims();
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        self.run_ops(|node, op, srcs| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| op.process(srcs))).map_err(
+                |payload| OpPanic {
+                    node,
+                    op: format!("{op:?}"),
+                    message: payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_default(),
+                },
+            )
+        })
+    }
+
     /// Execute the graph with debug prints
     pub fn execute_debug(&mut self) {
         fn format_duration(duration: &Duration) -> String {
@@ -270,6 +1475,7 @@ impl Graph {
                 format!("{}µs", duration.as_micros())
             }
         }
+        self.assert_no_unresolved_dims();
         // Track the number of views pointing to each tensor so we know when to clear
         if self.linearized_graph.is_none() {
             self.toposort();
@@ -364,6 +1570,63 @@ impl Graph {
         println!("Total: {}", format_duration(&start.elapsed()).bold());
         self.reset();
     }
+
+    /// Walk the execution order and track which tensors are alive at each step, mirroring the freeing done in `execute`.
+    pub fn visualize_execution_schedule(&mut self) -> ExecutionSchedule {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
+        // Output sizes are taken from the largest physical view of each output
+        let mut sizes: FxHashMap<(NodeIndex, u8), usize> = FxHashMap::default();
+        for edge in (&self.graph).edge_references() {
+            if let Some((_, out, mut st)) = edge.weight().as_data() {
+                st.resolve_global_dyn_dims(&self.dyn_map);
+                let n = st.n_physical_elements().to_usize().unwrap_or_default();
+                let size = sizes.entry((edge.source(), out)).or_default();
+                *size = (*size).max(n);
+            }
+        }
+        for (node, (out, st)) in &self.to_retrieve {
+            let mut st = *st;
+            st.resolve_global_dyn_dims(&self.dyn_map);
+            sizes
+                .entry((*node, *out))
+                .or_insert(st.n_physical_elements().to_usize().unwrap_or_default());
+        }
+
+        let mut outputs = FxHashMap::<_, Vec<_>>::default();
+        for (t, size) in sizes {
+            outputs.entry(t.0).or_default().push((t, size));
+        }
+
+        let mut live: Vec<((NodeIndex, u8), usize)> = vec![];
+        let mut steps = vec![];
+        let mut peak = 0;
+        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+            if let Some(o) = outputs.get(node) {
+                live.extend(o.iter().copied());
+            }
+            live.sort_by_key(|((n, i), _)| (n.index(), *i));
+            let mut freed = vec![];
+            for (id, ind, _) in src_ids {
+                let c = consumers.get_mut(&(*id, *ind)).unwrap();
+                *c -= 1;
+                if *c == 0 && !self.no_delete.contains(id) {
+                    freed.push((*id, *ind));
+                }
+            }
+            let step = ScheduleStep {
+                node: *node,
+                live: live.clone(),
+                freed,
+            };
+            peak = peak.max(step.live_elements());
+            live.retain(|(t, _)| !step.freed.contains(t));
+            steps.push(step);
+        }
+        ExecutionSchedule { steps, peak }
+    }
 }
 
 impl Deref for Graph {
@@ -40,6 +40,13 @@ impl<S: Shape> GraphTensor<S> {
         self
     }
 
+    /// Mark this input as holding fixed data that never changes between runs, so
+    /// [DedupeConstantInputs](crate::generic_compiler::DedupeConstantInputs) can merge it with identical inputs
+    pub fn mark_constant(self) -> Self {
+        self.graph().constant_inputs.insert(self.id);
+        self
+    }
+
     /// Mark this tensor to be retrieved later
     pub fn retrieve(self) -> Self {
         self.keep();
@@ -67,13 +74,10 @@ impl<S: Shape> GraphTensor<S> {
     ///     .set_dyn(vec![1., 2., 3., 4.], &[2, 2]);
     /// ```
     pub fn set_dyn<T: Data + Clone>(self, data: T, shape: &[usize]) -> Self {
-        // Report dyn dim values to graph dyn map
-        assert_eq!(
-            S::realized_shape().len(),
-            shape.len(),
-            "Number of dimensions don't match!"
-        );
-        for (d, s) in S::realized_shape().iter().zip(shape.iter()) {
+        // Report dyn dim values to graph dyn map. The tracker is used rather than S so shapeless tensors work too
+        let dims = self.shape.shape();
+        assert_eq!(dims.len(), shape.len(), "Number of dimensions don't match!");
+        for (d, s) in dims.iter().zip(shape.iter()) {
             if let Some(c) = d.to_symbols().pop() {
                 self.graph().dyn_map.insert(c, *s);
             }
@@ -93,13 +97,36 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
-    /// Get the contiguous data of the tensor
+    /// Get the contiguous data of the tensor, panicking if it isn't available
     pub fn data(&self) -> Vec<f32> {
-        let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
-        let orig_data = tensor.downcast_ref::<Vec<f32>>().unwrap();
+        self.try_data().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Get the contiguous data of the tensor, or why it isn't available
+    pub fn try_data(&self) -> Result<Vec<f32>, RetrieveError> {
+        let graph = self.graph();
+        let Some(tensor) = graph.get_tensor_ref(self.id, 0) else {
+            return Err(
+                if graph.to_retrieve.contains_key(&self.id) || graph.no_delete.contains(&self.id) {
+                    RetrieveError::NotExecuted(self.id)
+                } else {
+                    RetrieveError::NotMarked(self.id)
+                },
+            );
+        };
+        let host_data;
+        let orig_data = match tensor.downcast_ref::<Vec<f32>>() {
+            Some(d) => d,
+            None => {
+                host_data = tensor
+                    .to_host_vec()
+                    .expect("Tensor data can't be read back on the host");
+                &host_data
+            }
+        };
         let mut st = self.shape;
         if !st.is_reshaped() {
-            return orig_data.clone();
+            return Ok(orig_data.clone());
         }
         st.resolve_global_dyn_dims(&self.graph().dyn_map);
         let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
@@ -110,10 +137,38 @@ impl<S: Shape> GraphTensor<S> {
                 *r = orig_data[ind.exec_single_var(i)];
             }
         }
-        data
+        Ok(data)
     }
 }
 
+/// Why a tensor's data couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetrieveError {
+    /// The tensor was never marked with `retrieve()` or `keep()`, so its data was deleted during execution
+    NotMarked(NodeIndex),
+    /// The tensor is marked, but the graph hasn't been executed since (or its data was dropped)
+    NotExecuted(NodeIndex),
+}
+
+impl std::fmt::Display for RetrieveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetrieveError::NotMarked(n) => write!(
+                f,
+                "Tensor {} has no data, mark it with .retrieve() before executing the graph",
+                n.index()
+            ),
+            RetrieveError::NotExecuted(n) => write!(
+                f,
+                "Tensor {} has no data yet, execute the graph before reading it",
+                n.index()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RetrieveError {}
+
 impl<S: ConstShape> GraphTensor<S> {
     /// Set the value of the tensor matching the constant shape
     pub fn set<T: Data + Clone, D: ToData<S, T>>(self, data: D) -> Self {
@@ -123,10 +178,41 @@ impl<S: ConstShape> GraphTensor<S> {
         self
     }
 
+    /// Set the value of the tensor from an iterator, collecting it straight into the tensor's buffer
+    pub fn set_from_iter(self, data: impl IntoIterator<Item = f32>) -> Self {
+        let data = data.into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            data.len(),
+            S::NUMEL,
+            "Expected {} elements to set the tensor with",
+            S::NUMEL
+        );
+        self.set(data)
+    }
+
+    /// Set the value of the tensor from a slice living as long as the graph, such as weights in a memory mapped file.
+    /// The slice is copied into the graph once here and kept there, so running the graph doesn't copy it again.
+    /// It's only copied from the slice again if the tensor's data is dropped.
+    pub fn set_slice(self, data: &'static [f32]) -> Self {
+        assert_eq!(
+            data.len(),
+            S::NUMEL,
+            "Expected {} elements to set the tensor with",
+            S::NUMEL
+        );
+        self.graph().get_op_mut::<Function>(self.id).1 =
+            Box::new(move |_| vec![Tensor::new(data.to_vec())]);
+        self.graph()
+            .set_tensor(self.id, 0, Tensor::new(data.to_vec()));
+        self.keep()
+    }
+
     /// Set the tensor with a generating closure to be ran at runtime
     pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + 'static) -> Self {
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(loader())]);
+        // The loader may give different data each run
+        self.graph().constant_inputs.remove(&self.id);
         self
     }
 }
@@ -295,7 +295,8 @@ impl<S: Shape> GraphTensor<S> {
 
     /// Clip a tensor in a range
     pub fn clip(self, min: f32, max: f32) -> GraphTensor<S> {
-        self.min_f32(min).max_f32(max)
+        assert!(min <= max, "Clip range {min}..{max} is empty");
+        self.max_f32(min).min_f32(max)
     }
 }
 
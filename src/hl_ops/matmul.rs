@@ -100,6 +100,55 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension, E: Dimension, F: Di
     }
 }
 
+/// Matrix multiply against the transpose of the right hand side, `a @ b.T`, contracting the last
+/// dimension of both tensors directly rather than permuting the right hand side first
+pub trait MatmulTransposed<S: Shape> {
+    type Output;
+    fn matmul_transposed(self, rhs: GraphTensor<S>) -> Self::Output;
+}
+
+// ABxCB -> AC
+impl<A: Dimension, B: Dimension, C: Dimension> MatmulTransposed<(C, B)> for GraphTensor<(A, B)> {
+    type Output = GraphTensor<(A, C)>;
+    fn matmul_transposed(self, rhs: GraphTensor<(C, B)>) -> Self::Output {
+        let mul = self.expand::<(A, C, B), _>() * rhs.expand::<(A, C, B), _>();
+        mul.sum_reduce::<_, Axis<2>>()
+    }
+}
+
+// ABCxDC -> ABD
+impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension> MatmulTransposed<(D, C)>
+    for GraphTensor<(A, B, C)>
+{
+    type Output = GraphTensor<(A, B, D)>;
+    fn matmul_transposed(self, rhs: GraphTensor<(D, C)>) -> Self::Output {
+        let mul = self.expand::<(A, B, D, C), _>() * rhs.expand::<(A, B, D, C), _>();
+        mul.sum_reduce::<_, Axis<3>>()
+    }
+}
+
+// ABCxADC -> ABD
+impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension> MatmulTransposed<(A, D, C)>
+    for GraphTensor<(A, B, C)>
+{
+    type Output = GraphTensor<(A, B, D)>;
+    fn matmul_transposed(self, rhs: GraphTensor<(A, D, C)>) -> Self::Output {
+        let mul = self.expand::<(A, B, D, C), _>() * rhs.expand::<(A, B, D, C), _>();
+        mul.sum_reduce::<_, Axis<3>>()
+    }
+}
+
+// ABCDxABED -> ABCE
+impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension, E: Dimension>
+    MatmulTransposed<(A, B, E, D)> for GraphTensor<(A, B, C, D)>
+{
+    type Output = GraphTensor<(A, B, C, E)>;
+    fn matmul_transposed(self, rhs: GraphTensor<(A, B, E, D)>) -> Self::Output {
+        let mul = self.expand::<(A, B, C, E, D), _>() * rhs.expand::<(A, B, C, E, D), _>();
+        mul.sum_reduce::<_, Axis<4>>()
+    }
+}
+
 impl<A: Dimension> GraphTensor<(A,)> {
     /// Simple dot product of two vectors
     pub fn dot(self, rhs: GraphTensor<(A,)>) -> GraphTensor<R0> {
@@ -214,4 +263,23 @@ mod tests {
 
         assert_close(&c.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_matmul_transposed() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<4, 3>>().set(random_vec(12));
+        let c = a.matmul_transposed(b).retrieve();
+        let d = a.matmul(b.permute()).retrieve();
+        // Batched, as in attention scores
+        let q = cx.tensor::<R3<2, 3, 4>>().set(random_vec(24));
+        let k = cx.tensor::<R3<2, 5, 4>>().set(random_vec(40));
+        let e = q.matmul_transposed(k).retrieve();
+        let f = q.matmul(k.permute::<_, LAxes3<0, 2, 1>>()).retrieve();
+
+        cx.execute();
+
+        assert_close(&c.data(), &d.data());
+        assert_close(&e.data(), &f.data());
+    }
 }
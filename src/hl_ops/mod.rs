@@ -3,6 +3,8 @@ pub mod binary;
 pub mod matmul;
 pub use matmul::*;
 pub mod movement;
+pub use movement::PermuteError;
 pub mod other;
+pub use other::Bf16Tensor;
 pub mod reduction;
 pub mod unary;
@@ -1,4 +1,32 @@
 use crate::{op, prelude::*};
+use rustc_hash::FxHashMap;
+
+/// Why a list of axes can't be used to permute a tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermuteError {
+    /// There isn't exactly one axis per dimension
+    WrongRank { rank: usize, found: usize },
+    /// An axis doesn't exist in the tensor
+    OutOfRange { axis: isize, rank: usize },
+    /// An axis is used more than once
+    Repeated(isize),
+}
+
+impl std::fmt::Display for PermuteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermuteError::WrongRank { rank, found } => {
+                write!(f, "Permute needs {rank} axes, got {found}")
+            }
+            PermuteError::OutOfRange { axis, rank } => {
+                write!(f, "Axis {axis} is out of range for a tensor of rank {rank}")
+            }
+            PermuteError::Repeated(axis) => write!(f, "Permute axis {axis} is used more than once"),
+        }
+    }
+}
+
+impl std::error::Error for PermuteError {}
 
 impl<S: Shape> GraphTensor<S> {
     pub fn permute<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
@@ -10,6 +38,41 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Permute with axes chosen at runtime. Negative axes count back from the last axis.
+    /// Panics if the axes aren't a permutation of the tensor's axes, see [`GraphTensor::try_permute_axes`]
+    pub fn permute_axes<Dst: Shape>(self, axes: &[isize]) -> GraphTensor<Dst> {
+        self.try_permute_axes(axes)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Permute with axes chosen at runtime, or say why the axes aren't a permutation of the tensor's axes
+    pub fn try_permute_axes<Dst: Shape>(
+        mut self,
+        axes: &[isize],
+    ) -> Result<GraphTensor<Dst>, PermuteError> {
+        let rank = self.shape.len();
+        if axes.len() != rank {
+            return Err(PermuteError::WrongRank {
+                rank,
+                found: axes.len(),
+            });
+        }
+        let mut seen = vec![false; rank];
+        let mut normalized = Vec::with_capacity(rank);
+        for &axis in axes {
+            let a = if axis < 0 { axis + rank as isize } else { axis };
+            if a < 0 || a >= rank as isize {
+                return Err(PermuteError::OutOfRange { axis, rank });
+            }
+            if std::mem::replace(&mut seen[a as usize], true) {
+                return Err(PermuteError::Repeated(axis));
+            }
+            normalized.push(a as usize);
+        }
+        self.shape.permute(&normalized);
+        Ok(GraphTensor::from_id(self.id, self.shape, self.graph_ref))
+    }
+
     pub fn expand<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
     where
         S: BroadcastShapeTo<Dst, Ax>,
@@ -34,6 +97,21 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Insert a new axis at `axis` that repeats the tensor `times` times. No data is copied, reads along the new axis all hit the same elements
+    pub fn expand_dim<Dst: Shape>(
+        mut self,
+        axis: usize,
+        times: impl Into<Expression>,
+    ) -> GraphTensor<Dst> {
+        assert!(
+            axis <= self.shape.len(),
+            "Can't insert axis {axis} into a tensor of rank {}",
+            self.shape.len()
+        );
+        self.shape.expand(axis, times);
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
     pub fn reshape<N: Shape>(mut self) -> GraphTensor<N> {
         // Insert contiguous call
         self = self.contiguous();
@@ -44,6 +122,86 @@ impl<S: Shape> GraphTensor<S> {
         )
     }
 
+    /// Collapse the axes `start_axis..=end_axis` into a single axis. Negative axes count back from the last axis.
+    /// No data is moved unless the tensor has been permuted, sliced or padded.
+    pub fn flatten<Dst: Shape>(self, start_axis: isize, end_axis: isize) -> GraphTensor<Dst> {
+        let rank = self.shape.len();
+        let (start, end) = (
+            normalize_axis(start_axis, rank),
+            normalize_axis(end_axis, rank),
+        );
+        assert!(
+            start <= end,
+            "Can't flatten axes {start}..={end}, start comes after end"
+        );
+        let src = self.contiguous();
+        let dims = src
+            .shape
+            .indexes
+            .iter()
+            .map(|i| src.shape.dims[*i].expr())
+            .collect::<Vec<_>>();
+        let mut new_dims = dims[..start].to_vec();
+        new_dims.push(
+            dims[start..=end]
+                .iter()
+                .fold(Expression::from(1), |acc, d| acc * *d),
+        );
+        new_dims.extend_from_slice(&dims[end + 1..]);
+        GraphTensor::from_id(src.id, ShapeTracker::new(&new_dims), src.graph_ref)
+    }
+
+    /// Repeat each element along `axis` `times` times in a row, so `[a, b]` becomes `[a, a, b, b]`.
+    /// Unlike expanding, the repeats are realized into a new contiguous tensor
+    pub fn repeat_interleave<Dst: Shape>(
+        self,
+        axis: usize,
+        times: impl Into<Expression>,
+    ) -> GraphTensor<Dst> {
+        self.expand_dim::<()>(axis + 1, times)
+            .flatten(axis as isize, axis as isize + 1)
+    }
+
+    /// Split `axis` into several axes of the given sizes. Negative axes count back from the last axis.
+    /// No data is moved unless the tensor has been permuted, sliced or padded.
+    pub fn unflatten<Dst: Shape>(
+        self,
+        axis: isize,
+        sizes: &[impl Into<Expression> + Copy],
+    ) -> GraphTensor<Dst> {
+        let axis = normalize_axis(axis, self.shape.len());
+        let src = self.contiguous();
+        let dims = src
+            .shape
+            .indexes
+            .iter()
+            .map(|i| src.shape.dims[*i].expr())
+            .collect::<Vec<_>>();
+        let sizes = sizes.iter().map(|s| (*s).into()).collect::<Vec<_>>();
+        if let (Some(dim), Some(split)) = (
+            dims[axis].to_usize(),
+            sizes
+                .iter()
+                .map(|s| s.to_usize())
+                .product::<Option<usize>>(),
+        ) {
+            assert_eq!(
+                dim, split,
+                "Can't unflatten axis {axis} of size {dim} into {sizes:?}"
+            );
+        }
+        let mut new_dims = dims[..axis].to_vec();
+        new_dims.extend(sizes);
+        new_dims.extend_from_slice(&dims[axis + 1..]);
+        GraphTensor::from_id(src.id, ShapeTracker::new(&new_dims), src.graph_ref)
+    }
+
+    /// Rename the dynamic dimension `from` to `to`, such as when feeding a `Dyn<'A'>` output into a module expecting `Dyn<'B'>` of the same runtime size
+    pub fn relabel_dim<Dst: Shape>(mut self, from: char, to: char) -> GraphTensor<Dst> {
+        self.shape.relabel_dim(from, to);
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
     /// Dynamically reshape with annotations for the shape tracker
     pub fn dyn_reshape<N: Shape>(mut self, shape: Vec<Expression>) -> GraphTensor<N> {
         if !self.shape.indexes.iter().enumerate().all(|(a, b)| a == *b) {
@@ -63,6 +221,41 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Reinterpret this tensor with a dynamically sized shape, such as `(Dyn<'a'>, Dyn<'b'>)`.
+    /// The dynamic dimensions are checked against the tensor's element count when the graph runs.
+    pub fn realize_shape<Dst: Shape>(self) -> GraphTensor<Dst> {
+        let src = self.contiguous();
+        let numel = src.shape.n_elements();
+        let dst = Dst::realized_shape();
+        let tracker = ShapeTracker::new(&dst);
+        let dyn_map: *const FxHashMap<char, usize> = &src.graph().dyn_map;
+        let id = src
+            .graph()
+            .add_op(op::Function(
+                "RealizeShape".to_string(),
+                Box::new(move |mut inp| {
+                    let dyn_map = unsafe { dyn_map.as_ref().unwrap() };
+                    let dst_numel = dst
+                        .iter()
+                        .map(|d| {
+                            d.exec(dyn_map).unwrap_or_else(|| {
+                                panic!("Unresolved dimension in realized shape {dst:?}")
+                            })
+                        })
+                        .product::<usize>();
+                    let src_numel = numel.exec(dyn_map).unwrap();
+                    assert_eq!(
+                        src_numel, dst_numel,
+                        "Can't realize a tensor of {src_numel} elements as {dst:?} ({dst_numel} elements)"
+                    );
+                    vec![inp.pop().unwrap().0.cloned()]
+                }),
+            ))
+            .input(src.id, 0, src.shape)
+            .finish();
+        GraphTensor::from_id(id, tracker, src.graph_ref)
+    }
+
     pub fn sync_shape(self) -> Self {
         GraphTensor::from_id(
             self.id,
@@ -101,6 +294,30 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Take `len` elements starting at `start` along one axis. The bounds can be symbolic, such as the current length of a KV cache, and are resolved when the graph runs
+    pub fn slice_dyn<Dst: Shape>(
+        mut self,
+        axis: usize,
+        start: impl Into<Expression>,
+        len: impl Into<Expression>,
+    ) -> GraphTensor<Dst> {
+        assert!(
+            axis < self.shape.len(),
+            "Can't slice axis {axis} of a {}D tensor",
+            self.shape.len()
+        );
+        let start = start.into();
+        let mut ranges = vec![(Expression::from(0), Expression::from(i32::MAX)); self.shape.len()];
+        ranges[axis] = (start, start + len.into());
+        // Slicing a padded dimension is unsupported, so materialize the padding first
+        let padding = self.shape.padding[self.shape.indexes[axis]];
+        if padding.0 != 0 || padding.1 != 0 {
+            self = self.contiguous();
+        }
+        self.shape.slice(&ranges);
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
     /// Cut out 'size' elements every 'spacing' elements in the last dimension. 'size' must be smaller than the last dimension
     pub fn excise<Dst: Shape>(mut self, spacing: usize, size: usize) -> GraphTensor<Dst> {
         let n_dims = self.shape.len();
@@ -109,14 +326,14 @@ impl<S: Shape> GraphTensor<S> {
             / (spacing + size)
             * (spacing + size);
         let padding = total_size - self.shape.dims[self.shape.indexes[n_dims - 1]];
-        self.shape.padding[self.shape.indexes[n_dims - 1]].1 = padding;
+        self.shape.padding[self.shape.indexes[n_dims - 1]].1 = padding.into();
 
         self = self.contiguous();
         // Expand a new dimension to do the slicing on
         let n_rows = total_size / (spacing + size);
         self.shape.expand(n_dims, spacing + size);
         // self = self.contiguous();
-        self.shape.dims[self.shape.indexes[n_dims - 1]] = n_rows;
+        self.shape.dims[self.shape.indexes[n_dims - 1]] = n_rows.into();
         self.shape.fake[self.shape.indexes[n_dims]] = false;
 
         // Slice
@@ -136,7 +353,8 @@ impl<S: Shape> GraphTensor<S> {
         dilation: usize,
     ) -> GraphTensor<Dst> {
         let n_dims = self.shape.len();
-        let full_kernel = kernel + dilation;
+        // Dilation puts `dilation` elements between each element of the kernel
+        let full_kernel = kernel + (kernel - 1) * dilation;
         let dim_size = self.shape.dims[self.shape.indexes[n_dims - 1]];
         let number_of_windows = ((dim_size - full_kernel) / stride) + 1;
         // Expand new dimension
@@ -149,18 +367,19 @@ impl<S: Shape> GraphTensor<S> {
                 dim_size.big() * self.shape.dims[self.shape.indexes[n_dims - 1]].big();
             // Reshape into single dimension to pad
             self.shape.remove_dim(n_dims);
-            self.shape.dims[self.shape.indexes[n_dims - 1]] = actual_size.small();
-            self.shape.padding[self.shape.indexes[n_dims - 1]].1 = (mat_size - actual_size).small();
+            self.shape.dims[self.shape.indexes[n_dims - 1]] = actual_size.small().into();
+            self.shape.padding[self.shape.indexes[n_dims - 1]].1 =
+                (mat_size - actual_size).small().into();
             self = self.contiguous();
             // Reshape back (mats should be full now)
             self.shape.add_dim(n_dims, dim_size + stride);
         } else {
-            self.shape.dims[self.shape.indexes[n_dims]] = dim_size + stride;
+            self.shape.dims[self.shape.indexes[n_dims]] = (dim_size + stride).into();
         }
-        self.shape.dims[self.shape.indexes[n_dims - 1]] = number_of_windows;
+        self.shape.dims[self.shape.indexes[n_dims - 1]] = number_of_windows.into();
         // Slice down to kernel size
-        self.shape.mask[self.shape.indexes[n_dims]].1 = full_kernel;
-        self.shape.mask[self.shape.indexes[n_dims - 1]].1 = number_of_windows;
+        self.shape.mask[self.shape.indexes[n_dims]].1 = full_kernel.into();
+        self.shape.mask[self.shape.indexes[n_dims - 1]].1 = number_of_windows.into();
         self = self.contiguous();
 
         if dilation > 0 {
@@ -217,6 +436,143 @@ mod tests {
 
     crate::test_imports!();
 
+    #[test]
+    fn test_permute_negative_axes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 4>>().set(random_vec(24));
+        let b = a
+            .permute_axes::<R3<4, 2, 3>>(&[-1, 0, -2])
+            .contiguous()
+            .retrieve();
+        let c = a
+            .permute::<R3<4, 2, 3>, LAxes3<2, 0, 1>>()
+            .contiguous()
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_try_permute_axes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 4>>();
+        assert_eq!(
+            a.try_permute_axes::<()>(&[0, 0, 1]).unwrap_err(),
+            PermuteError::Repeated(0)
+        );
+        assert_eq!(
+            a.try_permute_axes::<()>(&[0, 3, 1]).unwrap_err(),
+            PermuteError::OutOfRange { axis: 3, rank: 3 }
+        );
+        assert_eq!(
+            a.try_permute_axes::<()>(&[0, 1]).unwrap_err(),
+            PermuteError::WrongRank { rank: 3, found: 2 }
+        );
+        let b = a.try_permute_axes::<R3<4, 2, 3>>(&[2, 0, 1]).unwrap();
+        assert_eq!(b.shape.shape_usize(), vec![4, 2, 3]);
+    }
+
+    #[test]
+    fn test_expand_dim() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = a.expand_dim::<R2<4, 3>>(0, 4);
+        let c = a.expand_dim::<R2<3, 2>>(1, 2);
+        let b_out = (b * 1.0).retrieve();
+        let c_out = (c * 1.0).retrieve();
+        cx.execute();
+
+        assert_eq!(b.shape.shape_usize(), vec![4, 3]);
+        assert_exact(
+            &b_out.data(),
+            &[1., 2., 3., 1., 2., 3., 1., 2., 3., 1., 2., 3.],
+        );
+        assert_exact(&c_out.data(), &[1., 1., 2., 2., 3., 3.]);
+    }
+
+    #[test]
+    fn test_repeat_interleave() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set([1., 2.]);
+        let b = a.repeat_interleave::<R1<4>>(0, 2).retrieve();
+        let c = cx
+            .tensor::<R2<2, 2>>()
+            .set([[1., 2.], [3., 4.]])
+            .repeat_interleave::<R2<2, 6>>(1, 3)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 1., 2., 2.]);
+        assert_exact(&c.data(), &[1., 1., 1., 2., 2., 2., 3., 3., 3., 4., 4., 4.]);
+    }
+
+    #[test]
+    fn test_flatten_unflatten() {
+        let mut cx = Graph::new();
+        let data = random_vec(24);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let b = a.flatten::<R2<2, 12>>(1, 2);
+        let c = b.unflatten::<R3<2, 3, 4>>(-1, &[3, 4]);
+        // Contiguous tensors are only reinterpreted
+        assert_eq!(b.id, a.id);
+        assert_eq!(c.id, a.id);
+        assert_eq!(b.shape.shape_usize(), vec![2, 12]);
+        assert_eq!(c.shape.shape_usize(), vec![2, 3, 4]);
+        let b = (b * 1.0).retrieve();
+        let c = (c * 1.0).retrieve();
+        let d = a
+            .permute::<R3<3, 2, 4>, _>()
+            .flatten::<R2<6, 4>>(0, 1)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &data);
+        assert_exact(&c.data(), &data);
+
+        let mut cx2 = Graph::new();
+        let f = cx2
+            .tensor::<R3<2, 3, 4>>()
+            .set(data)
+            .permute::<R3<3, 2, 4>, _>()
+            .reshape::<R2<6, 4>>()
+            .retrieve();
+        cx2.execute();
+        assert_exact(&d.data(), &f.data());
+    }
+
+    #[test]
+    fn test_realize_shape() {
+        let mut cx = Graph::new();
+        let data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>().set(data.clone());
+        let b = a.realize_shape::<(Dyn<'A'>, Dyn<'B'>)>();
+        let c = (b * 2.).retrieve();
+        cx.set_dyn_dim('A', 2);
+        cx.set_dyn_dim('B', 3);
+        cx.execute();
+
+        assert_eq!(
+            c.shape.shape(),
+            vec![Expression::from('A'), Expression::from('B')]
+        );
+        assert_close(
+            &c.data(),
+            &data.into_iter().map(|i| i * 2.).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't realize a tensor of 6 elements")]
+    fn test_realize_shape_mismatch() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        a.realize_shape::<(Dyn<'A'>, Dyn<'B'>)>().retrieve();
+        cx.set_dyn_dim('A', 3);
+        cx.set_dyn_dim('B', 3);
+        cx.execute();
+    }
+
     #[test]
     fn test_concat_1d() {
         let mut cx = Graph::new();
@@ -339,6 +695,56 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_slice_dyn() {
+        let mut cx = Graph::new();
+        let data = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+        let cache = cx.tensor::<R2<6, 2>>().set(data.clone());
+        let mut prefix = cache
+            .slice_dyn::<(Dyn<'L'>, LConst<2>)>(0, 0, 'L')
+            .retrieve();
+        let mut last = cache
+            .slice_dyn::<R2<1, 2>>(0, Expression::from('L') - 1, 1)
+            .retrieve();
+
+        for len in [2, 5] {
+            cx.set_dyn_dim('L', len);
+            cx.execute();
+            assert_exact(&prefix.data(), &data[..len * 2]);
+            assert_exact(&last.data(), &data[(len - 1) * 2..len * 2]);
+            prefix.drop();
+            last.drop();
+        }
+    }
+
+    #[test]
+    fn test_relabel_dim() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(Dyn<'A'>, LConst<2>)>()
+            .relabel_dim::<(Dyn<'B'>, LConst<2>)>('A', 'B');
+        let b = cx.tensor::<(Dyn<'B'>, LConst<2>)>();
+        let mut c = (a + b).retrieve();
+        assert_eq!(c.shape.shape()[0], 'B');
+
+        for len in [2, 3] {
+            let (a_data, b_data) = (random_vec(len * 2), random_vec(len * 2));
+            a.set_dyn(a_data.clone(), &[len, 2]);
+            b.set_dyn(b_data.clone(), &[len, 2]);
+            cx.execute();
+            assert!(!cx.dyn_map.contains_key(&'A'));
+            assert_close(
+                &c.data(),
+                &a_data
+                    .iter()
+                    .zip(&b_data)
+                    .map(|(a, b)| a + b)
+                    .collect::<Vec<_>>(),
+            );
+            c.drop();
+        }
+    }
+
     #[test]
     fn test_cumsum() {
         let mut cx = Graph::new();
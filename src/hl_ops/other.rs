@@ -2,6 +2,8 @@ use std::path::Path;
 
 use colored::Colorize;
 use itertools::Itertools;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rustc_hash::FxHashMap;
 
 use crate::{
     op::{self, Constant, ConstantValue},
@@ -16,8 +18,8 @@ impl<S: Shape> GraphTensor<S> {
             self = self.contiguous();
         }
         // Pad out length
-        let orig_length = self.shape.dims[self.shape.indexes[axis]];
-        self.shape.padding[self.shape.indexes[axis]].0 = orig_length - 1;
+        let orig_length = self.shape.dims[self.shape.indexes[axis]].expr();
+        self.shape.padding[self.shape.indexes[axis]].0 = (orig_length - 1).into();
         self = self.contiguous();
 
         // Pool
@@ -106,6 +108,34 @@ impl Graph {
         }
     }
 
+    /// Uniformly random numbers in [0, 1), seeded from the graph seed and this op's node index.
+    ///
+    /// The values only depend on the seed and the node, so every execution produces the same tensor. This is a fixed
+    /// random init (for weights and the like), not fresh noise on each run. Call [Graph::set_seed] to draw new values.
+    pub fn rand<S: Shape>(&mut self) -> GraphTensor<S> {
+        let shape = ShapeTracker::new(&S::realized_shape());
+        let numel = shape.n_elements();
+        let id = self
+            .add_op(op::Function(
+                "Rand".to_string(),
+                Box::new(|_| panic!("Rand op wasn't initialized")),
+            ))
+            .finish();
+        let (dyn_map, seed): (*const FxHashMap<char, usize>, *const u64) =
+            (&self.dyn_map, &self.seed);
+        self.get_op_mut::<op::Function>(id).1 = Box::new(move |_| {
+            let (dyn_map, seed) = unsafe { (dyn_map.as_ref().unwrap(), *seed) };
+            let mut rng = StdRng::seed_from_u64(
+                seed ^ (id.index() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+            );
+            let n = numel.exec(dyn_map).unwrap();
+            vec![Tensor::new(
+                (0..n).map(|_| rng.gen::<f32>()).collect::<Vec<_>>(),
+            )]
+        });
+        GraphTensor::from_id(id, shape, self)
+    }
+
     /// Lower left-hand triangle of 1s. Currently required to be square
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
@@ -116,6 +146,11 @@ impl Graph {
         (horizontal - (diagonal as f32 + 1.)).less_than(vertical)
     }
 
+    /// Causal attention mask, with 1s on and below the diagonal and 0s above it
+    pub fn causal_mask<S: Dimension>(&mut self) -> GraphTensor<(S, S)> {
+        self.tril::<S>(0)
+    }
+
     /// Upper right-hand triangle of 1s
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.triu
@@ -140,6 +175,57 @@ impl<S: Dimension, const DIM: usize> GraphTensor<(S, Const<DIM>)> {
 }
 
 impl<S: Shape> GraphTensor<S> {
+    /// Treating this tensor as a condition, take `on_true` where it's nonzero and `on_false` everywhere else
+    pub fn select(self, on_true: GraphTensor<S>, on_false: GraphTensor<S>) -> GraphTensor<S> {
+        let id = self
+            .graph()
+            .add_op(op::Select)
+            .input(self.id, 0, self.shape)
+            .input(on_true.id, 0, on_true.shape)
+            .input(on_false.id, 0, on_false.shape)
+            .finish();
+        GraphTensor::from_id(id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Stop gradients from flowing back through this tensor. The value passes through unchanged.
+    pub fn detach(self) -> GraphTensor<S> {
+        let id = self
+            .graph()
+            .add_op(op::StopGradient)
+            .input(self.id, 0, self.shape)
+            .finish();
+        // The input is passed through as-is, so the view stays the same
+        GraphTensor::from_id(id, self.shape, self.graph_ref)
+    }
+
+    /// Cast the stored data to another data type, such as `bf16` for storing activations and back to `f32` for compute.
+    /// The data is converted elementwise, so the view stays the same
+    pub fn cast<T: Dtype>(self) -> T::Tensor<S> {
+        let id = self
+            .graph()
+            .add_op(T::CastOp::default())
+            .input(self.id, 0, self.shape)
+            .finish();
+        T::wrap(GraphTensor::from_id(id, self.shape, self.graph_ref))
+    }
+
+    /// A constant tensor with the same shape as this one. Dynamic dimensions are resolved when the graph is ran
+    pub fn fill(&self, value: impl Into<ConstantValue>) -> GraphTensor<S> {
+        self.graph()
+            .constant(value)
+            .expand_to(self.shape.contiguous())
+    }
+
+    /// A tensor of zeros with the same shape as this one
+    pub fn zeros_like(&self) -> GraphTensor<S> {
+        self.fill(0.)
+    }
+
+    /// A tensor of ones with the same shape as this one
+    pub fn ones_like(&self) -> GraphTensor<S> {
+        self.fill(1.)
+    }
+
     /// Print the value of this tensor when the graph is ran
     pub fn print<T: ToString>(&self, message: T) {
         let message = message.to_string();
@@ -331,6 +417,57 @@ impl<S: Shape> GraphTensor<S> {
     }
 }
 
+/// A tensor stored as bf16. Other ops compute in f32 and can't read it, so it has to be cast back first
+#[derive(Clone, Copy)]
+pub struct Bf16Tensor<S: Shape>(pub(crate) GraphTensor<S>);
+
+impl<S: Shape> Bf16Tensor<S> {
+    /// The node producing this tensor
+    pub fn id(&self) -> NodeIndex {
+        self.0.id
+    }
+
+    /// Cast the data to another data type, such as back to `f32` for compute
+    pub fn cast<T: Dtype>(self) -> T::Tensor<S> {
+        self.0.cast::<T>()
+    }
+
+    /// Mark this tensor to not be deleted
+    pub fn keep(self) -> Self {
+        Self(self.0.keep())
+    }
+
+    /// Mark this tensor to be retrieved later
+    pub fn retrieve(self) -> Self {
+        Self(self.0.retrieve())
+    }
+
+    /// Remove this tensor's data from the graph
+    pub fn drop(&self) {
+        self.0.drop();
+    }
+
+    /// Get the data of the tensor, converted to f32
+    pub fn data(&self) -> Vec<f32> {
+        let tensor = self
+            .0
+            .graph()
+            .get_tensor_ref(self.0.id, 0)
+            .unwrap_or_else(|| panic!("{}", RetrieveError::NotExecuted(self.0.id)));
+        tensor
+            .downcast_ref::<Bf16Data>()
+            .unwrap()
+            .to_host_vec()
+            .unwrap()
+    }
+
+    /// The underlying handle, typed like any other f32 tensor. Ops reading it need their inputs promoted first by
+    /// running [`InsertCastOps`], otherwise they'll fail to read the bf16 data
+    pub fn into_mixed(self) -> GraphTensor<S> {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     crate::test_imports!();
@@ -367,14 +504,36 @@ mod tests {
         assert_exact(&arange.data(), &[0., 1., 2., 3., 4., 5.]);
     }
 
+    #[test]
+    fn test_ones_like() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor::<(Dyn<'N'>,)>();
+        let ones = a.ones_like().retrieve();
+        let zeros = (a.zeros_like() + a).retrieve();
+        a.set_dyn(vec![1., 2., 3., 4.], &[4]);
+        cx.execute();
+
+        assert_exact(&ones.data(), &[1.; 4]);
+        assert_exact(&zeros.data(), &[1., 2., 3., 4.]);
+    }
+
     #[test]
     fn test_tril() {
         let mut cx = Graph::new();
 
         let triangle = cx.tril::<LConst<5>>(1).retrieve();
+        let lower = cx.tril::<LConst<3>>(0).retrieve();
 
         cx.execute();
 
+        assert_exact(
+            &lower.data(),
+            &[[1.00, 0.00, 0.00], [1.00, 1.00, 0.00], [1.00, 1.00, 1.00]]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+        );
         assert_exact(
             &triangle.data(),
             &[
@@ -390,6 +549,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_causal_mask_select() {
+        let mut cx = Graph::new();
+        let scores = cx
+            .tensor::<R2<4, 4>>()
+            .set_from_iter((0..16).map(|i| i as f32));
+        let masked = cx
+            .causal_mask::<LConst<4>>()
+            .select(scores, cx.constant(f32::NEG_INFINITY).expand())
+            .retrieve();
+
+        cx.execute();
+
+        let inf = f32::NEG_INFINITY;
+        assert_exact(
+            &masked.data(),
+            &[
+                [0., inf, inf, inf],
+                [4., 5., inf, inf],
+                [8., 9., 10., inf],
+                [12., 13., 14., 15.],
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+        );
+    }
+
     #[test]
     fn test_triu() {
         let mut cx = Graph::new();
@@ -422,4 +609,52 @@ mod tests {
                 .collect::<Vec<_>>(),
         );
     }
+
+    #[test]
+    fn test_bf16_round_trip() {
+        let mut cx = Graph::new();
+        let data = random_vec(64);
+        let a = cx.tensor::<R2<8, 8>>().set(data.clone());
+        let stored = a.cast::<bf16>().retrieve();
+        let b = (stored.cast::<f32>() * 1.0).retrieve();
+        cx.execute();
+
+        // Stored in half the space
+        assert!(cx.get_tensor_ref(stored.id(), 0).unwrap().is::<Bf16Data>());
+        assert_eq!(stored.data().len(), 64);
+        // bf16 keeps 8 bits of mantissa
+        for (x, y) in data.iter().zip(b.data()) {
+            assert!((x - y).abs() <= x.abs() / 256., "{x} round tripped to {y}");
+        }
+    }
+
+    #[test]
+    fn test_seeded_rand() {
+        let run = |seed: u64, extra_nodes: bool| {
+            let mut cx = Graph::new();
+            cx.set_seed(seed);
+            let a = cx.rand::<R2<4, 8>>().retrieve();
+            if extra_nodes {
+                let _ = cx.rand::<R1<3>>() + cx.arange::<LConst<3>>();
+            }
+            cx.execute();
+            a.data()
+        };
+        let a = run(1, false);
+        assert!(a.iter().all(|i| (0.0..1.0).contains(i)));
+        assert_exact(&a, &run(1, false));
+        // Other nodes don't shift this op's randomness
+        assert_exact(&a, &run(1, true));
+        // Reseeding changes the values
+        assert_ne!(a, run(2, false));
+
+        // Executing again gives back the same values
+        let mut cx = Graph::new();
+        let b = cx.rand::<R1<16>>().retrieve();
+        cx.execute();
+        let first = b.data();
+        b.drop();
+        cx.execute();
+        assert_exact(&b.data(), &first);
+    }
 }
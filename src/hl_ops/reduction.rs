@@ -44,6 +44,118 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
 
+    /// Multiply together all elements along the reduced axes. An empty axis reduces to 1
+    pub fn prod_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let mut shape = self.shape;
+
+        let mut new_id = self.id;
+        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+            new_id = self
+                .graph()
+                .add_op(op::ProductReduce(dim))
+                .input(new_id, 0, shape)
+                .finish();
+            // Reduce shape
+            shape.remove_dim(dim);
+        }
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    /// Reduce the axes with any [ReduceOp](op::ReduceOp)
+    pub fn reduce<Dst: Shape, Ax: Axes>(self, reduce_op: op::ReduceOp) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let mut shape = self.shape;
+
+        let mut new_id = self.id;
+        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+            new_id = self
+                .graph()
+                .add_op(op::Reduce {
+                    axis: dim,
+                    op: reduce_op,
+                })
+                .input(new_id, 0, shape)
+                .finish();
+            // Reduce shape
+            shape.remove_dim(dim);
+        }
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    /// Take the smallest element along the reduced axes
+    pub fn min_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.reduce::<Dst, Ax>(op::ReduceOp::Min)
+    }
+
+    /// Sum reduce along an axis chosen at runtime. Negative axes count back from the last axis.
+    pub fn sum_reduce_axis<Dst: Shape>(self, axis: isize) -> GraphTensor<Dst> {
+        let dim = normalize_axis(axis, self.shape.len());
+        let mut shape = self.shape;
+        let new_id = self
+            .graph()
+            .add_op(op::SumReduce(dim))
+            .input(self.id, 0, shape)
+            .finish();
+        shape.remove_dim(dim);
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    /// Max reduce along an axis chosen at runtime. Negative axes count back from the last axis.
+    pub fn max_reduce_axis<Dst: Shape>(self, axis: isize) -> GraphTensor<Dst> {
+        let dim = normalize_axis(axis, self.shape.len());
+        let mut shape = self.shape;
+        let new_id = self
+            .graph()
+            .add_op(op::MaxReduce(dim))
+            .input(self.id, 0, shape)
+            .finish();
+        shape.remove_dim(dim);
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    /// Get the `k` largest values along an axis in descending order, along with their indices along that axis.
+    /// Negative axes count back from the last axis. Panics if `k` is larger than the axis.
+    pub fn topk<Dst: Shape>(self, k: usize, axis: isize) -> (GraphTensor<Dst>, GraphTensor<Dst>) {
+        let dim = normalize_axis(axis, self.shape.len());
+        if let Some(size) = self.shape.shape()[dim].to_usize() {
+            assert!(
+                k <= size,
+                "Can't take the top {k} of an axis of size {size}"
+            );
+        }
+        let topk = self
+            .graph()
+            .add_op(op::TopK { k, axis: dim })
+            .input(self.id, 0, self.shape)
+            .finish();
+        let mut dims = self
+            .shape
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect::<Vec<_>>();
+        dims[dim] = k.into();
+        let shape = ShapeTracker::new(&dims);
+        // Tensors only refer to an op's first output, so pull the indices out with a copy
+        let indices = self
+            .graph()
+            .add_op(op::Contiguous)
+            .input(topk, 1, shape)
+            .finish();
+        (
+            GraphTensor::from_id(topk, shape, self.graph_ref),
+            GraphTensor::from_id(indices, shape, self.graph_ref),
+        )
+    }
+
     pub fn mean_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -90,6 +202,67 @@ impl<S: Shape> GraphTensor<S> {
 mod tests {
     crate::test_imports!();
 
+    #[test]
+    fn test_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set([[1., -2., 3.], [4., 0.5, -6.]]);
+        let sum = a.reduce::<R1<2>, LAxis<1>>(ReduceOp::Sum).retrieve();
+        let max = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Max).retrieve();
+        let min = a.min_reduce::<R1<2>, LAxis<1>>().retrieve();
+        let prod = a.reduce::<R1<3>, LAxis<0>>(ReduceOp::Product).retrieve();
+        let all = a.reduce::<R0, LAxes2<0, 1>>(ReduceOp::Sum).retrieve();
+        cx.execute();
+
+        assert_exact(&sum.data(), &[2., -1.5]);
+        assert_exact(&max.data(), &[4., 0.5, 3.]);
+        assert_exact(&min.data(), &[-2., -6.]);
+        assert_exact(&prod.data(), &[4., -1., -18.]);
+        assert_exact(&all.data(), &[0.5]);
+    }
+
+    #[test]
+    fn test_topk() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set([[1., 5., 3., 5., 2.], [-1., -4., 0., 7., -2.]]);
+        let (values, indices) = a.topk::<R2<2, 2>>(2, 1);
+        values.retrieve();
+        indices.retrieve();
+        cx.execute();
+
+        assert_exact(&values.data(), &[5., 5., 7., 0.]);
+        assert_exact(&indices.data(), &[1., 3., 3., 2.]);
+    }
+
+    #[test]
+    fn test_topk_cse() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set([2., 8., -1., 4.]);
+        let (values, indices) = a.topk::<R1<2>>(2, 0);
+        // A copy of the values looks just like the copy pulling out the indices, apart from the output it reads
+        let copy = cx
+            .add_op(crate::op::Contiguous)
+            .input(values.id, 0, values.shape)
+            .finish();
+        let mut values = GraphTensor::<R1<2>>::from_id(copy, values.shape, &mut cx).retrieve();
+        let mut indices = indices.retrieve();
+
+        cx.compile(GenericCompiler::default(), (&mut values, &mut indices));
+        cx.execute();
+
+        assert_exact(&values.data(), &[8., 4.]);
+        assert_exact(&indices.data(), &[1., 3.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't take the top 6 of an axis of size 5")]
+    fn test_topk_too_large() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 5>>();
+        let _ = a.topk::<R2<2, 6>>(6, 1);
+    }
+
     #[test]
     fn test_sum_reduce() {
         let mut cx = Graph::new();
@@ -108,6 +281,47 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_prod_reduce() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let b = a.prod_reduce::<_, LAxis<1>>().retrieve();
+        let c = cx.tensor::<R2<2, 0>>().set(vec![]);
+        let d = c.prod_reduce::<R1<2>, LAxis<1>>().retrieve();
+
+        cx.execute();
+
+        let expected = a_data
+            .chunks(3)
+            .map(|r| r.iter().product::<f32>())
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+        assert_exact(&d.data(), &[1., 1.]);
+    }
+
+    #[test]
+    fn test_negative_axis_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = a.sum_reduce_axis::<R1<2>>(-1).retrieve();
+        let c = a.sum_reduce::<_, LAxis<1>>().retrieve();
+        let d = a.max_reduce_axis::<R1<3>>(-2).retrieve();
+        let e = a.max_reduce::<_, LAxis<0>>().retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &c.data());
+        assert_exact(&d.data(), &e.data());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_out_of_range_axis() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>();
+        a.sum_reduce_axis::<R1<2>>(-3);
+    }
+
     #[test]
     fn test_max_reduce() {
         let mut cx = Graph::new();
@@ -1,6 +1,9 @@
 use crate::{op, prelude::*};
 use std::ops::{Add, Mul, Neg};
 
+/// Negation stays a multiply by -1 in the primitive graph rather than being its own op, since compilers match
+/// subtraction (and through it sigmoid) and layer norm on that form. Backends lower what's left to their own
+/// negation ops, such as `Neg` in luminal_cpu and `CudaNeg` in luminal_cuda
 impl<S: Shape> Neg for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
@@ -60,6 +63,41 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
     }
 
+    /// 1.0 where the value is NaN, 0.0 otherwise
+    pub fn is_nan(self) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::IsNan)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// 1.0 where the value is positive or negative infinity, 0.0 otherwise
+    pub fn is_inf(self) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::IsInf)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Replace NaNs with `nan`, positive infinity with `posinf` and negative infinity with `neginf`.
+    /// Passing `0.0, f32::MAX, f32::MIN` matches the usual defaults
+    pub fn nan_to_num(self, nan: f32, posinf: f32, neginf: f32) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::NanToNum {
+                nan,
+                posinf,
+                neginf,
+            })
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
     /// The cos(x) function
     pub fn cos(self) -> GraphTensor<S> {
         ((std::f32::consts::PI / 2.) - self).sin()
@@ -182,8 +220,12 @@ impl<S: Shape> GraphTensor<S> {
 
     /// The sigmoid activation function
     pub fn sigmoid(self) -> GraphTensor<S> {
-        // Based on https://github.com/tinygrad/tinygrad/blob/9d142430cbe61121c864c0015f1de83c94a7d2c0/tinygrad/mlops.py#L70
-        1. / (1. + (-self).exp())
+        // Only ever exponentiate -|x| so nothing overflows:
+        // sigmoid(x) = 1 / (1 + exp(-|x|)) for x >= 0, exp(-|x|) / (1 + exp(-|x|)) for x < 0
+        let negative = self.less_than(self.graph().constant(0.).expand_to(self.shape));
+        let e = (self * (negative * 2. - 1.)).exp();
+        let p = 1. / (1. + e);
+        p * (negative * (e - 1.) + 1.)
     }
 
     /// The swish activation function
@@ -193,7 +235,11 @@ impl<S: Shape> GraphTensor<S> {
 
     /// The tanh activation function
     pub fn tanh(self) -> GraphTensor<S> {
-        (self * 2.0).sigmoid() * 2.0 - 1.0
+        // tanh(x) = sign(x) * (1 - exp(-2|x|)) / (1 + exp(-2|x|)), which never overflows
+        let negative = self.less_than(self.graph().constant(0.).expand_to(self.shape));
+        let sign = 1. - negative * 2.;
+        let e = (self * sign * -2.).exp();
+        sign * (1. - e) / (1. + e)
     }
 
     /// The leaky relu activation function
@@ -273,6 +319,45 @@ mod tests {
         assert_close(&r, &d_b.as_vec());
     }
 
+    #[test]
+    fn test_is_nan_is_inf() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<5>>()
+            .set([1., f32::NAN, f32::INFINITY, -f32::INFINITY, -2.]);
+        let nan = a.is_nan().retrieve();
+        let inf = a.is_inf().retrieve();
+
+        cx.execute();
+
+        assert_exact(&nan.data(), &[0., 1., 0., 0., 0.]);
+        assert_exact(&inf.data(), &[0., 0., 1., 1., 0.]);
+    }
+
+    #[test]
+    fn test_nan_to_num() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<5>>()
+            .set([1., f32::NAN, f32::INFINITY, -f32::INFINITY, -2.]);
+        let b = a.nan_to_num(0., 100., -100.).retrieve();
+
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 0., 100., -100., -2.]);
+    }
+
+    #[test]
+    fn test_clip() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 2>>().set([[-1., 0.25], [3., 0.75]]);
+        let b = a.permute::<_, LAxes2<1, 0>>().clip(0., 1.).retrieve();
+
+        cx.execute();
+
+        assert_exact(&b.data(), &[0., 1., 0.25, 0.75]);
+    }
+
     #[test]
     fn test_cos() {
         let mut cx = Graph::new();
@@ -322,6 +407,20 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_saturating_activations() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<5>>().set(vec![-100., -1., 0., 1., 100.]);
+        let b = a.sigmoid().retrieve();
+        let c = a.tanh().retrieve();
+        cx.execute();
+
+        let (b, c) = (b.data(), c.data());
+        assert!(b.iter().chain(&c).all(|v| v.is_finite()));
+        assert_close(&b, &[0., 0.26894143, 0.5, 0.7310586, 1.]);
+        assert_close(&c, &[-1., -0.7615942, 0., 0.7615942, 1.]);
+    }
+
     #[test]
     fn test_swish() {
         let mut cx = Graph::new();
@@ -82,7 +82,7 @@ fn delete_upstream(graph: &mut Graph, node: NodeIndex) {
         .collect::<Vec<_>>()
     {
         delete_upstream(graph, e);
-        graph.graph.remove_node(e);
+        graph.remove_node(e);
     }
 }
 
@@ -197,6 +197,28 @@ tuple_impls!([M1, M2, M3, M4, M5, M6, M7, M8, M9, M10] [0, 1, 2, 3, 4, 5, 6, 7,
 /// Tell luminal how to represent the module as a dict of (String, NodeIndex)'s
 pub trait SerializeModule {
     fn serialize(&self, s: &mut Serializer);
+
+    /// Total number of elements across all of the module's parameter tensors. Tensors shared between layers are counted once.
+    fn num_params(&self) -> usize {
+        let mut s = Serializer::default();
+        self.serialize(&mut s);
+        s.shapes
+            .values()
+            .map(|st| {
+                st.n_elements()
+                    .to_usize()
+                    .expect("Can't count parameters with dynamic shapes")
+            })
+            .sum()
+    }
+
+    /// Size in bytes of the module's parameters when stored as `T` (such as `f32` or `f16`)
+    fn param_bytes<T>(&self) -> usize
+    where
+        Self: Sized,
+    {
+        self.num_params() * std::mem::size_of::<T>()
+    }
 }
 
 impl<T: SerializeModule> SerializeModule for &T {
@@ -210,6 +232,7 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    shapes: FxHashMap<NodeIndex, ShapeTracker>,
 }
 
 impl Serializer {
@@ -220,6 +243,7 @@ impl Serializer {
         }
         // Insert tensor id
         self.state.insert(self.current_path.join("/"), tensor.id);
+        self.shapes.insert(tensor.id, tensor.shape);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
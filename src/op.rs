@@ -1,4 +1,8 @@
-use std::{any::Any, fmt::Debug};
+use std::{
+    any::Any,
+    fmt::Debug,
+    task::{Context, Poll},
+};
 
 use crate::prelude::*;
 
@@ -26,12 +30,37 @@ impl Tensor {
     pub fn is<T: Data>(&self) -> bool {
         self.data.as_any().is::<T>()
     }
+    /// Copy the data back to the host as f32s, if its data type supports it
+    pub fn to_host_vec(&self) -> Option<Vec<f32>> {
+        self.data.to_host_vec()
+    }
+    /// Check if asynchronously produced data has finished, swapping in the finished data if so
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.data.poll_ready(cx) {
+            Poll::Ready(Some(data)) => {
+                self.data = data;
+                Poll::Ready(())
+            }
+            Poll::Ready(None) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 /// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Copy the data back to the host as f32s, if possible
+    fn to_host_vec(&self) -> Option<Vec<f32>> {
+        None
+    }
+    /// Poll data that gets filled in asynchronously, such as a copy back from a device.
+    /// Returns the finished data to replace this with once it's ready, or `Ready(None)` if this data is already complete.
+    /// Data that returns `Pending` must wake the context's waker once it becomes ready
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Option<Box<dyn Data>>> {
+        Poll::Ready(None)
+    }
 }
 
 clone_trait_object!(Data);
@@ -43,6 +72,25 @@ impl Data for Vec<f32> {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn to_host_vec(&self) -> Option<Vec<f32>> {
+        Some(self.clone())
+    }
+}
+
+/// bf16 data stored on the host
+#[derive(Debug, Clone)]
+pub struct Bf16Data(pub Vec<bf16>);
+
+impl Data for Bf16Data {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn to_host_vec(&self) -> Option<Vec<f32>> {
+        Some(self.0.iter().map(|f| f.to_f32()).collect())
+    }
 }
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -82,6 +130,16 @@ pub trait Operator: Debug + as_any::AsAny {
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
         None
     }
+    /// The number of inputs this op consumes, if it's fixed
+    fn expected_inputs(&self) -> Option<usize> {
+        None
+    }
+    /// The name of the op's type, without its module path or generics
+    fn name(&self) -> &'static str {
+        let full = std::any::type_name::<Self>();
+        let base = full.split('<').next().unwrap();
+        base.rsplit("::").next().unwrap()
+    }
 }
 
 /// An opaque function running on CPU that takes in Vec<f32> tensors and outputs Vec<f32> tensors
@@ -139,6 +197,21 @@ impl Operator for Constant {
             ConstantValue::Float(f) => *f,
         }])]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(0)
+    }
+}
+
+/// Passes its input through untouched. Marks the point past which gradients shouldn't flow
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopGradient;
+impl Operator for StopGradient {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 // Unary Op (A -> A)
@@ -152,12 +225,28 @@ impl Operator for Contiguous {
         let inp_data = get_vec(&inp[0].0);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        // Compute indexes a block of outputs at a time
+        let mut block_stack = vec![];
+        for (b, block) in out_data.chunks_exact_mut(EXEC_LANES).enumerate() {
+            let start = b * EXEC_LANES;
+            let indexes = expr.0.exec_single_var_block(start, &mut block_stack);
+            let valids = expr.1.exec_single_var_block(start, &mut block_stack);
+            for ((out, index), valid) in block.iter_mut().zip(indexes).zip(valids) {
+                if valid != 0 {
+                    *out = inp_data[index];
+                }
+            }
+        }
+        let done = out_data.len() / EXEC_LANES * EXEC_LANES;
         let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
+        for (i, out) in out_data.iter_mut().enumerate().skip(done) {
             *out = get_index(inp_data, &expr, &mut stack, i);
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -173,6 +262,9 @@ impl Operator for Log2 {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -188,6 +280,9 @@ impl Operator for Exp2 {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -203,6 +298,9 @@ impl Operator for Sin {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -218,6 +316,9 @@ impl Operator for Recip {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -233,6 +334,9 @@ impl Operator for Sqrt {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 // Binary Ops (A x A -> A)
@@ -251,6 +355,9 @@ impl Operator for Add {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -267,6 +374,9 @@ impl Operator for Mul {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -283,6 +393,9 @@ impl Operator for Mod {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -300,19 +413,83 @@ impl Operator for LessThan {
         }
         vec![Tensor::new(out_data)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+}
+
+/// Picks from the second input where the first input (the condition) is nonzero, and from the third input elsewhere.
+/// Unlike masking with a multiply, values in the unpicked input never leak into the output, even if they're infinite
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Select;
+impl Operator for Select {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (cond, a, b) = (get_vec(&inp[0].0), get_vec(&inp[1].0), get_vec(&inp[2].0));
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let cexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let aexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let bexpr = (inp[2].1.index_expression(), inp[2].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = if get_index(cond, &cexpr, &mut stack, i) != 0. {
+                get_index(a, &aexpr, &mut stack, i)
+            } else {
+                get_index(b, &bexpr, &mut stack, i)
+            };
+        }
+        vec![Tensor::new(out_data)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(3)
+    }
 }
 
 // Reduce Ops (A -> B (different shape))
 
+/// The combiner used by a [Reduce]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Max,
+    Min,
+    Product,
+}
+
+impl ReduceOp {
+    /// The starting value of the reduction, which is also the result of reducing an empty axis
+    pub fn identity(&self) -> f32 {
+        match self {
+            ReduceOp::Sum => 0.0,
+            ReduceOp::Max => -f32::INFINITY,
+            ReduceOp::Min => f32::INFINITY,
+            ReduceOp::Product => 1.0,
+        }
+    }
+
+    /// Combine the accumulated value with the next element
+    pub fn combine(&self, acc: f32, x: f32) -> f32 {
+        match self {
+            ReduceOp::Sum => acc + x,
+            ReduceOp::Max => acc.max(x),
+            ReduceOp::Min => acc.min(x),
+            ReduceOp::Product => acc * x,
+        }
+    }
+}
+
+/// Reduce an axis with any [ReduceOp]
 #[derive(Debug, Clone, PartialEq)]
-pub struct SumReduce(pub usize);
-impl Operator for SumReduce {
+pub struct Reduce {
+    pub axis: usize,
+    pub op: ReduceOp,
+}
+impl Operator for Reduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![0.0; front_size * back_size];
+        let front_size = sh.iter().take(self.axis).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.axis + 1).product::<usize>().max(1);
+        let dim_size = sh[self.axis];
+        let mut result = vec![self.op.identity(); front_size * back_size];
         let input = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
@@ -320,39 +497,334 @@ impl Operator for SumReduce {
             for j in 0..back_size {
                 for k in 0..dim_size {
                     let orig_index = i * dim_size * back_size + k * back_size + j;
-                    result[i * back_size + j] += get_index(input, &expr, &mut stack, orig_index);
+                    let new_index = i * back_size + j;
+                    result[new_index] = self.op.combine(
+                        result[new_index],
+                        get_index(input, &expr, &mut stack, orig_index),
+                    );
                 }
             }
         }
         vec![Tensor::new(result)]
     }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SumReduce(pub usize);
+impl Operator for SumReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        Reduce {
+            axis: self.0,
+            op: ReduceOp::Sum,
+        }
+        .process(inp)
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MaxReduce(pub usize);
 impl Operator for MaxReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        Reduce {
+            axis: self.0,
+            op: ReduceOp::Max,
+        }
+        .process(inp)
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductReduce(pub usize);
+impl Operator for ProductReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        Reduce {
+            axis: self.0,
+            op: ReduceOp::Product,
+        }
+        .process(inp)
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// The k largest values along an axis in descending order (output 0) and their indices along that axis (output 1).
+/// Ties keep the lower index first
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopK {
+    pub k: usize,
+    pub axis: usize,
+}
+impl Operator for TopK {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![-f32::INFINITY; front_size * back_size];
+        let front_size = sh.iter().take(self.axis).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.axis + 1).product::<usize>().max(1);
+        let dim_size = sh[self.axis];
+        assert!(
+            self.k <= dim_size,
+            "Can't take the top {} of an axis of size {dim_size}",
+            self.k
+        );
+        let mut values = vec![0.0; front_size * self.k * back_size];
+        let mut indices = vec![0.0; front_size * self.k * back_size];
         let input = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
+        let mut row = Vec::with_capacity(dim_size);
+        for i in 0..front_size {
+            for j in 0..back_size {
+                row.clear();
+                row.extend((0..dim_size).map(|k| {
+                    let orig_index = i * dim_size * back_size + k * back_size + j;
+                    (get_index(input, &expr, &mut stack, orig_index), k)
+                }));
+                let order =
+                    |a: &(f32, usize), b: &(f32, usize)| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1));
+                // Partition out the top k before sorting only those
+                if self.k > 0 && self.k < dim_size {
+                    row.select_nth_unstable_by(self.k - 1, order);
+                }
+                row[..self.k].sort_unstable_by(order);
+                for (k, (value, index)) in row[..self.k].iter().enumerate() {
+                    let new_index = i * self.k * back_size + k * back_size + j;
+                    values[new_index] = *value;
+                    indices[new_index] = *index as f32;
+                }
+            }
+        }
+        vec![Tensor::new(values), Tensor::new(indices)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Outputs 1.0 where the input is NaN, and 0.0 everywhere else
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IsNan;
+impl Operator for IsNan {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = get_index(inp_data, &expr, &mut stack, i).is_nan() as i32 as f32;
+        }
+        vec![Tensor::new(out_data)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Outputs 1.0 where the input is positive or negative infinity, and 0.0 everywhere else
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IsInf;
+impl Operator for IsInf {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = get_index(inp_data, &expr, &mut stack, i).is_infinite() as i32 as f32;
+        }
+        vec![Tensor::new(out_data)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Replaces NaNs, positive infinities and negative infinities with finite values. Finite values pass through unchanged
+#[derive(Debug, Clone, PartialEq)]
+pub struct NanToNum {
+    pub nan: f32,
+    pub posinf: f32,
+    pub neginf: f32,
+}
+impl Operator for NanToNum {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            let x = get_index(inp_data, &expr, &mut stack, i);
+            *out = if x.is_nan() {
+                self.nan
+            } else if x == f32::INFINITY {
+                self.posinf
+            } else if x == f32::NEG_INFINITY {
+                self.neginf
+            } else {
+                x
+            };
+        }
+        vec![Tensor::new(out_data)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
 
+/// Normalizes each row along an axis to zero mean and unit variance, then optionally scales and shifts by the following inputs.
+/// Produced by [FuseLayerNorm](crate::generic_compiler::FuseLayerNorm) from the primitive layer norm subgraph
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedLayerNorm {
+    pub axis: usize,
+    pub epsilon: f32,
+    pub scale: bool,
+    pub shift: bool,
+}
+impl Operator for FusedLayerNorm {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.axis).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.axis + 1).product::<usize>().max(1);
+        let dim_size = sh[self.axis];
+        let data = inp.iter().map(|(t, _)| get_vec(t)).collect::<Vec<_>>();
+        let exprs = inp
+            .iter()
+            .map(|(_, s)| (s.index_expression(), s.valid_expression()))
+            .collect::<Vec<_>>();
+        let mut stack = vec![];
+        let mut out_data = vec![0.; front_size * dim_size * back_size];
         for i in 0..front_size {
             for j in 0..back_size {
+                let row = |k: usize| i * dim_size * back_size + k * back_size + j;
                 for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    let new_index = i * back_size + j;
-                    result[new_index] =
-                        result[new_index].max(get_index(input, &expr, &mut stack, orig_index));
+                    out_data[row(k)] = get_index(data[0], &exprs[0], &mut stack, row(k));
+                }
+                let mean = (0..dim_size).map(|k| out_data[row(k)]).sum::<f32>() / dim_size as f32;
+                let var = (0..dim_size)
+                    .map(|k| (out_data[row(k)] - mean).powi(2))
+                    .sum::<f32>()
+                    / dim_size as f32;
+                let inv_std = (var + self.epsilon).sqrt().recip();
+                for k in 0..dim_size {
+                    let idx = row(k);
+                    out_data[idx] = (out_data[idx] - mean) * inv_std;
+                    if self.scale {
+                        out_data[idx] *= get_index(data[1], &exprs[1], &mut stack, idx);
+                    }
+                    if self.shift {
+                        let b = self.scale as usize + 1;
+                        out_data[idx] += get_index(data[b], &exprs[b], &mut stack, idx);
+                    }
                 }
             }
         }
-        vec![Tensor::new(result)]
+        vec![Tensor::new(out_data)]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1 + self.scale as usize + self.shift as usize)
+    }
+}
+
+/// Data types a tensor can be stored in on the host. Compute happens in f32, so tensors in other dtypes get their own
+/// handle type that has to be cast back before it can be used with other ops.
+pub trait Dtype: 'static {
+    type CastOp: Operator + Default + 'static;
+    /// The handle for a tensor stored in this dtype
+    type Tensor<S: Shape>;
+    fn wrap<S: Shape>(tensor: GraphTensor<S>) -> Self::Tensor<S>;
+}
+
+impl Dtype for f32 {
+    type CastOp = CastToF32;
+    type Tensor<S: Shape> = GraphTensor<S>;
+    fn wrap<S: Shape>(tensor: GraphTensor<S>) -> Self::Tensor<S> {
+        tensor
+    }
+}
+
+impl Dtype for bf16 {
+    type CastOp = CastToBf16;
+    type Tensor<S: Shape> = Bf16Tensor<S>;
+    fn wrap<S: Shape>(tensor: GraphTensor<S>) -> Self::Tensor<S> {
+        Bf16Tensor(tensor)
+    }
+}
+
+/// Convert f32 data to bf16, halving its storage size
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CastToBf16;
+impl Operator for CastToBf16 {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = inp[0].0.borrowed();
+        if let Some(data) = inp.downcast_ref::<Bf16Data>() {
+            return vec![Tensor::new(data.clone())];
+        }
+        let data = inp.downcast_ref::<Vec<f32>>().unwrap();
+        vec![Tensor::new(Bf16Data(
+            data.iter().copied().map(bf16::from_f32).collect(),
+        ))]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Convert bf16 data back to f32
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CastToF32;
+impl Operator for CastToF32 {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = inp[0].0.borrowed();
+        if let Some(data) = inp.downcast_ref::<Vec<f32>>() {
+            return vec![Tensor::new(data.clone())];
+        }
+        let data = inp.downcast_ref::<Bf16Data>().unwrap();
+        vec![Tensor::new(
+            data.0.iter().map(|f| f.to_f32()).collect::<Vec<_>>(),
+        )]
+    }
+    fn expected_inputs(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Clone a primitive op. Returns None for any other op, since operators in general aren't clonable
+pub fn clone_primitive(op: &dyn Operator) -> Option<Box<dyn Operator>> {
+    macro_rules! try_clone {
+        ($($t:ty),*) => {
+            $(
+                if let Some(o) = op.as_any().downcast_ref::<$t>() {
+                    return Some(Box::new(o.clone()));
+                }
+            )*
+        };
     }
+    try_clone!(
+        Constant,
+        StopGradient,
+        Contiguous,
+        Log2,
+        Exp2,
+        Sin,
+        Recip,
+        Sqrt,
+        Add,
+        Mul,
+        Mod,
+        LessThan,
+        SumReduce,
+        MaxReduce,
+        ProductReduce
+    );
+    None
 }
 
 fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
@@ -6,6 +6,15 @@ pub trait Axes: 'static + Default + Copy + Clone {
     fn as_array() -> Self::Array;
 }
 
+/// Convert a signed axis into a positive one for a shape of the given rank. Negative axes count back from the last axis.
+pub fn normalize_axis(axis: isize, rank: usize) -> usize {
+    let normalized = if axis < 0 { axis + rank as isize } else { axis };
+    if normalized < 0 || normalized >= rank as isize {
+        panic!("Axis {axis} is out of range for a tensor of rank {rank}");
+    }
+    normalized as usize
+}
+
 /// A singular axis, e.g. `Axis<0>` or `Axis<1>`
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Axis<const I: usize>;
@@ -0,0 +1,288 @@
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    hash::Hash,
+    marker::PhantomData,
+    ops::{Add, AddAssign, Div, Mul, MulAssign, Rem, Sub, SubAssign},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+thread_local! {
+    static INTERNED: RefCell<Interner> = RefCell::default();
+}
+
+/// The expressions interned on a thread, each stored once and looked up by its index
+#[derive(Default)]
+struct Interner {
+    exprs: Vec<Expression>,
+    indexes: FxHashMap<Expression, usize>,
+}
+
+/// A handle to an [Expression] that is stored once per thread and shared by everything using it.
+///
+/// Shape trackers hold dozens of expressions, most of them identical constants, so storing them inline made every edge in the graph several kilobytes.
+/// Constants are kept inline rather than interned, so resolving dyn dims to new values on every execution doesn't grow the table.
+/// Symbolic expressions are stored in the thread's table until the thread exits, but those only come from building and compiling the graph.
+///
+/// To keep handles pointer sized, constants are stored shifted left with the low bit set, and interned expressions
+/// as their index in the table shifted left. Since the index is only valid on the thread that made it, handles (and
+/// the shape trackers holding them) can't be sent to other threads.
+#[derive(Clone, Copy)]
+pub struct InternedExpression(usize, PhantomData<*const ()>);
+
+enum Repr {
+    Constant(i64),
+    Interned(usize),
+}
+
+impl InternedExpression {
+    fn constant(n: i64) -> Option<Self> {
+        // Values that don't survive the shift get interned instead
+        (((n << 1) >> 1) == n).then_some(Self(((n << 1) | 1) as usize, PhantomData))
+    }
+
+    fn interned(index: usize) -> Self {
+        Self(index << 1, PhantomData)
+    }
+
+    fn repr(&self) -> Repr {
+        if self.0 & 1 == 1 {
+            Repr::Constant(self.0 as i64 >> 1)
+        } else {
+            Repr::Interned(self.0 >> 1)
+        }
+    }
+
+    /// Run `f` on the interned expression at `index`
+    fn with_interned<R>(index: usize, f: impl FnOnce(&Expression) -> R) -> R {
+        INTERNED.with_borrow(|interner| f(&interner.exprs[index]))
+    }
+}
+
+impl InternedExpression {
+    /// Intern an expression, reusing the existing copy if an identical one was interned before
+    pub fn new(expr: impl Into<Expression>) -> Self {
+        let expr = expr.into();
+        if let Some(c) = expr.as_constant().and_then(Self::constant) {
+            return c;
+        }
+        INTERNED.with_borrow_mut(|interner| {
+            let Interner { exprs, indexes } = interner;
+            Self::interned(*indexes.entry(expr).or_insert_with(|| {
+                exprs.push(expr);
+                exprs.len() - 1
+            }))
+        })
+    }
+
+    /// The number of distinct symbolic expressions interned on the current thread
+    pub fn interned_count() -> usize {
+        INTERNED.with_borrow(|interner| interner.exprs.len())
+    }
+
+    /// Check if two handles point to the same stored expression. Constants are compared by value
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+
+    /// The expression this handle refers to
+    pub fn expr(&self) -> Expression {
+        match self.repr() {
+            Repr::Constant(n) => n.into(),
+            Repr::Interned(i) => Self::with_interned(i, |e| *e),
+        }
+    }
+
+    /// The value of the expression if it's a constant, without building the expression
+    pub fn as_constant(&self) -> Option<i64> {
+        match self.repr() {
+            Repr::Constant(n) => Some(n),
+            Repr::Interned(_) => None,
+        }
+    }
+
+    /// The expression on the heap
+    pub fn big(&self) -> BigExpression {
+        self.into()
+    }
+
+    /// The value of the expression, if it doesn't depend on any variables
+    pub fn to_usize(&self) -> Option<usize> {
+        match self.repr() {
+            Repr::Constant(n) => Some(n as usize),
+            Repr::Interned(i) => Self::with_interned(i, |e| e.to_usize()),
+        }
+    }
+
+    /// The symbols used in this expression
+    pub fn to_symbols(&self) -> Vec<char> {
+        match self.repr() {
+            Repr::Constant(_) => vec![],
+            Repr::Interned(i) => Self::with_interned(i, |e| e.to_symbols()),
+        }
+    }
+
+    /// Evaluate the expression with the given variable values
+    pub fn exec(&self, variables: &FxHashMap<char, usize>) -> Option<usize> {
+        match self.repr() {
+            Repr::Constant(n) => Some(n as usize),
+            Repr::Interned(i) => Self::with_interned(i, |e| e.exec(variables)),
+        }
+    }
+
+    /// Evaluate the expression with the given variable values, using a preallocated stack
+    pub fn exec_stack(
+        &self,
+        variables: &FxHashMap<char, usize>,
+        stack: &mut Vec<i64>,
+    ) -> Option<usize> {
+        match self.repr() {
+            Repr::Constant(n) => Some(n as usize),
+            Repr::Interned(i) => Self::with_interned(i, |e| e.exec_stack(variables, stack)),
+        }
+    }
+}
+
+impl Default for InternedExpression {
+    fn default() -> Self {
+        Self::constant(0).unwrap()
+    }
+}
+
+impl Debug for InternedExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.expr().fmt(f)
+    }
+}
+
+impl std::fmt::Display for InternedExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.expr(), f)
+    }
+}
+
+impl<T> PartialEq<T> for InternedExpression
+where
+    for<'a> &'a T: Into<Expression>,
+{
+    fn eq(&self, other: &T) -> bool {
+        let other: Expression = other.into();
+        match self.repr() {
+            Repr::Constant(n) => other.as_constant() == Some(n),
+            Repr::Interned(i) => Self::with_interned(i, |e| e.terms == other.terms),
+        }
+    }
+}
+
+impl Eq for InternedExpression {}
+
+impl Hash for InternedExpression {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // Every value has a single representation (constants are only interned when too big to store inline), so this agrees with eq
+        match self.repr() {
+            Repr::Constant(n) => n.hash(state),
+            Repr::Interned(i) => Self::with_interned(i, |e| e.hash(state)),
+        }
+    }
+}
+
+impl From<Expression> for InternedExpression {
+    fn from(value: Expression) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<BigExpression> for InternedExpression {
+    fn from(value: BigExpression) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<usize> for InternedExpression {
+    fn from(value: usize) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<i32> for InternedExpression {
+    fn from(value: i32) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<char> for InternedExpression {
+    fn from(value: char) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<S: ExpressionStorage> From<InternedExpression> for GenericExpression<S> {
+    fn from(value: InternedExpression) -> Self {
+        match value.repr() {
+            Repr::Constant(n) => n.into(),
+            Repr::Interned(i) => InternedExpression::with_interned(i, |e| e.into()),
+        }
+    }
+}
+
+impl<S: ExpressionStorage> From<&InternedExpression> for GenericExpression<S> {
+    fn from(value: &InternedExpression) -> Self {
+        (*value).into()
+    }
+}
+
+impl<E: Into<Expression>> Add<E> for InternedExpression {
+    type Output = Expression;
+    fn add(self, rhs: E) -> Self::Output {
+        self.expr() + rhs
+    }
+}
+
+impl<E: Into<Expression>> Sub<E> for InternedExpression {
+    type Output = Expression;
+    fn sub(self, rhs: E) -> Self::Output {
+        self.expr() - rhs
+    }
+}
+
+impl<E: Into<Expression>> Mul<E> for InternedExpression {
+    type Output = Expression;
+    fn mul(self, rhs: E) -> Self::Output {
+        self.expr() * rhs
+    }
+}
+
+impl<E: Into<Expression>> Div<E> for InternedExpression {
+    type Output = Expression;
+    fn div(self, rhs: E) -> Self::Output {
+        self.expr() / rhs
+    }
+}
+
+impl<E: Into<Expression>> Rem<E> for InternedExpression {
+    type Output = Expression;
+    fn rem(self, rhs: E) -> Self::Output {
+        self.expr() % rhs
+    }
+}
+
+impl<E: Into<Expression>> AddAssign<E> for InternedExpression {
+    fn add_assign(&mut self, rhs: E) {
+        *self = Self::new(*self + rhs);
+    }
+}
+
+impl<E: Into<Expression>> SubAssign<E> for InternedExpression {
+    fn sub_assign(&mut self, rhs: E) {
+        *self = Self::new(*self - rhs);
+    }
+}
+
+impl<E: Into<Expression>> MulAssign<E> for InternedExpression {
+    fn mul_assign(&mut self, rhs: E) {
+        *self = Self::new(*self * rhs);
+    }
+}
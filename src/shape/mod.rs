@@ -1,5 +1,6 @@
 mod axes;
 mod broadcast;
+mod intern;
 mod permute;
 mod realize;
 mod slice;
@@ -8,6 +9,7 @@ pub mod tracker;
 
 pub use axes::*;
 pub use broadcast::*;
+pub use intern::*;
 pub use permute::*;
 pub use realize::*;
 pub use slice::*;
@@ -6,31 +6,38 @@ use std::{
     },
 };
 
-use itertools::Itertools;
 use rustc_hash::FxHashMap;
 use tinyvec::ArrayVec;
 
 /// A symbolic expression stored on the stack
-pub type Expression = GenericExpression<ArrayVec<[Term; 20]>>; // We need to figure out how to reduce this, can't be fixed at 20. ShapeTracker would take up 6 dims * 12 pads * 12 slices * 20 terms * 8 bytes = 138kb
+pub type Expression = GenericExpression<ArrayVec<[Term; 20]>>; // We need to figure out how to reduce this, can't be fixed at 20. ShapeTracker stores these as InternedExpressions so it doesn't hold 30 copies inline
 /// A symbolic expression stored on the heap
 pub type BigExpression = GenericExpression<Vec<Term>>;
 
 /// A single term of a symbolic expression such as a variable, number or operation.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Term {
-    Num(i32),
+    Num(i64),
     Var(char),
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    /// Modulo with the sign of the divisor (Python's `%`), so `-1 floor_mod 256` is `255`. See [`GenericExpression::floor_mod`]
+    FloorMod,
     Min,
     Max,
     And,
     Or,
     Gte,
     Lt,
+    /// Right shift, only produced by [`GenericExpression::lower_pow2_div_mod`]
+    Shr,
+    /// Bitwise and, only produced by [`GenericExpression::lower_pow2_div_mod`]
+    BitAnd,
+    /// Ternary `cond ? a : b`, taking three operands. See [`GenericExpression::select`]
+    Select,
 }
 
 impl std::fmt::Debug for Term {
@@ -43,12 +50,16 @@ impl std::fmt::Debug for Term {
             Term::Mul => write!(f, "*"),
             Term::Div => write!(f, "/"),
             Term::Mod => write!(f, "%"),
+            Term::FloorMod => write!(f, "floor_mod"),
             Term::Min => write!(f, "min"),
             Term::Max => write!(f, "max"),
             Term::And => write!(f, "&&"),
             Term::Or => write!(f, "||"),
             Term::Gte => write!(f, ">="),
             Term::Lt => write!(f, "<"),
+            Term::Shr => write!(f, ">>"),
+            Term::BitAnd => write!(f, "&"),
+            Term::Select => write!(f, "?"),
         }
     }
 }
@@ -67,17 +78,46 @@ impl Term {
             Term::Mul => Some(|a, b| a.checked_mul(b)),
             Term::Div => Some(|a, b| a.checked_div(b)),
             Term::Mod => Some(|a, b| a.checked_rem(b)),
+            Term::FloorMod => Some(|a, b| a.checked_rem(b)?.checked_add(b)?.checked_rem(b)),
             Term::Max => Some(|a, b| Some(a.max(b))),
             Term::Min => Some(|a, b| Some(a.min(b))),
             Term::And => Some(|a, b| Some((a != 0 && b != 0) as i64)),
             Term::Or => Some(|a, b| Some((a != 0 || b != 0) as i64)),
             Term::Gte => Some(|a, b| Some((a >= b) as i64)),
             Term::Lt => Some(|a, b| Some((a < b) as i64)),
+            Term::Shr => Some(|a, b| a.checked_shr(b.try_into().ok()?)),
+            Term::BitAnd => Some(|a, b| Some(a & b)),
             _ => None,
         }
     }
 }
 
+/// Number of consecutive positions evaluated together by [`GenericExpression::exec_single_var_block`]
+pub const EXEC_LANES: usize = 8;
+
+/// An error hit while evaluating an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// No value was given for this variable
+    MissingVariable(char),
+    /// A division or modulo by zero
+    DivByZero,
+    /// An operation overflowed
+    Overflow,
+}
+
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::MissingVariable(c) => write!(f, "no value given for variable '{c}'"),
+            ExecError::DivByZero => write!(f, "division by zero"),
+            ExecError::Overflow => write!(f, "integer overflow"),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
 /// Trait implemented on the 2 main symbolic expression storage types, Vec<Term> and ArrayVec<Term>
 #[allow(clippy::len_without_is_empty)]
 pub trait ExpressionStorage:
@@ -94,6 +134,8 @@ pub trait ExpressionStorage:
     fn pop(&mut self) -> Option<Term>;
     fn remove(&mut self, index: usize) -> Term;
     fn into_vec(self) -> Vec<Term>;
+    fn as_slice(&self) -> &[Term];
+    fn as_mut_slice(&mut self) -> &mut [Term];
 }
 
 // Implement the main storage types
@@ -113,6 +155,12 @@ impl ExpressionStorage for Vec<Term> {
     fn into_vec(self) -> Vec<Term> {
         self
     }
+    fn as_slice(&self) -> &[Term] {
+        self
+    }
+    fn as_mut_slice(&mut self) -> &mut [Term] {
+        self
+    }
 }
 
 impl<const C: usize> ExpressionStorage for ArrayVec<[Term; C]>
@@ -134,6 +182,12 @@ where
     fn into_vec(self) -> Vec<Term> {
         self.to_vec()
     }
+    fn as_slice(&self) -> &[Term] {
+        self
+    }
+    fn as_mut_slice(&mut self) -> &mut [Term] {
+        self
+    }
 }
 
 /// A symbolic expression
@@ -176,6 +230,17 @@ impl<S: ExpressionStorage + Clone> Debug for GenericExpression<S> {
                     symbols.pop().unwrap(),
                     symbols.pop().unwrap()
                 ),
+                Term::FloorMod => format!(
+                    "floor_mod({}, {})",
+                    symbols.pop().unwrap(),
+                    symbols.pop().unwrap()
+                ),
+                Term::Select => format!(
+                    "({} ? {} : {})",
+                    symbols.pop().unwrap(),
+                    symbols.pop().unwrap(),
+                    symbols.pop().unwrap()
+                ),
                 _ => format!(
                     "({}{term:?}{})",
                     symbols.pop().unwrap(),
@@ -200,6 +265,43 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         reduce_triples(self)
     }
 
+    /// Get the value if the expression is a single number, without evaluating it. Expressions that haven't been
+    /// simplified may be constant and still return None, use [`to_usize`](Self::to_usize) to evaluate fully
+    pub fn as_constant(&self) -> Option<i64> {
+        match self.terms.as_slice() {
+            [Term::Num(n)] => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Get the expression as `coefficient * var^power` if it only multiplies a single variable by itself and by numbers,
+    /// such as `x * x * 3`. Lets callers lower repeated multiplications to a power, since there's no power term
+    pub fn as_monomial(&self) -> Option<(i64, char, u32)> {
+        // Coefficient, variable and power of each stack entry
+        let mut stack: Vec<(i64, Option<char>, u32)> = vec![];
+        for &term in self.terms.as_slice() {
+            let entry = match term {
+                Term::Num(n) => (n, None, 0),
+                Term::Var(c) => (1, Some(c), 1),
+                Term::Mul => {
+                    let (a_coef, a_var, a_pow) = stack.pop()?;
+                    let (b_coef, b_var, b_pow) = stack.pop()?;
+                    let var = match (a_var, b_var) {
+                        (Some(a), Some(b)) if a != b => return None,
+                        (a, b) => a.or(b),
+                    };
+                    (a_coef.checked_mul(b_coef)?, var, a_pow + b_pow)
+                }
+                _ => return None,
+            };
+            stack.push(entry);
+        }
+        match stack[..] {
+            [(coef, Some(var), pow)] => Some((coef, var, pow)),
+            _ => None,
+        }
+    }
+
     /// Minimum
     pub fn min<E: Into<Self>>(self, rhs: E) -> Self {
         let mut rhs = rhs.into();
@@ -225,6 +327,14 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         rhs.simplify()
     }
 
+    /// Modulo taking the sign of the divisor, like Python's `%`. Unlike `%`, negative values wrap around: -1 by 256 is 255
+    pub fn floor_mod<E: Into<Self>>(self, rhs: E) -> Self {
+        let mut rhs = rhs.into();
+        rhs.terms.extend(self.terms);
+        rhs.terms.push(Term::FloorMod);
+        rhs.simplify()
+    }
+
     /// Greater than or equals
     pub fn gte<E: Into<Self>>(self, rhs: E) -> Self {
         let mut rhs = rhs.into();
@@ -252,6 +362,15 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         rhs.simplify()
     }
 
+    /// `if_true` where this condition is nonzero, otherwise `if_false`
+    pub fn select<A: Into<Self>, B: Into<Self>>(self, if_true: A, if_false: B) -> Self {
+        let mut terms = if_false.into().terms;
+        terms.extend(if_true.into().terms);
+        terms.extend(self.terms);
+        terms.push(Term::Select);
+        Self { terms }.simplify()
+    }
+
     /// Substitute an expression for a variable
     pub fn substitute<N: ExpressionStorage>(self, var: char, expr: GenericExpression<N>) -> Self {
         let mut new_terms = S::default();
@@ -269,6 +388,72 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         }
         Self { terms: new_terms }.simplify()
     }
+
+    /// Substitute the values of bound dynamic dimensions and simplify, so a kernel specialized to those values can use
+    /// literal numbers rather than runtime parameters. Variables that aren't bound are left as-is
+    pub fn replace_constant_folds_in_kernel(self, dyn_map: &FxHashMap<char, usize>) -> Self {
+        let mut new_terms = S::default();
+        let mut changed = false;
+        for term in self.terms.clone().into_iter() {
+            match term {
+                Term::Var(c) if dyn_map.contains_key(&c) => {
+                    new_terms.push(Term::Num(dyn_map[&c] as i64));
+                    changed = true;
+                }
+                _ => new_terms.push(term),
+            }
+        }
+        if !changed {
+            return self;
+        }
+        Self { terms: new_terms }.simplify()
+    }
+
+    /// Symbolic derivative with respect to a variable. Min, max, mod and the logical ops are treated as
+    /// piecewise constant, so their derivative is 0 if no input depends on the variable and None otherwise.
+    pub fn derivative(&self, var: char) -> Option<Self> {
+        // Stack of (expression, derivative) pairs
+        let mut stack: Vec<(Self, Option<Self>)> = vec![];
+        for term in self.terms.clone().into_iter() {
+            let entry = match term {
+                Term::Num(_) => (term.into(), Some(0.into())),
+                Term::Var(c) => (term.into(), Some((c == var).into())),
+                Term::Select => {
+                    let (c, _) = stack.pop().unwrap();
+                    let (a, da) = stack.pop().unwrap();
+                    let (b, db) = stack.pop().unwrap();
+                    // Piecewise, so away from where the condition flips it's the derivative of the chosen branch
+                    let derivative = da.zip(db).map(|(da, db)| c.clone().select(da, db));
+                    let mut terms = b.terms;
+                    terms.extend(a.terms);
+                    terms.extend(c.terms);
+                    terms.push(term);
+                    (Self { terms }, derivative)
+                }
+                _ => {
+                    let (a, da) = stack.pop().unwrap();
+                    let (b, db) = stack.pop().unwrap();
+                    let derivative = match (term, da, db) {
+                        (Term::Add, Some(da), Some(db)) => Some(da + db),
+                        (Term::Sub, Some(da), Some(db)) => Some(da - db),
+                        (Term::Mul, Some(da), Some(db)) => Some(da * b.clone() + a.clone() * db),
+                        (Term::Div, Some(da), Some(db)) if db == 0 => Some(da / b.clone()),
+                        (Term::Div, Some(da), Some(db)) => {
+                            Some((da * b.clone() - a.clone() * db) / (b.clone() * b.clone()))
+                        }
+                        (_, Some(da), Some(db)) if da == 0 && db == 0 => Some(0.into()),
+                        _ => None,
+                    };
+                    let mut terms = b.terms;
+                    terms.extend(a.terms);
+                    terms.push(term);
+                    (Self { terms }, derivative)
+                }
+            };
+            stack.push(entry);
+        }
+        stack.pop().unwrap().1
+    }
 }
 
 impl<S: ExpressionStorage> GenericExpression<S>
@@ -288,8 +473,14 @@ where
     pub fn exec_single_var_stack(&self, value: usize, stack: &mut Vec<i64>) -> usize {
         for term in &self.terms {
             match term {
-                Term::Num(n) => stack.push(*n as i64),
+                Term::Num(n) => stack.push(*n),
                 Term::Var(_) => stack.push(value as i64),
+                Term::Select => {
+                    let c = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    stack.push(if c != 0 { a } else { b });
+                }
                 _ => {
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
@@ -299,6 +490,66 @@ where
         }
         stack.pop().unwrap() as usize
     }
+    /// Evaluate the expression for `EXEC_LANES` consecutive values of the variable starting at `start`, using one value
+    /// for all variables. Each term is applied across every lane at once, so the arithmetic can be vectorized.
+    /// A division or modulo by zero in any lane is left to scalar evaluation, so it's reported the same way.
+    pub fn exec_single_var_block(
+        &self,
+        start: usize,
+        stack: &mut Vec<[i64; EXEC_LANES]>,
+    ) -> [usize; EXEC_LANES] {
+        fn lanes(
+            a: [i64; EXEC_LANES],
+            b: [i64; EXEC_LANES],
+            f: impl Fn(i64, i64) -> i64,
+        ) -> [i64; EXEC_LANES] {
+            std::array::from_fn(|i| f(a[i], b[i]))
+        }
+        stack.clear();
+        for term in self.terms.as_slice() {
+            let value = match term {
+                Term::Num(n) => [*n; EXEC_LANES],
+                Term::Var(_) => std::array::from_fn(|i| (start + i) as i64),
+                Term::Select => {
+                    let c = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    std::array::from_fn(|i| if c[i] != 0 { a[i] } else { b[i] })
+                }
+                _ => {
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    match term {
+                        Term::Add => lanes(a, b, i64::wrapping_add),
+                        Term::Sub => lanes(a, b, i64::wrapping_sub),
+                        Term::Mul => lanes(a, b, i64::wrapping_mul),
+                        Term::Div | Term::Mod | Term::FloorMod if b.contains(&0) => {
+                            let mut scalar_stack = vec![];
+                            return std::array::from_fn(|i| {
+                                self.exec_single_var_stack(start + i, &mut scalar_stack)
+                            });
+                        }
+                        Term::Div => lanes(a, b, i64::wrapping_div),
+                        Term::Mod => lanes(a, b, i64::wrapping_rem),
+                        Term::FloorMod => lanes(a, b, |a, b| {
+                            a.wrapping_rem(b).wrapping_add(b).wrapping_rem(b)
+                        }),
+                        Term::Max => lanes(a, b, i64::max),
+                        Term::Min => lanes(a, b, i64::min),
+                        Term::And => lanes(a, b, |a, b| (a != 0 && b != 0) as i64),
+                        Term::Or => lanes(a, b, |a, b| (a != 0 || b != 0) as i64),
+                        Term::Gte => lanes(a, b, |a, b| (a >= b) as i64),
+                        Term::Lt => lanes(a, b, |a, b| (a < b) as i64),
+                        Term::Shr => lanes(a, b, |a, b| a >> b),
+                        Term::BitAnd => lanes(a, b, |a, b| a & b),
+                        Term::Num(_) | Term::Var(_) | Term::Select => unreachable!(),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        stack.pop().unwrap().map(|i| i as usize)
+    }
     /// Evaluate the expression given variables.
     pub fn exec(&self, variables: &FxHashMap<char, usize>) -> Option<usize> {
         self.exec_stack(variables, &mut Vec::new())
@@ -309,26 +560,110 @@ where
         variables: &FxHashMap<char, usize>,
         stack: &mut Vec<i64>,
     ) -> Option<usize> {
+        match self.try_exec_stack(variables, stack) {
+            Ok(n) => Some(n),
+            Err(ExecError::MissingVariable(_)) => None,
+            Err(e) => panic!("Failed to evaluate {self:?}: {e}"),
+        }
+    }
+    /// Evaluate the expression given variables, returning an error if a variable is missing or an op can't be computed
+    pub fn try_exec(&self, variables: &FxHashMap<char, usize>) -> Result<usize, ExecError> {
+        self.try_exec_stack(variables, &mut Vec::new())
+    }
+    /// Evaluate the expression given variables, returning an error if a variable is missing or an op can't be computed.
+    /// This function requires a stack to be given for use as storage
+    pub fn try_exec_stack(
+        &self,
+        variables: &FxHashMap<char, usize>,
+        stack: &mut Vec<i64>,
+    ) -> Result<usize, ExecError> {
         for term in &self.terms {
             match term {
-                Term::Num(n) => stack.push(*n as i64),
-                Term::Var(c) =>
-                {
-                    #[allow(clippy::needless_borrow)]
-                    if let Some(n) = variables.get(&c) {
-                        stack.push(*n as i64)
-                    } else {
-                        return None;
-                    }
+                Term::Num(n) => stack.push(*n),
+                Term::Var(c) => {
+                    stack.push(*variables.get(c).ok_or(ExecError::MissingVariable(*c))? as i64)
+                }
+                Term::Select => {
+                    let c = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    stack.push(if c != 0 { a } else { b });
                 }
                 _ => {
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
-                    stack.push(term.as_op().unwrap()(a, b).unwrap());
+                    match term.as_op().unwrap()(a, b) {
+                        Some(n) => stack.push(n),
+                        None if matches!(term, Term::Div | Term::Mod | Term::FloorMod)
+                            && b == 0 =>
+                        {
+                            return Err(ExecError::DivByZero)
+                        }
+                        None => return Err(ExecError::Overflow),
+                    }
                 }
             }
         }
-        stack.pop().map(|i| i as usize)
+        Ok(stack.pop().unwrap() as usize)
+    }
+    /// Rewrite divisions and modulos by powers of two into shifts and masks, which are cheaper on GPUs.
+    /// Only done where the dividend is provably non-negative, since shifts round towards negative infinity.
+    /// Meant to be ran right before rendering an expression to kernel code.
+    pub fn lower_pow2_div_mod(mut self) -> Self {
+        // For each stack entry: whether it can't be negative, and the term index if it's a lone number
+        let mut stack: Vec<(bool, Option<usize>)> = vec![];
+        for i in 0..self.terms.len() {
+            let term = self.terms.as_slice()[i];
+            let entry = match term {
+                Term::Num(n) => (n >= 0, Some(i)),
+                Term::Var(_) => (true, None),
+                Term::Select => {
+                    stack.pop();
+                    let (a_non_neg, _) = stack.pop().unwrap();
+                    let (b_non_neg, _) = stack.pop().unwrap();
+                    (a_non_neg && b_non_neg, None)
+                }
+                _ => {
+                    let (a_non_neg, _) = stack.pop().unwrap();
+                    let (b_non_neg, b_num) = stack.pop().unwrap();
+                    let non_neg = match term {
+                        Term::Sub => false,
+                        Term::Max => a_non_neg || b_non_neg,
+                        Term::And | Term::Or | Term::Gte | Term::Lt => true,
+                        Term::Shr | Term::BitAnd => a_non_neg,
+                        Term::FloorMod => b_non_neg,
+                        _ => a_non_neg && b_non_neg,
+                    };
+                    // Masking off the low bits of a two's complement number is a floor mod, whatever its sign
+                    if let (Term::FloorMod, Some(ind)) = (term, b_num) {
+                        if let Term::Num(n) = self.terms.as_slice()[ind] {
+                            if n > 0 && (n as u64).is_power_of_two() {
+                                let terms = self.terms.as_mut_slice();
+                                terms[ind] = Term::Num(n - 1);
+                                terms[i] = Term::BitAnd;
+                            }
+                        }
+                    }
+                    if let (Term::Div | Term::Mod, true, Some(ind)) = (term, a_non_neg, b_num) {
+                        if let Term::Num(n) = self.terms.as_slice()[ind] {
+                            if n > 0 && (n as u64).is_power_of_two() {
+                                let (shift, op) = if term == Term::Div {
+                                    (n.trailing_zeros() as i64, Term::Shr)
+                                } else {
+                                    (n - 1, Term::BitAnd)
+                                };
+                                let terms = self.terms.as_mut_slice();
+                                terms[ind] = Term::Num(shift);
+                                terms[i] = op;
+                            }
+                        }
+                    }
+                    (non_neg, None)
+                }
+            };
+            stack.push(entry);
+        }
+        self
     }
     /// Retrieve all symbols in the expression.
     pub fn to_symbols(&self) -> Vec<char> {
@@ -342,6 +677,14 @@ where
             .collect()
     }
 
+    /// Retrieve each distinct symbol in the expression, in sorted order.
+    pub fn distinct_symbols(&self) -> Vec<char> {
+        let mut symbols = self.to_symbols();
+        symbols.sort_unstable();
+        symbols.dedup();
+        symbols
+    }
+
     /// Check if the '-' variable exists in the expression.
     pub fn is_unknown(&self) -> bool {
         self.terms
@@ -385,25 +728,31 @@ impl<S: ExpressionStorage> From<&char> for GenericExpression<S> {
 
 impl<S: ExpressionStorage> From<usize> for GenericExpression<S> {
     fn from(value: usize) -> Self {
-        GenericExpression::from(Term::Num(value as i32))
+        GenericExpression::from(Term::Num(value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<&usize> for GenericExpression<S> {
     fn from(value: &usize) -> Self {
-        GenericExpression::from(Term::Num(*value as i32))
+        GenericExpression::from(Term::Num(*value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<i32> for GenericExpression<S> {
     fn from(value: i32) -> Self {
-        GenericExpression::from(value as usize)
+        GenericExpression::from(Term::Num(value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<&i32> for GenericExpression<S> {
     fn from(value: &i32) -> Self {
-        GenericExpression::from(*value as usize)
+        GenericExpression::from(Term::Num(*value as i64))
+    }
+}
+
+impl<S: ExpressionStorage> From<i64> for GenericExpression<S> {
+    fn from(value: i64) -> Self {
+        GenericExpression::from(Term::Num(value))
     }
 }
 
@@ -424,7 +773,7 @@ impl<S: ExpressionStorage, T: ExpressionStorage> From<&GenericExpression<T>>
 {
     fn from(value: &GenericExpression<T>) -> Self {
         let mut s = S::default();
-        s.extend(value.terms.clone().into_vec());
+        s.extend(value.terms.as_slice().iter().copied());
         Self { terms: s }
     }
 }
@@ -617,31 +966,78 @@ impl<S: ExpressionStorage, E: Into<Self>> BitOrAssign<E> for GenericExpression<S
     }
 }
 
+/// Upper bound on the number of rewrites applied while simplifying a single expression
+const MAX_REWRITE_STEPS: usize = 1000;
+
+/// Buffers reused across rewrite steps and simplifications, so simplifying doesn't allocate on every step
+#[derive(Default)]
+struct SimplifyScratch {
+    starts: Vec<usize>,
+    stack: Vec<usize>,
+    leaves: Vec<(usize, usize)>,
+    triples: Vec<(Option<usize>, usize, Option<usize>)>,
+    triple_stack: Vec<(Option<usize>, Term)>,
+    bounds: Vec<(i64, i64)>,
+}
+
+thread_local! {
+    static SIMPLIFY_SCRATCH: std::cell::Cell<SimplifyScratch> = Default::default();
+}
+
+/// Apply `step` until it reports no change. If the rewrites cycle back to an earlier form or run past
+/// `MAX_REWRITE_STEPS`, stop and return the shortest form seen, breaking ties by term order so the result is stable.
+fn rewrite_to_fixpoint<S: ExpressionStorage>(
+    mut terms: S,
+    mut step: impl FnMut(&mut S) -> bool,
+) -> S {
+    let mut best = terms.clone();
+    // Detect cycles by comparing against one saved form, re-saved at doubling intervals (Brent's algorithm),
+    // so only the best form and the saved form are ever copied
+    let mut saved = terms.clone();
+    let mut interval = 1;
+    for i in 1..=MAX_REWRITE_STEPS {
+        if !step(&mut terms) {
+            return terms;
+        }
+        if (terms.len(), terms.as_slice()) < (best.len(), best.as_slice()) {
+            best.clone_from(&terms);
+        }
+        if terms == saved {
+            break;
+        }
+        if i == interval {
+            saved.clone_from(&terms);
+            interval *= 2;
+        }
+    }
+    best
+}
+
 pub fn reduce_triples<S: ExpressionStorage>(
     mut expr: GenericExpression<S>,
 ) -> GenericExpression<S> {
     fn get_triples<S: ExpressionStorage>(
-        exp: &GenericExpression<S>,
-    ) -> Vec<(Option<usize>, usize, Option<usize>)> {
-        // Mark all terms with their index
-        let terms = exp
-            .terms
-            .clone()
-            .into_iter()
-            .enumerate()
-            .collect::<Vec<_>>();
-        let mut stack = Vec::new();
-        let mut triples = vec![];
-        for (index, term) in terms {
+        terms: &S,
+        stack: &mut Vec<(Option<usize>, Term)>,
+        triples: &mut Vec<(Option<usize>, usize, Option<usize>)>,
+    ) {
+        stack.clear();
+        triples.clear();
+        for (index, &term) in terms.as_slice().iter().enumerate() {
             match term {
                 Term::Num(_) | Term::Var(_) => stack.push((Some(index), term)),
+                Term::Select => {
+                    // Not a triple, and not constant (constant conditions are folded separately)
+                    stack.truncate(stack.len() - 3);
+                    stack.push((None, Term::Var('-')));
+                }
                 _ => {
                     let (a_ind, a_term) = stack.pop().unwrap();
                     let (b_ind, b_term) = stack.pop().unwrap();
                     triples.push((a_ind, index, b_ind));
                     if let (Term::Num(a), Term::Num(b)) = (a_term, b_term) {
-                        if let Some(c) = term.as_op().unwrap()(a as i64, b as i64) {
-                            stack.push((None, Term::Num(c as i32)));
+                        if let Some(c) = term.as_op().unwrap()(a, b) {
+                            stack.push((None, Term::Num(c)));
                         } else {
                             break;
                         }
@@ -653,12 +1049,281 @@ pub fn reduce_triples<S: ExpressionStorage>(
                 }
             }
         }
-        triples
     }
-    fn remove_terms<S: ExpressionStorage>(terms: &mut S, inds: &[usize]) {
-        for ind in inds.iter().sorted().rev() {
-            terms.remove(*ind);
+    fn remove_terms<S: ExpressionStorage>(terms: &mut S, mut inds: [usize; 2]) {
+        inds.sort_unstable();
+        for ind in inds.into_iter().rev() {
+            terms.remove(ind);
+        }
+    }
+    /// Fold numeric coefficients of nested multiplications: (x * a) * b -> x * (a * b)
+    fn fold_mul_coefficients<S: ExpressionStorage>(
+        terms: &mut S,
+        triples: &[(Option<usize>, usize, Option<usize>)],
+        (a_ind, op_ind, b_ind): (Option<usize>, usize, Option<usize>),
+    ) -> bool {
+        if terms[op_ind] != Term::Mul {
+            return false;
+        }
+        // Find the outer constant and the root of the inner expression
+        let (outer, inner) = match (a_ind, b_ind) {
+            (None, Some(b)) => (b, op_ind - 1),
+            (Some(a), None) => (a, op_ind - 2),
+            _ => return false,
+        };
+        let Term::Num(outer_val) = terms[outer] else {
+            return false;
+        };
+        if terms[inner] != Term::Mul {
+            return false;
+        }
+        let Some((ia, _, ib)) = triples.iter().find(|(_, o, _)| *o == inner) else {
+            return false;
+        };
+        let Some(num) = [*ia, *ib]
+            .into_iter()
+            .flatten()
+            .find(|i| matches!(terms[*i], Term::Num(_)))
+        else {
+            return false;
+        };
+        let Term::Num(inner_val) = terms[num] else {
+            return false;
+        };
+        let Some(c) = inner_val.checked_mul(outer_val) else {
+            return false;
+        };
+        terms[num] = Term::Num(c);
+        remove_terms(terms, [op_ind, outer]);
+        true
+    }
+    /// Find the index of the first term of the subexpression ending at each index
+    fn subexpression_starts<S: ExpressionStorage>(
+        terms: &S,
+        starts: &mut Vec<usize>,
+        stack: &mut Vec<usize>,
+    ) {
+        starts.clear();
+        starts.resize(terms.len(), 0);
+        stack.clear();
+        for i in 0..terms.len() {
+            if matches!(terms[i], Term::Num(_) | Term::Var(_)) {
+                starts[i] = i;
+            } else {
+                if terms[i] == Term::Select {
+                    stack.pop();
+                }
+                stack.pop();
+                starts[i] = stack.pop().unwrap();
+            }
+            stack.push(starts[i]);
+        }
+    }
+    /// Pick the branch of a select with a constant condition, or with both branches the same: select(1, a, b) -> a
+    fn fold_select<S: ExpressionStorage>(
+        terms: &mut S,
+        SimplifyScratch { starts, stack, .. }: &mut SimplifyScratch,
+    ) -> bool {
+        if !terms.as_slice().contains(&Term::Select) {
+            return false;
+        }
+        subexpression_starts(terms, starts, stack);
+        for i in 0..terms.len() {
+            if terms[i] != Term::Select {
+                continue;
+            }
+            let a_end = starts[i - 1] - 1;
+            let b_end = starts[a_end] - 1;
+            let (a, b) = (starts[a_end]..=a_end, starts[b_end]..=b_end);
+            let keep = match terms[i - 1] {
+                Term::Num(0) => b.clone(),
+                Term::Num(_) => a,
+                _ if terms.as_slice()[a.clone()] == terms.as_slice()[b.clone()] => a,
+                _ => continue,
+            };
+            let mut new_terms = S::default();
+            new_terms.extend(terms.as_slice()[..*b.start()].iter().copied());
+            new_terms.extend(terms.as_slice()[keep].iter().copied());
+            new_terms.extend(terms.as_slice()[i + 1..].iter().copied());
+            *terms = new_terms;
+            return true;
+        }
+        false
+    }
+    /// Fold comparisons the bounds of their sides prove constant, then drop always true sides of ands.
+    /// Variables are never negative, so in a mask (x >= 0) && (x < n) -> x < n
+    fn fold_comparisons<S: ExpressionStorage>(
+        terms: &mut S,
+        SimplifyScratch {
+            starts,
+            stack,
+            bounds,
+            ..
+        }: &mut SimplifyScratch,
+    ) -> bool {
+        if !terms
+            .as_slice()
+            .iter()
+            .any(|t| matches!(t, Term::Gte | Term::Lt | Term::And))
+        {
+            return false;
+        }
+        subexpression_starts(terms, starts, stack);
+        const UNKNOWN: (i64, i64) = (i64::MIN, i64::MAX);
+        let is_bool = |(lo, hi): (i64, i64)| lo >= 0 && hi <= 1;
+        bounds.clear();
+        for i in 0..terms.len() {
+            let bound = match terms[i] {
+                Term::Num(n) => (n, n),
+                Term::Var(_) => (0, i64::MAX),
+                Term::Select => {
+                    bounds.pop();
+                    let (a, b) = (bounds.pop().unwrap(), bounds.pop().unwrap());
+                    (a.0.min(b.0), a.1.max(b.1))
+                }
+                term => {
+                    let (a, b) = (bounds.pop().unwrap(), bounds.pop().unwrap());
+                    let a_start = starts[i - 1];
+                    // The subexpression to replace this one with, if any
+                    let replacement = match term {
+                        Term::Gte if a.0 >= b.1 => Some(vec![Term::Num(1)]),
+                        Term::Gte if a.1 < b.0 => Some(vec![Term::Num(0)]),
+                        Term::Lt if a.1 < b.0 => Some(vec![Term::Num(1)]),
+                        Term::Lt if a.0 >= b.1 => Some(vec![Term::Num(0)]),
+                        Term::And if a == (1, 1) && is_bool(b) => {
+                            Some(terms.as_slice()[starts[a_start - 1]..a_start].to_vec())
+                        }
+                        Term::And if b == (1, 1) && is_bool(a) => {
+                            Some(terms.as_slice()[a_start..i].to_vec())
+                        }
+                        _ => None,
+                    };
+                    if let Some(replacement) = replacement {
+                        let mut new_terms = S::default();
+                        new_terms.extend(terms.as_slice()[..starts[i]].iter().copied());
+                        new_terms.extend(replacement);
+                        new_terms.extend(terms.as_slice()[i + 1..].iter().copied());
+                        *terms = new_terms;
+                        return true;
+                    }
+                    let non_neg = a.0 >= 0 && b.0 >= 0;
+                    match term {
+                        Term::Add => (a.0.saturating_add(b.0), a.1.saturating_add(b.1)),
+                        Term::Sub => (a.0.saturating_sub(b.1), a.1.saturating_sub(b.0)),
+                        Term::Mul if non_neg => (a.0.saturating_mul(b.0), a.1.saturating_mul(b.1)),
+                        Term::Div if non_neg && b.0 > 0 => (a.0 / b.1, a.1 / b.0),
+                        Term::Mod if non_neg && b.0 > 0 => (0, a.1.min(b.1 - 1)),
+                        Term::FloorMod if b.0 > 0 => (0, b.1 - 1),
+                        Term::Min => (a.0.min(b.0), a.1.min(b.1)),
+                        Term::Max => (a.0.max(b.0), a.1.max(b.1)),
+                        Term::And | Term::Or | Term::Gte | Term::Lt => (0, 1),
+                        _ => UNKNOWN,
+                    }
+                }
+            };
+            bounds.push(bound);
+        }
+        false
+    }
+    /// Collect repeated variables in a sum into numeric multiples: x + y + x -> x * 2 + y
+    fn collect_like_terms<S: ExpressionStorage>(
+        terms: &mut S,
+        SimplifyScratch {
+            starts,
+            stack,
+            leaves,
+            ..
+        }: &mut SimplifyScratch,
+    ) -> bool {
+        let n = terms.len();
+        subexpression_starts(terms, starts, stack);
+        fn gather_leaves<S: ExpressionStorage>(
+            terms: &S,
+            starts: &[usize],
+            root: usize,
+            leaves: &mut Vec<(usize, usize)>,
+        ) {
+            if terms[root] == Term::Add {
+                gather_leaves(terms, starts, root - 1, leaves);
+                gather_leaves(terms, starts, starts[root - 1] - 1, leaves);
+            } else {
+                leaves.push((starts[root], root));
+            }
+        }
+        // Variables with an optional numeric coefficient
+        let as_multiple = |(start, end): (usize, usize)| match end - start {
+            0 => match terms[start] {
+                Term::Var(c) => Some((c, 1)),
+                _ => None,
+            },
+            2 if terms[end] == Term::Mul => match (terms[start], terms[start + 1]) {
+                (Term::Num(k), Term::Var(c)) | (Term::Var(c), Term::Num(k)) => Some((c, k)),
+                _ => None,
+            },
+            _ => None,
+        };
+        // Go from the root down so the largest sums are collected first
+        for root in (0..n).rev() {
+            if terms[root] != Term::Add {
+                continue;
+            }
+            leaves.clear();
+            gather_leaves(terms, starts, root, leaves);
+            let mut coefficients: Vec<(char, i64, usize)> = vec![];
+            for (c, k) in leaves.iter().filter_map(|l| as_multiple(*l)) {
+                match coefficients.iter_mut().find(|(v, _, _)| *v == c) {
+                    Some((_, total, count)) => {
+                        let Some(t) = total.checked_add(k) else {
+                            return false;
+                        };
+                        *total = t;
+                        *count += 1;
+                    }
+                    None => coefficients.push((c, k, 1)),
+                }
+            }
+            if coefficients.iter().all(|(_, _, count)| *count == 1) {
+                continue;
+            }
+            // Rebuild the sum with each variable appearing once, at its first position
+            let mut summands: Vec<Vec<Term>> = vec![];
+            for &leaf in leaves.iter() {
+                let leaf_terms = (leaf.0..=leaf.1).map(|i| terms[i]).collect::<Vec<_>>();
+                let Some((c, _)) = as_multiple(leaf) else {
+                    summands.push(leaf_terms);
+                    continue;
+                };
+                let Some(ind) = coefficients.iter().position(|(v, _, _)| *v == c) else {
+                    continue;
+                };
+                let (_, k, count) = coefficients.remove(ind);
+                if count == 1 {
+                    summands.push(leaf_terms);
+                } else if k == 1 {
+                    summands.push(vec![Term::Var(c)]);
+                } else if k != 0 {
+                    summands.push(vec![Term::Num(k), Term::Var(c), Term::Mul]);
+                }
+            }
+            let mut summands = summands.into_iter();
+            let mut sum = summands.next().unwrap_or(vec![Term::Num(0)]);
+            for mut summand in summands {
+                summand.extend(sum);
+                summand.push(Term::Add);
+                sum = summand;
+            }
+            let mut new_terms = S::default();
+            for i in 0..starts[root] {
+                new_terms.push(terms[i]);
+            }
+            new_terms.extend(sum);
+            for i in root + 1..n {
+                new_terms.push(terms[i]);
+            }
+            *terms = new_terms;
+            return true;
         }
+        false
     }
 
     #[macro_export]
@@ -671,49 +1336,59 @@ pub fn reduce_triples<S: ExpressionStorage>(
             }
         };
     }
-    let mut changed = true;
-    while changed {
-        changed = false;
-        let triples = get_triples(&expr);
-        for (a_ind, op_ind, b_ind) in triples {
+    let mut scratch = SIMPLIFY_SCRATCH.take();
+    expr.terms = rewrite_to_fixpoint(expr.terms, |terms| {
+        if fold_select(terms, &mut scratch)
+            || fold_comparisons(terms, &mut scratch)
+            || collect_like_terms(terms, &mut scratch)
+        {
+            return true;
+        }
+        get_triples(terms, &mut scratch.triple_stack, &mut scratch.triples);
+        let triples = &scratch.triples;
+        for &(a_ind, op_ind, b_ind) in triples {
+            if fold_mul_coefficients(terms, triples, (a_ind, op_ind, b_ind)) {
+                return true;
+            }
             let mut inner_changed = true;
             match (
-                a_ind.map(|a| expr.terms[a]),
-                expr.terms[op_ind],
-                b_ind.map(|b| expr.terms[b]),
+                a_ind.map(|a| terms[a]),
+                terms[op_ind],
+                b_ind.map(|b| terms[b]),
             ) {
                 (Some(Term::Num(a)), term, Some(Term::Num(b))) if term.as_op().is_some() => {
-                    if let Some(c) = term.as_op().unwrap()(a as i64, b as i64) {
-                        expr.terms[unwrap_cont!(a_ind)] = Term::Num(c as i32);
-                        remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
+                    if let Some(c) = term.as_op().unwrap()(a, b) {
+                        terms[unwrap_cont!(a_ind)] = Term::Num(c);
+                        remove_terms(terms, [op_ind, unwrap_cont!(b_ind)]);
                     } else {
                         inner_changed = false;
                     }
                 }
                 // Remove min(i, inf) and min(inf, i)
-                (Some(Term::Num(a)), Term::Min, _) if a == i32::MAX => {
-                    remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(a_ind)]);
+                (Some(Term::Num(a)), Term::Min, _) if a == i32::MAX as i64 => {
+                    remove_terms(terms, [op_ind, unwrap_cont!(a_ind)]);
                 }
-                (_, Term::Min, Some(Term::Num(b))) if b == i32::MAX => {
-                    remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
+                (_, Term::Min, Some(Term::Num(b))) if b == i32::MAX as i64 => {
+                    remove_terms(terms, [op_ind, unwrap_cont!(b_ind)]);
                 }
                 // Remove max(i, inf) and max(inf, i)
-                (_, Term::Max, Some(Term::Num(i))) if i == i32::MAX => {
-                    remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(a_ind)]);
+                (_, Term::Max, Some(Term::Num(i))) if i == i32::MAX as i64 => {
+                    remove_terms(terms, [op_ind, unwrap_cont!(a_ind)]);
                 }
-                (Some(Term::Num(i)), Term::Max, _) if i == i32::MAX => {
-                    remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
+                (Some(Term::Num(i)), Term::Max, _) if i == i32::MAX as i64 => {
+                    remove_terms(terms, [op_ind, unwrap_cont!(b_ind)]);
                 }
                 _ => {
                     inner_changed = false;
                 }
             }
             if inner_changed {
-                changed = true;
-                break;
+                return true;
             }
         }
-    }
+        false
+    });
+    SIMPLIFY_SCRATCH.set(scratch);
     expr
 }
 
@@ -729,6 +1404,47 @@ mod tests {
         assert_eq!(n.exec(&[('x', 767)].into_iter().collect()).unwrap(), 768);
     }
 
+    #[test]
+    fn test_as_constant() {
+        let mut sum = Expression::from(2);
+        sum.terms.push(Term::Num(3));
+        sum.terms.push(Term::Add);
+        assert_eq!(sum.as_constant(), None);
+        assert_eq!(sum.simplify().as_constant(), Some(5));
+        assert_eq!((Expression::from('x') + 1).as_constant(), None);
+        assert_eq!(BigExpression::from(7).as_constant(), Some(7));
+    }
+
+    #[test]
+    fn test_distinct_symbols() {
+        let expr = BigExpression::from('y') + BigExpression::from('x') * 'x';
+        assert_eq!(expr.to_symbols().len(), 3);
+        assert_eq!(expr.distinct_symbols(), vec!['x', 'y']);
+        assert!(BigExpression::from(4).distinct_symbols().is_empty());
+    }
+
+    #[test]
+    fn test_lower_pow2_div_mod() {
+        let x = BigExpression::from('x');
+        assert_eq!(
+            format!("{:?}", (x.clone() % 256).lower_pow2_div_mod()),
+            "(x&255)"
+        );
+        assert_eq!(
+            format!("{:?}", (x.clone() / 8).lower_pow2_div_mod()),
+            "(x>>3)"
+        );
+        let lowered = ((x.clone() / 4) % 8 + x.clone() % 3).lower_pow2_div_mod();
+        assert_eq!(format!("{lowered:?}"), "(((x>>2)&7)+(x%3))");
+        for v in 0..100 {
+            let vars = [('x', v)].into_iter().collect();
+            assert_eq!(lowered.exec(&vars).unwrap(), (v / 4) % 8 + v % 3);
+        }
+        // x - 10 might be negative, so it's left alone
+        let maybe_neg = (x - 10) % 4;
+        assert_eq!(maybe_neg.clone().lower_pow2_div_mod(), maybe_neg);
+    }
+
     #[test]
     fn test_minimizations() {
         let expr = ((BigExpression::from('a') * 1) + 0) / 1 + (1 - 1);
@@ -736,6 +1452,188 @@ mod tests {
         assert_eq!(reduced_expr, 'a');
     }
 
+    #[test]
+    fn test_mul_coefficients() {
+        let x = Expression::from('x');
+        assert_eq!((x * 2) * 3, x * 6);
+        assert_eq!(Expression::from(3) * (x * 2), x * 6);
+        let cube = x * x * x * 2 * 4;
+        assert_eq!(cube.exec(&[('x', 3)].into_iter().collect()).unwrap(), 216);
+        assert_eq!(cube.terms.len(), 7);
+        assert_eq!(x * 2 * 2, x * 4);
+
+        assert_eq!((x * x).as_monomial(), Some((1, 'x', 2)));
+        assert_eq!(cube.as_monomial(), Some((8, 'x', 3)));
+        assert_eq!((x * 'y').as_monomial(), None);
+        assert_eq!((x * x + 1).as_monomial(), None);
+        assert_eq!(Expression::from(4).as_monomial(), None);
+    }
+
+    #[test]
+    fn test_large_constants() {
+        let x = Expression::from('x');
+        let big = (x * 65536) * 65536;
+        assert_eq!(big, x * (1_i64 << 32));
+        assert_eq!(
+            big.exec(&[('x', 3)].into_iter().collect()).unwrap(),
+            3 << 32
+        );
+        let product = BigExpression::from(100_000) * 100_000;
+        assert_eq!(product.to_usize().unwrap(), 10_000_000_000);
+    }
+
+    #[test]
+    fn test_derivative() {
+        let x = Expression::from('x');
+        assert_eq!((x * 4 + 7).derivative('x'), Some(4.into()));
+        assert_eq!((x * 4 + 7).derivative('y'), Some(0.into()));
+        assert_eq!((x * x).derivative('x'), Some(x * 2));
+        assert_eq!((x * 6 / 2).derivative('x'), Some(3.into()));
+        assert_eq!((Expression::from('y') % 3).derivative('x'), Some(0.into()));
+        assert_eq!(x.max(3).derivative('x'), None);
+    }
+
+    #[test]
+    fn test_exec_div_by_zero() {
+        let x = Expression::from('x');
+        let vars = [('x', 3)].into_iter().collect();
+        assert_eq!(
+            (x / 'y').try_exec(&vars),
+            Err(ExecError::MissingVariable('y'))
+        );
+        assert_eq!((x / 'x').try_exec(&vars), Ok(1));
+        let zero = [('x', 3), ('y', 0)].into_iter().collect();
+        assert_eq!((x / 'y').try_exec(&zero), Err(ExecError::DivByZero));
+        assert_eq!((x % 'y').try_exec(&zero), Err(ExecError::DivByZero));
+        assert_eq!((x / 0).try_exec(&vars), Err(ExecError::DivByZero));
+    }
+
+    #[test]
+    fn test_collect_like_terms() {
+        let (x, y) = (Expression::from('x'), Expression::from('y'));
+        assert_eq!(x + y + x, x * 2 + y);
+        assert_eq!(x * 3 + y + x * 2, x * 5 + y);
+        assert_eq!((x + y + x + y).terms.len(), 7);
+        assert_eq!((x * 2 + y + x * -2).simplify(), y);
+        let sum = (x + y) / 4 + x + y + x;
+        assert_eq!(
+            sum.exec(&[('x', 5), ('y', 3)].into_iter().collect()),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn test_fold_comparisons() {
+        let (x, y) = (Expression::from('x'), Expression::from('y'));
+        // Variables are never negative, and a modulo stays under its divisor
+        assert_eq!((x * 4 + y).gte(0), 1);
+        assert_eq!((x % 8).lt(8), 1);
+        assert_eq!((x % 8).gte(8), 0);
+        // x - 1 is negative for x = 0
+        assert_eq!((x - 1).gte(0).as_constant(), None);
+
+        // Compose (x % 8 < 8) && (x < n) without the operators' literal fast paths
+        let mut mask = x.lt('n');
+        mask.terms.extend([
+            Term::Num(8),
+            Term::Num(8),
+            Term::Var('x'),
+            Term::Mod,
+            Term::Lt,
+            Term::And,
+        ]);
+        assert_eq!(mask.clone().simplify(), x.lt('n'));
+        let vars = [('x', 5), ('n', 4)].into_iter().collect();
+        assert_eq!(mask.exec(&vars), x.lt('n').exec(&vars));
+        let mut mask = Expression::from(Term::Num(1));
+        mask.terms.extend(x.lt('n').terms);
+        mask.terms.push(Term::And);
+        assert_eq!(mask.simplify(), x.lt('n'));
+        // Only boolean sides can absorb an and, since 1 && y is 1 for any positive y
+        let mut not_bool = Expression::from(Term::Num(1));
+        not_bool.terms.push(Term::Var('y'));
+        not_bool.terms.push(Term::And);
+        assert_eq!(not_bool.clone().simplify(), not_bool);
+    }
+
+    #[test]
+    fn test_rewrite_terminates() {
+        // Two rules undoing each other: commute the operands of an add back and forth
+        let commute = |terms: &mut Vec<Term>| {
+            terms.swap(0, 1);
+            true
+        };
+        let x_first = vec![Term::Var('x'), Term::Var('y'), Term::Add];
+        let y_first = vec![Term::Var('y'), Term::Var('x'), Term::Add];
+        assert_eq!(
+            super::rewrite_to_fixpoint(x_first.clone(), commute),
+            x_first
+        );
+        assert_eq!(super::rewrite_to_fixpoint(y_first, commute), x_first);
+
+        // A rule that keeps expanding stops at the cap with the shortest form
+        let expand = |terms: &mut Vec<Term>| {
+            terms.extend([Term::Num(0), Term::Add]);
+            true
+        };
+        assert_eq!(super::rewrite_to_fixpoint(x_first.clone(), expand), x_first);
+
+        let x = Expression::from('x');
+        let expr = x * 2 + 'y' + x * 3;
+        assert_eq!(expr, x * 5 + 'y');
+        assert_eq!(expr.simplify(), expr);
+    }
+
+    #[test]
+    fn test_repeated_big_simplify() {
+        let (x, y) = (BigExpression::from('x'), BigExpression::from('y'));
+        let mut expr = BigExpression::from(0);
+        for i in 1..=40 {
+            expr = expr + (x.clone() * i) + (y.clone() * 2 + 1) / 2;
+        }
+        let vars = [('x', 3), ('y', 5)].into_iter().collect();
+        let expected = expr.exec(&vars).unwrap();
+        assert_eq!(expected, 3 * 820 + 5 * 40);
+        for _ in 0..1000 {
+            let simplified = expr.clone().simplify();
+            assert_eq!(simplified.exec(&vars), Some(expected));
+            assert!(simplified.terms.len() <= expr.terms.len());
+        }
+        // Conversions between storage types go through the same terms
+        let small = Expression::from(&(x * 4 + y));
+        assert_eq!(
+            BigExpression::from(small),
+            BigExpression::from('x') * 4 + 'y'
+        );
+    }
+
+    #[test]
+    fn test_exec_block() {
+        let mut tracker = ShapeTracker::new(&[Expression::from(6), Expression::from(7)]);
+        tracker.permute(&[1, 0]);
+        tracker.pad(&[(0.into(), 2.into()), (1.into(), 0.into())]);
+        tracker.slice(&[(1.into(), 8.into()), (0.into(), 5.into())]);
+        let n = tracker.n_elements().to_usize().unwrap();
+        let (mut block_stack, mut scalar_stack) = (vec![], vec![]);
+        for expr in [tracker.index_expression(), tracker.valid_expression()] {
+            for start in (0..n).step_by(EXEC_LANES) {
+                let block = expr.exec_single_var_block(start, &mut block_stack);
+                for (i, ind) in block.into_iter().enumerate() {
+                    assert_eq!(
+                        ind,
+                        expr.exec_single_var_stack(start + i, &mut scalar_stack)
+                    );
+                }
+            }
+        }
+        let x = Expression::from('x');
+        let expr = (x / 3) % 4 + x.min(5) * (x - 2).max(0);
+        assert_eq!(
+            expr.exec_single_var_block(8, &mut block_stack),
+            std::array::from_fn(|i| expr.exec_single_var(8 + i))
+        );
+    }
+
     #[test]
     fn test_substitution() {
         let main = Expression::from('x') - 255;
@@ -743,4 +1641,72 @@ mod tests {
         let new = main.substitute('x', sub);
         assert_eq!(new, (Expression::from('x') / 2) - 255);
     }
+
+    #[test]
+    fn test_replace_constant_folds_in_kernel() {
+        let idx = BigExpression::from('z') * 'N' + BigExpression::from('M') % 4;
+        let folded = idx.replace_constant_folds_in_kernel(&[('N', 512)].into_iter().collect());
+        assert_eq!(folded.distinct_symbols(), vec!['M', 'z']);
+        assert!(format!("{folded:?}").contains("512"));
+        let vars = [('z', 3), ('M', 6)].into_iter().collect();
+        assert_eq!(folded.exec(&vars).unwrap(), 3 * 512 + 2);
+        // Nothing bound, nothing changed
+        let x = BigExpression::from('x') * 2;
+        assert_eq!(
+            x.clone()
+                .replace_constant_folds_in_kernel(&Default::default()),
+            x
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let a = BigExpression::from('a') * 2;
+        let b = BigExpression::from('b') + 1;
+        assert_eq!(BigExpression::from(1).select(a.clone(), b.clone()), a);
+        assert_eq!(BigExpression::from(0).select(a.clone(), b.clone()), b);
+        assert_eq!(BigExpression::from('c').select(a.clone(), a.clone()), a);
+        // Nested within other terms
+        let nested = (BigExpression::from(2).select(a.clone(), b.clone()) + 3) * 'x';
+        assert_eq!(nested, (a.clone() + 3) * 'x');
+        let sel = BigExpression::from('c').select(a, b) + 1;
+        for (c, expected) in [(0, 9), (3, 11)] {
+            let vars = [('a', 5), ('b', 7), ('c', c)].into_iter().collect();
+            assert_eq!(sel.exec(&vars).unwrap(), expected);
+        }
+        // Single variable paths agree with each other
+        let x = Expression::from('x');
+        let single = (x % 2).select(x * 3, x + 10);
+        let mut block_stack = vec![];
+        assert_eq!(
+            single.exec_single_var_block(4, &mut block_stack),
+            std::array::from_fn(|i| single.exec_single_var(4 + i))
+        );
+        assert_eq!(single.exec_single_var(5), 15);
+        assert_eq!(single.exec_single_var(6), 16);
+    }
+
+    #[test]
+    fn test_floor_mod() {
+        assert_eq!(Expression::from(-1).floor_mod(256), 255);
+        assert_eq!(Expression::from(-1) % 256, -1);
+        assert_eq!(Expression::from(-1).floor_mod(-256), -1);
+
+        let x = BigExpression::from('x');
+        let floor_mod = (x.clone() - 257).floor_mod(256);
+        let rem = (x - 257) % 256;
+        let vars = [('x', 0)].into_iter().collect();
+        assert_eq!(floor_mod.exec(&vars).unwrap(), 255);
+        assert_eq!(rem.exec(&vars).unwrap(), -1_i64 as usize);
+        assert_eq!(floor_mod.exec_single_var(300), 43);
+        let mut block_stack = vec![];
+        assert_eq!(
+            floor_mod.exec_single_var_block(0, &mut block_stack),
+            std::array::from_fn(|i| floor_mod.exec_single_var(i))
+        );
+        // Powers of two lower to a mask even though the dividend can be negative
+        let lowered = floor_mod.clone().lower_pow2_div_mod();
+        assert!(lowered.terms.contains(&Term::BitAnd));
+        assert_eq!(lowered.exec(&vars).unwrap(), 255);
+    }
 }
@@ -5,11 +5,11 @@ use crate::prelude::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ShapeTracker {
-    pub dims: ArrayVec<[Expression; 6]>,
+    pub dims: ArrayVec<[InternedExpression; 6]>,
     pub indexes: ArrayVec<[usize; 6]>,
     pub fake: ArrayVec<[bool; 6]>,
-    pub mask: ArrayVec<[(Expression, Expression); 6]>,
-    pub padding: ArrayVec<[(Expression, Expression); 6]>,
+    pub mask: ArrayVec<[(InternedExpression, InternedExpression); 6]>,
+    pub padding: ArrayVec<[(InternedExpression, InternedExpression); 6]>,
 }
 
 impl ShapeTracker {
@@ -22,7 +22,7 @@ impl ShapeTracker {
             padding: Default::default(),
         };
         for (i, d) in dims.iter().enumerate() {
-            s.dims.push(*d);
+            s.dims.push((*d).into());
             s.indexes.push(i);
             s.fake.push(false);
             s.mask.push((0.into(), i32::MAX.into())); // Unset upper bound mask are i32::MAX
@@ -41,7 +41,7 @@ impl ShapeTracker {
     /// Add dim along a certian axis
     pub fn add_dim(&mut self, axis: usize, dim: impl Into<Expression>) {
         self.indexes.insert(axis, self.dims.len());
-        self.dims.push(dim.into());
+        self.dims.push(InternedExpression::new(dim));
         self.fake.push(false);
         self.mask.push((0.into(), i32::MAX.into()));
         self.padding.push((0.into(), 0.into()));
@@ -64,7 +64,7 @@ impl ShapeTracker {
         }
         self.mask.remove(index);
         self.padding.remove(index);
-        self.dims.remove(index)
+        self.dims.remove(index).expr()
     }
 
     /// Permute the dimensions
@@ -190,7 +190,7 @@ impl ShapeTracker {
 
     pub fn realize(mut self, dims: &[Expression]) -> Self {
         for (i, ind) in self.indexes.iter().enumerate() {
-            self.dims[*ind] = dims[i];
+            self.dims[*ind] = dims[i].into();
         }
         self
     }
@@ -232,8 +232,8 @@ impl ShapeTracker {
     /// Take a slice
     pub fn slice(&mut self, mask: &[(Expression, Expression)]) {
         for (ind, (b, t)) in mask.iter().enumerate().map(|(i, m)| (self.indexes[i], m)) {
-            self.mask[ind].0 = self.mask[ind].0.max(b.max(0));
-            self.mask[ind].1 = self.mask[ind].1.min(t.max(0));
+            self.mask[ind].0 = self.mask[ind].0.expr().max(b.max(0)).into();
+            self.mask[ind].1 = self.mask[ind].1.expr().min(t.max(0)).into();
         }
     }
 
@@ -276,38 +276,58 @@ impl ShapeTracker {
         dyn_dim_map: &FxHashMap<char, usize>,
         stack: &mut Vec<i64>,
     ) {
+        // Constant expressions resolve to themselves, so skip them to avoid interning on every run
+        let mut resolve = |e: &mut InternedExpression| {
+            if e.as_constant().is_none() {
+                *e = e.exec_stack(dyn_dim_map, stack).unwrap().into();
+            }
+        };
         for d in self.dims.iter_mut() {
-            *d = d.exec_stack(dyn_dim_map, stack).unwrap().into();
+            resolve(d);
         }
-        for (a, b) in self.padding.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+        for (a, b) in self.padding.iter_mut().chain(self.mask.iter_mut()) {
+            resolve(a);
+            resolve(b);
         }
-        for (a, b) in self.mask.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+    }
+
+    /// Rename the dynamic dimension `from` to `to` everywhere it appears in the shape
+    pub fn relabel_dim(&mut self, from: char, to: char) {
+        let relabel = |e: &mut InternedExpression| {
+            if e.to_symbols().contains(&from) {
+                *e = e.expr().substitute(from, Expression::from(to)).into();
+            }
+        };
+        for d in self.dims.iter_mut() {
+            relabel(d);
+        }
+        for (a, b) in self.padding.iter_mut().chain(self.mask.iter_mut()) {
+            relabel(a);
+            relabel(b);
         }
     }
 
     pub fn is_sliced(&self) -> bool {
         self.mask.iter().any(|(b, e)| {
-            b.to_usize().map(|i| i != 0).unwrap_or(true)
-                || e.to_usize().map(|n| n as i32 != i32::MAX).unwrap_or(true)
+            b.as_constant().map(|i| i != 0).unwrap_or(true)
+                || e.as_constant()
+                    .map(|n| n != i32::MAX as i64)
+                    .unwrap_or(true)
         })
     }
 
     pub fn is_padded(&self) -> bool {
         self.padding.iter().any(|(b, e)| {
-            b.to_usize().map(|i| i != 0).unwrap_or(true)
-                || e.to_usize().map(|n| n != 0).unwrap_or(true)
+            b.as_constant().map(|i| i != 0).unwrap_or(true)
+                || e.as_constant().map(|n| n != 0).unwrap_or(true)
         })
     }
 }
 
 fn pad_mask_dim(
     dim: impl Into<BigExpression>,
-    padding: (Expression, Expression),
-    mask: (Expression, Expression),
+    padding: (InternedExpression, InternedExpression),
+    mask: (InternedExpression, InternedExpression),
 ) -> BigExpression {
     (dim.into() + padding.0 + padding.1).min(mask.1) - mask.0
 }
@@ -343,9 +363,9 @@ fn combine_dims(mut shape: ShapeTracker) -> ShapeTracker {
 pub fn resolve_local_dyn_dims(a: &mut ShapeTracker, b: &mut ShapeTracker, default_to_one: bool) {
     // B to A
     for i in 0..a.dims.len() {
-        if a.dims[a.indexes[i]].is_unknown() {
+        if a.dims[a.indexes[i]].expr().is_unknown() {
             a.dims[a.indexes[i]] = b.dims[b.indexes[i]];
-            if a.dims[a.indexes[i]].is_unknown() && default_to_one {
+            if a.dims[a.indexes[i]].expr().is_unknown() && default_to_one {
                 a.dims[a.indexes[i]] = 1.into();
             }
         }
@@ -353,9 +373,9 @@ pub fn resolve_local_dyn_dims(a: &mut ShapeTracker, b: &mut ShapeTracker, defaul
 
     // A to B
     for i in 0..a.dims.len() {
-        if b.dims[b.indexes[i]].is_unknown() {
+        if b.dims[b.indexes[i]].expr().is_unknown() {
             b.dims[b.indexes[i]] = a.dims[a.indexes[i]];
-            if b.dims[b.indexes[i]].is_unknown() && default_to_one {
+            if b.dims[b.indexes[i]].expr().is_unknown() && default_to_one {
                 b.dims[b.indexes[i]] = 1.into();
             }
         }
@@ -396,4 +416,34 @@ mod tests {
 
         println!("x0: {:?}", x0.shape.index_expression());
     }
+
+    #[test]
+    fn test_interned_trackers() {
+        // Expressions are interned, so trackers stay small no matter how many terms their dims have
+        assert!(std::mem::size_of::<ShapeTracker>() < 512);
+        let make = || {
+            let mut tracker =
+                ShapeTracker::new(&[Expression::from('a') * 3 + 'b', Expression::from(8)]);
+            tracker.pad(&[(0.into(), 2.into()), (1.into(), 0.into())]);
+            tracker
+        };
+        let first = make();
+        let interned = InternedExpression::interned_count();
+        let trackers = (0..1000).map(|_| make()).collect::<Vec<_>>();
+        assert_eq!(InternedExpression::interned_count(), interned);
+        for tracker in &trackers {
+            assert_eq!(*tracker, first);
+            for (a, b) in tracker.dims.iter().zip(first.dims.iter()) {
+                assert!(a.ptr_eq(b));
+            }
+        }
+
+        // Resolving to new dim values every run (like a growing kv cache) produces constants, which aren't interned
+        for len in 0..1000 {
+            let mut resolved = first;
+            resolved.resolve_global_dyn_dims(&[('a', len), ('b', 3)].into_iter().collect());
+            assert_eq!(resolved.dims[0], len * 3 + 3);
+        }
+        assert_eq!(InternedExpression::interned_count(), interned);
+    }
 }
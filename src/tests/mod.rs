@@ -12,7 +12,7 @@ use std::fmt::Debug;
 
 use rand::{distributions::uniform::SampleRange, thread_rng, Rng};
 
-use crate::prelude::*;
+use crate::{op, prelude::*};
 
 // Integration and other tests
 
@@ -70,6 +70,897 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_large_chain_build() {
+    let start = std::time::Instant::now();
+    let mut cx = Graph::with_capacity(10_001, 10_000);
+    let a = cx.tensor::<R1<4>>();
+    let end = cx.add_op_chain(
+        a.id,
+        a.shape,
+        (0..10_000).map(|i| -> Box<dyn Operator> {
+            if i % 2 == 0 {
+                Box::new(crate::op::Exp2)
+            } else {
+                Box::new(crate::op::Log2)
+            }
+        }),
+    );
+    assert_eq!(cx.node_count(), 10_001);
+    assert_eq!(cx.edge_count(), 10_000);
+    assert_ne!(end, a.id);
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn test_execution_schedule() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let mut b = a;
+    for _ in 0..10 {
+        b = b.exp2();
+    }
+    b.retrieve();
+
+    let schedule = cx.visualize_execution_schedule();
+    assert_eq!(schedule.steps.len(), 11);
+    // Only an op's input and output are ever alive together
+    assert_eq!(
+        schedule.steps.iter().map(|s| s.live.len()).max().unwrap(),
+        2
+    );
+    assert_eq!(schedule.peak, 8);
+    assert_eq!(schedule.steps[0].live, vec![((a.id, 0), 4)]);
+    assert_eq!(schedule.steps[1].freed, vec![(a.id, 0)]);
+}
+
+#[test]
+fn test_graph_signature() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<(Dyn<'s'>, Const<4>)>("Input");
+    let b = cx.named_tensor::<R1<4>>("Bias");
+    let c = (a + b.expand()).retrieve();
+
+    assert_eq!(cx.input_count(), 2);
+    assert_eq!(cx.output_count(), 1);
+    let signature = cx.signature();
+    assert_eq!(
+        signature
+            .inputs
+            .iter()
+            .map(|t| (t.node, t.name.as_str(), t.shape.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (a.id, "Input", vec!['s'.into(), 4.into()]),
+            (b.id, "Bias", vec![4.into()]),
+        ]
+    );
+    assert_eq!(signature.outputs.len(), 1);
+    assert_eq!(signature.outputs[0].node, c.id);
+    assert_eq!(
+        signature.outputs[0].shape,
+        vec![Expression::from('s'), Expression::from(4)]
+    );
+    assert_eq!(
+        signature.to_string(),
+        format!(
+            "Input Input (0): (s, 4)\nInput Bias (1): (4)\nOutput Add ({}): (s, 4)\n",
+            c.id.index()
+        )
+    );
+}
+
+#[test]
+fn test_removed_inputs_pruned() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("a");
+    let w = cx.named_parameter::<R1<3>>("w");
+    let b = cx.named_tensor::<R1<3>>("b");
+    let _ = (b * 2.).retrieve();
+    cx.remove_node(a.id);
+    cx.remove_node(w.id);
+    // Removed indices get reused by new nodes, which mustn't show up as inputs or parameters
+    let reused = [cx.constant(1.).id, cx.constant(2.).id];
+    assert!(reused.contains(&a.id) && reused.contains(&w.id));
+    assert_eq!(cx.input_count(), 1);
+    assert_eq!(
+        cx.signature()
+            .inputs
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["b"]
+    );
+    assert!(cx.parameters().is_empty());
+
+    // Remapping carries the registrations over to the replacement
+    let replacement = cx.constant(3.).id;
+    remap(b.id, replacement, (), &mut cx);
+    cx.remove_node(b.id);
+    assert_eq!(cx.inputs[&replacement].0, "b");
+}
+
+#[test]
+fn test_clone_subgraph() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let w = cx.tensor::<R1<3>>().set(vec![0.5, -1., 2.]);
+    let hidden = a * w;
+    let out = hidden.exp2().retrieve();
+
+    let first = cx.clone_subgraph(out.id, &[a.id, w.id]);
+    let second = cx.clone_subgraph(out.id, &[a.id, w.id]);
+    assert_eq!(first.len(), 2);
+    assert!(!first.contains_key(&a.id) && !first.contains_key(&w.id));
+    for map in [&first, &second] {
+        assert_ne!(map[&hidden.id], hidden.id);
+        assert_ne!(map[&out.id], out.id);
+    }
+    assert_ne!(first[&hidden.id], second[&hidden.id]);
+
+    let clone1 = GraphTensor::<R1<3>>::from_id(first[&out.id], out.shape, &mut cx).retrieve();
+    let clone2 = GraphTensor::<R1<3>>::from_id(second[&out.id], out.shape, &mut cx).retrieve();
+    cx.execute();
+    assert_exact(&out.data(), &[0.5f32.exp2(), 0.25, 64.]);
+    assert_exact(&clone1.data(), &out.data());
+    assert_exact(&clone2.data(), &out.data());
+}
+
+#[test]
+fn test_input_arity() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>();
+    let b = cx.tensor::<R1<3>>();
+    let _ = (a + b).exp2().retrieve();
+    assert_eq!(cx.check_input_arity(), Ok(()));
+
+    // A binary op with only one input wired up
+    let add = cx.add_op(crate::op::Add).input(a.id, 0, a.shape).finish();
+    assert_eq!(
+        cx.check_input_arity(),
+        Err(format!(
+            "Node {} (Add) expects 2 inputs but has 1",
+            add.index()
+        ))
+    );
+}
+
+#[test]
+fn test_replace_subgraph() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set(vec![0.5, -1., 2.]);
+    let prod = a * b;
+    let fused = prod.exp2();
+    let out = (fused + a).retrieve();
+
+    let new_node = cx.replace_subgraph(
+        &[prod.id, fused.id],
+        &[a.id, b.id],
+        Box::new(crate::op::Function(
+            "MulExp2".to_string(),
+            Box::new(|inp| {
+                let a = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                let b = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                vec![Tensor::new(
+                    a.iter()
+                        .zip(b)
+                        .map(|(a, b)| (a * b).exp2())
+                        .collect::<Vec<_>>(),
+                )]
+            }),
+        )),
+    );
+    assert!(!cx.graph.contains_node(prod.id) && !cx.graph.contains_node(fused.id));
+    assert_eq!(cx.get_sources(new_node).len(), 2);
+    assert_eq!(cx.get_sources(out.id)[0].0, new_node);
+
+    cx.execute();
+    assert_close(&out.data(), &[0.5f32.exp2() + 1., 0.25 + 2., 64. + 3.]);
+}
+
+#[test]
+fn test_check_finite() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1., 0., 2., 4.]);
+    let b = a.recip();
+    let c = (b * 0.).retrieve();
+
+    let err = cx.execute_check_finite().unwrap_err();
+    assert_eq!(err.node, b.id);
+    assert_eq!(err.op, "Recip");
+    assert_eq!(err.input_shapes, vec![vec![4]]);
+    assert_eq!((err.index, err.value), (1, f32::INFINITY));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Node {} (Recip) produced inf at index 1 of output 0 (inputs: [[4]])",
+            b.id.index()
+        )
+    );
+
+    a.set(vec![1., 2., 4., 8.]);
+    assert_eq!(cx.execute_check_finite(), Ok(()));
+    assert_exact(&c.data(), &[0.; 4]);
+}
+
+#[test]
+fn test_bypass_node() {
+    let mut cx = Graph::new();
+    let data = vec![1., 2., 3., 4., 5., 6.];
+    let a = cx.tensor::<R2<2, 3>>().set(data.clone());
+    // A contiguous op on data that's already contiguous does nothing
+    let identity = cx
+        .add_op(crate::op::Contiguous)
+        .input(a.id, 0, a.shape)
+        .finish();
+    let identity = GraphTensor::<R2<2, 3>>::from_id(identity, a.shape, &mut cx);
+    let b = (identity.exp2() + identity).retrieve();
+    identity.retrieve();
+
+    let nodes = cx.graph.node_count();
+    assert_eq!(cx.bypass_node(identity.id), a.id);
+    assert_eq!(cx.graph.node_count(), nodes - 1);
+    assert!(cx.to_retrieve.contains_key(&a.id) && cx.no_delete.contains(&a.id));
+    cx.execute();
+    assert_exact(
+        &b.data(),
+        &data.iter().map(|i| i.exp2() + i).collect::<Vec<_>>(),
+    );
+    assert_exact(
+        cx.get_tensor_ref(a.id, 0)
+            .unwrap()
+            .downcast_ref::<Vec<f32>>()
+            .unwrap(),
+        &data,
+    );
+
+    // Bypassing into a node that's already retrieved keeps its retrieval
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(data).retrieve();
+    let identity = cx
+        .add_op(crate::op::Contiguous)
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut permuted = a.shape;
+    permuted.permute(&[1, 0]);
+    cx.to_retrieve.insert(identity, (0, permuted));
+    cx.bypass_node(identity);
+    assert_eq!(cx.to_retrieve[&a.id], (0, a.shape));
+}
+
+#[test]
+fn test_set_from_iter() {
+    static WEIGHTS: [f32; 6] = [0.5, 1., 1.5, 2., 2.5, 3.];
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R2<2, 3>>()
+        .set_from_iter((1..=6).map(|i| i as f32 * 0.5));
+    let b_in = cx.tensor::<R2<2, 3>>().set_slice(&WEIGHTS);
+    let c = cx.tensor::<R2<2, 3>>().set(WEIGHTS.to_vec());
+    let (a, b, c) = (
+        a.exp2().retrieve(),
+        b_in.exp2().retrieve(),
+        c.exp2().retrieve(),
+    );
+    cx.execute();
+
+    assert_exact(&a.data(), &c.data());
+    assert_exact(&b.data(), &c.data());
+    // The slice was copied in once and is kept across runs
+    assert_exact(&b_in.data(), &WEIGHTS);
+    cx.execute();
+    assert_exact(&b.data(), &c.data());
+    // Dropping it falls back to copying from the slice
+    b_in.drop();
+    cx.execute();
+    assert_exact(&b.data(), &c.data());
+}
+
+#[test]
+fn test_max_abs_diff() {
+    let a = [1., 2., 3., 4.];
+    let b = [1.5, 2., 1., 4.25];
+    assert_exact(&abs_diff(&a, &b), &[0.5, 0., 2., 0.25]);
+    assert_eq!(max_abs_diff(&a, &b), (2, 2.));
+
+    let mut cx = Graph::new();
+    let c = cx.tensor::<R1<4>>().set(a.to_vec());
+    let d = (c + 0.5).retrieve();
+    cx.execute();
+    assert_eq!(max_abs_diff(&d.data(), &a), (0, 0.5));
+    assert!(max_abs_diff(&a, &[1., f32::NAN, 3., 9.]).1.is_nan());
+}
+
+#[test]
+fn test_tensor_with() {
+    let mut cx = Graph::new();
+    let a = cx.tensor_with::<R1<3>>(vec![1., 2., 3.]);
+    let b = a.exp2().retrieve();
+    let c = cx
+        .tensor_with_dyn::<(Dyn<'a'>, Const<2>)>(vec![1., 2., 3., 4., 5., 6.], &[3, 2])
+        .sum_reduce::<_, Axis<1>>()
+        .retrieve();
+    cx.execute();
+
+    assert_exact(&b.data(), &[2., 4., 8.]);
+    assert_exact(&c.data(), &[3., 7., 11.]);
+}
+
+#[test]
+fn test_execute_async() {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    /// Data that becomes ready once the test finishes it, like a copy completing on another thread
+    #[derive(Debug, Clone, Default)]
+    struct Delayed(Arc<Mutex<(Option<Vec<f32>>, Option<Waker>)>>);
+    impl Data for Delayed {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Option<Box<dyn Data>>> {
+            let mut state = self.0.lock().unwrap();
+            if let Some(data) = state.0.take() {
+                return Poll::Ready(Some(Box::new(data)));
+            }
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+    /// Counts how many times the future asks to be polled again
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+    impl Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut cx = Graph::new();
+    let delayed = Delayed::default();
+    let a = cx
+        .tensor::<R1<3>>()
+        .set_dyn(delayed.clone(), &[3])
+        .retrieve();
+    let b = cx.tensor::<R1<3>>().set(vec![4., 5., 6.]).exp2().retrieve();
+    let wakes = Arc::new(CountWakes::default());
+    let waker = Waker::from(wakes.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut pending = std::pin::pin!(cx.execute_async());
+
+    // Nothing is ready, and the future shouldn't ask to be polled again until something changes
+    assert!(std::future::Future::poll(pending.as_mut(), &mut context).is_pending());
+    assert!(std::future::Future::poll(pending.as_mut(), &mut context).is_pending());
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+
+    // Finishing the data wakes the stored waker
+    let waker = {
+        let mut state = delayed.0.lock().unwrap();
+        state.0 = Some(vec![1., 2., 3.]);
+        state.1.take().unwrap()
+    };
+    waker.wake();
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    assert!(std::future::Future::poll(pending.as_mut(), &mut context).is_ready());
+
+    assert_exact(&a.data(), &[1., 2., 3.]);
+    assert_exact(&b.data(), &[16., 32., 64.]);
+}
+
+#[test]
+fn test_execute_catch_panics() {
+    #[derive(Debug)]
+    struct Panicking;
+    impl Operator for Panicking {
+        fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            panic!("bad request")
+        }
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.add_op(Panicking).input(a.id, 0, a.shape).finish();
+    let b = GraphTensor::<R1<3>>::from_id(b, a.shape, &mut cx);
+    let c = (b + 1.).retrieve();
+
+    let err = cx.execute_catch_panics().unwrap_err();
+    assert_eq!(err.node, b.id);
+    assert_eq!(err.op, "Panicking");
+    assert_eq!(err.message, "bad request");
+    assert_eq!(
+        err.to_string(),
+        format!("Node {} (Panicking) panicked: bad request", b.id.index())
+    );
+    assert_eq!(c.try_data(), Err(RetrieveError::NotExecuted(c.id)));
+}
+
+#[test]
+fn test_set_all_inputs() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("a");
+    let b = cx.named_tensor::<R1<3>>("b");
+    let w = cx.named_parameter::<R1<3>>("w").set([1., 1., 1.]);
+    let c = cx.tensor::<R1<3>>().set([0., 0., 1.]);
+    let d = (a * b + w + c).retrieve();
+
+    cx.set_all_inputs(
+        [("a", vec![1., 2., 3.]), ("b", vec![4., 5., 6.])]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+    .unwrap();
+    cx.execute();
+    assert_exact(&d.data(), &[5., 11., 20.]);
+
+    let err = cx
+        .set_all_inputs(
+            [("a", vec![1., 2., 3.]), ("x", vec![0.])]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+        .unwrap_err();
+    assert_eq!(
+        err,
+        InputMismatch {
+            missing: vec!["b".to_string()],
+            unexpected: vec!["x".to_string()],
+            wrong_length: vec![],
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Missing inputs: [b], unexpected inputs: [x]"
+    );
+
+    let err = cx
+        .set_all_inputs(
+            [("a", vec![1., 2.]), ("b", vec![4., 5., 6.])]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+        .unwrap_err();
+    assert_eq!(err.wrong_length, vec![("a".to_string(), 3, 2)]);
+    assert_eq!(
+        err.to_string(),
+        "Missing inputs: [], unexpected inputs: [], wrong lengths: [a (expected 3, got 2)]"
+    );
+    // Nothing was set, so the last good inputs are still in place
+    d.drop();
+    cx.execute();
+    assert_exact(&d.data(), &[5., 11., 20.]);
+}
+
+#[test]
+fn test_to_json() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("a");
+    let b = cx.named_tensor::<R1<3>>("b");
+    let c = (a + b).retrieve();
+
+    let json: serde_json::Value = serde_json::from_str(&cx.to_json()).unwrap();
+    let nodes = json["nodes"].as_array().unwrap();
+    assert_eq!(
+        nodes
+            .iter()
+            .map(|n| n["op"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["a Load", "b Load", "Add"]
+    );
+    assert_eq!(nodes[2]["id"], c.id.index());
+    assert_eq!(nodes[2]["shape"], serde_json::json!(["3"]));
+    let edges = json["edges"].as_array().unwrap();
+    assert_eq!(edges.len(), 2);
+    assert_eq!(edges[1]["src"], b.id.index());
+    assert_eq!(edges[1]["dst"], c.id.index());
+    assert_eq!(edges[1]["input"], 1);
+}
+
+#[test]
+fn test_structural_diff() {
+    let build = |cx: &mut Graph| {
+        let a = cx.named_tensor::<R2<3, 1>>("a");
+        a.sum_reduce::<_, Axis<1>>().exp2().retrieve()
+    };
+    let mut before = Graph::new();
+    build(&mut before);
+    let mut after = Graph::new();
+    let mut b = build(&mut after);
+    after.compile(RemoveSingleReductions, &mut b);
+
+    assert!(before.structural_diff(&before).is_empty());
+    let diff = before.structural_diff(&after);
+    assert_eq!(
+        diff,
+        GraphDiff {
+            added_nodes: vec!["a Load [3]".to_string()],
+            removed_nodes: vec!["SumReduce(1) [3]".to_string(), "a Load [3, 1]".to_string()],
+            added_edges: vec!["a Load [3] (0) -> Exp2 [3] (0)".to_string()],
+            removed_edges: vec![
+                "SumReduce(1) [3] (0) -> Exp2 [3] (0)".to_string(),
+                "a Load [3, 1] (0) -> SumReduce(1) [3] (0)".to_string()
+            ],
+        }
+    );
+    assert!(diff
+        .to_string()
+        .starts_with("+ node a Load [3]\n- node SumReduce(1) [3]\n"));
+}
+
+#[test]
+fn test_fuse_layernorm() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<3, 8>>().set(random_vec(24));
+    let weight = cx.tensor::<R1<8>>().set(random_vec(8));
+    let bias = cx.tensor::<R1<8>>().set(random_vec(8));
+    let mut b = (a.layer_norm::<Axis<1>, _>(1e-3) * weight.expand() + bias.expand()).retrieve();
+    let mut c = a.layer_norm::<Axis<0>, _>(1e-5).retrieve();
+    cx.execute();
+    let (unfused_b, unfused_c) = (b.data(), c.data());
+    b.drop();
+    c.drop();
+
+    cx.fuse_layernorm((&mut b, &mut c));
+    let norms = cx
+        .graph
+        .node_weights()
+        .filter_map(|op| op.as_any().downcast_ref::<op::FusedLayerNorm>().cloned())
+        .collect::<Vec<_>>();
+    assert_eq!(norms.len(), 2);
+    assert!(norms.contains(&op::FusedLayerNorm {
+        axis: 1,
+        epsilon: 1e-3,
+        scale: true,
+        shift: true
+    }));
+    assert!(norms.contains(&op::FusedLayerNorm {
+        axis: 0,
+        epsilon: 1e-5,
+        scale: false,
+        shift: false
+    }));
+    assert!(!cx
+        .graph
+        .node_weights()
+        .any(|op| op.as_any().is::<op::Sqrt>()));
+    cx.execute();
+
+    assert_close(&b.data(), &unfused_b);
+    assert_close(&c.data(), &unfused_c);
+}
+
+#[test]
+fn test_fuse_layernorm_permuted_consumer() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<3, 8>>().set(random_vec(24));
+    let weight = cx.tensor::<R2<8, 3>>().set(random_vec(24));
+    let mut b = (a.layer_norm::<Axis<1>, _>(1e-5).permute::<_, Axes2<1, 0>>() * weight).retrieve();
+    cx.execute();
+    let unfused = b.data();
+    b.drop();
+
+    cx.fuse_layernorm(&mut b);
+    // The multiply reads the norm transposed, so it can't be folded in as the scale
+    let norms = cx
+        .graph
+        .node_weights()
+        .filter_map(|op| op.as_any().downcast_ref::<op::FusedLayerNorm>().cloned())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        norms,
+        vec![op::FusedLayerNorm {
+            axis: 1,
+            epsilon: 1e-5,
+            scale: false,
+            shift: false
+        }]
+    );
+    cx.execute();
+
+    assert_close(&b.data(), &unfused);
+}
+
+#[test]
+fn test_op_histogram() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>();
+    let b = cx.tensor::<R1<3>>();
+    ((a + b) * a + b).exp2().retrieve();
+
+    let hist = cx.op_histogram();
+    assert_eq!(hist["Function"], 2);
+    assert_eq!(hist["Add"], 2);
+    assert_eq!(hist["Mul"], 1);
+    assert_eq!(hist["Exp2"], 1);
+    assert_eq!(hist.values().sum::<usize>(), cx.graph.node_count());
+}
+
+#[test]
+fn test_dedupe_constant_inputs() {
+    let mut cx = Graph::new();
+    let x = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let biases = (0..3)
+        .map(|_| cx.tensor::<R1<3>>().set([0.5, 0., -1.]).mark_constant())
+        .collect::<Vec<_>>();
+    let other = cx.tensor::<R1<3>>().set([0.5, 0., 1.]).mark_constant();
+    // Unmarked inputs, deferred loaders, parameters and named inputs can change between runs, so they're never merged
+    let unmarked = cx.tensor::<R1<3>>().set([0.5, 0., -1.]);
+    let deferred = cx
+        .tensor::<R1<3>>()
+        .mark_constant()
+        .set_deferred(|| vec![0.5, 0., -1.]);
+    let named = cx
+        .named_tensor::<R1<3>>("bias")
+        .set([0.5, 0., -1.])
+        .mark_constant();
+    let param = cx.tensor::<R1<3>>().set([0.5, 0., -1.]).mark_constant();
+    cx.params.push(param.id);
+    let unset = cx.tensor::<R1<3>>();
+    let mut out =
+        ((x + biases[0]) * biases[1] - biases[2] + other + deferred + unmarked + named + param)
+            .retrieve();
+    let mut doubled = (unset * 2.).retrieve();
+    assert_eq!(cx.op_histogram()["Function"], 10);
+
+    cx.compile(DedupeConstantInputs, (&mut out, &mut doubled));
+    assert_eq!(cx.op_histogram()["Function"], 8);
+    assert_eq!(cx.input_count(), 8);
+    unset.set([1., 2., 3.]);
+    cx.execute();
+
+    assert_exact(&out.data(), &[2.75, 0., -4.]);
+    assert_exact(&doubled.data(), &[2., 4., 6.]);
+}
+
+#[test]
+fn test_insert_cast_ops() {
+    let mut cx = Graph::new();
+    let a_data = random_vec(6);
+    let b_data = random_vec(6);
+    // Mixing dtypes takes opting out of the bf16 handle, since the ops can't read bf16 until casts are inserted
+    let a = cx
+        .tensor::<R1<6>>()
+        .set(a_data.clone())
+        .cast::<bf16>()
+        .into_mixed();
+    let b = cx.tensor::<R1<6>>().set(b_data.clone());
+    let c = (a + b).retrieve();
+    let d = (b * a).retrieve();
+    cx.insert_cast_ops();
+    // One cast up to f32 is shared by both ops
+    assert_eq!(cx.op_histogram()["CastToF32"], 1);
+    cx.execute();
+
+    assert!(cx.get_tensor_ref(c.id, 0).unwrap().is::<Vec<f32>>());
+    let a_data = a_data
+        .iter()
+        .map(|f| bf16::from_f32(*f).to_f32())
+        .collect::<Vec<_>>();
+    assert_close(
+        &c.data(),
+        &a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>(),
+    );
+    assert_close(
+        &d.data(),
+        &a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| a * b)
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_mark_debug() {
+    let mut cx = Graph::new();
+    let (a_data, b_data, c_data) = (random_vec(4), random_vec(4), random_vec(4));
+    let a = cx.tensor::<R1<4>>().set(a_data.clone());
+    let b = cx.tensor::<R1<4>>().set(b_data.clone());
+    let c = cx.tensor::<R1<4>>().set(c_data.clone());
+    let hidden = a + b;
+    let act = (hidden * c).relu();
+    let mut out = (act + a).retrieve();
+    let mut debug = hidden.id;
+    cx.mark_debug(debug);
+    cx.compile(GenericCompiler::default(), (&mut out, &mut debug));
+    cx.execute();
+
+    let expected = a_data
+        .iter()
+        .zip(&b_data)
+        .map(|(a, b)| a + b)
+        .collect::<Vec<_>>();
+    assert_close(&cx.get_debug(debug).unwrap(), &expected);
+    assert_close(
+        &out.data(),
+        &expected
+            .iter()
+            .zip(&c_data)
+            .zip(&a_data)
+            .map(|((h, c), a)| (h * c).max(0.) + a)
+            .collect::<Vec<_>>(),
+    );
+    // Unmarked intermediates are freed
+    assert!(cx.get_debug(act.id).is_none());
+}
+
+#[test]
+fn test_tensor_dyn() {
+    let mut cx = Graph::new();
+    let a = cx.tensor_dyn(&['N'.into(), 4.into()]);
+    assert_eq!(a.shape.len(), 2);
+    let data = random_vec(12);
+    a.set_dyn(data.clone(), &[3, 4]);
+    let b = a.sum_reduce_axis::<()>(-1).retrieve();
+    cx.execute();
+
+    assert_eq!(cx.dyn_map[&'N'], 3);
+    assert_close(
+        &b.data(),
+        &data.chunks(4).map(|r| r.iter().sum()).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_outputs() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>();
+    let b = (a * 2.).retrieve();
+    let outputs = cx.outputs();
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].0, b.id);
+    assert_eq!(outputs[0].1.shape_usize(), vec![2, 3]);
+
+    // Dynamic dimensions are resolved once they're set
+    let c = cx.tensor::<(Dyn<'s'>, Const<3>)>();
+    let d = c.exp().retrieve();
+    let shape = |cx: &Graph| {
+        cx.outputs()
+            .into_iter()
+            .find(|(n, _)| *n == d.id)
+            .unwrap()
+            .1
+    };
+    assert_eq!(
+        shape(&cx).shape(),
+        vec![BigExpression::from('s'), BigExpression::from(3)]
+    );
+    cx.set_dyn_dim('s', 5);
+    assert_eq!(shape(&cx).shape_usize(), vec![5, 3]);
+}
+
+#[test]
+fn test_try_data() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = a * 2.;
+    let c = (b + 1.).retrieve();
+
+    assert_eq!(b.try_data(), Err(RetrieveError::NotMarked(b.id)));
+    assert_eq!(c.try_data(), Err(RetrieveError::NotExecuted(c.id)));
+    cx.execute();
+    assert_eq!(b.try_data(), Err(RetrieveError::NotMarked(b.id)));
+    assert_eq!(c.try_data(), Ok(vec![3., 5., 7.]));
+}
+
+#[test]
+fn test_try_execute() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'s'>,)>();
+    let b = a.exp2().retrieve();
+    assert_eq!(
+        cx.try_execute(),
+        Err(GraphError::UnresolvedDim {
+            node: b.id,
+            op: "Exp2".to_string(),
+            input: 0,
+            dim: 's',
+        })
+    );
+
+    a.set_dyn(vec![0., 1., 2.], &[3]);
+    assert_eq!(cx.try_execute(), Ok(()));
+    assert_exact(&b.data(), &[1., 2., 4.]);
+}
+
+#[test]
+fn test_validate() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'s'>,)>();
+    let b = a.exp2().retrieve();
+    assert_eq!(
+        cx.validate(),
+        vec![GraphError::UnresolvedDim {
+            node: b.id,
+            op: "Exp2".to_string(),
+            input: 0,
+            dim: 's',
+        }]
+    );
+
+    // Also wire up an add with a missing input
+    cx.set_dyn_dim('s', 3);
+    let add = cx.add_op(crate::op::Add).input(a.id, 0, a.shape).finish();
+    cx.graph.remove_node(b.id);
+    let errors = cx.validate();
+    assert_eq!(
+        errors,
+        vec![
+            GraphError::WrongArity {
+                node: add,
+                op: "Add".to_string(),
+                expected: 2,
+                found: 1
+            },
+            GraphError::DanglingRetrieve(b.id),
+        ]
+    );
+}
+
+#[test]
+fn test_assert_acyclic() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>();
+    let b = a.exp2();
+    let c = b.sin();
+    let d = c.sqrt().retrieve();
+    assert_eq!(cx.assert_acyclic(), Ok(()));
+
+    // Rewire the sqrt back into the exp2, closing b -> c -> d -> b
+    cx.graph.add_edge(
+        d.id,
+        b.id,
+        Dependency::Data {
+            input_order: 1,
+            output_order: 0,
+            shape: d.shape,
+        },
+    );
+    let mut cycle = cx.assert_acyclic().unwrap_err();
+    assert_eq!(cycle.len(), 3);
+    let first = cycle.iter().position(|n| *n == b.id).unwrap();
+    cycle.rotate_left(first);
+    assert_eq!(cycle, vec![b.id, c.id, d.id]);
+}
+
+#[test]
+fn test_dfdx_round_trip() {
+    let mut cx = Graph::new();
+    let data = random_vec(12);
+    let a = cx.tensor::<R2<3, 4>>().set(data.clone()).retrieve();
+    let b = a.permute::<R2<4, 3>, _>().retrieve();
+    cx.execute();
+
+    let d_dev = dfdx::tensor::Cpu::default();
+    let d_a = to_dfdx(&a, &d_dev);
+    let d_b = to_dfdx(&b, &d_dev);
+    assert_exact(&d_a.as_vec(), &data);
+    let transposed = (0..12)
+        .map(|i| data[(i % 3) * 4 + i / 3])
+        .collect::<Vec<_>>();
+    assert_exact(&d_b.as_vec(), &transposed);
+
+    let mut cx = Graph::new();
+    let c: GraphTensor<R2<3, 4>> = from_dfdx(&d_a, &mut cx).retrieve();
+    cx.execute();
+    assert_exact(&c.data(), &data);
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_close_precision(a_vec, b_vec, 1e-3);
@@ -80,19 +971,39 @@ pub fn assert_close_precision(a_vec: &[f32], b_vec: &[f32], threshold: f32) {
     assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
     for (i, (a, b)) in a_vec.iter().zip(b_vec.iter()).enumerate() {
         if (a - b).abs() > threshold {
+            let (worst, max_diff) = max_abs_diff(a_vec, b_vec);
             panic!(
-                "{a} is not close to {b}, index {i}, avg distance: {}",
-                a_vec
-                    .iter()
-                    .zip(b_vec.iter())
-                    .map(|(a, b)| (a - b).abs())
-                    .sum::<f32>()
-                    / a_vec.len() as f32
+                "{a} is not close to {b}, index {i}, max distance: {max_diff} at index {worst}, avg distance: {}",
+                abs_diff(a_vec, b_vec).into_iter().sum::<f32>() / a_vec.len() as f32
             );
         }
     }
 }
 
+/// Elementwise absolute difference between two arrays
+pub fn abs_diff(a_vec: &[f32], b_vec: &[f32]) -> Vec<f32> {
+    assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
+    a_vec
+        .iter()
+        .zip(b_vec.iter())
+        .map(|(a, b)| (a - b).abs())
+        .collect()
+}
+
+/// The index and value of the largest absolute difference between two arrays. NaNs count as the largest difference.
+pub fn max_abs_diff(a_vec: &[f32], b_vec: &[f32]) -> (usize, f32) {
+    abs_diff(a_vec, b_vec)
+        .into_iter()
+        .enumerate()
+        .fold((0, 0.), |(i, max), (j, d)| {
+            if d > max || (d.is_nan() && !max.is_nan()) {
+                (j, d)
+            } else {
+                (i, max)
+            }
+        })
+}
+
 /// Ensure two arrays are exactly equal
 pub fn assert_exact<T: PartialEq + Debug>(a_vec: &[T], b_vec: &[T]) {
     assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
@@ -112,6 +1023,58 @@ pub fn random_vec_rng<R: Rng>(n: usize, rng: &mut R) -> Vec<f32> {
     (0..n).map(|_| rng.gen_range(-0.5..0.5)).collect()
 }
 
+/// Maps a constant luminal shape onto the equivalent dfdx shape
+#[cfg(test)]
+pub trait ToDfdxShape: ConstShape {
+    type Dfdx: dfdx::shapes::ConstShape;
+}
+
+#[cfg(test)]
+impl ToDfdxShape for () {
+    type Dfdx = ();
+}
+
+#[cfg(test)]
+macro_rules! impl_dfdx_shape {
+    ($($d:ident),+) => {
+        impl<$(const $d: usize),+> ToDfdxShape for ($(Const<$d>,)+) {
+            type Dfdx = ($(dfdx::shapes::Const<$d>,)+);
+        }
+    };
+}
+
+#[cfg(test)]
+impl_dfdx_shape!(A);
+#[cfg(test)]
+impl_dfdx_shape!(A, B);
+#[cfg(test)]
+impl_dfdx_shape!(A, B, C);
+#[cfg(test)]
+impl_dfdx_shape!(A, B, C, D);
+#[cfg(test)]
+impl_dfdx_shape!(A, B, C, D, E);
+#[cfg(test)]
+impl_dfdx_shape!(A, B, C, D, E, F);
+
+/// Convert a retrieved tensor into a dfdx tensor of the same shape
+#[cfg(test)]
+pub fn to_dfdx<S: ToDfdxShape>(
+    tensor: &GraphTensor<S>,
+    dev: &dfdx::tensor::Cpu,
+) -> dfdx::tensor::Tensor<S::Dfdx, f32, dfdx::tensor::Cpu> {
+    use dfdx::tensor::TensorFromVec;
+    dev.tensor_from_vec(tensor.data(), S::Dfdx::default())
+}
+
+/// Create a new input tensor in the graph holding the data of a dfdx tensor
+#[cfg(test)]
+pub fn from_dfdx<S: ToDfdxShape>(
+    tensor: &dfdx::tensor::Tensor<S::Dfdx, f32, dfdx::tensor::Cpu>,
+    cx: &mut Graph,
+) -> GraphTensor<S> {
+    cx.tensor::<S>().set(tensor.as_vec())
+}
+
 #[macro_export]
 macro_rules! test_imports {
     () => {
@@ -127,9 +1090,11 @@ macro_rules! test_imports {
                 Axis as LAxis, Const as LConst, *,
             },
             tests::{
+                abs_diff,
                 assert_close,
                 assert_close_precision,
                 assert_exact,
+                max_abs_diff,
                 // harness::{test_compilers_close, test_compilers_exact},
                 random_vec,
                 random_vec_rng,